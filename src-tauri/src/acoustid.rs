@@ -0,0 +1,230 @@
+use crate::fingerprint::Fingerprint;
+use crate::musicbrainz_client::DEFAULT_USER_AGENT;
+use crate::rate_limiter::RateLimiter;
+use crate::service_settings::{validate_service_url, ClientSettings, SharedSettings};
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::header::USER_AGENT;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+pub const DEFAULT_ENDPOINT: &str = "https://api.acoustid.org/v2/lookup";
+
+// AcoustID allows three requests per second
+const REQUEST_INTERVAL: Duration = Duration::from_millis(334);
+
+/// Where fingerprints are looked up and the application key that AcoustID
+/// requires, registered at https://acoustid.org/new-application
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AcoustIdSettings {
+    pub endpoint: String,
+    pub api_key: String,
+}
+
+impl Default for AcoustIdSettings {
+    fn default() -> Self {
+        AcoustIdSettings {
+            endpoint: DEFAULT_ENDPOINT.to_string(),
+            api_key: String::new(),
+        }
+    }
+}
+
+impl ClientSettings for AcoustIdSettings {
+    fn validate(&self) -> Result<()> {
+        validate_service_url("endpoint", &self.endpoint)
+    }
+}
+
+/// A MusicBrainz recording with the fingerprint, and how sure AcoustID is of it
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct AcoustIdMatch {
+    pub recording_id: String,
+    /// From 0 to 1
+    pub score: f64,
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    status: String,
+    #[serde(default)]
+    results: Vec<LookupResult>,
+    error: Option<LookupError>,
+}
+
+#[derive(Deserialize)]
+struct LookupResult {
+    score: f64,
+    #[serde(default)]
+    recordings: Vec<LookupRecording>,
+}
+
+#[derive(Deserialize)]
+struct LookupRecording {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct LookupError {
+    message: String,
+}
+
+pub struct AcoustIdClient {
+    client: Client,
+    settings: SharedSettings<AcoustIdSettings>,
+    rate_limiter: RateLimiter,
+}
+
+impl AcoustIdClient {
+    pub fn new(settings: AcoustIdSettings) -> Result<AcoustIdClient> {
+        Ok(AcoustIdClient {
+            client: Client::builder().build()?,
+            settings: SharedSettings::new(settings)?,
+            rate_limiter: RateLimiter::new(REQUEST_INTERVAL),
+        })
+    }
+
+    pub fn settings(&self) -> AcoustIdSettings {
+        self.settings.get()
+    }
+
+    pub fn set_settings(&self, settings: AcoustIdSettings) -> Result<()> {
+        self.settings.set(settings)
+    }
+
+    /// The recordings with the fingerprint, most likely first. A recording
+    /// that is part of several results keeps its best score.
+    pub async fn lookup(&self, fingerprint: &Fingerprint) -> Result<Vec<AcoustIdMatch>> {
+        let settings = self.settings();
+        if settings.api_key.trim().is_empty() {
+            bail!("No AcoustID API key is set");
+        }
+        let duration = fingerprint.duration_secs.to_string();
+        // fingerprints are long, so they go in the body
+        let form = [
+            ("format", "json"),
+            ("client", settings.api_key.trim()),
+            ("meta", "recordingids"),
+            ("duration", duration.as_str()),
+            ("fingerprint", fingerprint.fingerprint.as_str()),
+        ];
+
+        self.rate_limiter.wait_for_turn().await;
+        let response = self
+            .client
+            .post(&settings.endpoint)
+            .header(USER_AGENT, DEFAULT_USER_AGENT)
+            .form(&form[..])
+            .send()
+            .await
+            .with_context(|| format!("Request to {} failed", settings.endpoint))?;
+        // errors come with a status other than 200 but still as JSON
+        let status = response.status();
+        let body = response.text().await?;
+        let response: LookupResponse = serde_json::from_str(&body)
+            .map_err(|_| anyhow!("AcoustID answered {} for {}", status, settings.endpoint))?;
+        if response.status != "ok" {
+            let message = response.error.map(|error| error.message).unwrap_or(response.status);
+            bail!("AcoustID lookup failed: {}", message);
+        }
+
+        let mut matches: Vec<AcoustIdMatch> = Vec::new();
+        for result in response.results {
+            for recording in result.recordings {
+                match matches.iter_mut().find(|m| m.recording_id == recording.id) {
+                    Some(existing) => existing.score = existing.score.max(result.score),
+                    None => matches.push(AcoustIdMatch {
+                        recording_id: recording.id,
+                        score: result.score,
+                    }),
+                }
+            }
+        }
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{Matcher, Server};
+
+    fn client(endpoint: String) -> AcoustIdClient {
+        let settings = AcoustIdSettings {
+            endpoint,
+            api_key: "key".to_string(),
+        };
+        AcoustIdClient::new(settings).unwrap()
+    }
+
+    fn fingerprint() -> Fingerprint {
+        Fingerprint {
+            fingerprint: "AQAAS0kUZUkSZSpw".to_string(),
+            duration_secs: 241,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup_merges_and_sorts_recordings() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/v2/lookup")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("client".into(), "key".into()),
+                Matcher::UrlEncoded("meta".into(), "recordingids".into()),
+                Matcher::UrlEncoded("duration".into(), "241".into()),
+                Matcher::UrlEncoded("fingerprint".into(), "AQAAS0kUZUkSZSpw".into()),
+            ]))
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"status": "ok", "results": [
+                    {"id": "a", "score": 0.6, "recordings": [{"id": "r1"}, {"id": "r2"}]},
+                    {"id": "b", "score": 0.9, "recordings": [{"id": "r2"}]},
+                    {"id": "c", "score": 0.95}
+                ]}"#,
+            )
+            .create_async()
+            .await;
+
+        let matches = client(format!("{}/v2/lookup", server.url()))
+            .lookup(&fingerprint())
+            .await
+            .unwrap();
+        mock.assert_async().await;
+        assert_eq!(
+            matches,
+            vec![
+                AcoustIdMatch {
+                    recording_id: "r2".to_string(),
+                    score: 0.9,
+                },
+                AcoustIdMatch {
+                    recording_id: "r1".to_string(),
+                    score: 0.6,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_error() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("POST", "/v2/lookup")
+            .with_status(400)
+            .with_body(r#"{"status": "error", "error": {"code": 4, "message": "invalid API key"}}"#)
+            .create_async()
+            .await;
+
+        let error = client(format!("{}/v2/lookup", server.url()))
+            .lookup(&fingerprint())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("invalid API key"));
+
+        let no_key = AcoustIdClient::new(AcoustIdSettings::default()).unwrap();
+        assert!(no_key.lookup(&fingerprint()).await.is_err());
+    }
+}
@@ -1,50 +1,69 @@
-use crate::player::shared::PlaybackState;
+use crate::player::shared::{AudioPlayerCommand, PlaybackState};
 use anyhow::{anyhow, Context, Error};
 use cpal::traits::{DeviceTrait, HostTrait};
 use cpal::{
     default_host, Device, FromSample, OutputCallbackInfo, Sample, SampleFormat, SizedSample,
-    Stream, StreamConfig,
+    Stream, StreamConfig, StreamError, SupportedStreamConfig,
 };
 use ringbuf::consumer::Consumer;
 use ringbuf::HeapCons;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 
 pub fn start_cpal_audio_stream(
     state: Arc<Mutex<PlaybackState>>,
     sample_consumer: HeapCons<f32>,
-    sample_rate: u32,
-    channels: u16,
+    device: &Device,
+    output_config: &SupportedStreamConfig,
+    player_command_sender: Sender<AudioPlayerCommand>,
+    generation: u64,
 ) -> Result<Stream, Error> {
-    let device = get_default_audio_device()?;
-
-    let default_config = device.default_output_config()?;
-
-    // Use the audio file's sample rate and channel count, not the device's
+    // samples arrive already converted to the sample rate and channels of the output
     let config = StreamConfig {
-        channels,
-        sample_rate,
+        channels: output_config.channels(),
+        sample_rate: output_config.sample_rate(),
         buffer_size: cpal::BufferSize::Default,
     };
 
     println!("Using stream config: {:?}", config);
 
-    let stream = match default_config.sample_format() {
-        SampleFormat::F32 => {
-            build_specific_format_stream::<f32>(&device, &config, state, sample_consumer)?
-        }
-        SampleFormat::I16 => {
-            build_specific_format_stream::<i16>(&device, &config, state, sample_consumer)?
-        }
-        SampleFormat::U16 => {
-            build_specific_format_stream::<u16>(&device, &config, state, sample_consumer)?
+    // tell the player thread when the device is gone, so it can fall back to the default
+    let error_callback = move |err: StreamError| {
+        eprintln!("Audio stream error: {}", err);
+        if let StreamError::DeviceNotAvailable = err {
+            let _ = player_command_sender.send(AudioPlayerCommand::OutputDeviceLost(generation));
         }
+    };
+
+    let stream = match output_config.sample_format() {
+        SampleFormat::F32 => build_specific_format_stream::<f32, _>(
+            device,
+            &config,
+            state,
+            sample_consumer,
+            error_callback,
+        )?,
+        SampleFormat::I16 => build_specific_format_stream::<i16, _>(
+            device,
+            &config,
+            state,
+            sample_consumer,
+            error_callback,
+        )?,
+        SampleFormat::U16 => build_specific_format_stream::<u16, _>(
+            device,
+            &config,
+            state,
+            sample_consumer,
+            error_callback,
+        )?,
         _ => return Err(anyhow!("Unsupported sample format")),
     };
 
     Ok(stream)
 }
 
-fn get_default_audio_device() -> Result<Device, Error> {
+pub fn get_default_audio_device() -> Result<Device, Error> {
     let host = default_host();
     let device = host
         .default_output_device()
@@ -55,14 +74,16 @@ fn get_default_audio_device() -> Result<Device, Error> {
     Ok(device)
 }
 
-fn build_specific_format_stream<T>(
+fn build_specific_format_stream<T, E>(
     device: &Device,
     config: &StreamConfig,
     state: Arc<Mutex<PlaybackState>>,
     mut sample_consumer: HeapCons<f32>,
+    error_callback: E,
 ) -> Result<Stream, Error>
 where
     T: Sample + SizedSample + FromSample<f32>,
+    E: FnMut(StreamError) + Send + 'static,
 {
     let channels = config.channels as usize;
 
@@ -72,7 +93,7 @@ where
         move |data: &mut [T], oci: &OutputCallbackInfo| {
             audio_callback(data, oci, &state, &mut sample_consumer, channels)
         },
-        |err| eprintln!("Audio stream error: {}", err),
+        error_callback,
         None,
     )?;
 
@@ -130,6 +151,18 @@ fn audio_callback<T>(
     {
         // update position (approximate based on samples consumed)
         let mut state_guard = state.lock().unwrap();
-        state_guard.current_position_samples += (samples_read / channels) as u64;
+        let frames_read = (samples_read / channels) as u64;
+        match state_guard.frames_until_track_switch {
+            // the next track of a gapless transition started within this callback
+            Some(remaining) if frames_read >= remaining => {
+                state_guard.current_position_samples = frames_read - remaining;
+                state_guard.frames_until_track_switch = None;
+            }
+            Some(remaining) => {
+                state_guard.current_position_samples += frames_read;
+                state_guard.frames_until_track_switch = Some(remaining - frames_read);
+            }
+            None => state_guard.current_position_samples += frames_read,
+        }
     }
 }
@@ -1 +1,3 @@
-pub mod audio_thread;
\ No newline at end of file
+pub mod audio_thread;
+pub mod output_config;
+pub mod output_devices;
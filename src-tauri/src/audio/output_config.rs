@@ -0,0 +1,118 @@
+use anyhow::Error;
+use cpal::traits::DeviceTrait;
+use cpal::{Device, SupportedStreamConfig, SupportedStreamConfigRange};
+
+/// Picks the stream config to play a file with the given sample rate and channel
+/// count on the device. The file's own format is used if the device supports it,
+/// so nothing has to be converted. Otherwise the decoder resamples and mixes to
+/// what the device offers.
+pub fn choose_output_config(
+    device: &Device,
+    sample_rate: u32,
+    channels: u16,
+) -> Result<SupportedStreamConfig, Error> {
+    let default_config = device.default_output_config()?;
+    let supported_configs: Vec<SupportedStreamConfigRange> = match device.supported_output_configs() {
+        Ok(configs) => configs.collect(),
+        Err(e) => {
+            eprintln!("Failed to query supported output configs: {}", e);
+            Vec::new()
+        }
+    };
+    Ok(pick_output_config(
+        &supported_configs,
+        default_config,
+        sample_rate,
+        channels,
+    ))
+}
+
+fn pick_output_config(
+    supported_configs: &[SupportedStreamConfigRange],
+    default_config: SupportedStreamConfig,
+    sample_rate: u32,
+    channels: u16,
+) -> SupportedStreamConfig {
+    // mono is spread to the default channels ourselves rather than left to the device
+    let channel_candidates = [channels.max(default_config.channels()), default_config.channels()];
+
+    channel_candidates
+        .into_iter()
+        .find_map(|wanted_channels| {
+            supported_configs
+                .iter()
+                .filter(|config| {
+                    config.channels() == wanted_channels
+                        && config.sample_format() == default_config.sample_format()
+                })
+                .find_map(|config| config.try_with_sample_rate(sample_rate))
+        })
+        .unwrap_or(default_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::{SampleFormat, SupportedBufferSize};
+
+    fn range(channels: u16, min_rate: u32, max_rate: u32) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            min_rate,
+            max_rate,
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        )
+    }
+
+    fn default_config() -> SupportedStreamConfig {
+        SupportedStreamConfig::new(2, 48000, SupportedBufferSize::Unknown, SampleFormat::F32)
+    }
+
+    #[test]
+    fn test_uses_file_format_if_supported() {
+        let supported = [range(2, 44100, 192000)];
+        let config = pick_output_config(&supported, default_config(), 44100, 2);
+        assert_eq!((config.sample_rate(), config.channels()), (44100, 2));
+    }
+
+    #[test]
+    fn test_falls_back_to_default_for_unsupported_rate() {
+        let supported = [range(2, 48000, 48000)];
+        let config = pick_output_config(&supported, default_config(), 44100, 2);
+        assert_eq!((config.sample_rate(), config.channels()), (48000, 2));
+    }
+
+    #[test]
+    fn test_mono_plays_on_default_channels() {
+        let supported = [range(1, 8000, 192000), range(2, 8000, 192000)];
+        let config = pick_output_config(&supported, default_config(), 22050, 1);
+        assert_eq!((config.sample_rate(), config.channels()), (22050, 2));
+    }
+
+    #[test]
+    fn test_multichannel() {
+        // kept if the device supports it
+        let supported = [range(2, 48000, 48000), range(6, 48000, 48000)];
+        let config = pick_output_config(&supported, default_config(), 48000, 6);
+        assert_eq!(config.channels(), 6);
+
+        // downmixed otherwise
+        let supported = [range(2, 48000, 48000)];
+        let config = pick_output_config(&supported, default_config(), 48000, 6);
+        assert_eq!(config.channels(), 2);
+    }
+
+    #[test]
+    fn test_ignores_other_sample_formats() {
+        let supported = [SupportedStreamConfigRange::new(
+            2,
+            44100,
+            44100,
+            SupportedBufferSize::Unknown,
+            SampleFormat::I32,
+        )];
+        let config = pick_output_config(&supported, default_config(), 44100, 2);
+        assert_eq!(config.sample_rate(), 48000);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::audio::audio_thread::get_default_audio_device;
+use anyhow::{Context, Error};
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{available_hosts, host_from_id, Device, DeviceId};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tauri::{AppHandle, Manager};
+
+#[derive(Serialize, Clone, Debug)]
+pub struct OutputHost {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct OutputDevice {
+    pub id: String, // host and device, like "alsa:hw:CARD=PCH,DEV=0"
+    pub name: String,
+    pub host: String,
+    pub is_default: bool, // default output device of its host
+}
+
+/// Sent with the `audio:device-changed` event
+#[derive(Serialize, Clone, Debug)]
+pub struct OutputDeviceChanged {
+    pub device_id: Option<String>, // None for the default device
+    pub fallback: bool,            // the selected device disappeared
+}
+
+/// Persisted output device choice
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct OutputDeviceSettings {
+    pub device_id: Option<String>,
+}
+
+pub fn list_output_hosts() -> Vec<OutputHost> {
+    available_hosts()
+        .into_iter()
+        .map(|host_id| OutputHost {
+            id: host_id.to_string(),
+            name: host_id.name().to_string(),
+        })
+        .collect()
+}
+
+/// Lists the output devices of all available hosts
+pub fn list_output_devices() -> Vec<OutputDevice> {
+    let mut devices = Vec::new();
+    for host_id in available_hosts() {
+        let host = match host_from_id(host_id) {
+            Ok(host) => host,
+            Err(e) => {
+                eprintln!("Host {} unavailable: {}", host_id.name(), e);
+                continue;
+            }
+        };
+        let default_id = host
+            .default_output_device()
+            .and_then(|device| device.id().ok());
+        let output_devices = match host.output_devices() {
+            Ok(output_devices) => output_devices,
+            Err(e) => {
+                eprintln!("Failed to list devices of {}: {}", host_id.name(), e);
+                continue;
+            }
+        };
+        for device in output_devices {
+            let Ok(id) = device.id() else {
+                continue;
+            };
+            let name = device
+                .description()
+                .map(|description| description.name().to_string())
+                .unwrap_or_else(|_| id.1.clone());
+            devices.push(OutputDevice {
+                is_default: default_id.as_ref() == Some(&id),
+                id: id.to_string(),
+                name,
+                host: host_id.name().to_string(),
+            });
+        }
+    }
+    devices
+}
+
+/// Finds the output device with the given id, or the default device if the id is None
+pub fn find_output_device(device_id: Option<&String>) -> Result<Device, Error> {
+    let Some(device_id) = device_id else {
+        return get_default_audio_device();
+    };
+    let id = DeviceId::from_str(device_id)?;
+    let host = host_from_id(id.0)?;
+    let device = host
+        .device_by_id(&id)
+        .with_context(|| format!("Output device {} not found", device_id))?;
+
+    println!("Output device: {}", device.description()?);
+
+    Ok(device)
+}
+
+pub fn output_device_settings_path(app_handle: &AppHandle) -> Option<PathBuf> {
+    app_handle
+        .path()
+        .app_config_dir()
+        .map(|dir| dir.join("output_device.json"))
+        .ok()
+}
+
+pub fn load_output_device_settings(path: &Path) -> OutputDeviceSettings {
+    let Ok(json) = fs::read_to_string(path) else {
+        return OutputDeviceSettings::default();
+    };
+    serde_json::from_str(&json).unwrap_or_else(|e| {
+        eprintln!("Failed to read output device settings: {}", e);
+        OutputDeviceSettings::default()
+    })
+}
+
+pub fn save_output_device_settings(path: &Path, settings: &OutputDeviceSettings) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(settings)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_settings_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config").join("output_device.json");
+
+        let settings = OutputDeviceSettings {
+            device_id: Some("alsa:hw:CARD=PCH,DEV=0".to_string()),
+        };
+        save_output_device_settings(&path, &settings).unwrap();
+
+        assert_eq!(load_output_device_settings(&path), settings);
+    }
+
+    #[test]
+    fn test_missing_or_broken_settings() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("output_device.json");
+        assert_eq!(load_output_device_settings(&path), OutputDeviceSettings::default());
+
+        fs::write(&path, "not json").unwrap();
+        assert_eq!(load_output_device_settings(&path), OutputDeviceSettings::default());
+    }
+}
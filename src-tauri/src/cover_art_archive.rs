@@ -0,0 +1,437 @@
+use crate::musicbrainz_client::DEFAULT_USER_AGENT;
+use crate::rate_limiter::RateLimiter;
+use crate::service_settings::{validate_service_url, ClientSettings, SharedSettings};
+use crate::tag_journal::TagJournal;
+use crate::tags::cover_art::{embed_prepared_picture, prepare_image, save_cover, EmbedOptions};
+use anyhow::{anyhow, bail, Context, Result};
+use lofty::picture::PictureType;
+use reqwest::header::USER_AGENT;
+use reqwest::{Client, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const DEFAULT_BASE_URL: &str = "https://coverartarchive.org";
+
+// the archive publishes no rate limit of its own; keep to the one of MusicBrainz
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Where covers come from, the size they are downloaded in and how they are
+/// prepared before they are embedded or saved
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct CoverArtArchiveSettings {
+    pub base_url: String,
+    pub size: ImageSize,
+    pub image_options: EmbedOptions,
+}
+
+impl Default for CoverArtArchiveSettings {
+    fn default() -> Self {
+        CoverArtArchiveSettings {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            size: ImageSize::Large,
+            image_options: EmbedOptions::default(),
+        }
+    }
+}
+
+impl ClientSettings for CoverArtArchiveSettings {
+    fn validate(&self) -> Result<()> {
+        validate_service_url("base URL", &self.base_url)
+    }
+}
+
+/// The thumbnail sizes the archive has of every image, besides the original
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageSize {
+    Small,
+    Medium,
+    Large,
+    Original,
+}
+
+impl ImageSize {
+    fn suffix(self) -> &'static str {
+        match self {
+            ImageSize::Small => "-250",
+            ImageSize::Medium => "-500",
+            ImageSize::Large => "-1200",
+            ImageSize::Original => "",
+        }
+    }
+}
+
+/// Images are kept for releases and, taken from one of their releases, for release groups
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoverArtEntity {
+    Release,
+    ReleaseGroup,
+}
+
+impl CoverArtEntity {
+    fn path(self) -> &'static str {
+        match self {
+            CoverArtEntity::Release => "release",
+            CoverArtEntity::ReleaseGroup => "release-group",
+        }
+    }
+}
+
+/// One image of a release, with the URLs of the original and its thumbnails
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct CoverArtImage {
+    pub id: String,
+    pub types: Vec<String>,
+    pub front: bool,
+    pub back: bool,
+    pub comment: String,
+    pub approved: bool,
+    pub image_url: String,
+    /// By width, "250", "500" and "1200"
+    pub thumbnail_urls: BTreeMap<String, String>,
+}
+
+/// Which image to get: the front cover if there is no image ID
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct CoverArtRequest {
+    pub entity: CoverArtEntity,
+    pub mbid: String,
+    pub image_id: Option<String>,
+    pub size: Option<ImageSize>,
+}
+
+/// What to do with a downloaded cover
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoverTarget {
+    /// As the front cover of every file
+    Embed,
+    /// As `cover.jpg` in every folder of the files
+    Folder,
+}
+
+#[derive(Deserialize)]
+struct ImagesResponse {
+    images: Vec<ArchiveImage>,
+}
+
+#[derive(Deserialize)]
+struct ArchiveImage {
+    // a number in older responses
+    id: Value,
+    #[serde(default)]
+    types: Vec<String>,
+    #[serde(default)]
+    front: bool,
+    #[serde(default)]
+    back: bool,
+    #[serde(default)]
+    comment: String,
+    #[serde(default)]
+    approved: bool,
+    image: String,
+    #[serde(default)]
+    thumbnails: HashMap<String, String>,
+}
+
+pub struct CoverArtArchiveClient {
+    client: Client,
+    settings: SharedSettings<CoverArtArchiveSettings>,
+    rate_limiter: RateLimiter,
+    retry_delay: Duration,
+}
+
+impl CoverArtArchiveClient {
+    pub fn new(settings: CoverArtArchiveSettings) -> Result<CoverArtArchiveClient> {
+        Ok(CoverArtArchiveClient {
+            client: Client::builder().build()?,
+            settings: SharedSettings::new(settings)?,
+            rate_limiter: RateLimiter::new(REQUEST_INTERVAL),
+            retry_delay: RETRY_DELAY,
+        })
+    }
+
+    pub fn settings(&self) -> CoverArtArchiveSettings {
+        self.settings.get()
+    }
+
+    pub fn set_settings(&self, settings: CoverArtArchiveSettings) -> Result<()> {
+        self.settings.set(settings)
+    }
+
+    /// The images of a release or release group, none if the archive has none
+    pub async fn list_images(&self, entity: CoverArtEntity, mbid: &str) -> Result<Vec<CoverArtImage>> {
+        let url = self.url(&[entity.path(), mbid])?;
+        let Some(body) = self.get(&url).await? else {
+            return Ok(Vec::new());
+        };
+        let response: ImagesResponse =
+            serde_json::from_slice(&body).with_context(|| format!("Unexpected response from {}", url))?;
+        Ok(response.images.into_iter().map(to_image).collect())
+    }
+
+    /// Downloads an image in the requested size, or in the size of the settings
+    pub async fn download_image(&self, request: &CoverArtRequest) -> Result<Vec<u8>> {
+        let size = request.size.unwrap_or(self.settings().size);
+        let image = format!("{}{}", request.image_id.as_deref().unwrap_or("front"), size.suffix());
+        let url = self.url(&[request.entity.path(), &request.mbid, &image])?;
+        self.get(&url)
+            .await?
+            .ok_or_else(|| anyhow!("The Cover Art Archive has no image at {}", url))
+    }
+
+    fn url(&self, segments: &[&str]) -> Result<Url> {
+        let base_url = self.settings().base_url;
+        let mut url = Url::parse(&base_url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid base URL: {}", base_url))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    /// The body of a response, or None for 404. Images redirect to archive.org.
+    async fn get(&self, url: &Url) -> Result<Option<Vec<u8>>> {
+        let request = self.client.get(url.clone()).header(USER_AGENT, DEFAULT_USER_AGENT);
+        let response = self
+            .rate_limiter
+            .send(request, self.retry_delay)
+            .await
+            .with_context(|| format!("Request to {} failed", url))?;
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            bail!("The Cover Art Archive answered {} for {}", status, url);
+        }
+        Ok(Some(response.bytes().await?.to_vec()))
+    }
+}
+
+fn to_image(image: ArchiveImage) -> CoverArtImage {
+    let id = match image.id {
+        Value::String(id) => id,
+        id => id.to_string(),
+    };
+    // "small" and "large" are the old names of 250 and 500
+    let thumbnail_urls = image
+        .thumbnails
+        .into_iter()
+        .filter(|(size, _)| size.chars().all(|c| c.is_ascii_digit()))
+        .collect();
+    CoverArtImage {
+        id,
+        types: image.types,
+        front: image.front,
+        back: image.back,
+        comment: image.comment,
+        approved: image.approved,
+        image_url: image.image,
+        thumbnail_urls,
+    }
+}
+
+/// Downloads an image and embeds it in the files or saves it in their folders,
+/// prepared as the settings say. Returns the files or covers that were written.
+/// The embeds are one batch of the tag journal.
+pub async fn apply_cover_art(
+    client: &CoverArtArchiveClient,
+    tag_journal: &Mutex<TagJournal>,
+    request: &CoverArtRequest,
+    paths: &[String],
+    target: CoverTarget,
+    overwrite: bool,
+) -> Result<Vec<PathBuf>> {
+    let data = client.download_image(request).await?;
+    let options = client.settings().image_options;
+    match target {
+        CoverTarget::Embed => {
+            let (data, mime_type) = prepare_image(data, options)?;
+            let mut tag_journal = tag_journal.lock().unwrap();
+            let batch_id = tag_journal.start_batch("Embed cover art")?;
+            for path in paths {
+                let path = Path::new(path);
+                tag_journal.write_with_pictures(batch_id, path, || {
+                    embed_prepared_picture(path, data.clone(), mime_type.clone(), PictureType::CoverFront)
+                })?;
+            }
+            Ok(paths.iter().map(PathBuf::from).collect())
+        }
+        CoverTarget::Folder => {
+            let mut folders: Vec<&Path> = paths.iter().filter_map(|path| Path::new(path).parent()).collect();
+            folders.sort();
+            folders.dedup();
+            folders
+                .into_iter()
+                .map(|folder| save_cover(folder, &data, options, overwrite))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::cover_art::list_pictures;
+    use crate::test_support::sample_file;
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use mockito::Server;
+    use std::fs;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    const RELEASE_ID: &str = "b52a8f31-b5ab-34e9-92f4-f5b7110220f0";
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        data
+    }
+
+    fn client(base_url: String, image_options: EmbedOptions) -> CoverArtArchiveClient {
+        let mut client = CoverArtArchiveClient::new(CoverArtArchiveSettings {
+            base_url,
+            size: ImageSize::Medium,
+            image_options,
+        })
+        .unwrap();
+        client.rate_limiter = RateLimiter::new(Duration::ZERO);
+        client.retry_delay = Duration::ZERO;
+        client
+    }
+
+    #[tokio::test]
+    async fn test_list_images() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", format!("/release/{}", RELEASE_ID).as_str())
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"release": "https://musicbrainz.org/release/b52a8f31", "images": [{
+                    "id": 1234,
+                    "types": ["Front"],
+                    "front": true,
+                    "back": false,
+                    "comment": "",
+                    "approved": true,
+                    "image": "http://coverartarchive.org/release/b52a8f31/1234.jpg",
+                    "thumbnails": {
+                        "250": "http://coverartarchive.org/release/b52a8f31/1234-250.jpg",
+                        "500": "http://coverartarchive.org/release/b52a8f31/1234-500.jpg",
+                        "small": "http://coverartarchive.org/release/b52a8f31/1234-250.jpg"
+                    }
+                }]}"#,
+            )
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/release-group/none")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let client = client(server.url(), EmbedOptions::default());
+        let images = client.list_images(CoverArtEntity::Release, RELEASE_ID).await.unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].id, "1234");
+        assert!(images[0].front);
+        assert_eq!(images[0].thumbnail_urls.keys().collect::<Vec<_>>(), vec!["250", "500"]);
+
+        let images = client.list_images(CoverArtEntity::ReleaseGroup, "none").await.unwrap();
+        assert!(images.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retries_when_busy() {
+        let mut server = Server::new_async().await;
+        let busy = server
+            .mock("GET", format!("/release/{}", RELEASE_ID).as_str())
+            .with_status(503)
+            .with_header("retry-after", "0")
+            .expect(1)
+            .create_async()
+            .await;
+        let ok = server
+            .mock("GET", format!("/release/{}", RELEASE_ID).as_str())
+            .with_body(r#"{"images": []}"#)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = client(server.url(), EmbedOptions::default());
+        let images = client.list_images(CoverArtEntity::Release, RELEASE_ID).await.unwrap();
+        assert!(images.is_empty());
+        busy.assert_async().await;
+        ok.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_apply_cover_art() {
+        let mut server = Server::new_async().await;
+        let front = server
+            .mock("GET", format!("/release/{}/front-500", RELEASE_ID).as_str())
+            .with_header("content-type", "image/png")
+            .with_body(png(400, 200))
+            .expect(2)
+            .create_async()
+            .await;
+        server
+            .mock("GET", format!("/release/{}/99-1200", RELEASE_ID).as_str())
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let dir = tempdir().unwrap();
+        let path = sample_file("some_song.mp3", &dir.path().join("some_song.mp3"));
+        let paths = vec![path.to_string_lossy().to_string()];
+        let options = EmbedOptions {
+            max_size: Some(100),
+            jpeg_quality: None,
+        };
+        let client = client(server.url(), options);
+        let request = CoverArtRequest {
+            entity: CoverArtEntity::Release,
+            mbid: RELEASE_ID.to_string(),
+            image_id: None,
+            size: None,
+        };
+        let tag_journal = Mutex::new(TagJournal::open(&dir.path().join("tag_journal.sqlite3")).unwrap());
+
+        let written = apply_cover_art(&client, &tag_journal, &request, &paths, CoverTarget::Embed, false)
+            .await
+            .unwrap();
+        assert_eq!(written, vec![path.clone()]);
+        let pictures = list_pictures(&path).unwrap();
+        assert_eq!(pictures.len(), 1);
+        assert_eq!(pictures[0].picture_type, "CoverFront");
+        assert_eq!((pictures[0].width, pictures[0].height), (Some(100), Some(50)));
+        let batches = tag_journal.lock().unwrap().batches().unwrap();
+        assert_eq!(batches[0].description, "Embed cover art");
+        assert_eq!(batches[0].paths, paths);
+
+        let written = apply_cover_art(&client, &tag_journal, &request, &paths, CoverTarget::Folder, false)
+            .await
+            .unwrap();
+        assert_eq!(written, vec![dir.path().join("cover.jpg")]);
+        let cover = image::load_from_memory(&fs::read(&written[0]).unwrap()).unwrap();
+        assert_eq!(cover.width(), 100);
+        front.assert_async().await;
+
+        let missing = CoverArtRequest {
+            image_id: Some("99".to_string()),
+            size: Some(ImageSize::Large),
+            ..request
+        };
+        assert!(apply_cover_art(&client, &tag_journal, &missing, &paths, CoverTarget::Embed, false).await.is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use crate::library_db::LibraryDatabase;
+use crate::tags::reading_tags;
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use percent_encoding::percent_decode_str;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use tauri::http::{header, Request, Response, StatusCode, Uri};
+use tauri::{AppHandle, Manager, UriSchemeResponder};
+
+pub const COVER_SCHEME: &str = "cover";
+
+const MIN_THUMBNAIL_SIZE: u32 = 16;
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+const THUMBNAIL_QUALITY: u8 = 85;
+
+// image files next to the music that are used when a file has no embedded cover, in this order
+const FOLDER_COVER_NAMES: [&str; 3] = ["cover", "folder", "front"];
+const FOLDER_COVER_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+pub struct CoverImage {
+    pub data: Vec<u8>,
+    pub mime_type: &'static str,
+}
+
+/// A request for `cover://localhost/<percent-encoded song path>?size=<pixels>`.
+/// Without a size the cover comes in full resolution.
+#[derive(Debug, PartialEq)]
+struct CoverRequest {
+    path: String,
+    size: Option<u32>,
+}
+
+/// Handles a request of the `cover` URI scheme on its own thread. Only songs of
+/// the library database are served; thumbnails are cached in the app cache dir.
+pub fn handle_cover_request(app_handle: &AppHandle, request: Request<Vec<u8>>, responder: UriSchemeResponder) {
+    let app_handle = app_handle.clone();
+    thread::spawn(move || {
+        let response = match cover_response(&app_handle, request.uri()) {
+            Ok(Some(cover)) => Response::builder()
+                .header(header::CONTENT_TYPE, cover.mime_type)
+                .body(cover.data),
+            Ok(None) => Response::builder().status(StatusCode::NOT_FOUND).body(Vec::new()),
+            Err(e) => {
+                eprintln!("Failed to serve cover {}: {:?}", request.uri(), e);
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(e.to_string().into_bytes())
+            }
+        };
+        responder.respond(response.unwrap());
+    });
+}
+
+fn cover_response(app_handle: &AppHandle, uri: &Uri) -> Result<Option<CoverImage>> {
+    let Some(request) = parse_cover_uri(uri) else {
+        return Ok(None);
+    };
+    let library_database = app_handle.state::<Mutex<LibraryDatabase>>();
+    if !library_database.lock().unwrap().contains_song(&request.path)? {
+        return Ok(None);
+    }
+    let cache_dir = app_handle.path().app_cache_dir()?.join("covers");
+    load_cover(Path::new(&request.path), request.size, &cache_dir)
+}
+
+fn parse_cover_uri(uri: &Uri) -> Option<CoverRequest> {
+    let encoded_path = uri.path().strip_prefix('/')?;
+    let path = percent_decode_str(encoded_path).decode_utf8().ok()?.to_string();
+    if path.is_empty() {
+        return None;
+    }
+    let size = uri
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("size="))
+        .and_then(|size| size.parse::<u32>().ok())
+        .map(|size| size.clamp(MIN_THUMBNAIL_SIZE, MAX_THUMBNAIL_SIZE));
+    Some(CoverRequest { path, size })
+}
+
+/// The cover of a song: its embedded picture, or else an image like `cover.jpg`
+/// in its directory. With a size it is scaled down to fit into a square of that
+/// size and kept in the cache dir until the song or the folder image changes.
+pub fn load_cover(song_path: &Path, size: Option<u32>, cache_dir: &Path) -> Result<Option<CoverImage>> {
+    let folder_cover = song_path.parent().and_then(folder_cover_path);
+
+    let Some(size) = size else {
+        return Ok(find_cover(song_path, folder_cover.as_deref())?.map(|data| CoverImage {
+            mime_type: mime_type(&data),
+            data,
+        }));
+    };
+
+    let cache_path = thumbnail_cache_path(cache_dir, song_path, size);
+    let mut sources = vec![song_path];
+    sources.extend(folder_cover.as_deref());
+    if is_fresh(&cache_path, &sources) {
+        return Ok(Some(CoverImage {
+            data: fs::read(&cache_path)?,
+            mime_type: "image/jpeg",
+        }));
+    }
+
+    let Some(data) = find_cover(song_path, folder_cover.as_deref())? else {
+        return Ok(None);
+    };
+    let thumbnail = thumbnail(&data, size)?;
+    fs::create_dir_all(cache_dir)?;
+    fs::write(&cache_path, &thumbnail)
+        .with_context(|| format!("Failed to cache thumbnail: {}", cache_path.display()))?;
+    Ok(Some(CoverImage {
+        data: thumbnail,
+        mime_type: "image/jpeg",
+    }))
+}
+
+fn find_cover(song_path: &Path, folder_cover: Option<&Path>) -> Result<Option<Vec<u8>>> {
+    if let Some(picture) = reading_tags::read_cover(song_path)? {
+        return Ok(Some(picture.into_data()));
+    }
+    match folder_cover {
+        Some(path) => Ok(Some(fs::read(path)?)),
+        None => Ok(None),
+    }
+}
+
+/// An image file like `cover.jpg` or `Folder.png` in a directory
+fn folder_cover_path(dir: &Path) -> Option<PathBuf> {
+    let mut candidates: Vec<(usize, PathBuf)> = fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?.to_lowercase();
+            let extension = reading_tags::get_file_extension(&path)?;
+            if !FOLDER_COVER_EXTENSIONS.contains(&extension.as_str()) {
+                return None;
+            }
+            let rank = FOLDER_COVER_NAMES.iter().position(|name| *name == stem)?;
+            Some((rank, path))
+        })
+        .collect();
+    candidates.sort();
+    candidates.into_iter().next().map(|(_, path)| path)
+}
+
+/// Scales an image down to fit into a square of `size` pixels, as JPEG
+fn thumbnail(data: &[u8], size: u32) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(data).context("Failed to decode cover image")?;
+    let image = if image.width() > size || image.height() > size {
+        image.resize(size, size, FilterType::CatmullRom)
+    } else {
+        image
+    };
+
+    let mut thumbnail = Vec::new();
+    JpegEncoder::new_with_quality(&mut thumbnail, THUMBNAIL_QUALITY).encode_image(&image.to_rgb8())?;
+    Ok(thumbnail)
+}
+
+fn thumbnail_cache_path(cache_dir: &Path, song_path: &Path, size: u32) -> PathBuf {
+    // the hash only names the cache file, so it does not have to be stable across builds
+    let mut hasher = DefaultHasher::new();
+    song_path.hash(&mut hasher);
+    cache_dir.join(format!("{:016x}_{}.jpg", hasher.finish(), size))
+}
+
+/// Whether a cache file was written after all of its sources were last modified
+fn is_fresh(cache_path: &Path, sources: &[&Path]) -> bool {
+    let Ok(cached) = fs::metadata(cache_path).and_then(|metadata| metadata.modified()) else {
+        return false;
+    };
+    sources.iter().all(|source| {
+        fs::metadata(source)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified <= cached)
+    })
+}
+
+fn mime_type(data: &[u8]) -> &'static str {
+    image::guess_format(data)
+        .map(|format| format.to_mime_type())
+        .unwrap_or("application/octet-stream")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+
+    fn write_png(path: &Path, width: u32, height: u32) {
+        let image = RgbImage::from_pixel(width, height, image::Rgb([200, 40, 40]));
+        image.save_with_format(path, ImageFormat::Png).unwrap();
+    }
+
+    fn image_size(data: &[u8]) -> (u32, u32) {
+        let image = image::ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .unwrap()
+            .decode()
+            .unwrap();
+        (image.width(), image.height())
+    }
+
+    #[test]
+    fn test_parse_cover_uri() {
+        let uri: Uri = "cover://localhost/%2Fmusic%2FAlbum%20%C3%A4%2Fa.mp3?size=256".parse().unwrap();
+        assert_eq!(
+            parse_cover_uri(&uri),
+            Some(CoverRequest {
+                path: "/music/Album ä/a.mp3".to_string(),
+                size: Some(256),
+            })
+        );
+
+        // Windows and Android use http://cover.localhost
+        let uri: Uri = "http://cover.localhost/C%3A%5Cmusic%5Ca.mp3?size=100000".parse().unwrap();
+        assert_eq!(
+            parse_cover_uri(&uri),
+            Some(CoverRequest {
+                path: "C:\\music\\a.mp3".to_string(),
+                size: Some(MAX_THUMBNAIL_SIZE),
+            })
+        );
+
+        let uri: Uri = "cover://localhost/%2Fmusic%2Fa.mp3".parse().unwrap();
+        assert_eq!(parse_cover_uri(&uri).unwrap().size, None);
+        let uri: Uri = "cover://localhost/".parse().unwrap();
+        assert_eq!(parse_cover_uri(&uri), None);
+    }
+
+    #[test]
+    fn test_folder_cover_path() {
+        let dir = tempdir().unwrap();
+        assert_eq!(folder_cover_path(dir.path()), None);
+
+        fs::write(dir.path().join("back.jpg"), "").unwrap();
+        fs::write(dir.path().join("cover.txt"), "").unwrap();
+        assert_eq!(folder_cover_path(dir.path()), None);
+
+        fs::write(dir.path().join("Folder.PNG"), "").unwrap();
+        assert_eq!(folder_cover_path(dir.path()), Some(dir.path().join("Folder.PNG")));
+
+        fs::write(dir.path().join("cover.jpg"), "").unwrap();
+        assert_eq!(folder_cover_path(dir.path()), Some(dir.path().join("cover.jpg")));
+    }
+
+    #[test]
+    fn test_thumbnail_keeps_aspect_ratio_and_does_not_upscale() {
+        let dir = tempdir().unwrap();
+        write_png(&dir.path().join("large.png"), 800, 400);
+        write_png(&dir.path().join("small.png"), 100, 50);
+
+        let data = thumbnail(&fs::read(dir.path().join("large.png")).unwrap(), 256).unwrap();
+        assert_eq!(mime_type(&data), "image/jpeg");
+        assert_eq!(image_size(&data), (256, 128));
+
+        let data = thumbnail(&fs::read(dir.path().join("small.png")).unwrap(), 256).unwrap();
+        assert_eq!(image_size(&data), (100, 50));
+
+        assert!(thumbnail(b"not an image", 256).is_err());
+    }
+
+    #[test]
+    fn test_folder_cover_of_song_without_embedded_cover() {
+        let dir = tempdir().unwrap();
+        let song_path = dir.path().join("a.wav");
+        fs::copy("./tests/music_libraries/different_formats/some_song.wav", &song_path).unwrap();
+        let cache_dir = dir.path().join("cache");
+
+        assert!(load_cover(&song_path, None, &cache_dir).unwrap().is_none());
+        assert!(load_cover(&song_path, Some(64), &cache_dir).unwrap().is_none());
+
+        write_png(&dir.path().join("cover.png"), 300, 300);
+        let cover = load_cover(&song_path, None, &cache_dir).unwrap().unwrap();
+        assert_eq!(cover.mime_type, "image/png");
+        assert_eq!(image_size(&cover.data), (300, 300));
+
+        let cover = load_cover(&song_path, Some(64), &cache_dir).unwrap().unwrap();
+        assert_eq!(cover.mime_type, "image/jpeg");
+        assert_eq!(image_size(&cover.data), (64, 64));
+        assert!(thumbnail_cache_path(&cache_dir, &song_path, 64).exists());
+    }
+
+    #[test]
+    fn test_thumbnail_cache_is_renewed_when_the_cover_changes() {
+        let dir = tempdir().unwrap();
+        let song_path = dir.path().join("a.wav");
+        fs::copy("./tests/music_libraries/different_formats/some_song.wav", &song_path).unwrap();
+        let cache_dir = dir.path().join("cache");
+        let cover_path = dir.path().join("cover.png");
+
+        write_png(&cover_path, 300, 150);
+        let first = load_cover(&song_path, Some(64), &cache_dir).unwrap().unwrap();
+        assert_eq!(image_size(&first.data), (64, 32));
+
+        // served from the cache as long as nothing changed
+        let cache_path = thumbnail_cache_path(&cache_dir, &song_path, 64);
+        fs::write(&cache_path, b"cached").unwrap();
+        let cached = load_cover(&song_path, Some(64), &cache_dir).unwrap().unwrap();
+        assert_eq!(cached.data, b"cached");
+
+        write_png(&cover_path, 150, 300);
+        fs::File::options()
+            .write(true)
+            .open(&cover_path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        let renewed = load_cover(&song_path, Some(64), &cache_dir).unwrap().unwrap();
+        assert_eq!(image_size(&renewed.data), (32, 64));
+    }
+}
@@ -0,0 +1,179 @@
+use symphonia::core::audio::Channels;
+
+// -3 dB, the gain of channels that are shared between both sides
+const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Maps interleaved samples from the channel layout of a file to the channel
+/// count of the output device. Mono is spread to both sides, multichannel audio
+/// is downmixed to stereo. Outputs with more than two channels get the stereo
+/// mix on their first two channels.
+pub struct ChannelMixer {
+    input_channels: usize,
+    output_channels: usize,
+    // gains of every input channel on the left and the right output channel
+    stereo_gains: Vec<(f32, f32)>,
+}
+
+impl ChannelMixer {
+    pub fn new(layout: Channels, output_channels: usize) -> ChannelMixer {
+        let input_channels = layout.count();
+        let stereo_gains = if input_channels == 1 {
+            vec![(1.0, 1.0)]
+        } else {
+            normalize(layout.iter().map(stereo_gains).collect())
+        };
+        ChannelMixer {
+            input_channels,
+            output_channels,
+            stereo_gains,
+        }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.input_channels == self.output_channels
+    }
+
+    pub fn mix(&self, samples: &[f32]) -> Vec<f32> {
+        if self.is_passthrough() {
+            return samples.to_vec();
+        }
+
+        let frames = samples.len() / self.input_channels;
+        let mut mixed = Vec::with_capacity(frames * self.output_channels);
+
+        for frame in samples.chunks_exact(self.input_channels) {
+            let (left, right) = frame
+                .iter()
+                .zip(&self.stereo_gains)
+                .fold((0.0, 0.0), |(left, right), (sample, (left_gain, right_gain))| {
+                    (left + sample * left_gain, right + sample * right_gain)
+                });
+
+            if self.output_channels == 1 {
+                mixed.push((left + right) / 2.0);
+                continue;
+            }
+            mixed.push(left);
+            mixed.push(right);
+            mixed.extend(std::iter::repeat_n(0.0, self.output_channels - 2));
+        }
+        mixed
+    }
+}
+
+fn stereo_gains(channel: Channels) -> (f32, f32) {
+    let left = Channels::FRONT_LEFT_CENTRE
+        | Channels::REAR_LEFT
+        | Channels::REAR_LEFT_CENTRE
+        | Channels::SIDE_LEFT
+        | Channels::FRONT_LEFT_WIDE
+        | Channels::FRONT_LEFT_HIGH
+        | Channels::TOP_FRONT_LEFT
+        | Channels::TOP_REAR_LEFT;
+    let right = Channels::FRONT_RIGHT_CENTRE
+        | Channels::REAR_RIGHT
+        | Channels::REAR_RIGHT_CENTRE
+        | Channels::SIDE_RIGHT
+        | Channels::FRONT_RIGHT_WIDE
+        | Channels::FRONT_RIGHT_HIGH
+        | Channels::TOP_FRONT_RIGHT
+        | Channels::TOP_REAR_RIGHT;
+
+    if channel == Channels::FRONT_LEFT {
+        (1.0, 0.0)
+    } else if channel == Channels::FRONT_RIGHT {
+        (0.0, 1.0)
+    } else if channel == Channels::LFE1 || channel == Channels::LFE2 {
+        // most stereo speakers can't reproduce it anyway
+        (0.0, 0.0)
+    } else if left.contains(channel) {
+        (MINUS_3DB, 0.0)
+    } else if right.contains(channel) {
+        (0.0, MINUS_3DB)
+    } else {
+        // centre channels
+        (MINUS_3DB, MINUS_3DB)
+    }
+}
+
+/// Scales the gains down so that the mix can't clip
+fn normalize(gains: Vec<(f32, f32)>) -> Vec<(f32, f32)> {
+    let left_sum: f32 = gains.iter().map(|(left, _)| left).sum();
+    let right_sum: f32 = gains.iter().map(|(_, right)| right).sum();
+    let max_sum = left_sum.max(right_sum);
+    if max_sum <= 1.0 {
+        return gains;
+    }
+    gains
+        .into_iter()
+        .map(|(left, right)| (left / max_sum, right / max_sum))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stereo() -> Channels {
+        Channels::FRONT_LEFT | Channels::FRONT_RIGHT
+    }
+
+    fn surround_5_1() -> Channels {
+        Channels::FRONT_LEFT
+            | Channels::FRONT_RIGHT
+            | Channels::FRONT_CENTRE
+            | Channels::LFE1
+            | Channels::REAR_LEFT
+            | Channels::REAR_RIGHT
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let mixer = ChannelMixer::new(stereo(), 2);
+        assert!(mixer.is_passthrough());
+        assert_eq!(mixer.mix(&[0.1, 0.2, 0.3, 0.4]), vec![0.1, 0.2, 0.3, 0.4]);
+    }
+
+    #[test]
+    fn test_mono_to_stereo() {
+        let mixer = ChannelMixer::new(Channels::FRONT_LEFT, 2);
+        assert_eq!(mixer.mix(&[0.5, -0.25]), vec![0.5, 0.5, -0.25, -0.25]);
+    }
+
+    #[test]
+    fn test_stereo_to_mono() {
+        let mixer = ChannelMixer::new(stereo(), 1);
+        assert_eq!(mixer.mix(&[0.5, 0.25]), vec![0.375]);
+    }
+
+    #[test]
+    fn test_stereo_to_more_channels() {
+        let mixer = ChannelMixer::new(stereo(), 4);
+        assert_eq!(mixer.mix(&[0.5, 0.25]), vec![0.5, 0.25, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_5_1_downmix_does_not_clip() {
+        let mixer = ChannelMixer::new(surround_5_1(), 2);
+        let mixed = mixer.mix(&[1.0; 6]);
+        assert_eq!(mixed.len(), 2);
+        assert!(mixed.iter().all(|sample| *sample <= 1.0 + 1e-6));
+        assert!((mixed[0] - 1.0).abs() < 1e-6);
+        assert!((mixed[1] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_5_1_downmix_keeps_sides_apart() {
+        let mixer = ChannelMixer::new(surround_5_1(), 2);
+        // only the rear left channel is playing
+        let mixed = mixer.mix(&[0.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+        assert!(mixed[0] > 0.0);
+        assert_eq!(mixed[1], 0.0);
+    }
+
+    #[test]
+    fn test_lfe_is_dropped() {
+        let mixer = ChannelMixer::new(surround_5_1(), 2);
+        assert_eq!(mixer.mix(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0]), vec![0.0, 0.0]);
+    }
+}
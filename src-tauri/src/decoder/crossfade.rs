@@ -0,0 +1,186 @@
+use crate::decoder::track_source::TrackSource;
+use serde::Deserialize;
+use std::f32::consts::FRAC_PI_2;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CrossfadeCurve {
+    Linear,
+    EqualPower,
+    Logarithmic,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CrossfadeSettings {
+    pub duration_seconds: f32,
+    pub curve: CrossfadeCurve,
+}
+
+impl CrossfadeSettings {
+    pub fn frames(&self, sample_rate: u32) -> u64 {
+        (self.duration_seconds.max(0.0) as f64 * sample_rate as f64) as u64
+    }
+}
+
+// dynamic range of the logarithmic curve, it fades between -60 dB and 0 dB
+const LOGARITHMIC_RANGE_DB: f32 = 60.0;
+
+impl CrossfadeCurve {
+    /// Returns the gains of the outgoing and the incoming track at the given
+    /// progress of the fade between 0.0 and 1.0
+    pub fn gains(&self, progress: f32) -> (f32, f32) {
+        let t = progress.clamp(0.0, 1.0);
+        match self {
+            CrossfadeCurve::Linear => (1.0 - t, t),
+            // keeps the summed power constant, so uncorrelated tracks don't dip in the middle
+            CrossfadeCurve::EqualPower => ((t * FRAC_PI_2).cos(), (t * FRAC_PI_2).sin()),
+            // ramps linearly in dB, which sounds even to the ear
+            CrossfadeCurve::Logarithmic => (log_gain(1.0 - t), log_gain(t)),
+        }
+    }
+}
+
+fn log_gain(t: f32) -> f32 {
+    if t <= 0.0 {
+        return 0.0;
+    }
+    10f32.powf((t - 1.0) * LOGARITHMIC_RANGE_DB / 20.0)
+}
+
+/// A running crossfade from the track the decoder is playing into the incoming one
+pub struct Crossfade {
+    incoming: TrackSource,
+    curve: CrossfadeCurve,
+    total_frames: u64,
+    elapsed_frames: u64,
+    // decoded samples of the incoming track that haven't been mixed yet
+    incoming_samples: Vec<f32>,
+    incoming_finished: bool,
+}
+
+impl Crossfade {
+    pub fn new(incoming: TrackSource, settings: CrossfadeSettings, total_frames: u64) -> Crossfade {
+        Crossfade {
+            incoming,
+            curve: settings.curve,
+            total_frames: total_frames.max(1),
+            elapsed_frames: 0,
+            incoming_samples: Vec::new(),
+            incoming_finished: false,
+        }
+    }
+
+    pub fn incoming_path(&self) -> &str {
+        &self.incoming.path
+    }
+
+    pub fn set_incoming_gain(&mut self, gain: f32) {
+        self.incoming.set_gain(gain);
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.elapsed_frames >= self.total_frames
+    }
+
+    /// Mixes the incoming track into a chunk of interleaved samples of the outgoing track
+    pub fn mix(&mut self, outgoing: &mut [f32], channels: usize) {
+        while self.incoming_samples.len() < outgoing.len() && !self.incoming_finished {
+            match self.incoming.next_samples() {
+                Ok(Some(samples)) => self.incoming_samples.extend(samples),
+                Ok(None) => self.incoming_finished = true,
+                Err(e) => {
+                    eprintln!("Error reading packet of incoming track: {}", e);
+                    self.incoming_finished = true;
+                }
+            }
+        }
+
+        for (frame_index, frame) in outgoing.chunks_mut(channels).enumerate() {
+            let progress =
+                (self.elapsed_frames + frame_index as u64) as f32 / self.total_frames as f32;
+            let (outgoing_gain, incoming_gain) = self.curve.gains(progress);
+
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let incoming_sample = self
+                    .incoming_samples
+                    .get(frame_index * channels + channel)
+                    .copied()
+                    .unwrap_or(0.0);
+                *sample = *sample * outgoing_gain + incoming_sample * incoming_gain;
+            }
+        }
+
+        let mixed = outgoing.len().min(self.incoming_samples.len());
+        self.incoming_samples.drain(..mixed);
+        self.elapsed_frames += (outgoing.len() / channels) as u64;
+    }
+
+    /// Ends the crossfade and returns the incoming track together with its
+    /// decoded samples that are left over
+    pub fn finish(self) -> (TrackSource, Vec<f32>) {
+        (self.incoming, self.incoming_samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [CrossfadeCurve; 3] = [
+        CrossfadeCurve::Linear,
+        CrossfadeCurve::EqualPower,
+        CrossfadeCurve::Logarithmic,
+    ];
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_curves_start_and_end_fully_on_one_track() {
+        for curve in CURVES {
+            let (outgoing, incoming) = curve.gains(0.0);
+            assert_close(outgoing, 1.0);
+            assert_close(incoming, 0.0);
+
+            let (outgoing, incoming) = curve.gains(1.0);
+            assert_close(outgoing, 0.0);
+            assert_close(incoming, 1.0);
+        }
+    }
+
+    #[test]
+    fn test_equal_power_keeps_power_constant() {
+        for step in 0..=10 {
+            let (outgoing, incoming) = CrossfadeCurve::EqualPower.gains(step as f32 / 10.0);
+            assert_close(outgoing * outgoing + incoming * incoming, 1.0);
+        }
+    }
+
+    #[test]
+    fn test_logarithmic_is_linear_in_db() {
+        let (_, incoming) = CrossfadeCurve::Logarithmic.gains(0.5);
+        // halfway through a 60 dB ramp is -30 dB
+        assert_close(20.0 * incoming.log10(), -30.0);
+    }
+
+    #[test]
+    fn test_progress_is_clamped() {
+        assert_eq!(CrossfadeCurve::Linear.gains(-1.0), (1.0, 0.0));
+        assert_eq!(CrossfadeCurve::Linear.gains(2.0), (0.0, 1.0));
+    }
+
+    #[test]
+    fn test_settings_frames() {
+        let settings = CrossfadeSettings {
+            duration_seconds: 2.5,
+            curve: CrossfadeCurve::Linear,
+        };
+        assert_eq!(settings.frames(48000), 120000);
+    }
+}
@@ -1,5 +1,11 @@
+use crate::decoder::crossfade::CrossfadeSettings;
+use crate::decoder::track_source::TrackSource;
 
 pub enum DecoderCommand {
     Seek(u64), // seek to sample position
+    SetNext(TrackSource, Option<CrossfadeSettings>), // track to continue with at end of stream
+    ClearNext,
+    CrossfadeTo(TrackSource, CrossfadeSettings), // fade into another track right away
+    SetGain(f32), // normalization gain of the playing track
     Stop,
-}
\ No newline at end of file
+}
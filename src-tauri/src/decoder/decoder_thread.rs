@@ -1,65 +1,84 @@
-use crate::player::shared::{PlaybackState};
-use anyhow::{Context, Error};
+use crate::player::shared::{AudioPlayerCommand, PlaybackState};
+use anyhow::Error;
 use ringbuf::producer::Producer;
+use ringbuf::traits::Observer;
 use ringbuf::HeapProd;
-use std::io::ErrorKind;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
-use symphonia::core::audio::SampleBuffer;
-use symphonia::core::codecs::{Decoder, DecoderOptions};
-use symphonia::core::errors::Error::IoError;
-use symphonia::core::formats::{FormatReader, SeekMode, SeekTo};
-use symphonia::core::units::Time;
-use symphonia::default::get_codecs;
+use crate::decoder::crossfade::{Crossfade, CrossfadeSettings};
 use crate::decoder::decoder_commands::DecoderCommand;
+use crate::decoder::track_source::TrackSource;
+
+// frames of silence the incoming track is faded in over if the outgoing one ends early
+const SILENCE_CHUNK_FRAMES: usize = 1024;
 
 pub fn start_decoder_thread(
-    mut format_reader: Box<dyn FormatReader>,
+    mut source: TrackSource,
     mut producer: HeapProd<f32>,
     state: Arc<Mutex<PlaybackState>>,
     decoder_command_receiver: Receiver<DecoderCommand>,
+    player_command_sender: Sender<AudioPlayerCommand>,
+    generation: u64,
 ) -> Result<(), Error> {
+    println!("Sample rate: {}", source.sample_rate);
 
-    let default_track = format_reader
-        .default_track()
-        .context("No default track found")?;
-
-    let track_id = default_track.id;
-    let sample_rate = default_track
-        .codec_params
-        .sample_rate
-        .context("No sample rate found")?;
-
-    // update state with track info
-    {
-        let mut state = state.lock().unwrap();
-        state.sample_rate = sample_rate;
-        state.current_position_samples = 0;
-        state.is_playing = true;
-        state.is_paused = false;
-        state.needs_buffer_clear = false;
-    }
-
-    println!("Sample rate: {}", sample_rate);
-
-    // create decoder
-    let mut decoder = get_codecs().make(&default_track.codec_params, &DecoderOptions::default())?;
+    let channels = source.channels as usize;
+    // track to continue with at the end of the current one, crossfaded if
+    // settings are given and joined gaplessly otherwise
+    let mut next_source: Option<(TrackSource, Option<CrossfadeSettings>)> = None;
+    let mut crossfade: Option<Crossfade> = None;
+    // path of the track we switched to, reported to the player thread once the
+    // remaining samples of the previous track have been played
+    let mut pending_track_advance: Option<String> = None;
+    let mut reached_end_of_stream = false;
 
     // decode loop
     loop {
         // check for decoder commands (non-blocking)
         if let Ok(cmd) = decoder_command_receiver.try_recv() {
             match cmd {
-                DecoderCommand::Seek(target_samples) => decode_samples(
-                    state.clone(),
-                    &mut format_reader,
-                    &mut decoder,
-                    target_samples,
-                    sample_rate,
-                    track_id,
-                ),
+                DecoderCommand::Seek(target_samples) => {
+                    if let Some(active) = crossfade.take() {
+                        if pending_track_advance.take().is_some() {
+                            // the fade isn't audible yet, so the seek is meant for the outgoing track
+                            println!("Cancelled crossfade into {}", active.incoming_path());
+                        } else {
+                            source = active.finish().0;
+                        }
+                    }
+                    seek_source(state.clone(), &mut source, target_samples)
+                }
+                DecoderCommand::SetNext(next, crossfade_settings) => {
+                    println!("Next track: {}", next.path);
+                    next_source = Some((next, crossfade_settings));
+                }
+                DecoderCommand::ClearNext => next_source = None,
+                DecoderCommand::CrossfadeTo(incoming, crossfade_settings) => {
+                    if !has_same_format(&source, &incoming) {
+                        eprintln!("Can't crossfade into {}, output format differs", incoming.path);
+                        continue;
+                    }
+                    // a fade that is still running is cut short
+                    if let Some(active) = crossfade.take() {
+                        source = active.finish().0;
+                    }
+                    println!("Crossfading into: {}", incoming.path);
+                    // the prepared next track followed the one we're fading out of
+                    next_source = None;
+                    schedule_track_switch(&producer, &state, channels);
+                    pending_track_advance = Some(incoming.path.clone());
+                    let total_frames = crossfade_settings.frames(source.sample_rate);
+                    crossfade = Some(Crossfade::new(incoming, crossfade_settings, total_frames));
+                }
+                DecoderCommand::SetGain(gain) => match crossfade.as_mut() {
+                    // the player already considers the incoming track the current one
+                    Some(active) if pending_track_advance.is_none() => {
+                        active.set_incoming_gain(gain)
+                    }
+                    _ => source.set_gain(gain),
+                },
                 DecoderCommand::Stop => break,
             }
         }
@@ -69,43 +88,82 @@ pub fn start_decoder_thread(
             if !state.is_playing {
                 break;
             }
+            if state.frames_until_track_switch.is_none() {
+                if let Some(path) = pending_track_advance.take() {
+                    let _ = player_command_sender
+                        .send(AudioPlayerCommand::TrackAdvanced(generation, path));
+                }
+            }
         }
-        // Get next packet
-        let packet = match format_reader.next_packet() {
-            Ok(packet) => packet,
-            Err(IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => {
-                println!("End of stream");
-                break;
+
+        // start fading into the next track once the current one is about to end
+        if crossfade.is_none() {
+            if let (Some(remaining_frames), Some((next, Some(crossfade_settings)))) =
+                (source.remaining_frames(), &next_source)
+            {
+                if remaining_frames <= crossfade_settings.frames(source.sample_rate)
+                    && has_same_format(&source, next)
+                {
+                    let (next, crossfade_settings) = next_source.take().unwrap();
+                    println!("Crossfading into: {}", next.path);
+                    schedule_track_switch(&producer, &state, channels);
+                    pending_track_advance = Some(next.path.clone());
+                    crossfade = Some(Crossfade::new(
+                        next,
+                        crossfade_settings.unwrap(),
+                        remaining_frames,
+                    ));
+                }
             }
+        }
+
+        // Decode the next packet
+        let next_samples = match source.next_samples() {
+            Ok(next_samples) => next_samples,
             Err(e) => {
                 eprintln!("Error reading packet: {}", e);
                 break;
             }
         };
 
-        // skip packets for other tracks
-        if packet.track_id() != track_id {
-            continue;
-        }
-
-        // decode the packet
-        let decoded = match decoder.decode(&packet) {
-            Ok(decoded) => decoded,
-            Err(e) => {
-                eprintln!("Decode error: {}", e);
-                continue;
+        let mut samples = match (next_samples, crossfade.as_mut()) {
+            (Some(mut samples), Some(active)) => {
+                active.mix(&mut samples, channels);
+                samples
+            }
+            // the outgoing track ended before the fade did, fade in the rest over silence
+            (None, Some(active)) => {
+                let mut silence = vec![0.0; SILENCE_CHUNK_FRAMES * channels];
+                active.mix(&mut silence, channels);
+                silence
+            }
+            (Some(samples), None) => samples,
+            (None, None) => {
+                match next_source.take() {
+                    Some((next, _)) if has_same_format(&source, &next) => {
+                        println!("Gapless transition to: {}", next.path);
+                        schedule_track_switch(&producer, &state, channels);
+                        pending_track_advance = Some(next.path.clone());
+                        source = next;
+                        continue;
+                    }
+                    Some((next, _)) => {
+                        println!("Can't play {} gaplessly, output format differs", next.path);
+                    }
+                    None => {}
+                }
+                println!("End of stream");
+                reached_end_of_stream = true;
+                break;
             }
         };
 
-        // convert samples to f32 interleaved
-        let spec = *decoded.spec();
-        let duration = decoded.capacity() as u64;
-
-        // convert to f32 samples
-        let mut sample_buf = SampleBuffer::<f32>::new(duration, spec);
-        sample_buf.copy_interleaved_ref(decoded);
-
-        let samples = sample_buf.samples();
+        if crossfade.as_ref().is_some_and(Crossfade::is_complete) {
+            let (incoming, leftover_samples) = crossfade.take().unwrap().finish();
+            println!("Crossfade into {} complete", incoming.path);
+            source = incoming;
+            samples.extend(leftover_samples);
+        }
 
         // Push to ring buffer (blocking if full)
         let mut written = 0;
@@ -124,6 +182,11 @@ pub fn start_decoder_thread(
                 }
             }
 
+            // the output stream was dropped, e.g. by loading another track
+            if !producer.read_is_held() {
+                return Ok(());
+            }
+
             written += producer.push_slice(&samples[written..]);
 
             if written < samples.len() {
@@ -133,37 +196,64 @@ pub fn start_decoder_thread(
         }
     }
 
-    let mut state = state.lock().unwrap();
-    state.is_playing = false;
+    // let the audio callback play out what is left in the buffer before
+    // reporting the end of the track
+    if reached_end_of_stream && !wait_for_buffer_to_drain(&producer, &state) {
+        return Ok(());
+    }
+
+    {
+        let mut state = state.lock().unwrap();
+        state.is_playing = false;
+    }
+
+    if reached_end_of_stream {
+        if let Some(path) = pending_track_advance {
+            let _ = player_command_sender.send(AudioPlayerCommand::TrackAdvanced(generation, path));
+        }
+        let _ = player_command_sender.send(AudioPlayerCommand::TrackFinished(generation));
+    }
     Ok(())
 }
 
-fn decode_samples(
-    state: Arc<Mutex<PlaybackState>>,
-    format_reader: &mut Box<dyn FormatReader>,
-    decoder: &mut Box<dyn Decoder>,
-    target_samples: u64,
-    sample_rate: u32,
-    track_id: u32,
+fn has_same_format(source: &TrackSource, other: &TrackSource) -> bool {
+    source.sample_rate == other.sample_rate && source.channels == other.channels
+}
+
+/// Lets the audio callback reset the position once the samples of the previous
+/// track that are buffered right now have been played
+fn schedule_track_switch(
+    producer: &HeapProd<f32>,
+    state: &Arc<Mutex<PlaybackState>>,
+    channels: usize,
 ) {
-    let target_seconds = target_samples as f64 / sample_rate as f64;
+    let buffered_frames = producer.occupied_len() / channels;
+    state.lock().unwrap().frames_until_track_switch = Some(buffered_frames as u64);
+}
+
+/// Blocks until the audio callback consumed all buffered samples.
+/// Returns false if playback was stopped in the meantime.
+fn wait_for_buffer_to_drain(producer: &HeapProd<f32>, state: &Arc<Mutex<PlaybackState>>) -> bool {
+    while !producer.is_empty() {
+        if !state.lock().unwrap().is_playing || !producer.read_is_held() {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
 
-    let seek_result = format_reader.seek(
-        SeekMode::Accurate,
-        SeekTo::Time {
-            time: Time::from(target_seconds),
-            track_id: Some(track_id),
-        },
-    );
+fn seek_source(state: Arc<Mutex<PlaybackState>>, source: &mut TrackSource, target_samples: u64) {
+    let target_seconds = target_samples as f64 / source.sample_rate as f64;
 
-    if let Ok(seeked_result) = seek_result {
+    if let Ok(seeked_result) = source.seek(target_seconds) {
         println!("Seeked to timestamp: {}", seeked_result.actual_ts);
 
         let mut state = state.lock().unwrap();
         state.current_position_samples = target_samples;
         state.needs_buffer_clear = true;
-
-        decoder.reset();
+        // clearing the buffer drops what was left of a previous track
+        state.frames_until_track_switch = None;
     } else {
         eprintln!("Seek failed");
     }
@@ -0,0 +1,127 @@
+use std::ops::Range;
+use symphonia::core::meta::Tag;
+use symphonia::core::probe::ProbeResult;
+
+/// Encoder delay and padding of a track. Encoders add silence at the start and
+/// the end of a stream which has to be cut for tracks to join sample-accurately.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GaplessInfo {
+    pub delay: u64,                // frames of encoder delay at the start of the stream
+    pub valid_frames: Option<u64>, // frames of actual audio following the delay
+}
+
+impl GaplessInfo {
+    /// Returns the range of frames within a decoded packet that belongs to the
+    /// actual audio. `ts` is the timestamp of the first frame of the packet.
+    pub fn trim_range(&self, ts: u64, frames: usize) -> Range<usize> {
+        let packet_end = ts + frames as u64;
+        let audio_start = self.delay;
+        let audio_end = match self.valid_frames {
+            Some(valid_frames) => self.delay + valid_frames,
+            None => u64::MAX,
+        };
+
+        let start = audio_start.clamp(ts, packet_end) - ts;
+        let end = audio_end.clamp(ts, packet_end) - ts;
+
+        start as usize..end.max(start) as usize
+    }
+}
+
+/// Looks for an iTunSMPB tag in the metadata of a probed file. Symphonia already
+/// trims the delay and padding of MP3 files read from the LAME header, but not the
+/// one iTunes and other AAC encoders store in this tag.
+pub fn find_gapless_info(probe_result: &mut ProbeResult) -> Option<GaplessInfo> {
+    if let Some(metadata) = probe_result.format.metadata().current() {
+        if let Some(info) = itunsmpb_from_tags(metadata.tags()) {
+            return Some(info);
+        }
+    }
+
+    let metadata = probe_result.metadata.get()?;
+    itunsmpb_from_tags(metadata.current()?.tags())
+}
+
+fn itunsmpb_from_tags(tags: &[Tag]) -> Option<GaplessInfo> {
+    tags.iter()
+        .find(|tag| tag.key.to_lowercase().ends_with("itunsmpb"))
+        .and_then(|tag| parse_itunsmpb(&tag.value.to_string()))
+}
+
+/// Parses an iTunSMPB value like
+/// ` 00000000 00000840 000001CA 00000000003F31F6 00000000 ...`
+/// where the second field is the encoder delay, the third the padding and the
+/// fourth the number of valid frames, all in hex.
+pub fn parse_itunsmpb(value: &str) -> Option<GaplessInfo> {
+    let fields: Vec<u64> = value
+        .split_whitespace()
+        .take(4)
+        .map(|field| u64::from_str_radix(field, 16))
+        .collect::<Result<_, _>>()
+        .ok()?;
+
+    if fields.len() < 3 {
+        return None;
+    }
+
+    let valid_frames = fields.get(3).copied().filter(|frames| *frames > 0);
+
+    Some(GaplessInfo {
+        delay: fields[1],
+        valid_frames,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_itunsmpb() {
+        let info = parse_itunsmpb(
+            " 00000000 00000840 000001CA 00000000003F31F6 00000000 00000000 00000000",
+        );
+        assert_eq!(
+            info,
+            Some(GaplessInfo {
+                delay: 0x840,
+                valid_frames: Some(0x3F31F6),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_itunsmpb() {
+        assert_eq!(parse_itunsmpb(""), None);
+        assert_eq!(parse_itunsmpb("not hex at all"), None);
+    }
+
+    #[test]
+    fn test_trim_range() {
+        let info = GaplessInfo {
+            delay: 2112,
+            valid_frames: Some(4000),
+        };
+
+        // packet entirely within the delay
+        assert!(info.trim_range(0, 1024).is_empty());
+        // packet containing the end of the delay
+        assert_eq!(info.trim_range(2048, 1024), 64..1024);
+        // packet entirely within the audio
+        assert_eq!(info.trim_range(3072, 1024), 0..1024);
+        // packet containing the start of the padding (audio ends at frame 6112)
+        assert_eq!(info.trim_range(6144 - 1024, 1024), 0..992);
+        // packet entirely within the padding
+        assert!(info.trim_range(7168, 1024).is_empty());
+    }
+
+    #[test]
+    fn test_trim_range_without_frame_count() {
+        let info = GaplessInfo {
+            delay: 100,
+            valid_frames: None,
+        };
+        assert_eq!(info.trim_range(0, 1024), 100..1024);
+        assert_eq!(info.trim_range(1024, 1024), 0..1024);
+    }
+}
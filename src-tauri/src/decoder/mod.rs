@@ -1,2 +1,7 @@
 pub mod decoder_thread;
-pub mod decoder_commands;
\ No newline at end of file
+pub mod decoder_commands;
+pub mod channel_mixer;
+pub mod crossfade;
+pub mod gapless;
+pub mod resampler;
+pub mod track_source;
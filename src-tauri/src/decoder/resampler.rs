@@ -0,0 +1,199 @@
+use anyhow::Error;
+use rubato::{
+    Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+
+// input frames the resampler processes at once
+const CHUNK_FRAMES: usize = 1024;
+
+/// Converts interleaved samples between sample rates with band-limited sinc
+/// interpolation. The output is cut to exactly the duration of the input, so
+/// tracks still join without a gap.
+pub struct SampleRateConverter {
+    resampler: SincFixedIn<f32>,
+    ratio: f64,
+    channels: usize,
+    // deinterleaved input waiting for a full chunk
+    input_buffer: Vec<Vec<f32>>,
+    input_frames: u64,
+    output_frames: u64,
+    flushed: bool,
+}
+
+impl SampleRateConverter {
+    pub fn new(input_rate: u32, output_rate: u32, channels: usize) -> Result<SampleRateConverter, Error> {
+        let parameters = SincInterpolationParameters {
+            sinc_len: 256,
+            f_cutoff: 0.95,
+            interpolation: SincInterpolationType::Cubic,
+            oversampling_factor: 256,
+            window: WindowFunction::BlackmanHarris2,
+        };
+        let ratio = output_rate as f64 / input_rate as f64;
+        let resampler = SincFixedIn::<f32>::new(ratio, 1.0, parameters, CHUNK_FRAMES, channels)?;
+
+        Ok(SampleRateConverter {
+            resampler,
+            ratio,
+            channels,
+            input_buffer: vec![Vec::new(); channels],
+            input_frames: 0,
+            output_frames: 0,
+            flushed: false,
+        })
+    }
+
+    pub fn process(&mut self, samples: &[f32]) -> Result<Vec<f32>, Error> {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                self.input_buffer[channel].push(*sample);
+            }
+        }
+        self.input_frames += (samples.len() / self.channels) as u64;
+
+        let mut output = Vec::new();
+        while self.input_buffer[0].len() >= self.resampler.input_frames_next() {
+            let chunk_frames = self.resampler.input_frames_next();
+            let resampled = {
+                let chunk: Vec<&[f32]> = self
+                    .input_buffer
+                    .iter()
+                    .map(|channel| &channel[..chunk_frames])
+                    .collect();
+                self.resampler.process(&chunk, None)?
+            };
+            for channel in self.input_buffer.iter_mut() {
+                channel.drain(..chunk_frames);
+            }
+            self.append_interleaved(&resampled, &mut output);
+        }
+        Ok(output)
+    }
+
+    /// Pushes the buffered input and the tail of the filter out at the end of the stream
+    pub fn flush(&mut self) -> Result<Vec<f32>, Error> {
+        if self.flushed {
+            return Ok(Vec::new());
+        }
+        self.flushed = true;
+
+        let expected_frames = (self.input_frames as f64 * self.ratio).round() as u64;
+        let mut output = Vec::new();
+
+        if !self.input_buffer[0].is_empty() {
+            let resampled = self.resampler.process_partial(Some(self.input_buffer.as_slice()), None)?;
+            for channel in self.input_buffer.iter_mut() {
+                channel.clear();
+            }
+            self.append_interleaved(&resampled, &mut output);
+        }
+        while self.output_frames < expected_frames {
+            let resampled = self.resampler.process_partial::<Vec<f32>>(None, None)?;
+            if resampled[0].is_empty() {
+                break;
+            }
+            self.append_interleaved(&resampled, &mut output);
+        }
+
+        // cut the zero padding that was pushed through after the end of the input
+        let excess_frames = self.output_frames.saturating_sub(expected_frames) as usize;
+        output.truncate(output.len().saturating_sub(excess_frames * self.channels));
+        self.output_frames -= excess_frames as u64;
+        Ok(output)
+    }
+
+    /// Drops all buffered samples, e.g. after seeking
+    pub fn reset(&mut self) {
+        self.resampler.reset();
+        for channel in self.input_buffer.iter_mut() {
+            channel.clear();
+        }
+        self.input_frames = 0;
+        self.output_frames = 0;
+        self.flushed = false;
+    }
+
+    fn append_interleaved(&mut self, resampled: &[Vec<f32>], output: &mut Vec<f32>) {
+        let frames = resampled[0].len();
+        for frame in 0..frames {
+            for channel in resampled {
+                output.push(channel[frame]);
+            }
+        }
+        self.output_frames += frames as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, sample_rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| {
+                (2.0 * std::f32::consts::PI * frequency * frame as f32 / sample_rate as f32).sin()
+            })
+            .collect()
+    }
+
+    fn convert(converter: &mut SampleRateConverter, samples: &[f32]) -> Vec<f32> {
+        let mut output = Vec::new();
+        // feed in packet sized pieces like the decoder does
+        for packet in samples.chunks(1152 * converter.channels) {
+            output.extend(converter.process(packet).unwrap());
+        }
+        output.extend(converter.flush().unwrap());
+        output
+    }
+
+    #[test]
+    fn test_output_length_matches_ratio() {
+        let mut converter = SampleRateConverter::new(44100, 48000, 2).unwrap();
+        let input = vec![0.0; 44100 * 2];
+        let output = convert(&mut converter, &input);
+        assert_eq!(output.len(), 48000 * 2);
+    }
+
+    #[test]
+    fn test_downsampling_length() {
+        let mut converter = SampleRateConverter::new(96000, 44100, 1).unwrap();
+        let output = convert(&mut converter, &vec![0.0; 9600]);
+        assert_eq!(output.len(), 4410);
+    }
+
+    #[test]
+    fn test_sine_keeps_phase_and_amplitude() {
+        let mut converter = SampleRateConverter::new(44100, 48000, 1).unwrap();
+        let output = convert(&mut converter, &sine(440.0, 44100, 44100));
+        let expected = sine(440.0, 48000, 48000);
+
+        // compare away from the edges, where the filter has no full window
+        for frame in 1000..47000 {
+            assert!(
+                (output[frame] - expected[frame]).abs() < 1e-2,
+                "frame {}: expected {}, got {}",
+                frame,
+                expected[frame],
+                output[frame]
+            );
+        }
+    }
+
+    #[test]
+    fn test_flush_only_once() {
+        let mut converter = SampleRateConverter::new(44100, 48000, 2).unwrap();
+        converter.process(&vec![0.0; 2000]).unwrap();
+        converter.flush().unwrap();
+        assert!(converter.flush().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut converter = SampleRateConverter::new(44100, 48000, 1).unwrap();
+        converter.process(&vec![0.5; 3000]).unwrap();
+        converter.reset();
+        let output = convert(&mut converter, &vec![0.0; 4410]);
+        assert_eq!(output.len(), 4800);
+        assert!(output.iter().all(|sample| sample.abs() < 1e-6));
+    }
+}
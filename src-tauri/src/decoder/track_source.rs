@@ -0,0 +1,232 @@
+use crate::decoder::channel_mixer::ChannelMixer;
+use crate::decoder::gapless::{find_gapless_info, GaplessInfo};
+use crate::decoder::resampler::SampleRateConverter;
+use crate::player::probe::probe_audio_file;
+use anyhow::{Context, Error};
+use std::io::ErrorKind;
+use symphonia::core::audio::{Channels, SampleBuffer};
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error::IoError;
+use symphonia::core::formats::{FormatReader, SeekMode, SeekTo, SeekedTo};
+use symphonia::core::units::Time;
+use symphonia::default::get_codecs;
+
+/// A probed audio file together with its decoder, producing interleaved f32 samples
+/// in the file's own format or, after `convert_to`, in the format of the output
+pub struct TrackSource {
+    pub path: String,
+    pub sample_rate: u32, // sample rate of the produced samples
+    pub channels: u16,    // channel count of the produced samples
+    file_sample_rate: u32,
+    channel_layout: Channels,
+    channel_mixer: Option<ChannelMixer>,
+    sample_rate_converter: Option<SampleRateConverter>,
+    gain: f32, // linear gain for loudness normalization
+    format_reader: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    gapless_info: Option<GaplessInfo>,
+    end_ts: Option<u64>,  // timestamp after the last frame of actual audio, if known
+    position_ts: u64,     // timestamp after the last decoded frame
+}
+
+impl TrackSource {
+    pub fn open(path: &str) -> Result<TrackSource, Error> {
+        let mut probe_result = probe_audio_file(path)?;
+        let gapless_info = find_gapless_info(&mut probe_result);
+        let format_reader = probe_result.format;
+
+        let track = format_reader
+            .default_track()
+            .context("No default track found")?;
+
+        let track_id = track.id;
+        let sample_rate = track
+            .codec_params
+            .sample_rate
+            .context("No sample rate found")?;
+        let channel_layout = track
+            .codec_params
+            .channels
+            .unwrap_or(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let channels = channel_layout.count() as u16;
+
+        // symphonia trims the delay and padding itself if it found them
+        let gapless_info = match track.codec_params.delay {
+            Some(_) => None,
+            None => gapless_info,
+        };
+
+        let end_ts = match gapless_info {
+            Some(GaplessInfo {
+                delay,
+                valid_frames: Some(valid_frames),
+            }) => Some(delay + valid_frames),
+            _ => track
+                .codec_params
+                .n_frames
+                .map(|n_frames| track.codec_params.start_ts + n_frames),
+        };
+
+        let decoder = get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+        Ok(TrackSource {
+            path: path.to_string(),
+            sample_rate,
+            channels,
+            file_sample_rate: sample_rate,
+            channel_layout,
+            channel_mixer: None,
+            sample_rate_converter: None,
+            gain: 1.0,
+            format_reader,
+            decoder,
+            track_id,
+            gapless_info,
+            end_ts,
+            position_ts: 0,
+        })
+    }
+
+    /// Makes the source produce samples with the given sample rate and channel
+    /// count, resampling and up- or downmixing where the file differs
+    pub fn convert_to(&mut self, sample_rate: u32, channels: u16) -> Result<(), Error> {
+        let channel_mixer = ChannelMixer::new(self.channel_layout, channels as usize);
+        self.channel_mixer = (!channel_mixer.is_passthrough()).then_some(channel_mixer);
+        self.sample_rate_converter = if sample_rate != self.file_sample_rate {
+            Some(SampleRateConverter::new(
+                self.file_sample_rate,
+                sample_rate,
+                channels as usize,
+            )?)
+        } else {
+            None
+        };
+        if self.channel_mixer.is_some() || self.sample_rate_converter.is_some() {
+            println!(
+                "Converting {} Hz, {} channels to {} Hz, {} channels",
+                self.file_sample_rate,
+                self.channel_layout.count(),
+                sample_rate,
+                channels
+            );
+        }
+        self.sample_rate = sample_rate;
+        self.channels = channels;
+        Ok(())
+    }
+
+    /// Channel layout of the file, which is also the one of the produced samples
+    /// unless `convert_to` was called
+    pub fn channel_layout(&self) -> Channels {
+        self.channel_layout
+    }
+
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    /// Decodes the next packet into interleaved f32 samples with encoder delay and
+    /// padding removed, normalized and converted to the output format. Returns None at the end
+    /// of the stream.
+    pub fn next_samples(&mut self) -> Result<Option<Vec<f32>>, Error> {
+        loop {
+            let Some(mut samples) = self.next_decoded_samples()? else {
+                // the resampler still holds the last few milliseconds
+                return match &mut self.sample_rate_converter {
+                    Some(converter) => {
+                        let tail = converter.flush()?;
+                        Ok((!tail.is_empty()).then_some(tail))
+                    }
+                    None => Ok(None),
+                };
+            };
+
+            if self.gain != 1.0 {
+                for sample in samples.iter_mut() {
+                    *sample *= self.gain;
+                }
+            }
+
+            let samples = match &self.channel_mixer {
+                Some(channel_mixer) => channel_mixer.mix(&samples),
+                None => samples,
+            };
+            let samples = match &mut self.sample_rate_converter {
+                Some(converter) => converter.process(&samples)?,
+                None => samples,
+            };
+            // the resampler may have buffered the whole packet
+            if !samples.is_empty() {
+                return Ok(Some(samples));
+            }
+        }
+    }
+
+    fn next_decoded_samples(&mut self) -> Result<Option<Vec<f32>>, Error> {
+        loop {
+            let packet = match self.format_reader.next_packet() {
+                Ok(packet) => packet,
+                Err(IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            // skip packets for other tracks
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            // decode the packet
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    eprintln!("Decode error: {}", e);
+                    continue;
+                }
+            };
+
+            let spec = *decoded.spec();
+            let duration = decoded.capacity() as u64;
+            let frames = decoded.frames();
+            let channels = spec.channels.count();
+
+            // convert to f32 interleaved samples
+            let mut sample_buf = SampleBuffer::<f32>::new(duration, spec);
+            sample_buf.copy_interleaved_ref(decoded);
+
+            self.position_ts = packet.ts() + frames as u64;
+
+            let frame_range = match &self.gapless_info {
+                Some(gapless_info) => gapless_info.trim_range(packet.ts(), frames),
+                None => 0..frames,
+            };
+
+            let samples = &sample_buf.samples()[frame_range.start * channels..frame_range.end * channels];
+            return Ok(Some(samples.to_vec()));
+        }
+    }
+
+    /// Number of frames left until the end of the track at the output sample rate,
+    /// if the length is known
+    pub fn remaining_frames(&self) -> Option<u64> {
+        self.end_ts.map(|end_ts| {
+            let remaining_file_frames = end_ts.saturating_sub(self.position_ts);
+            remaining_file_frames * self.sample_rate as u64 / self.file_sample_rate as u64
+        })
+    }
+
+    pub fn seek(&mut self, position_seconds: f64) -> Result<SeekedTo, Error> {
+        let seeked_to = self.format_reader.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(position_seconds),
+                track_id: Some(self.track_id),
+            },
+        )?;
+        self.decoder.reset();
+        if let Some(converter) = &mut self.sample_rate_converter {
+            converter.reset();
+        }
+        Ok(seeked_to)
+    }
+}
@@ -0,0 +1,404 @@
+use crate::decoder::track_source::TrackSource;
+use crate::tags::reading_tags::read_audio_file_properties;
+use anyhow::{bail, Error};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+
+// the parameters of Chromaprint's default algorithm (TEST2), which AcoustID expects
+const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+const FRAME_STEP: usize = FRAME_SIZE / 3;
+const MIN_FREQUENCY: f64 = 28.0;
+const MAX_FREQUENCY: f64 = 3520.0;
+const BANDS: usize = 12;
+const CHROMA_FILTER: [f64; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+const NORMALIZE_THRESHOLD: f64 = 0.01;
+const ALGORITHM: u8 = 1;
+// like fpcalc, only the beginning of the track is fingerprinted
+const MAX_SECONDS: usize = 120;
+
+/// The filter type, band, height and width of a classifier and the thresholds
+/// that quantize its response
+struct Classifier {
+    filter: u8,
+    y: usize,
+    height: usize,
+    width: usize,
+    thresholds: [f64; 3],
+}
+
+const fn classifier(filter: u8, y: usize, height: usize, width: usize, thresholds: [f64; 3]) -> Classifier {
+    Classifier {
+        filter,
+        y,
+        height,
+        width,
+        thresholds,
+    }
+}
+
+const CLASSIFIERS: [Classifier; 16] = [
+    classifier(0, 4, 3, 15, [1.98215, 2.35817, 2.63523]),
+    classifier(4, 4, 6, 15, [-1.03809, -0.651211, -0.282167]),
+    classifier(1, 0, 4, 16, [-0.298702, 0.119262, 0.558497]),
+    classifier(3, 8, 2, 12, [-0.105439, 0.0153946, 0.135898]),
+    classifier(3, 4, 4, 8, [-0.142891, 0.0258736, 0.200632]),
+    classifier(4, 0, 3, 5, [-0.826319, -0.590612, -0.368214]),
+    classifier(1, 2, 2, 9, [-0.557409, -0.233035, 0.0534525]),
+    classifier(2, 7, 3, 4, [-0.0646826, 0.00620476, 0.0784847]),
+    classifier(2, 6, 2, 16, [-0.192387, -0.029699, 0.215855]),
+    classifier(2, 1, 3, 2, [-0.0397818, -0.00568076, 0.0292026]),
+    classifier(5, 10, 1, 15, [-0.53823, -0.369934, -0.190235]),
+    classifier(3, 6, 2, 10, [-0.124877, 0.0296483, 0.139239]),
+    classifier(2, 1, 1, 14, [-0.101475, 0.0225617, 0.231971]),
+    classifier(3, 5, 6, 4, [-0.0799915, -0.00729616, 0.063262]),
+    classifier(1, 9, 2, 12, [-0.272556, 0.019424, 0.302559]),
+    classifier(3, 4, 2, 14, [-0.164292, -0.0321188, 0.08463]),
+];
+
+const MAX_CLASSIFIER_WIDTH: usize = 16;
+
+/// A compressed, base64 encoded Chromaprint fingerprint and the duration of the
+/// whole track, as AcoustID takes them
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Fingerprint {
+    pub fingerprint: String,
+    pub duration_secs: u32,
+}
+
+/// Decodes the beginning of a file and fingerprints it
+pub fn fingerprint_file(path: &Path) -> Result<Fingerprint, Error> {
+    let duration_millis = read_audio_file_properties(path)?.duration_millis;
+    let mut source = TrackSource::open(&path.to_string_lossy())?;
+    source.convert_to(SAMPLE_RATE, 1)?;
+    let mut fingerprinter = Fingerprinter::new();
+    while let Some(samples) = source.next_samples()? {
+        if !fingerprinter.add_samples(&samples) {
+            break;
+        }
+    }
+    let fingerprint = fingerprinter.finish();
+    if fingerprint.is_empty() {
+        bail!("{} is too short to fingerprint", path.display());
+    }
+    Ok(Fingerprint {
+        fingerprint: encode_fingerprint(&fingerprint),
+        duration_secs: (duration_millis / 1000) as u32,
+    })
+}
+
+/// Computes the raw fingerprint of mono samples at 11025 Hz, one 32 bit
+/// subfingerprint per frame step
+struct Fingerprinter {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    bands: Vec<Option<usize>>, // chroma band of every FFT bin
+    samples: Vec<f32>,         // not yet processed samples
+    sample_count: usize,
+    chroma: VecDeque<[f64; BANDS]>, // the last frames for the chroma filter
+    image: Vec<[f64; BANDS]>,       // filtered and normalized chroma, one row per frame
+}
+
+impl Fingerprinter {
+    fn new() -> Fingerprinter {
+        let window = (0..FRAME_SIZE)
+            .map(|i| 0.54 - 0.46 * (2.0 * std::f32::consts::PI * i as f32 / (FRAME_SIZE - 1) as f32).cos())
+            .collect();
+        Fingerprinter {
+            fft: FftPlanner::new().plan_fft_forward(FRAME_SIZE),
+            window,
+            bands: chroma_bands(),
+            samples: Vec::with_capacity(FRAME_SIZE * 2),
+            sample_count: 0,
+            chroma: VecDeque::with_capacity(CHROMA_FILTER.len()),
+            image: Vec::new(),
+        }
+    }
+
+    /// Adds samples and returns whether more are wanted
+    fn add_samples(&mut self, samples: &[f32]) -> bool {
+        let wanted = (MAX_SECONDS * SAMPLE_RATE as usize - self.sample_count).min(samples.len());
+        self.sample_count += wanted;
+        self.samples.extend_from_slice(&samples[..wanted]);
+        let mut start = 0;
+        while self.samples.len() - start >= FRAME_SIZE {
+            let frame = self.samples[start..start + FRAME_SIZE].to_vec();
+            self.add_frame(&frame);
+            start += FRAME_STEP;
+        }
+        self.samples.drain(..start);
+        self.sample_count < MAX_SECONDS * SAMPLE_RATE as usize
+    }
+
+    fn finish(self) -> Vec<u32> {
+        if self.image.len() < MAX_CLASSIFIER_WIDTH {
+            return Vec::new();
+        }
+        let integral = IntegralImage::new(&self.image);
+        (0..=self.image.len() - MAX_CLASSIFIER_WIDTH)
+            .map(|x| subfingerprint(&integral, x))
+            .collect()
+    }
+
+    fn add_frame(&mut self, frame: &[f32]) {
+        let mut buffer: Vec<Complex<f32>> = frame
+            .iter()
+            .zip(&self.window)
+            .map(|(sample, window)| Complex::new(sample * window, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+
+        let mut chroma = [0.0; BANDS];
+        for (bin, band) in self.bands.iter().enumerate() {
+            if let Some(band) = band {
+                chroma[*band] += buffer[bin].norm_sqr() as f64;
+            }
+        }
+
+        if self.chroma.len() == CHROMA_FILTER.len() {
+            self.chroma.pop_front();
+        }
+        self.chroma.push_back(chroma);
+        if self.chroma.len() < CHROMA_FILTER.len() {
+            return;
+        }
+        let mut filtered = [0.0; BANDS];
+        for (frame, coefficient) in self.chroma.iter().zip(CHROMA_FILTER) {
+            for (filtered, value) in filtered.iter_mut().zip(frame) {
+                *filtered += value * coefficient;
+            }
+        }
+        self.image.push(normalize(filtered));
+    }
+}
+
+/// The chroma band of every bin in the frequency range, from its pitch class
+fn chroma_bands() -> Vec<Option<usize>> {
+    let bin_of = |frequency: f64| (FRAME_SIZE as f64 * frequency / SAMPLE_RATE as f64).round() as usize;
+    let min_bin = bin_of(MIN_FREQUENCY).max(1);
+    let max_bin = bin_of(MAX_FREQUENCY).min(FRAME_SIZE / 2);
+    (0..max_bin)
+        .map(|bin| {
+            if bin < min_bin {
+                return None;
+            }
+            let frequency = bin as f64 * SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+            // octaves above A0
+            let octave = (frequency / 27.5).log2();
+            Some((BANDS as f64 * octave.fract()) as usize)
+        })
+        .collect()
+}
+
+/// Scales to unit length, or to zero for near silence
+fn normalize(chroma: [f64; BANDS]) -> [f64; BANDS] {
+    let norm = chroma.iter().map(|value| value * value).sum::<f64>().sqrt();
+    if norm < NORMALIZE_THRESHOLD {
+        return [0.0; BANDS];
+    }
+    chroma.map(|value| value / norm)
+}
+
+/// Sums over the image so that the sum of any rectangle takes four lookups
+struct IntegralImage {
+    sums: Vec<[f64; BANDS + 1]>, // with a row and column of zeros in front
+}
+
+impl IntegralImage {
+    fn new(image: &[[f64; BANDS]]) -> IntegralImage {
+        let mut sums = vec![[0.0; BANDS + 1]; image.len() + 1];
+        for (row, values) in image.iter().enumerate() {
+            for (column, value) in values.iter().enumerate() {
+                sums[row + 1][column + 1] = value + sums[row][column + 1] + sums[row + 1][column] - sums[row][column];
+            }
+        }
+        IntegralImage { sums }
+    }
+
+    /// Sum of rows `x1..x2` and bands `y1..y2`
+    fn area(&self, x1: usize, y1: usize, x2: usize, y2: usize) -> f64 {
+        self.sums[x2][y2] - self.sums[x1][y2] - self.sums[x2][y1] + self.sums[x1][y1]
+    }
+}
+
+/// Two bits from every classifier, gray coded
+fn subfingerprint(image: &IntegralImage, x: usize) -> u32 {
+    const GRAY_CODE: [u32; 4] = [0, 1, 3, 2];
+    CLASSIFIERS.iter().fold(0, |bits, classifier| {
+        let value = apply_filter(image, classifier, x);
+        let [t0, t1, t2] = classifier.thresholds;
+        let quantized = if value < t1 {
+            if value < t0 { 0 } else { 1 }
+        } else if value < t2 {
+            2
+        } else {
+            3
+        };
+        (bits << 2) | GRAY_CODE[quantized]
+    })
+}
+
+/// Compares parts of a rectangle of the image, `width` frames from `x` and
+/// `height` bands from the classifier's band
+fn apply_filter(image: &IntegralImage, classifier: &Classifier, x: usize) -> f64 {
+    let Classifier { y, height: h, width: w, .. } = *classifier;
+    let area = |x1: usize, y1: usize, x2: usize, y2: usize| image.area(x1, y1, x2, y2);
+    let (a, b) = match classifier.filter {
+        0 => (area(x, y, x + w, y + h), 0.0),
+        1 => {
+            let h2 = h / 2;
+            (area(x, y + h2, x + w, y + h), area(x, y, x + w, y + h2))
+        }
+        2 => {
+            let w2 = w / 2;
+            (area(x + w2, y, x + w, y + h), area(x, y, x + w2, y + h))
+        }
+        3 => {
+            let (w2, h2) = (w / 2, h / 2);
+            (
+                area(x, y, x + w2, y + h2) + area(x + w2, y + h2, x + w, y + h),
+                area(x, y + h2, x + w2, y + h) + area(x + w2, y, x + w, y + h2),
+            )
+        }
+        4 => {
+            let h3 = h / 3;
+            (
+                area(x, y + h3, x + w, y + 2 * h3),
+                area(x, y, x + w, y + h3) + area(x, y + 2 * h3, x + w, y + h),
+            )
+        }
+        _ => {
+            let w3 = w / 3;
+            (
+                area(x + w3, y, x + 2 * w3, y + h),
+                area(x, y, x + w3, y + h) + area(x + 2 * w3, y, x + w, y + h),
+            )
+        }
+    };
+    ((1.0 + a) / (1.0 + b)).ln()
+}
+
+/// Compresses a raw fingerprint like Chromaprint and encodes it as URL safe base64
+pub fn encode_fingerprint(fingerprint: &[u32]) -> String {
+    encode_base64(&compress(fingerprint))
+}
+
+/// The algorithm and length, then the positions of the bits that changed from
+/// one subfingerprint to the next as 3 bit gaps, with gaps of 7 or more
+/// continued in 5 bits
+fn compress(fingerprint: &[u32]) -> Vec<u8> {
+    const MAX_NORMAL_GAP: u32 = 7;
+    let mut normal = Vec::new();
+    let mut exceptional = Vec::new();
+    let mut previous = 0;
+    for &subfingerprint in fingerprint {
+        let mut changed = subfingerprint ^ previous;
+        previous = subfingerprint;
+        let mut last_bit = 0;
+        while changed != 0 {
+            let bit = changed.trailing_zeros() + 1;
+            let gap = bit - last_bit;
+            normal.push(gap.min(MAX_NORMAL_GAP));
+            if gap >= MAX_NORMAL_GAP {
+                exceptional.push(gap - MAX_NORMAL_GAP);
+            }
+            last_bit = bit;
+            changed &= changed - 1;
+        }
+        normal.push(0);
+    }
+
+    let length = fingerprint.len() as u32;
+    let mut bytes = vec![ALGORITHM, (length >> 16) as u8, (length >> 8) as u8, length as u8];
+    bytes.extend(pack_bits(&normal, 3));
+    bytes.extend(pack_bits(&exceptional, 5));
+    bytes
+}
+
+/// Packs values of `bits` bits each, least significant bit first
+fn pack_bits(values: &[u32], bits: u32) -> Vec<u8> {
+    let mut bytes = vec![0u8; (values.len() * bits as usize).div_ceil(8)];
+    for (index, value) in values.iter().enumerate() {
+        for bit in 0..bits {
+            if value & (1 << bit) != 0 {
+                let position = index * bits as usize + bit as usize;
+                bytes[position / 8] |= 1 << (position % 8);
+            }
+        }
+    }
+    bytes
+}
+
+/// Base64 with `-` and `_` and without padding
+fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| group | ((*byte as u32) << (16 - 8 * i)));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[((group >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::write_tone_wav;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_compress() {
+        // the cases of Chromaprint's own compressor tests
+        assert_eq!(compress(&[1]), vec![1, 0, 0, 1, 0x01]);
+        assert_eq!(compress(&[7]), vec![1, 0, 0, 1, 0x49, 0x00]);
+        assert_eq!(compress(&[1 << 6]), vec![1, 0, 0, 1, 0x07, 0x00]);
+        assert_eq!(compress(&[1 << 8]), vec![1, 0, 0, 1, 0x07, 0x02]);
+        assert_eq!(compress(&[1, 0]), vec![1, 0, 0, 2, 0x41, 0x00]);
+        assert_eq!(compress(&[1, 1]), vec![1, 0, 0, 2, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!(encode_base64(b"x"), "eA");
+        assert_eq!(encode_base64(b"xyz"), "eHl6");
+        assert_eq!(encode_base64(&[0xfb, 0xff]), "-_8");
+        assert_eq!(encode_fingerprint(&[1]), "AQAAAQE");
+    }
+
+    #[test]
+    fn test_fingerprint_file() {
+        let dir = tempdir().unwrap();
+        let loud = dir.path().join("loud.wav");
+        let quiet = dir.path().join("quiet.wav");
+        let other = dir.path().join("other.wav");
+        write_tone_wav(&loud, 440.0, 0.8, 12);
+        write_tone_wav(&quiet, 440.0, 0.2, 12);
+        write_tone_wav(&other, 311.0, 0.8, 12);
+
+        let fingerprint = fingerprint_file(&loud).unwrap();
+        assert_eq!(fingerprint.duration_secs, 12);
+        assert!(fingerprint.fingerprint.starts_with("AQAA"));
+        // the chroma is normalized, so the level does not matter
+        assert_eq!(fingerprint, fingerprint_file(&quiet).unwrap());
+        assert_ne!(fingerprint.fingerprint, fingerprint_file(&other).unwrap().fingerprint);
+    }
+
+    #[test]
+    fn test_fingerprint_length() {
+        let mut fingerprinter = Fingerprinter::new();
+        let seconds = 10;
+        let samples: Vec<f32> = (0..SAMPLE_RATE as usize * seconds)
+            .map(|i| (i as f32 * 0.1).sin() * 0.5)
+            .collect();
+        assert!(fingerprinter.add_samples(&samples));
+        let frames = (samples.len() - FRAME_SIZE) / FRAME_STEP + 1;
+        let fingerprint = fingerprinter.finish();
+        assert_eq!(fingerprint.len(), frames - (CHROMA_FILTER.len() - 1) - (MAX_CLASSIFIER_WIDTH - 1));
+    }
+}
@@ -1,21 +1,67 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 mod player;
 pub mod read_music_library;
+mod cover_protocol;
+mod library_db;
+mod library_scan;
+mod library_watcher;
 mod tags;
 mod decoder;
 mod audio;
+mod loudness;
 pub mod musicbrainz;
+mod musicbrainz_client;
 mod musicbrainz_tag_mapping;
+mod musicbrainz_matching;
+mod musicbrainz_album;
+mod musicbrainz_scoring;
+mod musicbrainz_lookup;
+#[cfg(test)]
+mod musicbrainz_fixtures;
+mod fingerprint;
+mod acoustid;
+mod rate_limiter;
+mod cover_art_archive;
+mod service_settings;
+mod tag_journal;
+#[cfg(test)]
+mod test_support;
 
+use crate::audio::output_devices::{
+    list_output_devices, list_output_hosts, load_output_device_settings,
+    output_device_settings_path, OutputDevice, OutputHost,
+};
+use crate::cover_protocol::{handle_cover_request, COVER_SCHEME};
+use crate::decoder::crossfade::CrossfadeSettings;
+use crate::library_db::LibraryDatabase;
+use crate::library_scan::LibraryScanJob;
+use crate::library_watcher::LibraryWatcher;
+use crate::loudness::analysis::LoudnessAnalysisJob;
+use crate::acoustid::{AcoustIdClient, AcoustIdSettings};
+use crate::cover_art_archive::{
+    CoverArtArchiveClient, CoverArtArchiveSettings, CoverArtEntity, CoverArtImage, CoverArtRequest, CoverTarget,
+};
+use crate::musicbrainz_album::{AlbumGroup, ReleaseMatch, TrackTags};
+use crate::musicbrainz_client::{MusicBrainzClient, MusicBrainzSettings};
+use crate::musicbrainz_lookup::FileLookup;
+use crate::musicbrainz_matching::{Candidate, FieldDiff};
+use crate::musicbrainz_scoring::ScoringSettings;
+use crate::player::normalization::NormalizationSettings;
 use crate::player::shared::AudioPlayerCommand;
 use crate::player::threads::player_thread::player_thread;
-use crate::read_music_library::{read_music_library, Library};
-use crate::tags::writing_tags::{write_tags_to_file, get_supported_tags as get_supported_tags_list};
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::{mpsc, Arc};
-use std::thread;
-use tauri::{Manager, State};
+use crate::read_music_library::Library;
+use crate::service_settings::{load_service_settings, save_service_settings, service_settings_path, ServiceSettings};
+use crate::tag_journal::{TagBatch, TagEdit, TagJournal};
+use crate::tags::batch_edit::{batch_edit, BatchEdit, BatchEditResult};
+use crate::tags::cover_art::{self, EmbedOptions, PictureInfo};
+use crate::tags::filename_pattern::{self, FileRename, FilenamePattern, FilenameTags};
+use crate::tags::multi_value::{SplitSettings, TagMap};
+use crate::tags::tag_containers::{self, parse_tag_type, FileTags};
+use crate::tags::writing_tags::{patch_tags_in_file, write_tags_to_file, get_supported_tags as get_supported_tags_list, TagPatch};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tauri::{AppHandle, Emitter, Manager, RunEvent, State};
 
 #[tauri::command]
 fn load_and_play(path: String, audio_player: State<AudioPlayer>) -> Result<(), String> {
@@ -35,9 +81,26 @@ fn toggle_playback(audio_player: State<AudioPlayer>) -> Result<(), String> {
     Ok(())
 }
 
+/// Returns the library as it was at the last scan, empty if it never was scanned
 #[tauri::command]
-fn get_music_library(path: String) -> Library {
-    read_music_library(Path::new(&path))
+fn get_music_library(path: String, library_database: State<Mutex<LibraryDatabase>>) -> Result<Library, String> {
+    library_database
+        .lock()
+        .unwrap()
+        .cached_library(Path::new(&path))
+        .map_err(|e| e.to_string())
+}
+
+/// Starts rescanning the library in the background. The changes come in with
+/// `library:changed` events; the library is watched for changes afterwards.
+#[tauri::command]
+fn rescan_music_library(path: String, app_handle: AppHandle, library_scan: State<LibraryScanJob>) {
+    library_scan.start(app_handle, PathBuf::from(path));
+}
+
+#[tauri::command]
+fn cancel_library_scan(library_scan: State<LibraryScanJob>) {
+    library_scan.cancel();
 }
 
 #[tauri::command]
@@ -62,48 +125,763 @@ fn seek(position_millis: u32, state: State<AudioPlayer>) -> Result<(), String> {
 }
 
 #[tauri::command]
-fn write_tags(path: String, tags: HashMap<String, String>) -> Result<(), String> {
-    write_tags_to_file(Path::new(&path), &tags).map_err(|e| e.to_string())?;
+fn enqueue(paths: Vec<String>, audio_player: State<AudioPlayer>) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::Enqueue(paths))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn insert_into_queue(
+    index: usize,
+    paths: Vec<String>,
+    audio_player: State<AudioPlayer>,
+) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::InsertIntoQueue(index, paths))
+        .map_err(|e| e.to_string())?;
     Ok(())
 }
 
+#[tauri::command]
+fn remove_from_queue(index: usize, audio_player: State<AudioPlayer>) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::RemoveFromQueue(index))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
 
+#[tauri::command]
+fn move_in_queue(from: usize, to: usize, audio_player: State<AudioPlayer>) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::MoveInQueue(from, to))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
 
 #[tauri::command]
-fn get_supported_tags() -> Vec<String> {
-    get_supported_tags_list()
+fn clear_queue(audio_player: State<AudioPlayer>) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::ClearQueue)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn play_queue_index(index: usize, audio_player: State<AudioPlayer>) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::PlayQueueIndex(index))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn next_track(audio_player: State<AudioPlayer>) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::Next)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn previous_track(audio_player: State<AudioPlayer>) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::Previous)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn set_next_track(path: Option<String>, audio_player: State<AudioPlayer>) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::SetNextTrack(path))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn set_crossfade(
+    settings: Option<CrossfadeSettings>,
+    audio_player: State<AudioPlayer>,
+) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::SetCrossfade(settings))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn set_normalization(
+    settings: NormalizationSettings,
+    audio_player: State<AudioPlayer>,
+) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::SetNormalization(settings))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn get_output_hosts() -> Vec<OutputHost> {
+    list_output_hosts()
+}
+
+#[tauri::command]
+fn get_output_devices() -> Vec<OutputDevice> {
+    list_output_devices()
+}
+
+#[tauri::command]
+fn get_output_device(app_handle: AppHandle) -> Option<String> {
+    output_device_settings_path(&app_handle)
+        .and_then(|path| load_output_device_settings(&path).device_id)
+}
+
+#[tauri::command]
+fn set_output_device(device_id: Option<String>, audio_player: State<AudioPlayer>) -> Result<(), String> {
+    audio_player
+        .sender
+        .send(AudioPlayerCommand::SetOutputDevice(device_id))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn analyze_loudness(
+    paths: Vec<String>,
+    app_handle: AppHandle,
+    loudness_analysis: State<LoudnessAnalysisJob>,
+) -> Result<(), String> {
+    loudness_analysis
+        .start(app_handle, paths)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn cancel_loudness_analysis(loudness_analysis: State<LoudnessAnalysisJob>) {
+    loudness_analysis.cancel();
+}
+
+#[tauri::command]
+fn write_tags(path: String, tags: TagMap, tag_journal: State<Mutex<TagJournal>>) -> Result<(), String> {
+    let path = Path::new(&path);
+    tag_journal
+        .lock()
+        .unwrap()
+        .write_one("Edit tags", path, || write_tags_to_file(path, &tags))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Sets and removes single keys, keeping everything else in the tag
+#[tauri::command]
+fn patch_tags(path: String, patch: TagPatch, tag_journal: State<Mutex<TagJournal>>) -> Result<(), String> {
+    let path = Path::new(&path);
+    tag_journal
+        .lock()
+        .unwrap()
+        .write_one("Edit tags", path, || patch_tags_in_file(path, &patch))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Applies field operations to many files as one batch, emitting `tags:batch-progress`.
+/// If a file fails, the files written before it get their tags from before back.
+#[tauri::command(async)]
+fn batch_edit_tags(
+    edit: BatchEdit,
+    app_handle: AppHandle,
+    tag_journal: State<Mutex<TagJournal>>,
+) -> Result<BatchEditResult, String> {
+    run_batch_edit(&edit, &app_handle, &tag_journal)
+}
+
+fn run_batch_edit(
+    edit: &BatchEdit,
+    app_handle: &AppHandle,
+    tag_journal: &Mutex<TagJournal>,
+) -> Result<BatchEditResult, String> {
+    let mut tag_journal = tag_journal.lock().unwrap();
+    batch_edit(&mut tag_journal, edit, |progress| {
+        _ = app_handle.emit("tags:batch-progress", progress);
+    })
+    .map_err(|e| e.to_string())
+}
+
+/// The tags every file would get from its path, None for paths the pattern does not match
+#[tauri::command]
+fn preview_tags_from_filenames(paths: Vec<String>, pattern: String) -> Result<Vec<FilenameTags>, String> {
+    let pattern = FilenamePattern::parse(&pattern).map_err(|e| e.to_string())?;
+    Ok(filename_pattern::preview_tags_from_paths(&paths, &pattern))
+}
+
+/// Writes the tags parsed from the paths as a batch edit, emitting `tags:batch-progress`
+#[tauri::command(async)]
+fn tags_from_filenames(
+    paths: Vec<String>,
+    pattern: String,
+    app_handle: AppHandle,
+    tag_journal: State<Mutex<TagJournal>>,
+) -> Result<BatchEditResult, String> {
+    let pattern = FilenamePattern::parse(&pattern).map_err(|e| e.to_string())?;
+    let edit = filename_pattern::tags_from_paths_edit(&paths, &pattern).map_err(|e| e.to_string())?;
+    run_batch_edit(&edit, &app_handle, &tag_journal)
+}
+
+/// Where every file would be moved to, with the collisions
+#[tauri::command]
+fn preview_file_renames(
+    paths: Vec<String>,
+    pattern: String,
+    target_dir: Option<String>,
+) -> Result<Vec<FileRename>, String> {
+    let pattern = FilenamePattern::parse(&pattern).map_err(|e| e.to_string())?;
+    Ok(filename_pattern::plan_renames(&paths, &pattern, target_dir.as_deref().map(Path::new)))
+}
+
+/// Moves the files to paths built from their tags, below `target_dir` or their own folder.
+/// Their tag history moves along.
+#[tauri::command(async)]
+fn rename_files_from_tags(
+    paths: Vec<String>,
+    pattern: String,
+    target_dir: Option<String>,
+    tag_journal: State<Mutex<TagJournal>>,
+) -> Result<Vec<FileRename>, String> {
+    let pattern = FilenamePattern::parse(&pattern).map_err(|e| e.to_string())?;
+    Ok(filename_pattern::rename_files(
+        &mut tag_journal.lock().unwrap(),
+        &paths,
+        &pattern,
+        target_dir.as_deref().map(Path::new),
+    ))
+}
+
+/// Searches MusicBrainz for a song, best candidates first, each with its score
+/// breakdown and the tags it would write. Songs without a title are identified
+/// by their fingerprint.
+#[tauri::command]
+async fn search_musicbrainz(
+    path: String,
+    musicbrainz_client: State<'_, MusicBrainzClient>,
+    acoustid_client: State<'_, AcoustIdClient>,
+    app_handle: AppHandle,
+) -> Result<Vec<Candidate>, String> {
+    let scoring_settings = service_settings(&app_handle).musicbrainz_scoring;
+    musicbrainz_matching::search_candidates(
+        &musicbrainz_client,
+        &acoustid_client,
+        Path::new(&path),
+        &scoring_settings,
+    )
+    .await
+}
+
+/// Like `search_musicbrainz`, but by the fingerprint even if the song has a title
+#[tauri::command]
+async fn identify_musicbrainz(
+    path: String,
+    musicbrainz_client: State<'_, MusicBrainzClient>,
+    acoustid_client: State<'_, AcoustIdClient>,
+    app_handle: AppHandle,
+) -> Result<Vec<Candidate>, String> {
+    let scoring_settings = service_settings(&app_handle).musicbrainz_scoring;
+    musicbrainz_matching::identify_candidates(
+        &musicbrainz_client,
+        &acoustid_client,
+        Path::new(&path),
+        &scoring_settings,
+    )
+    .await
+}
+
+/// The current MusicBrainz tags of files that carry MusicBrainz IDs, an ISRC or
+/// a barcode, without a search. They are applied like an album match.
+#[tauri::command]
+async fn lookup_musicbrainz_tags(
+    paths: Vec<String>,
+    musicbrainz_client: State<'_, MusicBrainzClient>,
+    app_handle: AppHandle,
+) -> Result<Vec<FileLookup>, String> {
+    let scoring_settings = service_settings(&app_handle).musicbrainz_scoring;
+    Ok(musicbrainz_lookup::lookup_files(&musicbrainz_client, &paths, &scoring_settings).await)
+}
+
+#[tauri::command]
+fn get_musicbrainz_scoring(app_handle: AppHandle) -> ScoringSettings {
+    service_settings(&app_handle).musicbrainz_scoring
+}
+
+/// Saves the weights and preferred countries candidates are scored with
+#[tauri::command]
+fn set_musicbrainz_scoring(settings: ScoringSettings, app_handle: AppHandle) -> Result<(), String> {
+    update_service_settings(&app_handle, |service_settings| service_settings.musicbrainz_scoring = settings)
+}
+
+/// Groups files into albums by their album tags, or by folder
+#[tauri::command(async)]
+fn group_album_files(paths: Vec<String>) -> Vec<AlbumGroup> {
+    musicbrainz_album::group_album_files(&paths)
 }
 
+/// Matches the files of one album against the tracklists of releases, best release first
+#[tauri::command]
+async fn match_musicbrainz_album(
+    paths: Vec<String>,
+    musicbrainz_client: State<'_, MusicBrainzClient>,
+    app_handle: AppHandle,
+) -> Result<Vec<ReleaseMatch>, String> {
+    let scoring_settings = service_settings(&app_handle).musicbrainz_scoring;
+    musicbrainz_album::match_album(&musicbrainz_client, &paths, &scoring_settings).await
+}
+
+/// Writes the accepted fields of a release's tags to every file as one batch
+#[tauri::command]
+fn apply_musicbrainz_album(
+    album: String,
+    files: Vec<TrackTags>,
+    accepted: Vec<String>,
+    tag_journal: State<Mutex<TagJournal>>,
+) -> Result<i64, String> {
+    musicbrainz_album::apply_album(&mut tag_journal.lock().unwrap(), &album, &files, &accepted)
+        .map_err(|e| e.to_string())
+}
+
+/// The online service settings as saved, the defaults if there are none
+fn service_settings(app_handle: &AppHandle) -> ServiceSettings {
+    service_settings_path(app_handle)
+        .map(|path| load_service_settings(&path))
+        .unwrap_or_default()
+}
+
+/// Changes the saved online service settings
+fn update_service_settings(app_handle: &AppHandle, update: impl FnOnce(&mut ServiceSettings)) -> Result<(), String> {
+    let path = service_settings_path(app_handle).ok_or("No config directory to save the settings in".to_string())?;
+    let mut settings = load_service_settings(&path);
+    update(&mut settings);
+    save_service_settings(&path, &settings).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_musicbrainz_settings(musicbrainz_client: State<MusicBrainzClient>) -> MusicBrainzSettings {
+    musicbrainz_client.settings()
+}
 
+/// Saves the server, user agent and cache TTL that MusicBrainz requests use
+#[tauri::command]
+fn set_musicbrainz_settings(
+    settings: MusicBrainzSettings,
+    musicbrainz_client: State<MusicBrainzClient>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    musicbrainz_client.set_settings(settings.clone()).map_err(|e| e.to_string())?;
+    update_service_settings(&app_handle, |service_settings| service_settings.musicbrainz = settings)
+}
+
+#[tauri::command]
+fn get_acoustid_settings(acoustid_client: State<AcoustIdClient>) -> AcoustIdSettings {
+    acoustid_client.settings()
+}
+
+/// Saves the lookup endpoint and the API key that fingerprints are looked up with
+#[tauri::command]
+fn set_acoustid_settings(
+    settings: AcoustIdSettings,
+    acoustid_client: State<AcoustIdClient>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    acoustid_client.set_settings(settings.clone()).map_err(|e| e.to_string())?;
+    update_service_settings(&app_handle, |service_settings| service_settings.acoustid = settings)
+}
+
+/// The tags of a candidate field by field next to the current tags of the file
+#[tauri::command]
+fn preview_musicbrainz_tags(path: String, tags: TagMap) -> Result<Vec<FieldDiff>, String> {
+    musicbrainz_matching::preview_tags(Path::new(&path), &tags).map_err(|e| e.to_string())
+}
+
+/// Writes the accepted fields of a candidate, the rejected ones keep their value
+#[tauri::command]
+fn apply_musicbrainz_tags(
+    path: String,
+    tags: TagMap,
+    accepted: Vec<String>,
+    tag_journal: State<Mutex<TagJournal>>,
+) -> Result<Vec<FieldDiff>, String> {
+    let path = Path::new(&path);
+    let mut changes = Vec::new();
+    tag_journal
+        .lock()
+        .unwrap()
+        .write_one("Apply MusicBrainz tags", path, || {
+            changes = musicbrainz_matching::apply_tags(path, &tags, &accepted)?;
+            Ok(())
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(changes)
+}
+
+/// The embedded pictures of a file with their type, MIME type and dimensions
+#[tauri::command]
+fn get_pictures(path: String) -> Result<Vec<PictureInfo>, String> {
+    cover_art::list_pictures(Path::new(&path)).map_err(|e| e.to_string())
+}
+
+/// Embeds an image file as a picture of the given type, replacing pictures of that type
+#[tauri::command]
+fn embed_picture(
+    path: String,
+    image_path: String,
+    picture_type: String,
+    options: Option<EmbedOptions>,
+    tag_journal: State<Mutex<TagJournal>>,
+) -> Result<(), String> {
+    let picture_type = cover_art::parse_picture_type(&picture_type).map_err(|e| e.to_string())?;
+    let path = Path::new(&path);
+    tag_journal
+        .lock()
+        .unwrap()
+        .write_one_with_pictures("Embed picture", path, || {
+            cover_art::embed_picture(path, Path::new(&image_path), picture_type, options.unwrap_or_default())
+        })
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn replace_picture(
+    path: String,
+    index: usize,
+    image_path: String,
+    options: Option<EmbedOptions>,
+    tag_journal: State<Mutex<TagJournal>>,
+) -> Result<(), String> {
+    let path = Path::new(&path);
+    tag_journal
+        .lock()
+        .unwrap()
+        .write_one_with_pictures("Replace picture", path, || {
+            cover_art::replace_picture(path, index, Path::new(&image_path), options.unwrap_or_default())
+        })
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn remove_picture(path: String, index: usize, tag_journal: State<Mutex<TagJournal>>) -> Result<(), String> {
+    let path = Path::new(&path);
+    tag_journal
+        .lock()
+        .unwrap()
+        .write_one_with_pictures("Remove picture", path, || cover_art::remove_picture(path, index))
+        .map_err(|e| e.to_string())
+}
+
+/// Saves a picture as `cover.jpg` next to the file and returns its path
+#[tauri::command]
+fn export_cover(path: String, index: Option<usize>, overwrite: bool) -> Result<String, String> {
+    cover_art::export_cover(Path::new(&path), index, overwrite)
+        .map(|cover_path| cover_path.to_string_lossy().to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// The images the Cover Art Archive has of a release or release group
+#[tauri::command]
+async fn get_cover_art_images(
+    entity: CoverArtEntity,
+    mbid: String,
+    cover_art_archive_client: State<'_, CoverArtArchiveClient>,
+) -> Result<Vec<CoverArtImage>, String> {
+    cover_art_archive_client
+        .list_images(entity, &mbid)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Downloads an image from the Cover Art Archive and embeds it as the front cover
+/// of the files or saves it as `cover.jpg` in their folders. Returns the paths written.
+#[tauri::command]
+async fn apply_cover_art(
+    request: CoverArtRequest,
+    paths: Vec<String>,
+    target: CoverTarget,
+    overwrite: bool,
+    cover_art_archive_client: State<'_, CoverArtArchiveClient>,
+    tag_journal: State<'_, Mutex<TagJournal>>,
+) -> Result<Vec<String>, String> {
+    let written = cover_art_archive::apply_cover_art(
+        &cover_art_archive_client,
+        &tag_journal,
+        &request,
+        &paths,
+        target,
+        overwrite,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(written.iter().map(|path| path.to_string_lossy().to_string()).collect())
+}
+
+#[tauri::command]
+fn get_cover_art_settings(cover_art_archive_client: State<CoverArtArchiveClient>) -> CoverArtArchiveSettings {
+    cover_art_archive_client.settings()
+}
+
+/// Saves the base URL, download size and image constraints of the Cover Art Archive
+#[tauri::command]
+fn set_cover_art_settings(
+    settings: CoverArtArchiveSettings,
+    cover_art_archive_client: State<CoverArtArchiveClient>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    cover_art_archive_client.set_settings(settings.clone()).map_err(|e| e.to_string())?;
+    update_service_settings(&app_handle, |service_settings| service_settings.cover_art_archive = settings)
+}
+
+/// The tag edits of a file, newest first
+#[tauri::command]
+fn get_tag_history(path: String, tag_journal: State<Mutex<TagJournal>>) -> Result<Vec<TagEdit>, String> {
+    tag_journal.lock().unwrap().file_history(&path).map_err(|e| e.to_string())
+}
+
+/// Every batch of tag edits, newest first
+#[tauri::command]
+fn get_tag_batches(tag_journal: State<Mutex<TagJournal>>) -> Result<Vec<TagBatch>, String> {
+    tag_journal.lock().unwrap().batches().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_tag_batch_edits(batch_id: i64, tag_journal: State<Mutex<TagJournal>>) -> Result<Vec<TagEdit>, String> {
+    tag_journal.lock().unwrap().batch_edits(batch_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn revert_tag_edit(edit_id: i64, tag_journal: State<Mutex<TagJournal>>) -> Result<(), String> {
+    tag_journal.lock().unwrap().revert_edit(edit_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn revert_tag_batch(batch_id: i64, tag_journal: State<Mutex<TagJournal>>) -> Result<(), String> {
+    tag_journal.lock().unwrap().revert_batch(batch_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_tag_split_settings(library_database: State<Mutex<LibraryDatabase>>) -> SplitSettings {
+    library_database.lock().unwrap().split_settings().clone()
+}
+
+/// Saves the separators values are split on. They apply from the next scan on,
+/// which reads every file again.
+#[tauri::command]
+fn set_tag_split_settings(
+    settings: SplitSettings,
+    library_database: State<Mutex<LibraryDatabase>>,
+) -> Result<(), String> {
+    library_database
+        .lock()
+        .unwrap()
+        .set_split_settings(settings)
+        .map_err(|e| e.to_string())
+}
+
+/// Every tag container of a file with the keys they disagree on
+#[tauri::command]
+fn get_file_tags(path: String) -> Result<FileTags, String> {
+    tag_containers::read_file_tags(Path::new(&path)).map_err(|e| e.to_string())
+}
+
+/// The tag journal saves the primary tag only, so a revert undoes what this did to
+/// the primary tag but not to the other tags of the file
+#[tauri::command]
+fn merge_tag_types(path: String, into: String, tag_journal: State<Mutex<TagJournal>>) -> Result<(), String> {
+    let into = parse_tag_type(&into).map_err(|e| e.to_string())?;
+    let path = Path::new(&path);
+    tag_journal
+        .lock()
+        .unwrap()
+        .write_one_with_pictures("Merge tags", path, || tag_containers::merge_tag_types(path, into))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Like `merge_tag_types`, only a copy into the primary tag can be reverted
+#[tauri::command]
+fn copy_tag_type(
+    path: String,
+    from: String,
+    to: String,
+    tag_journal: State<Mutex<TagJournal>>,
+) -> Result<(), String> {
+    let from = parse_tag_type(&from).map_err(|e| e.to_string())?;
+    let to = parse_tag_type(&to).map_err(|e| e.to_string())?;
+    let path = Path::new(&path);
+    tag_journal
+        .lock()
+        .unwrap()
+        .write_one_with_pictures("Copy tags", path, || tag_containers::copy_tag_type(path, from, to))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Only a stripped primary tag can be reverted, other tag types are gone for good
+#[tauri::command]
+fn strip_tag_types(path: String, tag_types: Vec<String>, tag_journal: State<Mutex<TagJournal>>) -> Result<(), String> {
+    let tag_types = tag_types
+        .iter()
+        .map(|tag_type| parse_tag_type(tag_type))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+    let path = Path::new(&path);
+    tag_journal
+        .lock()
+        .unwrap()
+        .write_one_with_pictures("Strip tags", path, || tag_containers::strip_tag_types(path, &tag_types))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn get_supported_tags() -> Vec<String> {
+    get_supported_tags_list()
+}
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_opener::init())
+        .register_asynchronous_uri_scheme_protocol(COVER_SCHEME, |context, request, responder| {
+            handle_cover_request(context.app_handle(), request, responder)
+        })
         .setup(|app| {
             let (sender, receiver) = mpsc::channel();
             let app_handle_arc = Arc::new(app.handle().clone());
 
-            // spawn player thread with app handle for event emission
-            thread::spawn(move || player_thread(receiver, app_handle_arc));
+            // spawn player thread with app handle for event emission and a sender
+            // so that the decoder can report the end of a track back to it
+            let player_sender = sender.clone();
+            let player_thread_handle = thread::spawn(move || player_thread(receiver, player_sender, app_handle_arc));
+
+            app.manage(AudioPlayer {
+                sender,
+                player_thread: Mutex::new(Some(player_thread_handle)),
+            });
+            app.manage(LoudnessAnalysisJob::default());
+
+            let library_database_path = app.path().app_data_dir()?.join("library.sqlite3");
+            app.manage(Mutex::new(LibraryDatabase::open(&library_database_path)?));
+            app.manage(LibraryWatcher::default());
+            app.manage(LibraryScanJob::default());
 
-            app.manage(AudioPlayer { sender });
+            let tag_journal_path = app.path().app_data_dir()?.join("tag_journal.sqlite3");
+            app.manage(Mutex::new(TagJournal::open(&tag_journal_path)?));
+
+            let settings = service_settings(app.handle());
+            let musicbrainz_cache_path = app.path().app_data_dir()?.join("musicbrainz_cache.sqlite3");
+            app.manage(MusicBrainzClient::new(settings.musicbrainz, Some(&musicbrainz_cache_path))?);
+            app.manage(AcoustIdClient::new(settings.acoustid)?);
+            app.manage(CoverArtArchiveClient::new(settings.cover_art_archive)?);
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             load_and_play,
             toggle_playback,
             get_music_library,
+            rescan_music_library,
+            cancel_library_scan,
             volume_change,
             seek,
+            enqueue,
+            insert_into_queue,
+            remove_from_queue,
+            move_in_queue,
+            clear_queue,
+            play_queue_index,
+            next_track,
+            previous_track,
+            set_next_track,
+            set_crossfade,
+            set_normalization,
+            get_output_hosts,
+            get_output_devices,
+            get_output_device,
+            set_output_device,
+            analyze_loudness,
+            cancel_loudness_analysis,
             write_tags,
+            patch_tags,
+            batch_edit_tags,
+            preview_tags_from_filenames,
+            tags_from_filenames,
+            preview_file_renames,
+            rename_files_from_tags,
+            search_musicbrainz,
+            identify_musicbrainz,
+            lookup_musicbrainz_tags,
+            get_musicbrainz_scoring,
+            set_musicbrainz_scoring,
+            get_musicbrainz_settings,
+            set_musicbrainz_settings,
+            get_acoustid_settings,
+            set_acoustid_settings,
+            preview_musicbrainz_tags,
+            apply_musicbrainz_tags,
+            group_album_files,
+            match_musicbrainz_album,
+            apply_musicbrainz_album,
+            get_pictures,
+            embed_picture,
+            replace_picture,
+            remove_picture,
+            export_cover,
+            get_cover_art_images,
+            apply_cover_art,
+            get_cover_art_settings,
+            set_cover_art_settings,
+            get_tag_history,
+            get_tag_batches,
+            get_tag_batch_edits,
+            revert_tag_edit,
+            revert_tag_batch,
+            get_tag_split_settings,
+            set_tag_split_settings,
+            get_file_tags,
+            merge_tag_types,
+            copy_tag_type,
+            strip_tag_types,
             get_supported_tags
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            if let RunEvent::Exit = event {
+                app_handle.state::<AudioPlayer>().shutdown();
+            }
+        });
 }
 
 pub struct AudioPlayer {
     pub sender: mpsc::Sender<AudioPlayerCommand>,
+    player_thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AudioPlayer {
+    /// Stops playback and waits for the player thread to clean up
+    fn shutdown(&self) {
+        let _ = self.sender.send(AudioPlayerCommand::Shutdown);
+        if let Some(player_thread) = self.player_thread.lock().unwrap().take() {
+            let _ = player_thread.join();
+        }
+    }
 }
@@ -0,0 +1,510 @@
+use crate::read_music_library::{Library, Song};
+use crate::tags::multi_value::{split_values, SplitSettings};
+use crate::tags::reading_tags;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use walkdir::{DirEntry, WalkDir};
+
+// the songs table is dropped and the libraries are scanned again when this changes
+const SCHEMA_VERSION: i64 = 2;
+
+const SPLIT_SETTINGS_KEY: &str = "split_settings";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS songs (
+    path TEXT PRIMARY KEY,
+    library_dir TEXT NOT NULL,
+    name TEXT NOT NULL,
+    mtime_millis INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    duration_millis INTEGER NOT NULL,
+    tags TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS songs_by_library_dir ON songs (library_dir);
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+";
+
+/// On-disk index of the music libraries, so that only new and changed files
+/// have to be read again with lofty
+pub struct LibraryDatabase {
+    connection: Connection,
+    split_settings: SplitSettings,
+}
+
+/// Modification time and size of a file, to tell whether it changed since it was read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileState {
+    mtime_millis: i64,
+    size: i64,
+}
+
+/// Songs that were added, changed or removed, sent with the `library:changed` event
+#[derive(Serialize, Clone, Default)]
+pub struct LibraryChanges {
+    pub library_dir: String,
+    pub added: Vec<Song>,
+    pub changed: Vec<Song>,
+    pub removed: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl LibraryChanges {
+    pub fn new(library_dir: &Path) -> LibraryChanges {
+        LibraryChanges {
+            library_dir: library_dir.to_string_lossy().to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty() && self.errors.is_empty()
+    }
+}
+
+pub struct MusicFile {
+    pub path: String,
+    pub name: String,
+    pub state: FileState,
+}
+
+impl LibraryDatabase {
+    pub fn open(path: &Path) -> Result<LibraryDatabase> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open library database: {}", path.display()))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            connection.execute_batch("DROP TABLE IF EXISTS songs")?;
+            connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        connection.execute_batch(SCHEMA)?;
+        let split_settings = load_setting(&connection, SPLIT_SETTINGS_KEY)?.unwrap_or_default();
+        Ok(LibraryDatabase {
+            connection,
+            split_settings,
+        })
+    }
+
+    /// How values like "Rock; Pop" are split into several when files are read
+    pub fn split_settings(&self) -> &SplitSettings {
+        &self.split_settings
+    }
+
+    /// Changes how values are split. Every file is read again at the next scan then.
+    pub fn set_split_settings(&mut self, split_settings: SplitSettings) -> Result<()> {
+        if split_settings == self.split_settings {
+            return Ok(());
+        }
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            params![SPLIT_SETTINGS_KEY, serde_json::to_string(&split_settings)?],
+        )?;
+        transaction.execute("UPDATE songs SET mtime_millis = -1", [])?;
+        transaction.commit()?;
+        self.split_settings = split_settings;
+        Ok(())
+    }
+
+    /// The songs of a library as far as it has been scanned
+    pub fn cached_library(&self, library_dir: &Path) -> Result<Library> {
+        Ok(Library {
+            songs: load_songs(&self.connection, &library_dir.to_string_lossy())?,
+            errors: Vec::new(),
+        })
+    }
+
+    pub fn contains_song(&self, path: &str) -> Result<bool> {
+        let mut statement = self.connection.prepare_cached("SELECT 1 FROM songs WHERE path = ?1")?;
+        Ok(statement.exists(params![path])?)
+    }
+
+    /// Modification time and size of every indexed file of a library
+    pub fn file_states(&self, library_dir: &Path) -> Result<HashMap<String, FileState>> {
+        load_file_states(&self.connection, &library_dir.to_string_lossy())
+    }
+
+    pub fn save_songs(&mut self, library_dir: &Path, songs: &[(&Song, FileState)]) -> Result<()> {
+        let library_dir = library_dir.to_string_lossy();
+        let transaction = self.connection.transaction()?;
+        for (song, state) in songs {
+            save_song(&transaction, &library_dir, song, *state)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_songs(&mut self, paths: &[String]) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        for path in paths {
+            transaction.execute("DELETE FROM songs WHERE path = ?1", params![path])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Updates the index for paths that changed on disk. A path can be a file or
+    /// a directory; paths that no longer exist drop their songs and, for
+    /// directories, the songs below them.
+    pub fn update_paths(&mut self, library_dir: &Path, paths: &[PathBuf]) -> Result<LibraryChanges> {
+        let mut changes = LibraryChanges::new(library_dir);
+        let transaction = self.connection.transaction()?;
+        let known_files = load_file_states(&transaction, &changes.library_dir)?;
+
+        for path in paths {
+            if !path.exists() {
+                let removed: Vec<&String> = known_files
+                    .keys()
+                    .filter(|known_path| Path::new(known_path).starts_with(path))
+                    .collect();
+                for removed_path in removed {
+                    transaction.execute("DELETE FROM songs WHERE path = ?1", params![removed_path])?;
+                    changes.removed.push(removed_path.clone());
+                }
+                continue;
+            }
+
+            for music_file in list_music_files(path, &mut changes.errors) {
+                let known_file = known_files.get(&music_file.path);
+                if known_file == Some(&music_file.state) {
+                    continue;
+                }
+                match read_song(&music_file, &self.split_settings) {
+                    Ok(song) => {
+                        save_song(&transaction, &changes.library_dir, &song, music_file.state)?;
+                        match known_file {
+                            Some(_) => changes.changed.push(song),
+                            None => changes.added.push(song),
+                        }
+                    }
+                    Err(error) => {
+                        changes.errors.push(error);
+                        // it will be read again at the next scan
+                        if known_file.is_some() {
+                            transaction.execute("DELETE FROM songs WHERE path = ?1", params![music_file.path])?;
+                            changes.removed.push(music_file.path);
+                        }
+                    }
+                }
+            }
+        }
+        transaction.commit()?;
+
+        changes.removed.sort();
+        changes.removed.dedup();
+        Ok(changes)
+    }
+}
+
+/// Reads the tags and properties of a music file
+pub fn read_song(music_file: &MusicFile, split_settings: &SplitSettings) -> Result<Song, String> {
+    let mut properties = reading_tags::read_audio_file_properties(Path::new(&music_file.path))
+        .map_err(|e| format!("{}, {:?}", music_file.path, e))?;
+    split_values(&mut properties.tags, split_settings);
+    Ok(Song {
+        path: music_file.path.clone(),
+        name: music_file.name.clone(),
+        duration_millis: properties.duration_millis,
+        tags: properties.tags,
+    })
+}
+
+fn list_music_files(dir: &Path, errors: &mut Vec<String>) -> Vec<MusicFile> {
+    let mut music_files = Vec::new();
+    for entry_result in WalkDir::new(dir).into_iter() {
+        match entry_result {
+            Ok(entry) => music_files.extend(music_file(&entry, errors)),
+            Err(entry) => errors.push(format!("Directory traversal error: {}", entry)),
+        }
+    }
+    music_files
+}
+
+/// The music file at a directory entry, None for other files and directories
+pub fn music_file(entry: &DirEntry, errors: &mut Vec<String>) -> Option<MusicFile> {
+    if !reading_tags::is_music_file(entry) {
+        return None;
+    }
+
+    let Some(path) = entry.path().to_str() else {
+        errors.push(format!("Invalid UTF-8 in path: {:?}", entry.path()));
+        return None;
+    };
+    let name = entry.file_name().to_str()?;
+    let state = match file_state(entry.path()) {
+        Ok(state) => state,
+        Err(e) => {
+            errors.push(format!("{}, {:?}", path, e));
+            return None;
+        }
+    };
+
+    Some(MusicFile {
+        path: path.to_string(),
+        name: name.to_string(),
+        state,
+    })
+}
+
+fn file_state(path: &Path) -> Result<FileState> {
+    let metadata = fs::metadata(path)?;
+    let mtime_millis = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_millis() as i64;
+    Ok(FileState {
+        mtime_millis,
+        size: metadata.len() as i64,
+    })
+}
+
+fn load_file_states(connection: &Connection, library_dir: &str) -> Result<HashMap<String, FileState>> {
+    let mut statement =
+        connection.prepare("SELECT path, mtime_millis, size FROM songs WHERE library_dir = ?1")?;
+    let rows = statement.query_map(params![library_dir], |row| {
+        Ok((
+            row.get(0)?,
+            FileState {
+                mtime_millis: row.get(1)?,
+                size: row.get(2)?,
+            },
+        ))
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+fn save_song(connection: &Connection, library_dir: &str, song: &Song, state: FileState) -> Result<()> {
+    connection.execute(
+        "INSERT INTO songs (path, library_dir, name, mtime_millis, size, duration_millis, tags)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT (path) DO UPDATE SET
+            library_dir = excluded.library_dir,
+            name = excluded.name,
+            mtime_millis = excluded.mtime_millis,
+            size = excluded.size,
+            duration_millis = excluded.duration_millis,
+            tags = excluded.tags",
+        params![
+            song.path,
+            library_dir,
+            song.name,
+            state.mtime_millis,
+            state.size,
+            song.duration_millis,
+            serde_json::to_string(&song.tags)?,
+        ],
+    )?;
+    Ok(())
+}
+
+fn load_setting<T: DeserializeOwned>(connection: &Connection, key: &str) -> Result<Option<T>> {
+    let mut statement = connection.prepare("SELECT value FROM settings WHERE key = ?1")?;
+    let mut rows = statement.query(params![key])?;
+    match rows.next()? {
+        Some(row) => {
+            let value: String = row.get(0)?;
+            let setting = serde_json::from_str(&value).with_context(|| format!("Broken setting {}", key))?;
+            Ok(Some(setting))
+        }
+        None => Ok(None),
+    }
+}
+
+fn load_songs(connection: &Connection, library_dir: &str) -> Result<Vec<Song>> {
+    let mut statement = connection.prepare(
+        "SELECT path, name, duration_millis, tags FROM songs
+         WHERE library_dir = ?1 ORDER BY path",
+    )?;
+    let rows = statement.query_map(params![library_dir], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, u32>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    })?;
+
+    let mut songs = Vec::new();
+    for row in rows {
+        let (path, name, duration_millis, tags) = row?;
+        songs.push(Song {
+            tags: serde_json::from_str(&tags)
+                .with_context(|| format!("Broken tags of {} in the library database", path))?,
+            path,
+            name,
+            duration_millis,
+        });
+    }
+    Ok(songs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::writing_tags::write_tags_to_file;
+    use std::fs::copy;
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+
+    fn song_names(library: &Library) -> Vec<String> {
+        library.songs.iter().map(|song| song.name.clone()).collect()
+    }
+
+    #[test]
+    fn test_never_scanned_library_is_empty() {
+        let dir = tempdir().unwrap();
+        let database = LibraryDatabase::open(&dir.path().join("library.sqlite3")).unwrap();
+        assert!(database.cached_library(dir.path()).unwrap().songs.is_empty());
+    }
+
+    #[test]
+    fn test_save_and_remove_songs() {
+        let dir = tempdir().unwrap();
+        let database_path = dir.path().join("library.sqlite3");
+        let library_dir = dir.path().join("music");
+        let song = Song {
+            path: library_dir.join("a.mp3").to_string_lossy().to_string(),
+            name: "a.mp3".to_string(),
+            duration_millis: 1000,
+            tags: HashMap::from([("TrackTitle".to_string(), vec!["Title".to_string()])]),
+        };
+        let state = FileState {
+            mtime_millis: 1,
+            size: 2,
+        };
+
+        let mut database = LibraryDatabase::open(&database_path).unwrap();
+        database.save_songs(&library_dir, &[(&song, state)]).unwrap();
+        assert_eq!(database.file_states(&library_dir).unwrap().get(&song.path), Some(&state));
+        assert!(database.contains_song(&song.path).unwrap());
+        assert!(!database.contains_song("/elsewhere/a.mp3").unwrap());
+
+        // survives reopening
+        let mut database = LibraryDatabase::open(&database_path).unwrap();
+        let cached = database.cached_library(&library_dir).unwrap();
+        assert_eq!(song_names(&cached), vec!["a.mp3"]);
+        assert_eq!(cached.songs[0].tags, song.tags);
+
+        database.remove_songs(std::slice::from_ref(&song.path)).unwrap();
+        assert!(database.cached_library(&library_dir).unwrap().songs.is_empty());
+    }
+
+    #[test]
+    fn test_changed_split_settings_read_every_file_again() {
+        let dir = tempdir().unwrap();
+        let database_path = dir.path().join("library.sqlite3");
+        let library_dir = dir.path().join("music");
+        fs::create_dir_all(&library_dir).unwrap();
+        let path = library_dir.join("a.mp3");
+        copy("./tests/music_libraries/different_formats/some_song.mp3", &path).unwrap();
+        let tags = HashMap::from([("Genre".to_string(), vec!["Rock; Pop".to_string()])]);
+        write_tags_to_file(&path, &tags).unwrap();
+
+        let mut database = LibraryDatabase::open(&database_path).unwrap();
+        database.update_paths(&library_dir, std::slice::from_ref(&library_dir)).unwrap();
+        let cached = database.cached_library(&library_dir).unwrap();
+        assert_eq!(cached.songs[0].tags["Genre"], vec!["Rock", "Pop"]);
+
+        let no_splitting = SplitSettings {
+            separators: HashMap::new(),
+        };
+        database.set_split_settings(no_splitting.clone()).unwrap();
+        let changes = database.update_paths(&library_dir, std::slice::from_ref(&library_dir)).unwrap();
+        assert_eq!(changes.changed.len(), 1);
+        assert_eq!(changes.changed[0].tags["Genre"], vec!["Rock; Pop"]);
+
+        // survives reopening
+        let database = LibraryDatabase::open(&database_path).unwrap();
+        assert_eq!(database.split_settings(), &no_splitting);
+    }
+
+    #[test]
+    fn test_outdated_schema_is_replaced() {
+        let dir = tempdir().unwrap();
+        let database_path = dir.path().join("library.sqlite3");
+        let connection = Connection::open(&database_path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE songs (path TEXT PRIMARY KEY, library_dir TEXT NOT NULL, cover_base64 TEXT);
+                 INSERT INTO songs VALUES ('/music/a.mp3', '/music', NULL);",
+            )
+            .unwrap();
+        drop(connection);
+
+        let database = LibraryDatabase::open(&database_path).unwrap();
+        assert!(database.cached_library(Path::new("/music")).unwrap().songs.is_empty());
+        assert!(database.file_states(Path::new("/music")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_update_paths() {
+        let dir = tempdir().unwrap();
+        let library_dir = dir.path().join("music");
+        fs::create_dir_all(library_dir.join("album")).unwrap();
+        copy("./tests/music_libraries/different_formats/some_song.mp3", library_dir.join("a.mp3")).unwrap();
+        copy("./tests/music_libraries/different_formats/some_song.wav", library_dir.join("b.wav")).unwrap();
+
+        let mut database = LibraryDatabase::open(&dir.path().join("library.sqlite3")).unwrap();
+        database.update_paths(&library_dir, std::slice::from_ref(&library_dir)).unwrap();
+
+        fs::remove_file(library_dir.join("b.wav")).unwrap();
+        copy("./tests/music_libraries/different_formats/some_audio.flac", library_dir.join("album/c.flac")).unwrap();
+        fs::write(library_dir.join("album/cover.txt"), "not music").unwrap();
+
+        let changes = database
+            .update_paths(&library_dir, &[library_dir.join("b.wav"), library_dir.join("album")])
+            .unwrap();
+        let added: Vec<&String> = changes.added.iter().map(|song| &song.name).collect();
+        assert_eq!(added, vec!["c.flac"]);
+        assert!(changes.changed.is_empty());
+        assert_eq!(changes.removed, vec![library_dir.join("b.wav").to_string_lossy().to_string()]);
+
+        let cached = database.cached_library(&library_dir).unwrap();
+        assert_eq!(song_names(&cached), vec!["a.mp3", "c.flac"]);
+
+        // unchanged files are no change
+        let changes = database.update_paths(&library_dir, &[library_dir.join("a.mp3")]).unwrap();
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn test_update_paths_of_changed_file_and_removed_directory() {
+        let dir = tempdir().unwrap();
+        let library_dir = dir.path().join("music");
+        fs::create_dir_all(library_dir.join("album")).unwrap();
+        copy("./tests/music_libraries/different_formats/some_song.mp3", library_dir.join("a.mp3")).unwrap();
+        copy("./tests/music_libraries/different_formats/some_song.wav", library_dir.join("album/b.wav")).unwrap();
+        copy("./tests/music_libraries/different_formats/some_audio.flac", library_dir.join("album/c.flac")).unwrap();
+
+        let mut database = LibraryDatabase::open(&dir.path().join("library.sqlite3")).unwrap();
+        database.update_paths(&library_dir, std::slice::from_ref(&library_dir)).unwrap();
+
+        let changed_path = library_dir.join("a.mp3");
+        let tags = HashMap::from([("TrackTitle".to_string(), vec!["Changed".to_string()])]);
+        write_tags_to_file(&changed_path, &tags).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&changed_path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        fs::remove_dir_all(library_dir.join("album")).unwrap();
+
+        let changes = database
+            .update_paths(&library_dir, &[changed_path, library_dir.join("album")])
+            .unwrap();
+        assert_eq!(changes.changed.len(), 1);
+        assert_eq!(changes.changed[0].tags.get("TrackTitle"), Some(&vec!["Changed".to_string()]));
+        assert_eq!(changes.removed.len(), 2);
+        assert_eq!(database.cached_library(&library_dir).unwrap().songs.len(), 1);
+    }
+}
@@ -0,0 +1,474 @@
+use crate::library_db::{music_file, read_song, LibraryChanges, LibraryDatabase, MusicFile};
+use crate::library_watcher::watch_library;
+use anyhow::Result;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tauri::{AppHandle, Emitter, Manager};
+use walkdir::WalkDir;
+
+// files read in parallel and then saved and sent to the frontend together
+const CHUNK_SIZE: usize = 256;
+// progress while walking the directory tree is reported every this many files
+const FOUND_PROGRESS_INTERVAL: usize = 1000;
+
+/// Sent with the `library:scan-progress` event
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ScanProgress {
+    pub library_dir: String,
+    pub found: usize,    // music files found so far
+    pub to_parse: usize, // new or changed files among them, known once the walk is done
+    pub parsed: usize,
+    pub failed: usize,
+    pub current_path: Option<String>,
+}
+
+/// Sent with the `library:scan-finished` event
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ScanFinished {
+    pub library_dir: String,
+    pub found: usize,
+    pub parsed: usize,
+    pub failed: usize,
+    pub removed: usize,
+    pub cancelled: bool,
+}
+
+/// The running library scan, if any
+#[derive(Default)]
+pub struct LibraryScanJob {
+    cancel_flag: Arc<Mutex<Option<Arc<AtomicBool>>>>,
+}
+
+impl LibraryScanJob {
+    /// Scans a library on a background thread, cancelling a scan that is still
+    /// running. New and changed songs are sent in chunks with `library:changed`,
+    /// next to `library:scan-progress` and finally `library:scan-finished`.
+    /// Afterwards the library is watched for changes.
+    pub fn start(&self, app_handle: AppHandle, library_dir: PathBuf) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        if let Some(previous) = self.cancel_flag.lock().unwrap().replace(cancelled.clone()) {
+            previous.store(true, Ordering::Relaxed);
+        }
+
+        let cancel_flag = self.cancel_flag.clone();
+        thread::spawn(move || {
+            let library_database = app_handle.state::<Mutex<LibraryDatabase>>();
+            let result = scan_library(
+                &library_database,
+                &library_dir,
+                &cancelled,
+                |progress| {
+                    _ = app_handle.emit("library:scan-progress", progress);
+                },
+                |changes| {
+                    _ = app_handle.emit("library:changed", changes);
+                },
+            );
+
+            // a newer scan may have taken the slot already
+            let mut running = cancel_flag.lock().unwrap();
+            if running.as_ref().is_some_and(|running| Arc::ptr_eq(running, &cancelled)) {
+                *running = None;
+            }
+            drop(running);
+
+            match result {
+                Ok(finished) => {
+                    let cancelled = finished.cancelled;
+                    _ = app_handle.emit("library:scan-finished", finished);
+                    if !cancelled {
+                        if let Err(e) = watch_library(&app_handle, &library_dir) {
+                            eprintln!("Failed to watch {}: {}", library_dir.display(), e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to scan {}: {}", library_dir.display(), e);
+                    _ = app_handle.emit(
+                        "library:scan-finished",
+                        ScanFinished {
+                            library_dir: library_dir.to_string_lossy().to_string(),
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+        });
+    }
+
+    pub fn cancel(&self) {
+        if let Some(cancelled) = self.cancel_flag.lock().unwrap().as_ref() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Brings the index of a library up to date with the files on disk. Only files
+/// whose modification time or size changed are read again, on the rayon thread
+/// pool, and deleted files are dropped. The database is only locked to save
+/// each chunk, so the cached library stays available during the scan.
+pub fn scan_library(
+    library_database: &Mutex<LibraryDatabase>,
+    library_dir: &Path,
+    cancelled: &AtomicBool,
+    mut on_progress: impl FnMut(&ScanProgress),
+    mut on_changes: impl FnMut(LibraryChanges),
+) -> Result<ScanFinished> {
+    let mut progress = ScanProgress {
+        library_dir: library_dir.to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let mut finished = ScanFinished {
+        library_dir: progress.library_dir.clone(),
+        ..Default::default()
+    };
+
+    let mut walk_errors = LibraryChanges::new(library_dir);
+    let mut music_files = Vec::new();
+    for entry_result in WalkDir::new(library_dir).sort_by_file_name() {
+        if cancelled.load(Ordering::Relaxed) {
+            finished.cancelled = true;
+            return Ok(finished);
+        }
+        let entry = match entry_result {
+            Ok(entry) => entry,
+            Err(entry) => {
+                walk_errors.errors.push(format!("Directory traversal error: {}", entry));
+                continue;
+            }
+        };
+        let Some(music_file) = music_file(&entry, &mut walk_errors.errors) else {
+            continue;
+        };
+        progress.found += 1;
+        if progress.found % FOUND_PROGRESS_INTERVAL == 0 {
+            progress.current_path = Some(music_file.path.clone());
+            on_progress(&progress);
+        }
+        music_files.push(music_file);
+    }
+    if !walk_errors.is_empty() {
+        on_changes(walk_errors);
+    }
+
+    let (known_files, split_settings) = {
+        let library_database = library_database.lock().unwrap();
+        (library_database.file_states(library_dir)?, library_database.split_settings().clone())
+    };
+    let changed_files: Vec<&MusicFile> = music_files
+        .iter()
+        .filter(|music_file| known_files.get(&music_file.path) != Some(&music_file.state))
+        .collect();
+    progress.to_parse = changed_files.len();
+    on_progress(&progress);
+
+    for chunk in changed_files.chunks(CHUNK_SIZE) {
+        if cancelled.load(Ordering::Relaxed) {
+            finished.cancelled = true;
+            break;
+        }
+
+        let songs: Vec<_> = chunk
+            .par_iter()
+            .map(|music_file| read_song(music_file, &split_settings))
+            .collect();
+
+        let mut changes = LibraryChanges::new(library_dir);
+        let mut saved = Vec::new();
+        for (music_file, song) in chunk.iter().zip(songs) {
+            let known = known_files.contains_key(&music_file.path);
+            match song {
+                Ok(song) => saved.push((song, music_file.state)),
+                Err(error) => {
+                    progress.failed += 1;
+                    changes.errors.push(error);
+                    // it will be read again at the next scan
+                    if known {
+                        changes.removed.push(music_file.path.clone());
+                    }
+                }
+            }
+        }
+        progress.parsed += saved.len();
+
+        {
+            let mut library_database = library_database.lock().unwrap();
+            let songs: Vec<_> = saved.iter().map(|(song, state)| (song, *state)).collect();
+            library_database.save_songs(library_dir, &songs)?;
+            library_database.remove_songs(&changes.removed)?;
+        }
+        for (song, _) in saved {
+            if known_files.contains_key(&song.path) {
+                changes.changed.push(song);
+            } else {
+                changes.added.push(song);
+            }
+        }
+
+        progress.current_path = chunk.last().map(|music_file| music_file.path.clone());
+        on_progress(&progress);
+        on_changes(changes);
+    }
+
+    finished.found = progress.found;
+    finished.parsed = progress.parsed;
+    finished.failed = progress.failed;
+    if finished.cancelled {
+        return Ok(finished);
+    }
+
+    let existing_paths: HashSet<&String> = music_files.iter().map(|music_file| &music_file.path).collect();
+    let removed: Vec<String> = known_files
+        .into_keys()
+        .filter(|path| !existing_paths.contains(path))
+        .collect();
+    if !removed.is_empty() {
+        library_database.lock().unwrap().remove_songs(&removed)?;
+        finished.removed = removed.len();
+        on_changes(LibraryChanges {
+            removed,
+            ..LibraryChanges::new(library_dir)
+        });
+    }
+
+    println!(
+        "Scanned {}: {} files, {} read, {} failed, {} removed",
+        finished.library_dir, finished.found, finished.parsed, finished.failed, finished.removed
+    );
+    Ok(finished)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_music_library::Library;
+    use crate::tags::multi_value::TagMap;
+    use crate::tags::writing_tags::write_tags_to_file;
+    use std::fs;
+    use std::fs::copy;
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+
+    fn open_database(dir: &Path) -> Mutex<LibraryDatabase> {
+        Mutex::new(LibraryDatabase::open(&dir.join("library.sqlite3")).unwrap())
+    }
+
+    fn rescan(library_database: &Mutex<LibraryDatabase>, library_dir: &Path) -> Library {
+        scan_library(library_database, library_dir, &AtomicBool::new(false), |_| {}, |_| {}).unwrap();
+        library_database.lock().unwrap().cached_library(library_dir).unwrap()
+    }
+
+    fn song_names(library: &Library) -> Vec<String> {
+        library.songs.iter().map(|song| song.name.clone()).collect()
+    }
+
+    #[test]
+    fn test_rescan_and_cache() {
+        let dir = tempdir().unwrap();
+        let library_dir = dir.path().join("music");
+        fs::create_dir(&library_dir).unwrap();
+        copy("./tests/music_libraries/different_formats/some_song.mp3", library_dir.join("a.mp3")).unwrap();
+        copy("./tests/music_libraries/different_formats/some_audio.flac", library_dir.join("b.flac")).unwrap();
+
+        let library = rescan(&open_database(dir.path()), &library_dir);
+        assert_eq!(song_names(&library), vec!["a.mp3", "b.flac"]);
+
+        // reopening returns the same songs without reading the files
+        let library_database = LibraryDatabase::open(&dir.path().join("library.sqlite3")).unwrap();
+        let cached = library_database.cached_library(&library_dir).unwrap();
+        assert_eq!(song_names(&cached), vec!["a.mp3", "b.flac"]);
+        assert_eq!(cached.songs[0].tags, library.songs[0].tags);
+        assert_eq!(cached.songs[0].duration_millis, library.songs[0].duration_millis);
+    }
+
+    #[test]
+    fn test_rescan_picks_up_added_changed_and_removed_files() {
+        let dir = tempdir().unwrap();
+        let library_dir = dir.path().join("music");
+        fs::create_dir(&library_dir).unwrap();
+        copy("./tests/music_libraries/different_formats/some_song.mp3", library_dir.join("a.mp3")).unwrap();
+        copy("./tests/music_libraries/different_formats/some_song.wav", library_dir.join("b.wav")).unwrap();
+
+        let library_database = open_database(dir.path());
+        rescan(&library_database, &library_dir);
+
+        fs::remove_file(library_dir.join("b.wav")).unwrap();
+        copy("./tests/music_libraries/different_formats/some_audio.flac", library_dir.join("c.flac")).unwrap();
+        let changed_path = library_dir.join("a.mp3");
+        let tags = TagMap::from([("TrackTitle".to_string(), vec!["Changed".to_string()])]);
+        write_tags_to_file(&changed_path, &tags).unwrap();
+        // make sure the change is visible even on file systems with a coarse mtime
+        fs::File::options()
+            .write(true)
+            .open(&changed_path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+
+        let mut changes = Vec::new();
+        let finished = scan_library(
+            &library_database,
+            &library_dir,
+            &AtomicBool::new(false),
+            |_| {},
+            |change| changes.push(change),
+        )
+        .unwrap();
+        assert_eq!(finished.found, 2);
+        assert_eq!(finished.parsed, 2);
+        assert_eq!(finished.removed, 1);
+
+        let added: Vec<&String> = changes.iter().flat_map(|c| &c.added).map(|song| &song.name).collect();
+        let changed: Vec<&String> = changes.iter().flat_map(|c| &c.changed).map(|song| &song.name).collect();
+        let removed: Vec<&String> = changes.iter().flat_map(|c| &c.removed).collect();
+        assert_eq!(added, vec!["c.flac"]);
+        assert_eq!(changed, vec!["a.mp3"]);
+        assert_eq!(removed, vec![&library_dir.join("b.wav").to_string_lossy().to_string()]);
+
+        let library = library_database.lock().unwrap().cached_library(&library_dir).unwrap();
+        assert_eq!(song_names(&library), vec!["a.mp3", "c.flac"]);
+        assert_eq!(library.songs[0].tags.get("TrackTitle"), Some(&vec!["Changed".to_string()]));
+    }
+
+    #[test]
+    fn test_unchanged_files_are_not_read_again() {
+        let dir = tempdir().unwrap();
+        let library_dir = dir.path().join("music");
+        fs::create_dir(&library_dir).unwrap();
+        copy("./tests/music_libraries/different_formats/some_song.mp3", library_dir.join("a.mp3")).unwrap();
+
+        let library_database = open_database(dir.path());
+        rescan(&library_database, &library_dir);
+
+        let finished =
+            scan_library(&library_database, &library_dir, &AtomicBool::new(false), |_| {}, |_| {}).unwrap();
+        assert_eq!(finished.found, 1);
+        assert_eq!(finished.parsed, 0);
+    }
+
+    #[test]
+    fn test_libraries_are_kept_apart() {
+        let dir = tempdir().unwrap();
+        let first = dir.path().join("first");
+        let second = dir.path().join("second");
+        fs::create_dir(&first).unwrap();
+        fs::create_dir(&second).unwrap();
+        copy("./tests/music_libraries/different_formats/some_song.mp3", first.join("a.mp3")).unwrap();
+
+        let library_database = open_database(dir.path());
+        rescan(&library_database, &first);
+        let library = rescan(&library_database, &second);
+        assert!(library.songs.is_empty());
+        assert_eq!(library_database.lock().unwrap().cached_library(&first).unwrap().songs.len(), 1);
+    }
+
+    #[test]
+    fn test_songs_come_in_chunks_with_progress() {
+        let dir = tempdir().unwrap();
+        let library_dir = dir.path().join("music");
+        fs::create_dir(&library_dir).unwrap();
+        let file_count = CHUNK_SIZE + 10;
+        for index in 0..file_count {
+            copy(
+                "./tests/music_libraries/different_formats/some_song.wav",
+                library_dir.join(format!("{:04}.wav", index)),
+            )
+            .unwrap();
+        }
+
+        let mut progress = Vec::new();
+        let mut chunks = Vec::new();
+        let finished = scan_library(
+            &open_database(dir.path()),
+            &library_dir,
+            &AtomicBool::new(false),
+            |p| progress.push(p.clone()),
+            |changes| chunks.push(changes.added.len()),
+        )
+        .unwrap();
+
+        assert_eq!(chunks, vec![CHUNK_SIZE, 10]);
+        assert_eq!(finished.parsed, file_count);
+        let last = progress.last().unwrap();
+        assert_eq!((last.found, last.to_parse, last.parsed, last.failed), (file_count, file_count, file_count, 0));
+        assert!(last.current_path.as_ref().unwrap().ends_with(&format!("{:04}.wav", file_count - 1)));
+    }
+
+    #[test]
+    fn test_nonexistent_folder_is_reported() {
+        let dir = tempdir().unwrap();
+        let mut errors = Vec::new();
+        let finished = scan_library(
+            &open_database(dir.path()),
+            &dir.path().join("nonexistent"),
+            &AtomicBool::new(false),
+            |_| {},
+            |changes| errors.extend(changes.errors),
+        )
+        .unwrap();
+        assert_eq!(finished.found, 0);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_empty_folder_has_no_songs() {
+        let dir = tempdir().unwrap();
+        let library_dir = dir.path().join("library");
+        fs::create_dir(&library_dir).unwrap();
+        let library = rescan(&open_database(dir.path()), &library_dir);
+        assert!(library.songs.is_empty());
+        assert!(library.errors.is_empty());
+    }
+
+    #[test]
+    fn test_reads_tags() {
+        let dir = tempdir().unwrap();
+        let library_dir = Path::new("./tests/music_libraries/one_file_with_tags");
+        let library = rescan(&open_database(dir.path()), library_dir);
+        assert_eq!(song_names(&library), vec!["some_song.mp3"]);
+        assert_eq!(library.songs[0].tags.get("TrackNumber"), Some(&vec!["1".to_string()]));
+    }
+
+    #[test]
+    fn test_failed_files_are_reported() {
+        let dir = tempdir().unwrap();
+        let library_dir = dir.path().join("music");
+        fs::create_dir(&library_dir).unwrap();
+        fs::write(library_dir.join("broken.mp3"), "not really music").unwrap();
+
+        let mut errors = Vec::new();
+        let finished = scan_library(
+            &open_database(dir.path()),
+            &library_dir,
+            &AtomicBool::new(false),
+            |_| {},
+            |changes| errors.extend(changes.errors),
+        )
+        .unwrap();
+        assert_eq!(finished.failed, 1);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("broken.mp3"));
+    }
+
+    #[test]
+    fn test_cancelled_scan_keeps_the_index() {
+        let dir = tempdir().unwrap();
+        let library_dir = dir.path().join("music");
+        fs::create_dir(&library_dir).unwrap();
+        copy("./tests/music_libraries/different_formats/some_song.mp3", library_dir.join("a.mp3")).unwrap();
+
+        let library_database = open_database(dir.path());
+        rescan(&library_database, &library_dir);
+        fs::remove_file(library_dir.join("a.mp3")).unwrap();
+
+        let finished =
+            scan_library(&library_database, &library_dir, &AtomicBool::new(true), |_| {}, |_| {}).unwrap();
+        assert!(finished.cancelled);
+        assert_eq!(library_database.lock().unwrap().cached_library(&library_dir).unwrap().songs.len(), 1);
+    }
+}
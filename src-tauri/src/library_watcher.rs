@@ -0,0 +1,164 @@
+use crate::library_db::LibraryDatabase;
+use anyhow::Result;
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, DebouncedEvent, DebouncedEventKind, Debouncer};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+
+// events of a path are collected until it has been quiet for this long
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Watches the library roots for changes. Dropping a debouncer stops its watcher.
+#[derive(Default)]
+pub struct LibraryWatcher {
+    debouncers: Mutex<HashMap<PathBuf, Debouncer<RecommendedWatcher>>>,
+}
+
+impl LibraryWatcher {
+    /// Starts watching a library root, unless it is watched already. `on_change`
+    /// receives the paths of every debounced burst of events.
+    pub fn watch(&self, library_dir: &Path, on_change: impl Fn(Vec<PathBuf>) + Send + 'static) -> Result<()> {
+        let mut debouncers = self.debouncers.lock().unwrap();
+        if debouncers.contains_key(library_dir) {
+            return Ok(());
+        }
+
+        let mut debouncer = new_debouncer(DEBOUNCE_TIMEOUT, move |result: DebounceEventResult| {
+            handle_debounced(result, &on_change)
+        })?;
+        debouncer.watcher().watch(library_dir, RecursiveMode::Recursive)?;
+        println!("Watching {}", library_dir.display());
+
+        debouncers.insert(library_dir.to_path_buf(), debouncer);
+        Ok(())
+    }
+}
+
+/// Starts watching a library root, updating the library database and emitting
+/// `library:changed` for every burst of changes
+pub fn watch_library(app_handle: &AppHandle, library_dir: &Path) -> Result<()> {
+    let handle = app_handle.clone();
+    let dir = library_dir.to_path_buf();
+    app_handle.state::<LibraryWatcher>().watch(library_dir, move |paths| {
+        let library_database = handle.state::<Mutex<LibraryDatabase>>();
+        let changes = library_database.lock().unwrap().update_paths(&dir, &paths);
+        match changes {
+            Ok(changes) if !changes.is_empty() => {
+                _ = handle.emit("library:changed", changes);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to update the library after changes in {}: {}", dir.display(), e),
+        }
+    })
+}
+
+/// Hands the settled paths of a debounced burst of events to `on_change`, if any
+fn handle_debounced(result: DebounceEventResult, on_change: &impl Fn(Vec<PathBuf>)) {
+    match result {
+        Ok(events) => {
+            let paths = settled_paths(events);
+            if !paths.is_empty() {
+                on_change(paths);
+            }
+        }
+        Err(e) => eprintln!("Library watcher error: {}", e),
+    }
+}
+
+/// Paths whose events have settled. Paths that are still being written to,
+/// like a file in the middle of being copied, come again once they are done.
+fn settled_paths(events: Vec<DebouncedEvent>) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = events
+        .into_iter()
+        .filter(|event| event.kind == DebouncedEventKind::Any)
+        .map(|event| event.path)
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify_debouncer_mini::notify;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::sync::mpsc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_settled_paths() {
+        let events = vec![
+            DebouncedEvent::new(PathBuf::from("/music/b.mp3"), DebouncedEventKind::Any),
+            DebouncedEvent::new(PathBuf::from("/music/a.mp3"), DebouncedEventKind::Any),
+            DebouncedEvent::new(PathBuf::from("/music/b.mp3"), DebouncedEventKind::Any),
+            DebouncedEvent::new(PathBuf::from("/music/c.flac"), DebouncedEventKind::AnyContinuous),
+        ];
+        assert_eq!(
+            settled_paths(events),
+            vec![PathBuf::from("/music/a.mp3"), PathBuf::from("/music/b.mp3")]
+        );
+    }
+
+    #[test]
+    fn test_handle_debounced() {
+        let reported = RefCell::new(Vec::new());
+        let on_change = |paths: Vec<PathBuf>| reported.borrow_mut().push(paths);
+
+        handle_debounced(
+            Ok(vec![
+                DebouncedEvent::new(PathBuf::from("/music/b.mp3"), DebouncedEventKind::Any),
+                DebouncedEvent::new(PathBuf::from("/music/a.mp3"), DebouncedEventKind::Any),
+            ]),
+            &on_change,
+        );
+        // a file that is still being copied and watcher errors report nothing
+        handle_debounced(
+            Ok(vec![DebouncedEvent::new(PathBuf::from("/music/c.flac"), DebouncedEventKind::AnyContinuous)]),
+            &on_change,
+        );
+        handle_debounced(Err(notify::Error::generic("watch failed")), &on_change);
+
+        assert_eq!(
+            reported.into_inner(),
+            vec![vec![PathBuf::from("/music/a.mp3"), PathBuf::from("/music/b.mp3")]]
+        );
+    }
+
+    // depends on the OS watcher and takes a few seconds, run with --ignored
+    #[test]
+    #[ignore]
+    fn test_burst_of_changes_is_debounced() {
+        let dir = tempdir().unwrap();
+        let library_dir = dir.path().canonicalize().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        let watcher = LibraryWatcher::default();
+        watcher
+            .watch(&library_dir, move |paths| sender.send(paths).unwrap())
+            .unwrap();
+        // watching again is a no-op
+        watcher.watch(&library_dir, |_| panic!("second watcher")).unwrap();
+
+        let names = ["a.mp3", "b.mp3", "c.mp3"];
+        for name in names {
+            // several events for every file
+            fs::write(library_dir.join(name), "not really").unwrap();
+            fs::write(library_dir.join(name), "not really music").unwrap();
+        }
+
+        // the burst may be split up at a debouncer tick, but every file comes only once
+        let mut reported = Vec::new();
+        while reported.len() < names.len() {
+            reported.extend(receiver.recv_timeout(DEBOUNCE_TIMEOUT * 5).unwrap());
+        }
+        assert!(receiver.recv_timeout(DEBOUNCE_TIMEOUT * 2).is_err());
+        reported.sort();
+        let expected: Vec<PathBuf> = names.iter().map(|name| library_dir.join(name)).collect();
+        assert_eq!(reported, expected);
+    }
+}
@@ -0,0 +1,357 @@
+use crate::decoder::track_source::TrackSource;
+use crate::loudness::meter::{integrated_loudness, LoudnessMeasurement, LoudnessMeter};
+use crate::tag_journal::TagJournal;
+use crate::tags::multi_value::{first_value, TagMap};
+use crate::tags::reading_tags::read_audio_file_properties;
+use crate::tags::writing_tags::{patch_tags_in_file, TagPatch};
+use anyhow::Error;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tauri::{AppHandle, Emitter, Manager};
+
+// ReplayGain 2.0 reference loudness
+const REFERENCE_LUFS: f64 = -18.0;
+
+const TRACK_GAIN: &str = "ReplayGainTrackGain";
+const TRACK_PEAK: &str = "ReplayGainTrackPeak";
+const ALBUM_GAIN: &str = "ReplayGainAlbumGain";
+const ALBUM_PEAK: &str = "ReplayGainAlbumPeak";
+
+/// Sent with the `loudness:progress` event after every analyzed file
+#[derive(Serialize, Clone, Debug)]
+pub struct LoudnessProgress {
+    pub path: String,
+    pub completed: usize,
+    pub total: usize,
+}
+
+/// Sent with the `loudness:finished` event
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct LoudnessAnalysisResult {
+    pub tagged: Vec<String>,
+    pub failed: Vec<String>,
+    pub cancelled: bool,
+}
+
+/// The running loudness analysis, if any. Only one runs at a time.
+#[derive(Default)]
+pub struct LoudnessAnalysisJob {
+    cancel_flag: Arc<Mutex<Option<Arc<AtomicBool>>>>,
+}
+
+impl LoudnessAnalysisJob {
+    /// Analyzes the files on a background thread, emitting `loudness:progress`
+    /// and finally `loudness:finished`
+    pub fn start(&self, app_handle: AppHandle, paths: Vec<String>) -> Result<(), Error> {
+        let mut running = self.cancel_flag.lock().unwrap();
+        if running.is_some() {
+            anyhow::bail!("Loudness analysis is already running");
+        }
+        let cancelled = Arc::new(AtomicBool::new(false));
+        *running = Some(cancelled.clone());
+
+        let cancel_flag = self.cancel_flag.clone();
+        thread::spawn(move || {
+            let tag_journal = app_handle.state::<Mutex<TagJournal>>();
+            let result = analyze_loudness(&paths, &tag_journal, &cancelled, |progress| {
+                _ = app_handle.emit("loudness:progress", progress);
+            });
+            *cancel_flag.lock().unwrap() = None;
+            _ = app_handle.emit("loudness:finished", result);
+        });
+        Ok(())
+    }
+
+    pub fn cancel(&self) {
+        if let Some(cancelled) = self.cancel_flag.lock().unwrap().as_ref() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Measures the loudness of every file and of the albums among them and writes
+/// the ReplayGain tags, as one batch of the tag journal. Albums are tagged as soon
+/// as all their tracks are measured, so a cancelled analysis leaves no album half tagged.
+pub fn analyze_loudness(
+    paths: &[String],
+    tag_journal: &Mutex<TagJournal>,
+    cancelled: &AtomicBool,
+    mut on_progress: impl FnMut(LoudnessProgress),
+) -> LoudnessAnalysisResult {
+    let mut result = LoudnessAnalysisResult::default();
+    let mut completed = 0;
+    let mut batch_id = None;
+
+    for (album, album_paths) in group_by_album(paths) {
+        let mut measured_tracks = Vec::new();
+        for path in album_paths {
+            let measurement = match measure_track(&path, cancelled) {
+                Ok(Some(measurement)) => Some(measurement),
+                Ok(None) => {
+                    result.cancelled = true;
+                    return result;
+                }
+                Err(e) => {
+                    eprintln!("Failed to measure loudness of {}: {}", path, e);
+                    result.failed.push(path.clone());
+                    None
+                }
+            };
+            completed += 1;
+            on_progress(LoudnessProgress {
+                path: path.clone(),
+                completed,
+                total: paths.len(),
+            });
+            if let Some(measurement) = measurement {
+                measured_tracks.push((path, measurement));
+            }
+        }
+
+        let album_gain = album.and_then(|_| album_replay_gain(&measured_tracks));
+        for (path, measurement) in measured_tracks {
+            let Some(track_gain) = replay_gain(&measurement.blocks, measurement.true_peak) else {
+                println!("{} is too short or silent to measure, not tagging it", path);
+                continue;
+            };
+            let write = || write_replay_gain_tags(&path, track_gain, album_gain);
+            match journaled_write(tag_journal, &mut batch_id, &path, write) {
+                Ok(()) => result.tagged.push(path),
+                Err(e) => {
+                    eprintln!("Failed to write ReplayGain tags to {}: {}", path, e);
+                    result.failed.push(path);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Writes a file through the tag journal, in a batch that is started with the
+/// first write so that an analysis that tags nothing leaves no batch behind
+fn journaled_write(
+    tag_journal: &Mutex<TagJournal>,
+    batch_id: &mut Option<i64>,
+    path: &str,
+    write: impl FnOnce() -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut tag_journal = tag_journal.lock().unwrap();
+    let batch_id = match *batch_id {
+        Some(batch_id) => batch_id,
+        None => *batch_id.insert(tag_journal.start_batch("Analyze loudness")?),
+    };
+    tag_journal.write(batch_id, Path::new(path), write)
+}
+
+/// Decodes a file and measures it. Returns None if the analysis was cancelled.
+fn measure_track(path: &str, cancelled: &AtomicBool) -> Result<Option<LoudnessMeasurement>, Error> {
+    let mut source = TrackSource::open(path)?;
+    let mut meter = LoudnessMeter::new(source.sample_rate, source.channel_layout());
+    while let Some(samples) = source.next_samples()? {
+        if cancelled.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        meter.add_samples(&samples);
+    }
+    Ok(Some(meter.finish()))
+}
+
+/// Gain in dB to reach the reference loudness, and the peak
+fn replay_gain(blocks: &[f64], true_peak: f64) -> Option<(f64, f64)> {
+    integrated_loudness(blocks).map(|loudness| (REFERENCE_LUFS - loudness, true_peak))
+}
+
+fn album_replay_gain(tracks: &[(String, LoudnessMeasurement)]) -> Option<(f64, f64)> {
+    let blocks: Vec<f64> = tracks
+        .iter()
+        .flat_map(|(_, measurement)| measurement.blocks.iter().copied())
+        .collect();
+    let true_peak = tracks
+        .iter()
+        .map(|(_, measurement)| measurement.true_peak)
+        .fold(0.0, f64::max);
+    replay_gain(&blocks, true_peak)
+}
+
+/// Groups the files by MusicBrainz release, or by album title and album artist.
+/// Files without album tags form groups of their own, without an album key.
+fn group_by_album(paths: &[String]) -> Vec<(Option<String>, Vec<String>)> {
+    let mut groups: Vec<(Option<String>, Vec<String>)> = Vec::new();
+    for path in paths {
+        let tags = read_audio_file_properties(Path::new(path))
+            .map(|properties| properties.tags)
+            .unwrap_or_default();
+        let Some(album) = album_key(&tags) else {
+            groups.push((None, vec![path.clone()]));
+            continue;
+        };
+        match groups.iter_mut().find(|(key, _)| key.as_ref() == Some(&album)) {
+            Some((_, album_paths)) => album_paths.push(path.clone()),
+            None => groups.push((Some(album), vec![path.clone()])),
+        }
+    }
+    groups
+}
+
+fn album_key(tags: &TagMap) -> Option<String> {
+    let non_empty = |key: &str| first_value(tags, key).map(|value| value.trim()).filter(|value| !value.is_empty());
+    if let Some(release_id) = non_empty("MusicBrainzReleaseId") {
+        return Some(format!("release:{}", release_id));
+    }
+    let album_title = non_empty("AlbumTitle")?;
+    let album_artist = non_empty("AlbumArtist").unwrap_or_default();
+    Some(format!("album:{}\u{0}{}", album_title, album_artist))
+}
+
+fn write_replay_gain_tags(
+    path: &str,
+    (track_gain_db, track_peak): (f64, f64),
+    album_gain: Option<(f64, f64)>,
+) -> Result<(), Error> {
+    // drop old values, whatever case or naming they were read with
+    let remove = read_audio_file_properties(Path::new(path))?
+        .tags
+        .into_keys()
+        .filter(|key| {
+            [TRACK_GAIN, TRACK_PEAK, ALBUM_GAIN, ALBUM_PEAK]
+                .iter()
+                .any(|replay_gain_key| is_replay_gain_key(key, replay_gain_key))
+        })
+        .collect();
+
+    let mut set = TagMap::new();
+    set.insert(TRACK_GAIN.to_string(), vec![format_gain(track_gain_db)]);
+    set.insert(TRACK_PEAK.to_string(), vec![format_peak(track_peak)]);
+    if let Some((album_gain_db, album_peak)) = album_gain {
+        set.insert(ALBUM_GAIN.to_string(), vec![format_gain(album_gain_db)]);
+        set.insert(ALBUM_PEAK.to_string(), vec![format_peak(album_peak)]);
+    }
+    patch_tags_in_file(Path::new(path), &TagPatch { set, remove })
+}
+
+/// Matches "ReplayGainTrackGain" as well as "REPLAYGAIN_TRACK_GAIN"
+fn is_replay_gain_key(key: &str, replay_gain_key: &str) -> bool {
+    key.replace('_', "").eq_ignore_ascii_case(replay_gain_key)
+}
+
+fn format_gain(gain_db: f64) -> String {
+    format!("{:.2} dB", gain_db)
+}
+
+fn format_peak(peak: f64) -> String {
+    format!("{:.6}", peak)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{tag_map, write_sine_wav};
+    use std::fs::copy;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_album_key() {
+        let by_release = tag_map(&[("MusicBrainzReleaseId", &["abc"]), ("AlbumTitle", &["Album"])]);
+        assert_eq!(album_key(&by_release), Some("release:abc".to_string()));
+
+        let by_title = tag_map(&[("AlbumTitle", &["Album"]), ("AlbumArtist", &["Artist"])]);
+        let other_artist = tag_map(&[("AlbumTitle", &["Album"]), ("AlbumArtist", &["Other"])]);
+        assert!(album_key(&by_title).is_some());
+        assert_ne!(album_key(&by_title), album_key(&other_artist));
+
+        assert_eq!(album_key(&tag_map(&[("AlbumTitle", &[" "])])), None);
+        assert_eq!(album_key(&TagMap::new()), None);
+    }
+
+    #[test]
+    fn test_replay_gain_key_names() {
+        assert!(is_replay_gain_key("REPLAYGAIN_TRACK_GAIN", TRACK_GAIN));
+        assert!(is_replay_gain_key("ReplayGainTrackGain", TRACK_GAIN));
+        assert!(!is_replay_gain_key("REPLAYGAIN_TRACK_PEAK", TRACK_GAIN));
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_gain(-6.5432), "-6.54 dB");
+        assert_eq!(format_gain(1.5), "1.50 dB");
+        assert_eq!(format_peak(0.98765432), "0.987654");
+    }
+
+    fn journal(dir: &Path) -> Mutex<TagJournal> {
+        Mutex::new(TagJournal::open(&dir.join("tag_journal.sqlite3")).unwrap())
+    }
+
+    #[test]
+    fn test_writes_replay_gain_tags() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("sine.wav");
+        // -20 dBFS reads -20 LUFS, 2 dB below the reference
+        write_sine_wav(&path, 0.1, 3);
+        let path = path.to_string_lossy().to_string();
+
+        let tag_journal = journal(dir.path());
+        let mut progress = Vec::new();
+        let result = analyze_loudness(std::slice::from_ref(&path), &tag_journal, &AtomicBool::new(false), |p| progress.push(p));
+
+        assert_eq!(result.tagged, vec![path.clone()]);
+        assert!(!result.cancelled);
+        assert_eq!(progress.len(), 1);
+        assert_eq!(progress[0].completed, 1);
+        assert_eq!(progress[0].total, 1);
+
+        let tags = read_audio_file_properties(Path::new(&path)).unwrap().tags;
+        let gain: f64 = tags[TRACK_GAIN][0].trim_end_matches(" dB").parse().unwrap();
+        assert!((gain - 2.0).abs() < 0.1, "gain {}", gain);
+        let peak: f64 = tags[TRACK_PEAK][0].parse().unwrap();
+        assert!((peak - 0.1).abs() < 0.01, "peak {}", peak);
+        // no album tags
+        assert!(!tags.contains_key(ALBUM_GAIN));
+
+        let batches = tag_journal.lock().unwrap().batches().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].description, "Analyze loudness");
+        assert_eq!(batches[0].paths, vec![path]);
+    }
+
+    #[test]
+    fn test_too_short_or_silent_files_are_not_tagged() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("some_audio.flac");
+        copy("./tests/music_libraries/different_formats/some_audio.flac", &path).unwrap();
+        let path = path.to_string_lossy().to_string();
+
+        let tag_journal = journal(dir.path());
+        let result = analyze_loudness(std::slice::from_ref(&path), &tag_journal, &AtomicBool::new(false), |_| {});
+
+        assert!(result.tagged.is_empty());
+        assert!(result.failed.is_empty());
+        assert!(tag_journal.lock().unwrap().batches().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cancelled_analysis_writes_nothing() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("sine.wav");
+        write_sine_wav(&path, 0.1, 1);
+        let path = path.to_string_lossy().to_string();
+
+        let result = analyze_loudness(std::slice::from_ref(&path), &journal(dir.path()), &AtomicBool::new(true), |_| {});
+
+        assert!(result.cancelled);
+        assert!(result.tagged.is_empty());
+        let tags = read_audio_file_properties(Path::new(&path)).unwrap().tags;
+        assert!(!tags.contains_key(TRACK_GAIN));
+    }
+
+    #[test]
+    fn test_unreadable_file_fails() {
+        let dir = tempdir().unwrap();
+        let paths = ["/nonexistent/file.mp3".to_string()];
+        let result = analyze_loudness(&paths, &journal(dir.path()), &AtomicBool::new(false), |_| {});
+        assert_eq!(result.failed, vec!["/nonexistent/file.mp3".to_string()]);
+        assert!(result.tagged.is_empty());
+    }
+}
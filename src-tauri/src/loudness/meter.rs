@@ -0,0 +1,362 @@
+use std::f64::consts::PI;
+use symphonia::core::audio::Channels;
+
+// loudness of a block whose mean square energy is 1
+const LOUDNESS_OFFSET: f64 = -0.691;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+// gating blocks are 400 ms long and overlap by 75%, so a new one starts every 100 ms
+const STEPS_PER_BLOCK: usize = 4;
+const STEPS_PER_SECOND: u32 = 10;
+// true peak is measured on a 4x oversampled signal
+const OVERSAMPLING: usize = 4;
+const INTERPOLATOR_TAPS: usize = 49;
+
+/// Measures loudness and true peak of interleaved samples as described in
+/// ITU-R BS.1770-4 and EBU R128
+pub struct LoudnessMeter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<KWeightingFilter>,
+    interpolators: Vec<PeakInterpolator>,
+    step_frames: usize,
+    step_energy: f64,
+    step_position: usize,
+    recent_steps: Vec<f64>,
+    blocks: Vec<f64>,
+    true_peak: f64,
+}
+
+/// Gating block energies and true peak of a track. Blocks of several tracks
+/// can be combined to measure the loudness of an album.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoudnessMeasurement {
+    pub blocks: Vec<f64>,
+    pub true_peak: f64, // linear
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, layout: Channels) -> LoudnessMeter {
+        let weights: Vec<f64> = if layout.count() == 1 {
+            vec![1.0]
+        } else {
+            layout.iter().map(channel_weight).collect()
+        };
+        let channels = weights.len();
+        LoudnessMeter {
+            channels,
+            weights,
+            filters: (0..channels).map(|_| KWeightingFilter::new(sample_rate)).collect(),
+            interpolators: (0..channels).map(|_| PeakInterpolator::new()).collect(),
+            step_frames: (sample_rate as f64 / STEPS_PER_SECOND as f64).round() as usize,
+            step_energy: 0.0,
+            step_position: 0,
+            recent_steps: Vec::with_capacity(STEPS_PER_BLOCK),
+            blocks: Vec::new(),
+            true_peak: 0.0,
+        }
+    }
+
+    pub fn add_samples(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            let mut energy = 0.0;
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = *sample as f64;
+                let filtered = self.filters[channel].process(sample);
+                energy += self.weights[channel] * filtered * filtered;
+
+                let peak = self.interpolators[channel].process(sample);
+                self.true_peak = self.true_peak.max(peak);
+            }
+
+            self.step_energy += energy;
+            self.step_position += 1;
+            if self.step_position == self.step_frames {
+                self.finish_step();
+            }
+        }
+    }
+
+    pub fn finish(self) -> LoudnessMeasurement {
+        // an incomplete last block is not part of the measurement
+        LoudnessMeasurement {
+            blocks: self.blocks,
+            true_peak: self.true_peak,
+        }
+    }
+
+    fn finish_step(&mut self) {
+        if self.recent_steps.len() == STEPS_PER_BLOCK {
+            self.recent_steps.remove(0);
+        }
+        self.recent_steps.push(self.step_energy / self.step_frames as f64);
+        self.step_energy = 0.0;
+        self.step_position = 0;
+
+        if self.recent_steps.len() == STEPS_PER_BLOCK {
+            let block_energy = self.recent_steps.iter().sum::<f64>() / STEPS_PER_BLOCK as f64;
+            self.blocks.push(block_energy);
+        }
+    }
+}
+
+#[cfg(test)]
+impl LoudnessMeasurement {
+    pub fn integrated_loudness(&self) -> Option<f64> {
+        integrated_loudness(&self.blocks)
+    }
+}
+
+/// Gated integrated loudness in LUFS, None if everything is below the absolute gate
+pub fn integrated_loudness(blocks: &[f64]) -> Option<f64> {
+    let above_absolute_gate: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|energy| loudness(*energy) > ABSOLUTE_GATE_LUFS)
+        .collect();
+    if above_absolute_gate.is_empty() {
+        return None;
+    }
+
+    let relative_gate = loudness(mean(&above_absolute_gate)) + RELATIVE_GATE_LU;
+    let above_relative_gate: Vec<f64> = above_absolute_gate
+        .into_iter()
+        .filter(|energy| loudness(*energy) > relative_gate)
+        .collect();
+    Some(loudness(mean(&above_relative_gate)))
+}
+
+fn loudness(energy: f64) -> f64 {
+    LOUDNESS_OFFSET + 10.0 * energy.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Channel weights of BS.1770: surround channels count 1.41 times, LFE not at all
+fn channel_weight(channel: Channels) -> f64 {
+    let surround = Channels::REAR_LEFT
+        | Channels::REAR_RIGHT
+        | Channels::SIDE_LEFT
+        | Channels::SIDE_RIGHT
+        | Channels::REAR_LEFT_CENTRE
+        | Channels::REAR_RIGHT_CENTRE;
+    if channel == Channels::LFE1 || channel == Channels::LFE2 {
+        0.0
+    } else if surround.contains(channel) {
+        1.41
+    } else {
+        1.0
+    }
+}
+
+/// Biquad filter in direct form II transposed
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.state[0];
+        self.state[0] = self.b[1] * input - self.a[0] * output + self.state[1];
+        self.state[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+}
+
+/// The K-weighting of BS.1770, a high shelf modelling the head followed by a
+/// high pass. The coefficients are derived for the sample rate instead of using
+/// the ones the standard lists for 48 kHz.
+struct KWeightingFilter {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeightingFilter {
+    fn new(sample_rate: u32) -> KWeightingFilter {
+        let sample_rate = sample_rate as f64;
+
+        let frequency = 1681.974450955533;
+        let gain_db = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * frequency / sample_rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            state: [0.0; 2],
+        };
+
+        let frequency = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * frequency / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            state: [0.0; 2],
+        };
+
+        KWeightingFilter { shelf, high_pass }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+}
+
+/// Finds the peak between samples with a windowed sinc interpolator
+struct PeakInterpolator {
+    // taps of every phase, phase 0 returns the samples themselves
+    phases: Vec<Vec<f64>>,
+    history: Vec<f64>,
+    position: usize,
+}
+
+impl PeakInterpolator {
+    fn new() -> PeakInterpolator {
+        let centre = (INTERPOLATOR_TAPS / 2) as f64;
+        let taps: Vec<f64> = (0..INTERPOLATOR_TAPS)
+            .map(|n| {
+                let t = (n as f64 - centre) / OVERSAMPLING as f64;
+                let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+                let window = 0.5 - 0.5 * (2.0 * PI * n as f64 / (INTERPOLATOR_TAPS - 1) as f64).cos();
+                sinc * window
+            })
+            .collect();
+        let phases: Vec<Vec<f64>> = (0..OVERSAMPLING)
+            .map(|phase| taps.iter().skip(phase).step_by(OVERSAMPLING).copied().collect())
+            .collect();
+        let history_len = phases[0].len();
+        PeakInterpolator {
+            phases,
+            history: vec![0.0; history_len],
+            position: 0,
+        }
+    }
+
+    /// Adds a sample and returns the highest absolute value of the
+    /// interpolated signal since the previous sample
+    fn process(&mut self, sample: f64) -> f64 {
+        let len = self.history.len();
+        self.history[self.position] = sample;
+        let mut peak = sample.abs();
+        for phase in &self.phases {
+            let interpolated: f64 = phase
+                .iter()
+                .enumerate()
+                .map(|(k, tap)| tap * self.history[(self.position + len - k) % len])
+                .sum();
+            peak = peak.max(interpolated.abs());
+        }
+        self.position = (self.position + 1) % len;
+        peak
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stereo() -> Channels {
+        Channels::FRONT_LEFT | Channels::FRONT_RIGHT
+    }
+
+    fn stereo_sine(frequency: f64, amplitude: f64, phase: f64, sample_rate: u32, seconds: f64) -> Vec<f32> {
+        let frames = (sample_rate as f64 * seconds) as usize;
+        (0..frames)
+            .flat_map(|frame| {
+                let t = frame as f64 / sample_rate as f64;
+                let sample = (amplitude * (2.0 * PI * frequency * t + phase).sin()) as f32;
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    fn measure(samples: &[f32], sample_rate: u32) -> LoudnessMeasurement {
+        let mut meter = LoudnessMeter::new(sample_rate, stereo());
+        // in pieces, like decoded packets
+        for packet in samples.chunks(2304) {
+            meter.add_samples(packet);
+        }
+        meter.finish()
+    }
+
+    fn db_to_linear(db: f64) -> f64 {
+        10f64.powf(db / 20.0)
+    }
+
+    #[test]
+    fn test_sine_at_minus_23_dbfs() {
+        // EBU Tech 3341 case 1: a 1 kHz sine at -23 dBFS in both channels reads -23 LUFS
+        for sample_rate in [44100, 48000, 96000] {
+            let samples = stereo_sine(1000.0, db_to_linear(-23.0), 0.0, sample_rate, 20.0);
+            let loudness = measure(&samples, sample_rate).integrated_loudness().unwrap();
+            assert!((loudness + 23.0).abs() < 0.1, "{} Hz: {} LUFS", sample_rate, loudness);
+        }
+    }
+
+    #[test]
+    fn test_silence_is_gated() {
+        let mut samples = stereo_sine(1000.0, db_to_linear(-23.0), 0.0, 48000, 10.0);
+        samples.extend(vec![0.0; 48000 * 2 * 10]);
+        let loudness = measure(&samples, 48000).integrated_loudness().unwrap();
+        assert!((loudness + 23.0).abs() < 0.1, "{} LUFS", loudness);
+
+        assert_eq!(measure(&vec![0.0; 48000 * 2 * 5], 48000).integrated_loudness(), None);
+    }
+
+    #[test]
+    fn test_relative_gate() {
+        // like EBU Tech 3341 case 3: the quiet parts are more than 10 LU below and is ignored
+        let mut samples = stereo_sine(1000.0, db_to_linear(-36.0), 0.0, 48000, 5.0);
+        samples.extend(stereo_sine(1000.0, db_to_linear(-23.0), 0.0, 48000, 30.0));
+        samples.extend(stereo_sine(1000.0, db_to_linear(-36.0), 0.0, 48000, 5.0));
+        let loudness = measure(&samples, 48000).integrated_loudness().unwrap();
+        assert!((loudness + 23.0).abs() < 0.1, "{} LUFS", loudness);
+    }
+
+    #[test]
+    fn test_album_loudness_from_combined_blocks() {
+        let loud = measure(&stereo_sine(1000.0, db_to_linear(-20.0), 0.0, 48000, 10.0), 48000);
+        let quiet = measure(&stereo_sine(1000.0, db_to_linear(-26.0), 0.0, 48000, 10.0), 48000);
+        let blocks: Vec<f64> = loud.blocks.iter().chain(&quiet.blocks).copied().collect();
+        let album = integrated_loudness(&blocks).unwrap();
+
+        // the mean energy of -20 and -26 LUFS
+        let expected = 10.0 * ((10f64.powf(-2.0) + 10f64.powf(-2.6)) / 2.0).log10();
+        assert!((album - expected).abs() < 0.1, "expected {}, got {}", expected, album);
+    }
+
+    #[test]
+    fn test_true_peak_between_samples() {
+        // a sine at a quarter of the sample rate, sampled 45 degrees off its peaks
+        let samples = stereo_sine(12000.0, 1.0, PI / 4.0, 48000, 1.0);
+        let sample_peak = samples.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(sample_peak < 0.71);
+
+        let true_peak = measure(&samples, 48000).true_peak;
+        assert!((true_peak - 1.0).abs() < 0.03, "true peak {}", true_peak);
+    }
+
+    #[test]
+    fn test_lfe_does_not_count() {
+        let layout = Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::LFE1;
+        let mut meter = LoudnessMeter::new(48000, layout);
+        let samples: Vec<f32> = stereo_sine(1000.0, 0.5, 0.0, 48000, 5.0)
+            .chunks_exact(2)
+            .flat_map(|frame| [0.0, 0.0, frame[0]])
+            .collect();
+        meter.add_samples(&samples);
+        assert_eq!(meter.finish().integrated_loudness(), None);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::read_music_library::Song;
-use reqwest::Client;
-use serde::Deserialize;
+use crate::tags::multi_value::first_value;
+use crate::musicbrainz_client::MusicBrainzClient;
+use serde::{Deserialize, Deserializer};
 pub use serde_json::Value;
 use std::collections::HashMap;
 
@@ -16,8 +17,11 @@ pub struct Recording {
     pub genres: Option<Vec<Genre>>,
     #[serde(default)]
     pub disambiguation: Option<String>,
-    #[serde(default)]
+    #[serde(default, rename = "first-release-date")]
     pub first_release_date: Option<String>,
+    /// In milliseconds
+    #[serde(default)]
+    pub length: Option<u64>,
     #[serde(flatten)]
     pub extra: HashMap<String, Value>,
 }
@@ -27,7 +31,7 @@ pub struct ArtistCredit {
     pub name: String,
     pub artist: Artist,
     pub joinphrase: Option<String>,
-    #[serde(default)]
+    #[serde(flatten)]
     pub extra: HashMap<String, serde_json::Value>,
 }
 
@@ -35,6 +39,7 @@ pub struct ArtistCredit {
 pub struct Artist {
     pub id: String,
     pub name: String,
+    #[serde(rename = "sort-name")]
     pub sort_name: Option<String>,
     #[serde(flatten)]
     pub extra: HashMap<String, Value>,
@@ -49,13 +54,17 @@ pub struct Release {
     pub media: Option<Vec<ReleaseMedia>>,
     #[serde(rename = "artist-credit")]
     pub artist_credit: Option<Vec<ArtistCredit>>,
+    #[serde(rename = "release-group")]
     pub release_group: Option<ReleaseGroup>,
+    #[serde(rename = "release-events")]
     pub events: Option<Vec<ReleaseEvent>>,
+    #[serde(rename = "label-info")]
     pub labels: Option<Vec<Label>>,
     pub asin: Option<String>,
     pub barcode: Option<String>,
     pub status: Option<String>,
     pub release_type: Option<Vec<String>>,
+    #[serde(default, rename = "text-representation", deserialize_with = "text_representation_script")]
     pub script: Option<String>,
     pub disambiguation: Option<String>,
     #[serde(flatten)]
@@ -65,34 +74,94 @@ pub struct Release {
 #[derive(Debug, Clone, Deserialize)]
 pub struct ReleaseGroup {
     pub id: String,
+    #[serde(rename = "first-release-date")]
     pub first_release_date: Option<String>,
+    #[serde(default, rename = "primary-type")]
+    pub primary_type: Option<String>,
+    #[serde(default, rename = "secondary-types")]
+    pub secondary_types: Option<Vec<String>>,
     #[serde(flatten)]
     pub extra: HashMap<String, Value>,
 }
 
+/// A release event, with the country code of its area
 #[derive(Debug, Clone, Deserialize)]
+#[serde(from = "RawReleaseEvent")]
 pub struct ReleaseEvent {
     pub date: Option<String>,
     pub country: Option<String>,
-    #[serde(flatten)]
     pub extra: HashMap<String, Value>,
 }
 
+#[derive(Deserialize)]
+struct RawReleaseEvent {
+    date: Option<String>,
+    area: Option<Area>,
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct Area {
+    #[serde(default, rename = "iso-3166-1-codes")]
+    iso_3166_1_codes: Vec<String>,
+}
+
+impl From<RawReleaseEvent> for ReleaseEvent {
+    fn from(event: RawReleaseEvent) -> Self {
+        ReleaseEvent {
+            date: event.date,
+            country: event.area.and_then(|area| area.iso_3166_1_codes.into_iter().next()),
+            extra: event.extra,
+        }
+    }
+}
+
+/// An entry of the label info of a release, with the label flattened into it
 #[derive(Debug, Clone, Deserialize)]
+#[serde(from = "LabelInfo")]
 pub struct Label {
     pub name: Option<String>,
     pub catalog_number: Option<String>,
     pub label_code: Option<u32>,
-    #[serde(flatten)]
     pub extra: HashMap<String, Value>,
 }
 
+#[derive(Deserialize)]
+struct LabelInfo {
+    #[serde(rename = "catalog-number")]
+    catalog_number: Option<String>,
+    label: Option<LabelEntity>,
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct LabelEntity {
+    name: Option<String>,
+    #[serde(rename = "label-code")]
+    label_code: Option<u32>,
+}
+
+impl From<LabelInfo> for Label {
+    fn from(info: LabelInfo) -> Self {
+        Label {
+            name: info.label.as_ref().and_then(|label| label.name.clone()),
+            catalog_number: info.catalog_number,
+            label_code: info.label.and_then(|label| label.label_code),
+            extra: info.extra,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ReleaseMedia {
+    #[serde(rename = "track-count")]
     pub track_count: Option<u32>,
     pub position: Option<u32>,
     pub tracks: Option<Vec<Track>>,
     pub format: Option<String>,
+    #[serde(rename = "format-id")]
     pub format_id: Option<String>,
     #[serde(flatten)]
     pub extra: HashMap<String, Value>,
@@ -107,6 +176,9 @@ pub struct Track {
     pub isrcs: Option<Vec<String>>,
     #[serde(default)]
     pub title: Option<String>,
+    /// Only in release lookups with `inc=recordings`
+    #[serde(default)]
+    pub recording: Option<Box<Recording>>,
     #[serde(flatten)]
     pub extra: HashMap<String, Value>,
 }
@@ -127,47 +199,165 @@ pub struct Genre {
     pub extra: HashMap<String, Value>,
 }
 
+/// The script of the `text-representation` of a release
+fn text_representation_script<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    struct TextRepresentation {
+        script: Option<String>,
+    }
+    Ok(Option::<TextRepresentation>::deserialize(deserializer)?.and_then(|text| text.script))
+}
+
 #[derive(Deserialize)]
 struct SearchResponse {
     recordings: Vec<Recording>,
 }
 
-pub async fn search_song_on_musicbrainz(song: &Song) -> Result<Vec<Recording>, String> {
-    let client = Client::builder()
-        .user_agent("tag-player/0.1.0")
-        .build()
-        .map_err(|e| e.to_string())?;
+#[derive(Deserialize)]
+struct ReleaseSearchResponse {
+    releases: Vec<Release>,
+}
 
-    let query = build_query(song);
-        let url = format!(
-            "https://musicbrainz.org/ws/2/recording?query={}&fmt=json&limit=10&inc=artist-credits+releases+artist-credits+release-groups+aliases+tags+genres+isrcs",
-            query
-        );
+#[derive(Deserialize)]
+struct IsrcResponse {
+    recordings: Vec<Recording>,
+}
 
+/// Searches recordings by the title, artist and album of a song
+pub async fn search_song_on_musicbrainz(client: &MusicBrainzClient, song: &Song) -> Result<Vec<Recording>, String> {
+    let query = build_query(song);
     let result: SearchResponse = client
-        .get(&url)
-        .send()
+        .get(
+            "recording",
+            &[
+                ("query", query.as_str()),
+                ("limit", "10"),
+                ("inc", "artist-credits+releases+release-groups+aliases+tags+genres+isrcs"),
+            ],
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(result.recordings)
+}
+
+/// Searches releases by album title and artist
+pub async fn search_releases(
+    client: &MusicBrainzClient,
+    album: &str,
+    artist: Option<&str>,
+    limit: usize,
+) -> Result<Vec<Release>, String> {
+    let mut query = format!("release:\"{}\"", escape_query(album));
+    if let Some(artist) = artist {
+        query.push_str(&format!(" AND artist:\"{}\"", escape_query(artist)));
+    }
+    let limit = limit.to_string();
+    let result: ReleaseSearchResponse = client
+        .get("release", &[("query", query.as_str()), ("limit", limit.as_str())])
         .await
-        .map_err(|e| e.to_string())?
-        .json()
+        .map_err(|e| e.to_string())?;
+    Ok(result.releases)
+}
+
+/// Searches releases by barcode. Only releases with this barcode are kept, with
+/// or without leading zeros, so that an EAN-13 finds the same release as its UPC-A.
+pub async fn search_releases_by_barcode(client: &MusicBrainzClient, barcode: &str) -> Result<Vec<Release>, String> {
+    let digits = barcode.trim().trim_start_matches('0');
+    if digits.is_empty() {
+        return Ok(Vec::new());
+    }
+    // MusicBrainz matches barcodes exactly, so search both the UPC-A and the EAN-13 form
+    let query = format!(
+        "barcode:\"{:0>12}\" OR barcode:\"{:0>13}\"",
+        escape_query(digits),
+        escape_query(digits)
+    );
+    let result: ReleaseSearchResponse = client
+        .get("release", &[("query", query.as_str()), ("limit", "5")])
         .await
         .map_err(|e| e.to_string())?;
+    Ok(result
+        .releases
+        .into_iter()
+        .filter(|release| {
+            release
+                .barcode
+                .as_deref()
+                .is_some_and(|found| found.trim().trim_start_matches('0') == digits)
+        })
+        .collect())
+}
 
+/// True for a MusicBrainz ID, a UUID like `b52a8f31-b5ab-34e9-92f4-f5b7110220f0`
+pub fn is_mbid(id: &str) -> bool {
+    id.len() == 36
+        && id.char_indices().all(|(index, c)| match index {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+/// True for an ISRC, 12 letters and digits like `USGF19942501`
+pub fn is_isrc(isrc: &str) -> bool {
+    isrc.len() == 12 && isrc.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// The recordings with an ISRC, usually one
+pub async fn lookup_isrc(client: &MusicBrainzClient, isrc: &str) -> Result<Vec<Recording>, String> {
+    if !is_isrc(isrc) {
+        return Err(format!("Invalid ISRC: {}", isrc));
+    }
+    let result: IsrcResponse = client
+        .get(
+            &format!("isrc/{}", isrc),
+            &[("inc", "artist-credits+releases+release-groups+isrcs")],
+        )
+        .await
+        .map_err(|e| e.to_string())?;
     Ok(result.recordings)
 }
 
+/// A release with its whole tracklist and the recordings on it
+pub async fn lookup_release(client: &MusicBrainzClient, release_id: &str) -> Result<Release, String> {
+    if !is_mbid(release_id) {
+        return Err(format!("Invalid MusicBrainz release ID: {}", release_id));
+    }
+    client
+        .get(
+            &format!("release/{}", release_id),
+            &[("inc", "recordings+artist-credits+isrcs+release-groups+labels+genres")],
+        )
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// A recording with its releases, e.g. one that AcoustID found by fingerprint
+pub async fn lookup_recording(client: &MusicBrainzClient, recording_id: &str) -> Result<Recording, String> {
+    if !is_mbid(recording_id) {
+        return Err(format!("Invalid MusicBrainz recording ID: {}", recording_id));
+    }
+    client
+        .get(
+            &format!("recording/{}", recording_id),
+            &[("inc", "artist-credits+releases+release-groups+media+isrcs+tags+genres")],
+        )
+        .await
+        .map_err(|e| e.to_string())
+}
+
 fn build_query(song: &Song) -> String {
     let mut parts = Vec::new();
 
-    if let Some(title) = song.tags.get("TrackTitle") {
+    if let Some(title) = first_value(&song.tags, "TrackTitle") {
         parts.push(format!("recording:\"{}\"", escape_query(title)));
     }
 
-    if let Some(artist) = song.tags.get("TrackArtist") {
+    if let Some(artist) = first_value(&song.tags, "TrackArtist") {
         parts.push(format!("artist:\"{}\"", escape_query(artist)));
     }
 
-    if let Some(album) = song.tags.get("AlbumTitle") {
+    if let Some(album) = first_value(&song.tags, "AlbumTitle") {
         parts.push(format!("release:\"{}\"", escape_query(album)));
     }
 
@@ -182,75 +372,231 @@ fn escape_query(s: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::musicbrainz_client::MusicBrainzSettings;
+    use mockito::{Matcher, Server};
     use std::collections::HashMap;
-    use tokio::time::{sleep, Duration};
+
+    const SEARCH_RESPONSE: &str = r#"{
+        "created": "2024-01-01T00:00:00.000Z",
+        "count": 1,
+        "offset": 0,
+        "recordings": [{
+            "id": "5fb524f1-8cc8-4c04-a921-e34c0a911ea7",
+            "score": 100,
+            "title": "Smells Like Teen Spirit",
+            "length": 301920,
+            "first-release-date": "1991-09-10",
+            "artist-credit": [{
+                "name": "Nirvana",
+                "artist": {"id": "5b11f4ce-a62d-471e-81fc-a69a8278c7da", "name": "Nirvana", "sort-name": "Nirvana"}
+            }],
+            "releases": [{
+                "id": "b52a8f31-b5ab-34e9-92f4-f5b7110220f0",
+                "title": "Nevermind",
+                "status": "Official",
+                "date": "1991-09-24",
+                "country": "US"
+            }],
+            "isrcs": ["USGF19942501"]
+        }]
+    }"#;
 
     #[tokio::test]
     async fn test_search_song_on_musicbrainz_smells_like_teen_spirit() {
-        sleep(Duration::from_secs_f64(1.1)).await;
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("GET", "/ws/2/recording")
+            .match_query(Matcher::UrlEncoded(
+                "query".to_string(),
+                "recording:\"Smells Like Teen Spirit\" AND artist:\"Nirvana\" AND release:\"Nevermind\"".to_string(),
+            ))
+            .with_header("content-type", "application/json")
+            .with_body(SEARCH_RESPONSE)
+            .create_async()
+            .await;
+        let settings = MusicBrainzSettings {
+            base_url: server.url(),
+            ..Default::default()
+        };
+        let client = MusicBrainzClient::new(settings, None).unwrap();
 
         let mut tags = HashMap::new();
-        tags.insert("TrackTitle".to_string(), "Smells Like Teen Spirit".to_string());
-        tags.insert("TrackArtist".to_string(), "Nirvana".to_string());
-        tags.insert("AlbumTitle".to_string(), "Nevermind".to_string());
+        tags.insert("TrackTitle".to_string(), vec!["Smells Like Teen Spirit".to_string()]);
+        tags.insert("TrackArtist".to_string(), vec!["Nirvana".to_string()]);
+        tags.insert("AlbumTitle".to_string(), vec!["Nevermind".to_string()]);
 
         let song = Song {
             path: "/test/path.mp3".to_string(),
             name: "Smells Like Teen Spirit.mp3".to_string(),
             duration_millis: 301000,
             tags,
-            cover_base64: None,
         };
 
-        let result = search_song_on_musicbrainz(&song).await;
-
-        if let Err(ref e) = result {
-            println!("Error: {}", e);
-            // Try to get more details
-            let client = reqwest::Client::builder()
-                .user_agent("tag-player/0.1.0")
-                .build()
-                .unwrap();
-            let query = build_query(&song);
-            let url = format!(
-                "https://musicbrainz.org/ws/2/recording?query={}&fmt=json&limit=10&inc=artist-credits+releases+artist-credits+release-groups+aliases+tags+genres+isrcs",
-                query
-            );
-            println!("URL: {}", url);
-            let resp = client.get(&url).send().await.unwrap();
-            let text = resp.text().await.unwrap();
-            
-            // Try to deserialize to see what fails
-            let parsed: Result<SearchResponse, _> = serde_json::from_str(&text);
-            if let Err(ref de) = parsed {
-                println!("Deserialization error: {}", de);
-            }
-            
-            println!("Response (first 1000 chars): {}", &text[..1000.min(text.len())]);
-        }
-        assert!(result.is_ok(), "Search should succeed: {:?}", result);
-        let recordings = result.unwrap();
-        assert!(!recordings.is_empty(), "Should find at least one recording");
+        let recordings = search_song_on_musicbrainz(&client, &song).await.unwrap();
+        mock.assert_async().await;
+        assert_eq!(recordings.len(), 1);
 
         let first = &recordings[0];
         assert_eq!(first.title, "Smells Like Teen Spirit");
+        assert_eq!(first.extra["score"], 100);
 
-        // Debug: print the response to see what's actually returned
-        println!("First recording: {:#?}", first);
-
-        // Verify artist is returned
-        assert!(
-            first.artist_credit.is_some(),
-            "Should have artist_credit, got: {:#?}", first.artist_credit
-        );
         let artist_credit = first.artist_credit.as_ref().unwrap();
-        assert!(!artist_credit.is_empty(), "Should have at least one artist");
         assert_eq!(artist_credit[0].artist.name, "Nirvana");
 
-        // Verify album is returned
-        assert!(first.releases.is_some(), "Should have releases");
         let releases = first.releases.as_ref().unwrap();
-        assert!(!releases.is_empty(), "Should have at least one release");
         assert_eq!(releases[0].title, "Nevermind");
+        assert_eq!(first.isrcs.as_deref(), Some(&["USGF19942501".to_string()][..]));
+    }
+
+    // a release lookup as MusicBrainz sends it, trimmed to one track
+    const RELEASE_LOOKUP: &str = r#"{
+        "id": "b52a8f31-b5ab-34e9-92f4-f5b7110220f0",
+        "title": "Nevermind",
+        "status": "Official",
+        "status-id": "4e304316-386d-3409-af2e-78857eec5cfe",
+        "quality": "normal",
+        "packaging": "Jewel Case",
+        "date": "1991-09-24",
+        "country": "US",
+        "barcode": "720642442524",
+        "asin": "B000003TA4",
+        "disambiguation": "",
+        "text-representation": {"language": "eng", "script": "Latn"},
+        "cover-art-archive": {"artwork": true, "count": 2, "front": true, "back": true, "darkened": false},
+        "release-events": [{
+            "date": "1991-09-24",
+            "area": {
+                "id": "489ce91b-6658-3307-9877-795b68554c98",
+                "name": "United States",
+                "sort-name": "United States",
+                "disambiguation": "",
+                "iso-3166-1-codes": ["US"]
+            }
+        }],
+        "artist-credit": [{
+            "name": "Nirvana",
+            "joinphrase": "",
+            "artist": {
+                "id": "5b11f4ce-a62d-471e-81fc-a69a8278c7da",
+                "name": "Nirvana",
+                "sort-name": "Nirvana",
+                "type": "Group",
+                "disambiguation": "90s US grunge band"
+            }
+        }],
+        "release-group": {
+            "id": "1b022e01-4da6-387b-8658-8678046e4cef",
+            "title": "Nevermind",
+            "first-release-date": "1991-09-24",
+            "primary-type": "Album",
+            "primary-type-id": "f529b476-6e62-324f-b0aa-1f3e33d313fc",
+            "secondary-types": [],
+            "disambiguation": ""
+        },
+        "label-info": [{
+            "catalog-number": "DGCD-24425",
+            "label": {
+                "id": "f1e8ad5f-bc2c-4e45-a1a5-0ec19fbf6dd0",
+                "name": "DGC",
+                "sort-name": "DGC",
+                "label-code": 7266,
+                "disambiguation": ""
+            }
+        }],
+        "media": [{
+            "position": 1,
+            "title": "",
+            "format": "CD",
+            "format-id": "9712d52a-4509-3d4b-a1a2-67c88c643e31",
+            "track-count": 12,
+            "track-offset": 0,
+            "tracks": [{
+                "id": "2d6bd5a5-b2e4-3c8b-92f8-5a2e1e4a4f4c",
+                "number": "1",
+                "position": 1,
+                "title": "Smells Like Teen Spirit",
+                "length": 301920,
+                "recording": {
+                    "id": "5fb524f1-8cc8-4c04-a921-e34c0a911ea7",
+                    "title": "Smells Like Teen Spirit",
+                    "length": 301920,
+                    "first-release-date": "1991-09-10",
+                    "disambiguation": "",
+                    "video": false
+                }
+            }]
+        }]
+    }"#;
+
+    #[test]
+    fn test_release_reads_hyphenated_fields() {
+        let release: Release = serde_json::from_str(RELEASE_LOOKUP).unwrap();
+
+        let release_group = release.release_group.as_ref().unwrap();
+        assert_eq!(release_group.id, "1b022e01-4da6-387b-8658-8678046e4cef");
+        assert_eq!(release_group.first_release_date.as_deref(), Some("1991-09-24"));
+
+        let events = release.events.as_ref().unwrap();
+        assert_eq!(events[0].country.as_deref(), Some("US"));
+        assert_eq!(events[0].date.as_deref(), Some("1991-09-24"));
+
+        let labels = release.labels.as_ref().unwrap();
+        assert_eq!(labels[0].name.as_deref(), Some("DGC"));
+        assert_eq!(labels[0].catalog_number.as_deref(), Some("DGCD-24425"));
+        assert_eq!(labels[0].label_code, Some(7266));
+
+        assert_eq!(release.script.as_deref(), Some("Latn"));
+        let artist = &release.artist_credit.as_ref().unwrap()[0].artist;
+        assert_eq!(artist.sort_name.as_deref(), Some("Nirvana"));
+
+        let medium = &release.media.as_ref().unwrap()[0];
+        assert_eq!(medium.track_count, Some(12));
+        assert_eq!(medium.format_id.as_deref(), Some("9712d52a-4509-3d4b-a1a2-67c88c643e31"));
+        let recording = medium.tracks.as_ref().unwrap()[0].recording.as_ref().unwrap();
+        assert_eq!(recording.first_release_date.as_deref(), Some("1991-09-10"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_build_query_escapes_quotes() {
+        let mut tags = HashMap::new();
+        tags.insert("TrackTitle".to_string(), vec!["Say \"Hi\"".to_string()]);
+        let song = Song {
+            path: String::new(),
+            name: String::new(),
+            duration_millis: 0,
+            tags,
+        };
+        assert_eq!(build_query(&song), "recording:\"Say \\\"Hi\\\"\"");
+    }
+
+    #[test]
+    fn test_is_mbid() {
+        assert!(is_mbid("b52a8f31-b5ab-34e9-92f4-f5b7110220f0"));
+        assert!(is_mbid("B52A8F31-B5AB-34E9-92F4-F5B7110220F0"));
+        assert!(!is_mbid("b52a8f31b5ab34e992f4f5b7110220f0"));
+        assert!(!is_mbid("b52a8f31-b5ab-34e9-92f4-f5b7110220f0/../../"));
+        assert!(!is_mbid("g52a8f31-b5ab-34e9-92f4-f5b7110220f0"));
+        assert!(!is_mbid(""));
+    }
+
+    #[test]
+    fn test_is_isrc() {
+        assert!(is_isrc("USGF19942501"));
+        assert!(!is_isrc("US-GF1-99-42501"));
+        assert!(!is_isrc("USGF1994250"));
+        assert!(!is_isrc("USGF1994250/"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_ids_are_not_requested() {
+        let server = Server::new_async().await;
+        let settings = MusicBrainzSettings {
+            base_url: server.url(),
+            ..Default::default()
+        };
+        let client = MusicBrainzClient::new(settings, None).unwrap();
+        assert!(lookup_release(&client, "../artist/x").await.unwrap_err().contains("Invalid"));
+        assert!(lookup_recording(&client, "found").await.unwrap_err().contains("Invalid"));
+        assert!(lookup_isrc(&client, "US GF 1994 2501").await.unwrap_err().contains("Invalid"));
+    }
+}
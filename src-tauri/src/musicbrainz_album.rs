@@ -0,0 +1,600 @@
+use crate::musicbrainz::{lookup_release, search_releases, Recording, Release, ReleaseMedia, Track};
+use crate::musicbrainz_client::MusicBrainzClient;
+use crate::musicbrainz_matching::apply_tags;
+use crate::musicbrainz_scoring::{
+    self, credit_name, normalize_title, score_tracklist, string_similarity, ScoreBreakdown, ScoringSettings, TracklistFit,
+};
+use crate::musicbrainz_tag_mapping::recording_to_tags;
+use crate::tag_journal::TagJournal;
+use crate::tags::multi_value::{first_value, TagMap};
+use crate::tags::reading_tags::read_audio_file_properties;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+// how many releases of the search are looked up with their tracklist
+const RELEASES_TO_COMPARE: usize = 5;
+// a file and a track that are less alike are not paired
+const MIN_PAIR_SIMILARITY: f64 = 0.4;
+
+/// Files that belong to one album, by their album tags or else their folder
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct AlbumGroup {
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub paths: Vec<String>,
+}
+
+/// A release with every file assigned to one of its tracks
+#[derive(Serialize, Clone, Debug)]
+pub struct ReleaseMatch {
+    pub release_id: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub date: Option<String>,
+    pub country: Option<String>,
+    pub track_count: usize,
+    pub disc_count: usize,
+    /// How well the tracklist fits the files, from 0 to 1
+    pub score: f64,
+    pub breakdown: ScoreBreakdown,
+    pub tracks: Vec<TrackMatch>,
+    /// Files no track fits
+    pub unmatched: Vec<String>,
+}
+
+/// A file with the track of the release it was assigned to and the tags it gets
+#[derive(Serialize, Clone, Debug)]
+pub struct TrackMatch {
+    pub path: String,
+    pub disc_number: u32,
+    pub track_number: String,
+    pub title: Option<String>,
+    pub length_millis: Option<u32>,
+    pub tags: TagMap,
+}
+
+/// The tags of the chosen release for one file
+#[derive(Deserialize, Clone, Debug)]
+pub struct TrackTags {
+    pub path: String,
+    pub tags: TagMap,
+}
+
+/// What the files of an album are compared by
+#[derive(Clone, Debug)]
+struct LocalFile {
+    path: String,
+    title: String,
+    duration_millis: u32,
+    disc_number: Option<u32>,
+    track_number: Option<u32>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    /// The album artist, or else the track artist, to search releases by
+    artist: Option<String>,
+}
+
+/// A track of a release with the medium it is on
+pub(crate) struct ReleaseTrack<'a> {
+    pub medium: &'a ReleaseMedia,
+    pub medium_position: u32,
+    pub track: &'a Track,
+}
+
+/// Groups files by album title and album artist. Files without an album artist
+/// are grouped by album title and folder, so that the tracks of a compilation
+/// stay together, and files without an album title by their folder alone.
+pub fn group_album_files(paths: &[String]) -> Vec<AlbumGroup> {
+    let mut groups: Vec<(String, AlbumGroup)> = Vec::new();
+    for path in paths {
+        let file = read_local_file(path);
+        let key = match (&file.album, &file.album_artist) {
+            (Some(album), Some(album_artist)) => {
+                format!("album:{}\u{0}{}", album.to_lowercase(), album_artist.to_lowercase())
+            }
+            (Some(album), None) => format!("folder:{}\u{0}{}", parent_dir(path), album.to_lowercase()),
+            (None, _) => format!("folder:{}", parent_dir(path)),
+        };
+        match groups.iter_mut().find(|(group_key, _)| *group_key == key) {
+            Some((_, group)) => group.paths.push(path.clone()),
+            None => groups.push((
+                key,
+                AlbumGroup {
+                    album: file.album,
+                    artist: file.album_artist,
+                    paths: vec![path.clone()],
+                },
+            )),
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Searches releases for the files of one album and matches every tracklist
+/// against them, best release first
+pub async fn match_album(
+    client: &MusicBrainzClient,
+    paths: &[String],
+    settings: &ScoringSettings,
+) -> Result<Vec<ReleaseMatch>, String> {
+    let files: Vec<LocalFile> = paths.iter().map(|path| read_local_file(path)).collect();
+    let first = files.first().ok_or("No files to match")?;
+    // without album tags the folder is usually named after the album
+    let album = most_common(files.iter().map(|file| file.album.clone()))
+        .or_else(|| Path::new(&first.path).parent().and_then(|dir| dir.file_name()).map(|name| name.to_string_lossy().to_string()))
+        .ok_or("The files have no album title")?;
+    let artist = most_common(files.iter().map(|file| file.artist.clone()));
+
+    let releases = search_releases(client, &album, artist.as_deref(), RELEASES_TO_COMPARE).await?;
+    let mut matches = Vec::new();
+    for release in releases {
+        // one release that fails to load leaves the others to choose from
+        match lookup_release(client, &release.id).await {
+            Ok(release) => matches.push(match_release(&files, &release, settings)),
+            Err(e) => eprintln!("Failed to look up release {}: {}", release.id, e),
+        }
+    }
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(matches)
+}
+
+/// Writes the accepted fields of every file's tags as one journal batch, so that
+/// the whole album can be reverted at once. Stops at the first file that fails.
+pub fn apply_album(journal: &mut TagJournal, album: &str, files: &[TrackTags], accepted: &[String]) -> Result<i64> {
+    let batch_id = journal.start_batch(&format!("Apply MusicBrainz release: {}", album))?;
+    for file in files {
+        let path = Path::new(&file.path);
+        journal.write(batch_id, path, || apply_tags(path, &file.tags, accepted).map(|_| ()))?;
+    }
+    Ok(batch_id)
+}
+
+fn read_local_file(path: &str) -> LocalFile {
+    let (duration_millis, tags) = read_audio_file_properties(Path::new(path))
+        .map(|properties| (properties.duration_millis, properties.tags))
+        .unwrap_or_default();
+    let non_empty = |key: &str| first_value(&tags, key).map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+    let title = non_empty("TrackTitle").unwrap_or_else(|| title_from_file_name(path));
+    LocalFile {
+        path: path.to_string(),
+        title: normalize_title(&title),
+        duration_millis,
+        disc_number: non_empty("DiscNumber").and_then(|value| leading_number(&value)),
+        track_number: non_empty("TrackNumber").and_then(|value| leading_number(&value)),
+        album: non_empty("AlbumTitle"),
+        album_artist: non_empty("AlbumArtist"),
+        artist: non_empty("AlbumArtist").or_else(|| non_empty("TrackArtist")),
+    }
+}
+
+/// Assigns the files to the tracks of the release and scores the tracklist
+fn match_release(files: &[LocalFile], release: &Release, settings: &ScoringSettings) -> ReleaseMatch {
+    let tracks = release_tracks(release);
+    let assignment = assign(files, &tracks);
+
+    let mut track_matches = Vec::new();
+    let mut unmatched = Vec::new();
+    let (mut duration_sum, mut title_sum, mut disc_sum) = (0.0, 0.0, 0.0);
+    for (file, track_index) in files.iter().zip(&assignment) {
+        let Some(track_index) = *track_index else {
+            unmatched.push(file.path.clone());
+            continue;
+        };
+        let release_track = &tracks[track_index];
+        duration_sum += duration_similarity(file, release_track.track);
+        title_sum += title_similarity(file, release_track.track);
+        // files without a disc number fit on any disc
+        disc_sum += match file.disc_number {
+            Some(disc_number) if disc_number != release_track.medium_position => 0.0,
+            _ => 1.0,
+        };
+        track_matches.push(TrackMatch {
+            path: file.path.clone(),
+            disc_number: release_track.medium_position,
+            track_number: track_number(release_track),
+            title: release_track.track.title.clone(),
+            length_millis: release_track.track.length,
+            tags: track_tags(release, release_track),
+        });
+    }
+
+    let fit = TracklistFit {
+        file_count: files.len(),
+        track_count: tracks.len(),
+        paired_count: track_matches.len(),
+        duration_sum,
+        title_sum,
+        disc_sum,
+    };
+    let breakdown = score_tracklist(&fit, settings);
+
+    ReleaseMatch {
+        release_id: release.id.clone(),
+        title: release.title.clone(),
+        artist: release.artist_credit.as_deref().map(credit_name),
+        date: release.date.clone(),
+        country: release.country.clone(),
+        track_count: tracks.len(),
+        disc_count: release.media.as_ref().map_or(0, |media| media.len()),
+        score: breakdown.score,
+        breakdown,
+        tracks: track_matches,
+        unmatched,
+    }
+}
+
+pub(crate) fn release_tracks(release: &Release) -> Vec<ReleaseTrack<'_>> {
+    let mut release_tracks = Vec::new();
+    for (index, medium) in release.media.iter().flatten().enumerate() {
+        let medium_position = medium.position.unwrap_or(index as u32 + 1);
+        for track in medium.tracks.iter().flatten() {
+            release_tracks.push(ReleaseTrack {
+                medium,
+                medium_position,
+                track,
+            });
+        }
+    }
+    release_tracks
+}
+
+/// Pairs files and tracks, the most alike first, so that every track gets one file at most
+fn assign(files: &[LocalFile], tracks: &[ReleaseTrack]) -> Vec<Option<usize>> {
+    let mut pairs = Vec::new();
+    for (file_index, file) in files.iter().enumerate() {
+        for (track_index, track) in tracks.iter().enumerate() {
+            let similarity = pair_similarity(file, track);
+            if similarity >= MIN_PAIR_SIMILARITY {
+                pairs.push((similarity, file_index, track_index));
+            }
+        }
+    }
+    pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut assignment = vec![None; files.len()];
+    let mut taken = vec![false; tracks.len()];
+    for (_, file_index, track_index) in pairs {
+        if assignment[file_index].is_none() && !taken[track_index] {
+            assignment[file_index] = Some(track_index);
+            taken[track_index] = true;
+        }
+    }
+    assignment
+}
+
+fn pair_similarity(file: &LocalFile, release_track: &ReleaseTrack) -> f64 {
+    let position = match (file.track_number, release_track.track.position) {
+        (Some(track_number), Some(position)) => {
+            let same_disc = file.disc_number.is_none_or(|disc_number| disc_number == release_track.medium_position);
+            if same_disc && track_number == position { 1.0 } else { 0.0 }
+        }
+        _ => 0.5,
+    };
+    0.5 * title_similarity(file, release_track.track) + 0.3 * duration_similarity(file, release_track.track) + 0.2 * position
+}
+
+fn title_similarity(file: &LocalFile, track: &Track) -> f64 {
+    let title = track
+        .title
+        .as_deref()
+        .or(track.recording.as_ref().map(|recording| recording.title.as_str()))
+        .unwrap_or_default();
+    string_similarity(&file.title, &normalize_title(title))
+}
+
+/// 1 within two seconds, falling to 0 at 15 seconds; 0.5 when the length is unknown
+fn duration_similarity(file: &LocalFile, track: &Track) -> f64 {
+    let Some(length) = track.length.filter(|_| file.duration_millis > 0) else {
+        return 0.5;
+    };
+    musicbrainz_scoring::duration_similarity((length as f64 - file.duration_millis as f64).abs())
+}
+
+/// The tags of the recording on this track of this release. The release-wide
+/// tags come from `recording_to_tags`, the position on the release from the track.
+pub(crate) fn track_tags(release: &Release, release_track: &ReleaseTrack) -> TagMap {
+    let track = release_track.track;
+    let mut recording = match &track.recording {
+        Some(recording) => (**recording).clone(),
+        None => Recording {
+            id: String::new(),
+            title: track.title.clone().unwrap_or_default(),
+            artist_credit: None,
+            releases: None,
+            isrcs: track.isrcs.clone(),
+            tags: None,
+            genres: None,
+            disambiguation: None,
+            first_release_date: None,
+            length: track.length.map(u64::from),
+            extra: Default::default(),
+        },
+    };
+    recording.releases = Some(vec![Release {
+        media: None,
+        ..release.clone()
+    }]);
+
+    let mut tags = recording_to_tags(&recording);
+    if recording.id.is_empty() {
+        tags.remove("MusicBrainzRecordingId");
+    }
+    if let Some(title) = &track.title {
+        tags.insert("TrackTitle".to_string(), vec![title.clone()]);
+    }
+    if let Some(track_id) = &track.id {
+        tags.insert("MusicBrainzTrackId".to_string(), vec![track_id.clone()]);
+    }
+    if let Some(format) = &release_track.medium.format {
+        tags.insert("Media".to_string(), vec![format.clone()]);
+    }
+    let track_total = release_track.medium.tracks.as_ref().map_or(0, |tracks| tracks.len());
+    let disc_total = release.media.as_ref().map_or(0, |media| media.len());
+    tags.insert("TrackNumber".to_string(), vec![track_number(release_track)]);
+    tags.insert("TrackTotal".to_string(), vec![track_total.to_string()]);
+    tags.insert("DiscNumber".to_string(), vec![release_track.medium_position.to_string()]);
+    tags.insert("DiscTotal".to_string(), vec![disc_total.to_string()]);
+    tags
+}
+
+fn track_number(release_track: &ReleaseTrack) -> String {
+    release_track
+        .track
+        .number
+        .clone()
+        .or_else(|| release_track.track.position.map(|position| position.to_string()))
+        .unwrap_or_default()
+}
+
+fn most_common(values: impl Iterator<Item = Option<String>>) -> Option<String> {
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    for value in values.flatten() {
+        match counts.iter_mut().find(|(known, _)| *known == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value, 1)),
+        }
+    }
+    // the first of the most common ones
+    counts.into_iter().rev().max_by_key(|(_, count)| *count).map(|(value, _)| value)
+}
+
+fn parent_dir(path: &str) -> String {
+    Path::new(path).parent().map(|dir| dir.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+/// "01 - Title.mp3" -> "Title"
+fn title_from_file_name(path: &str) -> String {
+    let stem = Path::new(path).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    stem.trim_start_matches(|c: char| c.is_ascii_digit() || c.is_whitespace() || c == '-' || c == '.' || c == '_')
+        .to_string()
+}
+
+/// "3/12" -> 3
+pub(crate) fn leading_number(value: &str) -> Option<u32> {
+    let digits: String = value.trim().chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::musicbrainz_fixtures::{self as fixtures, artist_credit, recording};
+    use crate::musicbrainz_client::MusicBrainzSettings;
+    use crate::tags::writing_tags::write_tags_to_file;
+    use crate::test_support::{sample_file, tag_map, tagged_song};
+    use mockito::{Matcher, Server};
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    fn local_file(path: &str, title: &str, duration_millis: u32, position: Option<(u32, u32)>) -> LocalFile {
+        LocalFile {
+            path: path.to_string(),
+            title: normalize_title(title),
+            duration_millis,
+            disc_number: position.map(|(disc, _)| disc),
+            track_number: position.map(|(_, track)| track),
+            album: Some("Album".to_string()),
+            album_artist: Some("Artist".to_string()),
+            artist: Some("Artist".to_string()),
+        }
+    }
+
+    fn track(position: u32, title: &str, length: u32) -> Track {
+        Track {
+            id: Some(format!("track-{}", title)),
+            number: Some(position.to_string()),
+            position: Some(position),
+            length: Some(length),
+            isrcs: None,
+            title: Some(title.to_string()),
+            recording: Some(Box::new(recording(&format!("recording-{}", title), title))),
+            extra: HashMap::new(),
+        }
+    }
+
+    fn medium(position: u32, tracks: Vec<Track>) -> ReleaseMedia {
+        ReleaseMedia {
+            track_count: Some(tracks.len() as u32),
+            position: Some(position),
+            tracks: Some(tracks),
+            format: Some("CD".to_string()),
+            format_id: None,
+            extra: HashMap::new(),
+        }
+    }
+
+    fn release(id: &str, media: Vec<ReleaseMedia>) -> Release {
+        Release {
+            date: Some("2001-02-03".to_string()),
+            country: Some("DE".to_string()),
+            media: Some(media),
+            artist_credit: Some(artist_credit("Artist")),
+            status: Some("Official".to_string()),
+            ..fixtures::release(id)
+        }
+    }
+
+    #[test]
+    fn test_match_release_assigns_files_to_tracks() {
+        let files = vec![
+            local_file("/music/b.mp3", "Second Song", 200_500, None),
+            local_file("/music/a.mp3", "First Song", 180_000, None),
+            local_file("/music/c.mp3", "Third Song (Live)", 240_000, Some((2, 1))),
+        ];
+        let release = release(
+            "release-id",
+            vec![
+                medium(1, vec![track(1, "First Song", 181_000), track(2, "Second Song", 200_000)]),
+                medium(2, vec![track(1, "Third Song (Live)", 241_000)]),
+            ],
+        );
+
+        let release_match = match_release(&files, &release, &ScoringSettings::default());
+        assert!(release_match.unmatched.is_empty());
+        assert!(release_match.score > 0.95, "{}", release_match.score);
+        assert_eq!((release_match.track_count, release_match.disc_count), (3, 2));
+
+        let positions: Vec<(&str, u32, &str)> = release_match
+            .tracks
+            .iter()
+            .map(|track| (track.path.as_str(), track.disc_number, track.track_number.as_str()))
+            .collect();
+        assert_eq!(positions, vec![("/music/b.mp3", 1, "2"), ("/music/a.mp3", 1, "1"), ("/music/c.mp3", 2, "1")]);
+
+        let tags = &release_match.tracks[2].tags;
+        assert_eq!(tags["TrackTitle"], vec!["Third Song (Live)"]);
+        assert_eq!(tags["AlbumTitle"], vec!["Album"]);
+        assert_eq!(tags["MusicBrainzRecordingId"], vec!["recording-Third Song (Live)"]);
+        assert_eq!(tags["MusicBrainzReleaseId"], vec!["release-id"]);
+        assert_eq!(tags["TrackNumber"], vec!["1"]);
+        assert_eq!(tags["TrackTotal"], vec!["1"]);
+        assert_eq!(tags["DiscNumber"], vec!["2"]);
+        assert_eq!(tags["DiscTotal"], vec!["2"]);
+        assert_eq!(tags["AlbumArtist"], vec!["Artist"]);
+    }
+
+    #[test]
+    fn test_match_release_prefers_the_fitting_tracklist() {
+        let files = vec![
+            local_file("/music/1.mp3", "Intro", 60_000, Some((1, 1))),
+            local_file("/music/2.mp3", "Hit Single", 210_000, Some((1, 2))),
+        ];
+        let album = release("album", vec![medium(1, vec![track(1, "Intro", 60_000), track(2, "Hit Single", 210_000)])]);
+        // a compilation with the single in another version and many other tracks
+        let compilation = release(
+            "compilation",
+            vec![medium(
+                1,
+                vec![
+                    track(1, "Other Song", 100_000),
+                    track(2, "Hit Single (Radio Edit)", 190_000),
+                    track(3, "Another Song", 300_000),
+                    track(4, "Last Song", 320_000),
+                ],
+            )],
+        );
+
+        let album_match = match_release(&files, &album, &ScoringSettings::default());
+        let compilation_match = match_release(&files, &compilation, &ScoringSettings::default());
+        assert!(album_match.score > compilation_match.score);
+        assert!(album_match.unmatched.is_empty());
+        assert_eq!(compilation_match.unmatched, vec!["/music/1.mp3"]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_release_lookup_is_skipped() {
+        const FOUND: &str = "0f2d6f2b-4b48-4ff9-8d0b-8c1b53a4f1f1";
+        const BROKEN: &str = "9a6b2c1e-6f3a-4d2b-9e61-2f3c0b7d8e5a";
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/ws/2/release")
+            .match_query(Matcher::Any)
+            .with_body(format!(
+                r#"{{"releases": [{{"id": "{}", "title": "Album"}}, {{"id": "{}", "title": "Album"}}]}}"#,
+                BROKEN, FOUND
+            ))
+            .create_async()
+            .await;
+        server
+            .mock("GET", format!("/ws/2/release/{}", BROKEN).as_str())
+            .match_query(Matcher::Any)
+            .with_status(500)
+            .create_async()
+            .await;
+        server
+            .mock("GET", format!("/ws/2/release/{}", FOUND).as_str())
+            .match_query(Matcher::Any)
+            .with_body(format!(
+                r#"{{"id": "{}", "title": "Album", "media": [{{"position": 1, "tracks": [
+                    {{"id": "t1", "number": "1", "position": 1, "title": "Song", "length": 1000}}
+                ]}}]}}"#,
+                FOUND
+            ))
+            .create_async()
+            .await;
+
+        let dir = tempdir().unwrap();
+        let path = tagged_song(
+            &dir.path().join("song.mp3"),
+            &tag_map(&[("AlbumTitle", &["Album"]), ("TrackTitle", &["Song"])]),
+        );
+
+        let settings = MusicBrainzSettings {
+            base_url: server.url(),
+            ..Default::default()
+        };
+        let client = MusicBrainzClient::new(settings, None).unwrap();
+        let matches = match_album(&client, &[path.to_string_lossy().to_string()], &ScoringSettings::default())
+            .await
+            .unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].release_id, FOUND);
+    }
+
+    #[test]
+    fn test_group_album_files() {
+        let dir = tempdir().unwrap();
+        let paths: Vec<String> = ["a.mp3", "b.mp3", "folder/c.mp3", "folder/d.mp3", "folder/e.mp3", "folder/f.mp3", "g.mp3"]
+            .iter()
+            .map(|name| {
+                let path = sample_file("some_song.mp3", &dir.path().join(name));
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let album_tags = |key: &str, artist: &str| {
+            HashMap::from([
+                ("AlbumTitle".to_string(), vec!["Album".to_string()]),
+                (key.to_string(), vec![artist.to_string()]),
+            ])
+        };
+        write_tags_to_file(Path::new(&paths[0]), &album_tags("AlbumArtist", "Artist")).unwrap();
+        write_tags_to_file(Path::new(&paths[1]), &album_tags("AlbumArtist", "ARTIST")).unwrap();
+        write_tags_to_file(Path::new(&paths[2]), &TagMap::new()).unwrap();
+        write_tags_to_file(Path::new(&paths[3]), &TagMap::new()).unwrap();
+        // a compilation without album artist stays together, but apart from
+        // an album of the same title in another folder
+        write_tags_to_file(Path::new(&paths[4]), &album_tags("TrackArtist", "One")).unwrap();
+        write_tags_to_file(Path::new(&paths[5]), &album_tags("TrackArtist", "Two")).unwrap();
+        write_tags_to_file(Path::new(&paths[6]), &album_tags("TrackArtist", "Artist")).unwrap();
+
+        let groups = group_album_files(&paths);
+        let group_paths: Vec<Vec<String>> = groups.iter().map(|group| group.paths.clone()).collect();
+        assert_eq!(
+            group_paths,
+            vec![paths[0..2].to_vec(), paths[2..4].to_vec(), paths[4..6].to_vec(), paths[6..7].to_vec()]
+        );
+        assert_eq!(groups[0].album.as_deref(), Some("Album"));
+        assert_eq!(groups[0].artist.as_deref(), Some("Artist"));
+        assert_eq!(groups[1].album, None);
+        assert_eq!(groups[2].album.as_deref(), Some("Album"));
+        assert_eq!(groups[2].artist, None);
+    }
+
+    #[test]
+    fn test_title_helpers() {
+        assert_eq!(title_from_file_name("/music/01 - Some Title.mp3"), "Some Title");
+        assert_eq!(leading_number("3/12"), Some(3));
+        assert_eq!(most_common(vec![Some("a".to_string()), None, Some("b".to_string()), Some("b".to_string())].into_iter()), Some("b".to_string()));
+    }
+}
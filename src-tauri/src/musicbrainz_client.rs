@@ -0,0 +1,361 @@
+use crate::rate_limiter::RateLimiter;
+use crate::service_settings::{validate_service_url, ClientSettings, SharedSettings};
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::header::USER_AGENT;
+use reqwest::{Client, Url};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_BASE_URL: &str = "https://musicbrainz.org";
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    "tag-player/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/ReinhardtJ/tag-player )"
+);
+const DEFAULT_CACHE_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+// MusicBrainz allows one request per second and answers 503 to clients that send more
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const CACHE_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS responses (
+    url TEXT PRIMARY KEY,
+    time_millis INTEGER NOT NULL,
+    body TEXT NOT NULL
+);
+";
+
+/// Where requests go and how they identify themselves. MusicBrainz asks for a
+/// user agent with the name, version and contact of the application.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct MusicBrainzSettings {
+    /// The server, `https://musicbrainz.org` or a mirror
+    pub base_url: String,
+    pub user_agent: String,
+    /// How long responses are taken from the cache, 0 turns the cache off
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for MusicBrainzSettings {
+    fn default() -> Self {
+        MusicBrainzSettings {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            cache_ttl_secs: DEFAULT_CACHE_TTL_SECS,
+        }
+    }
+}
+
+impl ClientSettings for MusicBrainzSettings {
+    fn validate(&self) -> Result<()> {
+        validate_service_url("base URL", &self.base_url)?;
+        if self.user_agent.trim().is_empty() {
+            bail!("The user agent must not be empty");
+        }
+        Ok(())
+    }
+}
+
+/// The one client all MusicBrainz requests go through, so that they keep to the
+/// rate limit together. Responses are cached on disk.
+pub struct MusicBrainzClient {
+    client: Client,
+    settings: SharedSettings<MusicBrainzSettings>,
+    cache: Option<ResponseCache>,
+    rate_limiter: RateLimiter,
+    retry_delay: Duration,
+}
+
+impl MusicBrainzClient {
+    /// A client with a response cache at `cache_path`, or without a cache
+    pub fn new(settings: MusicBrainzSettings, cache_path: Option<&Path>) -> Result<MusicBrainzClient> {
+        let cache_ttl_secs = settings.cache_ttl_secs;
+        let settings = SharedSettings::new(settings)?;
+        let cache = match cache_path {
+            Some(cache_path) => Some(ResponseCache::open(cache_path, cache_ttl_secs)?),
+            None => None,
+        };
+        Ok(MusicBrainzClient {
+            client: Client::builder().build()?,
+            settings,
+            cache,
+            rate_limiter: RateLimiter::new(REQUEST_INTERVAL),
+            retry_delay: RETRY_DELAY,
+        })
+    }
+
+    pub fn settings(&self) -> MusicBrainzSettings {
+        self.settings.get()
+    }
+
+    pub fn set_settings(&self, settings: MusicBrainzSettings) -> Result<()> {
+        self.settings.set(settings)
+    }
+
+    /// Gets `/ws/2/<endpoint>` as JSON, e.g. `recording` with a `query` parameter
+    pub async fn get<T: DeserializeOwned>(&self, endpoint: &str, query: &[(&str, &str)]) -> Result<T> {
+        let settings = self.settings();
+        let url = Url::parse_with_params(
+            &format!("{}/ws/2/{}", settings.base_url.trim_end_matches('/'), endpoint),
+            query.iter().copied().chain([("fmt", "json")]),
+        )?;
+        let body = self.get_text(&url, &settings).await?;
+        serde_json::from_str(&body).with_context(|| format!("Unexpected response from {}", url))
+    }
+
+    async fn get_text(&self, url: &Url, settings: &MusicBrainzSettings) -> Result<String> {
+        let ttl = Duration::from_secs(settings.cache_ttl_secs);
+        let cache = self.cache.as_ref().filter(|_| !ttl.is_zero());
+        if let Some(cache) = cache {
+            match cache.get(url.as_str(), ttl) {
+                Ok(Some(body)) => return Ok(body),
+                Ok(None) => {}
+                Err(e) => eprintln!("Failed to read the MusicBrainz cache: {:?}", e),
+            }
+        }
+
+        let request = self.client.get(url.clone()).header(USER_AGENT, &settings.user_agent);
+        let response = self
+            .rate_limiter
+            .send(request, self.retry_delay)
+            .await
+            .with_context(|| format!("Request to {} failed", url))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!("MusicBrainz answered {} for {}", status, url));
+        }
+        let body = response.text().await?;
+
+        if let Some(cache) = cache {
+            if let Err(e) = cache.put(url.as_str(), &body) {
+                eprintln!("Failed to write the MusicBrainz cache: {:?}", e);
+            }
+        }
+        Ok(body)
+    }
+}
+
+/// Response bodies by URL in SQLite
+struct ResponseCache {
+    connection: Mutex<Connection>,
+}
+
+impl ResponseCache {
+    /// Opens the cache and drops the responses older than `ttl_secs`
+    fn open(path: &Path, ttl_secs: u64) -> Result<ResponseCache> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open MusicBrainz cache: {}", path.display()))?;
+        connection.execute_batch(CACHE_SCHEMA)?;
+        let oldest = now_millis() - Duration::from_secs(ttl_secs).as_millis() as i64;
+        connection.execute("DELETE FROM responses WHERE time_millis < ?1", params![oldest])?;
+        Ok(ResponseCache {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn get(&self, url: &str, ttl: Duration) -> Result<Option<String>> {
+        let oldest = now_millis() - ttl.as_millis() as i64;
+        let body = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT body FROM responses WHERE url = ?1 AND time_millis >= ?2",
+                params![url, oldest],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(body)
+    }
+
+    fn put(&self, url: &str, body: &str) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO responses (url, time_millis, body) VALUES (?1, ?2, ?3)",
+            params![url, now_millis(), body],
+        )?;
+        Ok(())
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limiter::MAX_RETRIES;
+    use crate::test_support::advance;
+    use mockito::{Matcher, Server};
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    fn settings(base_url: &str) -> MusicBrainzSettings {
+        MusicBrainzSettings {
+            base_url: base_url.to_string(),
+            user_agent: "tag-player-tests/1.0 ( test@example.com )".to_string(),
+            cache_ttl_secs: 60,
+        }
+    }
+
+    fn fast_client(settings: MusicBrainzSettings, cache_path: Option<&Path>) -> MusicBrainzClient {
+        let mut client = MusicBrainzClient::new(settings, cache_path).unwrap();
+        client.rate_limiter = RateLimiter::new(Duration::ZERO);
+        client.retry_delay = Duration::ZERO;
+        client
+    }
+
+    #[test]
+    fn test_validate_settings() {
+        assert!(MusicBrainzSettings::default().validate().is_ok());
+        assert!(settings("ftp://example.com").validate().is_err());
+        assert!(settings("not a url").validate().is_err());
+        let mut no_user_agent = settings("http://localhost:5000");
+        no_user_agent.user_agent = " ".to_string();
+        assert!(no_user_agent.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sends_user_agent_to_base_url() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("GET", "/ws/2/recording")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("query".to_string(), "title:\"A & B\"".to_string()),
+                Matcher::UrlEncoded("fmt".to_string(), "json".to_string()),
+            ]))
+            .match_header("user-agent", "tag-player-tests/1.0 ( test@example.com )")
+            .with_body(r#"{"count": 0}"#)
+            .create_async()
+            .await;
+
+        // a trailing slash in the base URL does not matter
+        let client = fast_client(settings(&format!("{}/", server.url())), None);
+        let response: Value = client.get("recording", &[("query", "title:\"A & B\"")]).await.unwrap();
+        assert_eq!(response["count"], 0);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_retries_on_503() {
+        let mut server = Server::new_async().await;
+        let busy = server
+            .mock("GET", "/ws/2/recording")
+            .match_query(Matcher::Any)
+            .with_status(503)
+            .with_header("retry-after", "0")
+            .expect(2)
+            .create_async()
+            .await;
+        let ok = server
+            .mock("GET", "/ws/2/recording")
+            .match_query(Matcher::Any)
+            .with_body(r#"{"count": 1}"#)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = fast_client(settings(&server.url()), None);
+        let response: Value = client.get("recording", &[("query", "x")]).await.unwrap();
+        assert_eq!(response["count"], 1);
+        busy.assert_async().await;
+        ok.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_retries() {
+        let mut server = Server::new_async().await;
+        let busy = server
+            .mock("GET", "/ws/2/recording")
+            .match_query(Matcher::Any)
+            .with_status(503)
+            .expect(MAX_RETRIES as usize + 1)
+            .create_async()
+            .await;
+        let not_found = server
+            .mock("GET", "/ws/2/release")
+            .match_query(Matcher::Any)
+            .with_status(404)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = fast_client(settings(&server.url()), None);
+        assert!(client.get::<Value>("recording", &[("query", "x")]).await.is_err());
+        // other errors are not retried
+        assert!(client.get::<Value>("release", &[("query", "x")]).await.is_err());
+        busy.assert_async().await;
+        not_found.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_caches_responses() {
+        let dir = tempdir().unwrap();
+        let cache_path = dir.path().join("musicbrainz_cache.sqlite3");
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("GET", "/ws/2/recording")
+            .match_query(Matcher::Any)
+            .with_body(r#"{"count": 2}"#)
+            .expect(3)
+            .create_async()
+            .await;
+
+        let client = fast_client(settings(&server.url()), Some(&cache_path));
+        for _ in 0..3 {
+            let response: Value = client.get("recording", &[("query", "x")]).await.unwrap();
+            assert_eq!(response["count"], 2);
+        }
+        // another query is another response
+        client.get::<Value>("recording", &[("query", "y")]).await.unwrap();
+
+        // the cache survives the client, a TTL of 0 turns it off
+        let client = fast_client(settings(&server.url()), Some(&cache_path));
+        client.get::<Value>("recording", &[("query", "x")]).await.unwrap();
+        let mut no_cache = settings(&server.url());
+        no_cache.cache_ttl_secs = 0;
+        client.set_settings(no_cache).unwrap();
+        client.get::<Value>("recording", &[("query", "x")]).await.unwrap();
+        mock.assert_async().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keeps_to_the_rate_limit() {
+        // one request per second, as MusicBrainz asks
+        let client = Arc::new(MusicBrainzClient::new(settings("http://localhost"), None).unwrap());
+        let turns = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let turns = turns.clone();
+            async move {
+                for _ in 0..3 {
+                    client.rate_limiter.wait_for_turn().await;
+                    turns.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        advance(Duration::ZERO).await;
+        assert_eq!(turns.load(Ordering::SeqCst), 1);
+        advance(Duration::from_millis(999)).await;
+        assert_eq!(turns.load(Ordering::SeqCst), 1);
+        advance(Duration::from_millis(1)).await;
+        assert_eq!(turns.load(Ordering::SeqCst), 2);
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(turns.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,72 @@
+//! Songs, recordings and releases for the tests of the MusicBrainz modules.
+//! Everything that is not an argument is empty; tests fill in what they compare
+//! with struct update syntax.
+
+use crate::musicbrainz::{Artist, ArtistCredit, Recording, Release};
+use crate::read_music_library::Song;
+use std::collections::HashMap;
+
+pub fn song(title: &str, artist: &str, duration_millis: u32) -> Song {
+    let mut tags = HashMap::new();
+    tags.insert("TrackTitle".to_string(), vec![title.to_string()]);
+    tags.insert("TrackArtist".to_string(), vec![artist.to_string()]);
+    Song {
+        path: "/music/song.mp3".to_string(),
+        name: "song.mp3".to_string(),
+        duration_millis,
+        tags,
+    }
+}
+
+/// One artist, credited by their name
+pub fn artist_credit(name: &str) -> Vec<ArtistCredit> {
+    vec![ArtistCredit {
+        name: name.to_string(),
+        artist: Artist {
+            id: "artist-id".to_string(),
+            name: name.to_string(),
+            sort_name: None,
+            extra: HashMap::new(),
+        },
+        joinphrase: None,
+        extra: HashMap::new(),
+    }]
+}
+
+pub fn recording(id: &str, title: &str) -> Recording {
+    Recording {
+        id: id.to_string(),
+        title: title.to_string(),
+        artist_credit: None,
+        releases: None,
+        isrcs: None,
+        tags: None,
+        genres: None,
+        disambiguation: None,
+        first_release_date: None,
+        length: None,
+        extra: HashMap::new(),
+    }
+}
+
+/// A release titled "Album"
+pub fn release(id: &str) -> Release {
+    Release {
+        id: id.to_string(),
+        title: "Album".to_string(),
+        date: None,
+        country: None,
+        media: None,
+        artist_credit: None,
+        release_group: None,
+        events: None,
+        labels: None,
+        asin: None,
+        barcode: None,
+        status: None,
+        release_type: None,
+        script: None,
+        disambiguation: None,
+        extra: HashMap::new(),
+    }
+}
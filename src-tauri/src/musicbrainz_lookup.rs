@@ -0,0 +1,330 @@
+use crate::musicbrainz::{
+    is_isrc, is_mbid, lookup_isrc, lookup_recording, lookup_release, search_releases_by_barcode, Release,
+};
+use crate::musicbrainz_album::{leading_number, release_tracks, track_tags, ReleaseTrack};
+use crate::musicbrainz_client::MusicBrainzClient;
+use crate::musicbrainz_matching::{rank_candidates, read_song};
+use crate::musicbrainz_scoring::{normalize_title, ScoringSettings};
+use crate::read_music_library::Song;
+use crate::tags::multi_value::{first_value, TagMap};
+use serde::Serialize;
+use std::path::Path;
+
+/// The identifier of a file that MusicBrainz found it by
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LookupMethod {
+    ReleaseId,
+    RecordingId,
+    Isrc,
+    Barcode,
+}
+
+/// The tags MusicBrainz has now for a file that was identified before
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FileLookup {
+    pub path: String,
+    /// None if the file has no identifier MusicBrainz knows, the tags are empty then
+    pub method: Option<LookupMethod>,
+    pub tags: TagMap,
+    pub error: Option<String>,
+}
+
+/// Looks every file up by the MusicBrainz IDs, ISRC or barcode in its tags
+pub async fn lookup_files(client: &MusicBrainzClient, paths: &[String], settings: &ScoringSettings) -> Vec<FileLookup> {
+    let mut lookups = Vec::new();
+    for path in paths {
+        let (method, tags, error) = match lookup_file(client, Path::new(path), settings).await {
+            Ok(Some((method, tags))) => (Some(method), tags, None),
+            Ok(None) => (None, TagMap::new(), None),
+            Err(e) => (None, TagMap::new(), Some(e)),
+        };
+        lookups.push(FileLookup {
+            path: path.clone(),
+            method,
+            tags,
+            error,
+        });
+    }
+    lookups
+}
+
+/// Tries the identifiers of the file from the most to the least exact one. A
+/// method that fails counts as not finding the file, the next one is tried.
+/// The errors are returned only if no method found it.
+async fn lookup_file(
+    client: &MusicBrainzClient,
+    path: &Path,
+    settings: &ScoringSettings,
+) -> Result<Option<(LookupMethod, TagMap)>, String> {
+    let song = read_song(path)?;
+    let mut errors = Vec::new();
+    for method in [LookupMethod::ReleaseId, LookupMethod::RecordingId, LookupMethod::Isrc, LookupMethod::Barcode] {
+        match lookup_by(client, &song, method, settings).await {
+            Ok(Some(tags)) => return Ok(Some((method, tags))),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Failed to look up {} by {:?}: {}", path.display(), method, e);
+                errors.push(e);
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(None)
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+/// The tags of the file by one of its identifiers, None if it has no such
+/// identifier or MusicBrainz knows no track for it
+async fn lookup_by(
+    client: &MusicBrainzClient,
+    song: &Song,
+    method: LookupMethod,
+    settings: &ScoringSettings,
+) -> Result<Option<TagMap>, String> {
+    match method {
+        LookupMethod::ReleaseId => {
+            // a malformed ID is never sent; the next method is tried instead
+            let Some(release_id) = first_value(&song.tags, "MusicBrainzReleaseId").filter(|id| is_mbid(id)) else {
+                return Ok(None);
+            };
+            let release = lookup_release(client, release_id).await?;
+            Ok(tags_on_release(song, &release))
+        }
+        LookupMethod::RecordingId => {
+            let Some(recording_id) = first_value(&song.tags, "MusicBrainzRecordingId").filter(|id| is_mbid(id)) else {
+                return Ok(None);
+            };
+            let recording = lookup_recording(client, recording_id).await?;
+            let candidate = rank_candidates(song, &[recording], settings).into_iter().next();
+            Ok(candidate.map(|candidate| candidate.tags))
+        }
+        LookupMethod::Isrc => {
+            let Some(isrc) = first_value(&song.tags, "Isrc").filter(|isrc| is_isrc(isrc)) else {
+                return Ok(None);
+            };
+            let recordings = lookup_isrc(client, isrc).await?;
+            let candidate = rank_candidates(song, &recordings, settings).into_iter().next();
+            Ok(candidate.map(|candidate| candidate.tags))
+        }
+        LookupMethod::Barcode => {
+            let Some(barcode) = first_value(&song.tags, "Barcode") else {
+                return Ok(None);
+            };
+            for found in search_releases_by_barcode(client, barcode).await? {
+                // search results have no tracklist; one that fails to load leaves the others
+                match lookup_release(client, &found.id).await {
+                    Ok(release) => {
+                        if let Some(tags) = tags_on_release(song, &release) {
+                            return Ok(Some(tags));
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to look up release {}: {}", found.id, e),
+                }
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// The tags of the file's track on the release, found by track ID, recording ID,
+/// disc and track number or else by title
+fn tags_on_release(song: &Song, release: &Release) -> Option<TagMap> {
+    let tracks = release_tracks(release);
+    let track_id = first_value(&song.tags, "MusicBrainzTrackId");
+    let recording_id = first_value(&song.tags, "MusicBrainzRecordingId");
+    let disc_number = first_value(&song.tags, "DiscNumber").and_then(leading_number).unwrap_or(1);
+    let track_number = first_value(&song.tags, "TrackNumber").and_then(leading_number);
+    let title = first_value(&song.tags, "TrackTitle").map(normalize_title);
+
+    let release_track = tracks
+        .iter()
+        .find(|release_track| track_id.is_some() && release_track.track.id.as_deref() == track_id)
+        .or_else(|| {
+            tracks.iter().find(|release_track| {
+                let track_recording_id = release_track.track.recording.as_ref().map(|recording| recording.id.as_str());
+                recording_id.is_some() && track_recording_id == recording_id
+            })
+        })
+        .or_else(|| {
+            tracks.iter().find(|release_track| {
+                track_number.is_some()
+                    && release_track.medium_position == disc_number
+                    && track_position(release_track) == track_number
+            })
+        })
+        .or_else(|| {
+            tracks.iter().find(|release_track| {
+                title.is_some() && release_track.track.title.as_deref().map(normalize_title) == title
+            })
+        })?;
+    Some(track_tags(release, release_track))
+}
+
+fn track_position(release_track: &ReleaseTrack) -> Option<u32> {
+    let track = release_track.track;
+    track.position.or_else(|| track.number.as_deref().and_then(leading_number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::musicbrainz_client::MusicBrainzSettings;
+    use crate::test_support::{tag_map, tagged_song};
+    use mockito::{Matcher, Server, ServerGuard};
+    use tempfile::tempdir;
+
+    const RELEASE_ID: &str = "b52a8f31-b5ab-34e9-92f4-f5b7110220f0";
+    const BROKEN_RELEASE_ID: &str = "9a6b2c1e-6f3a-4d2b-9e61-2f3c0b7d8e5a";
+
+    const RELEASE: &str = r#"{
+        "id": "b52a8f31-b5ab-34e9-92f4-f5b7110220f0",
+        "title": "Nevermind",
+        "status": "Official",
+        "date": "1991-09-24",
+        "country": "US",
+        "barcode": "720642442524",
+        "text-representation": {"language": "eng", "script": "Latn"},
+        "release-events": [{"date": "1991-09-24", "area": {"name": "United States", "iso-3166-1-codes": ["US"]}}],
+        "artist-credit": [{"name": "Nirvana", "artist": {"id": "5b11f4ce", "name": "Nirvana", "sort-name": "Nirvana"}}],
+        "release-group": {"id": "1b022e01", "first-release-date": "1991-09-24", "primary-type": "Album"},
+        "label-info": [{"catalog-number": "DGCD-24425", "label": {"id": "f1e8ad5f", "name": "DGC", "label-code": 7266}}],
+        "media": [{
+            "position": 1,
+            "format": "CD",
+            "track-count": 2,
+            "tracks": [
+                {"id": "t1", "number": "1", "position": 1, "title": "Smells Like Teen Spirit", "length": 301920,
+                 "recording": {"id": "r1", "title": "Smells Like Teen Spirit"}},
+                {"id": "t2", "number": "2", "position": 2, "title": "In Bloom", "length": 254800,
+                 "recording": {"id": "r2", "title": "In Bloom"}}
+            ]
+        }]
+    }"#;
+
+    fn client(server: &ServerGuard) -> MusicBrainzClient {
+        let settings = MusicBrainzSettings {
+            base_url: server.url(),
+            ..Default::default()
+        };
+        MusicBrainzClient::new(settings, None).unwrap()
+    }
+
+    #[test]
+    fn test_tags_on_release() {
+        let release: Release = serde_json::from_str(RELEASE).unwrap();
+        let song = |tags: &[(&str, &[&str])]| Song {
+            path: String::new(),
+            name: String::new(),
+            duration_millis: 0,
+            tags: tag_map(tags),
+        };
+
+        let tags = tags_on_release(&song(&[("MusicBrainzTrackId", &["t2"])]), &release).unwrap();
+        assert_eq!(tags["TrackTitle"], vec!["In Bloom"]);
+        let tags = tags_on_release(&song(&[("MusicBrainzRecordingId", &["r1"])]), &release).unwrap();
+        assert_eq!(tags["TrackTitle"], vec!["Smells Like Teen Spirit"]);
+        let tags = tags_on_release(&song(&[("TrackNumber", &["2/12"])]), &release).unwrap();
+        assert_eq!(tags["MusicBrainzTrackId"], vec!["t2"]);
+        let tags = tags_on_release(&song(&[("TrackTitle", &["in bloom"])]), &release).unwrap();
+        assert_eq!(tags["TrackNumber"], vec!["2"]);
+        assert!(tags_on_release(&song(&[("TrackNumber", &["2"]), ("DiscNumber", &["2"])]), &release).is_none());
+
+        // the renamed fields of a release lookup
+        assert_eq!(tags["Label"], vec!["DGC"]);
+        assert_eq!(tags["CatalogNumber"], vec!["DGCD-24425"]);
+        assert_eq!(tags["MusicBrainzReleaseGroupId"], vec!["1b022e01"]);
+        assert_eq!(tags["OriginalReleaseDate"], vec!["1991-09-24"]);
+        assert_eq!(tags["Barcode"], vec!["720642442524"]);
+        assert_eq!(tags["ReleaseCountry"], vec!["US"]);
+        assert_eq!(tags["Script"], vec!["Latn"]);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_files() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", format!("/ws/2/release/{}", RELEASE_ID).as_str())
+            .match_query(Matcher::Any)
+            .with_body(RELEASE)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/ws/2/release")
+            .match_query(Matcher::UrlEncoded(
+                "query".into(),
+                "barcode:\"720642442524\" OR barcode:\"0720642442524\"".into(),
+            ))
+            .with_body(format!(r#"{{"releases": [{}]}}"#, RELEASE))
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/ws/2/isrc/USGF19942501")
+            .match_query(Matcher::Any)
+            .with_body(
+                r#"{"isrc": "USGF19942501", "recordings": [{
+                    "id": "r1",
+                    "title": "Smells Like Teen Spirit",
+                    "releases": [{"id": "b52a8f31-b5ab-34e9-92f4-f5b7110220f0", "title": "Nevermind"}]
+                }]}"#,
+            )
+            .create_async()
+            .await;
+        server
+            .mock("GET", format!("/ws/2/release/{}", BROKEN_RELEASE_ID).as_str())
+            .match_query(Matcher::Any)
+            .with_status(500)
+            .create_async()
+            .await;
+
+        let dir = tempdir().unwrap();
+        let song = |name: &str, tags: &[(&str, &[&str])]| tagged_song(&dir.path().join(name), &tag_map(tags));
+        let by_release = song("release.mp3", &[("MusicBrainzReleaseId", &[RELEASE_ID]), ("TrackNumber", &["2"])]);
+        let by_isrc = song("isrc.mp3", &[("Isrc", &["USGF19942501"])]);
+        // the EAN-13 form of the release's UPC-A
+        let by_barcode = song(
+            "barcode.mp3",
+            &[("Barcode", &["0720642442524"]), ("TrackTitle", &["Smells Like Teen Spirit"])],
+        );
+        let unknown = song("unknown.mp3", &[("TrackTitle", &["Untitled"])]);
+        // a failing method counts as not found, the next one is tried
+        let fallback = song(
+            "fallback.mp3",
+            &[("MusicBrainzReleaseId", &[BROKEN_RELEASE_ID]), ("Isrc", &["USGF19942501"])],
+        );
+        let broken = song("broken.mp3", &[("MusicBrainzReleaseId", &[BROKEN_RELEASE_ID])]);
+        // malformed values are skipped without a request
+        let invalid = song(
+            "invalid.mp3",
+            &[("MusicBrainzReleaseId", &["../artist/x"]), ("Isrc", &["USGF1994250/"])],
+        );
+        let paths: Vec<String> = [by_release, by_isrc, by_barcode, unknown, fallback, broken, invalid]
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+
+        let lookups = lookup_files(&client(&server), &paths, &ScoringSettings::default()).await;
+        let methods: Vec<Option<LookupMethod>> = lookups.iter().map(|lookup| lookup.method).collect();
+        assert_eq!(
+            methods,
+            vec![
+                Some(LookupMethod::ReleaseId),
+                Some(LookupMethod::Isrc),
+                Some(LookupMethod::Barcode),
+                None,
+                Some(LookupMethod::Isrc),
+                None,
+                None,
+            ]
+        );
+        assert_eq!(lookups[0].tags["MusicBrainzTrackId"], vec!["t2"]);
+        assert_eq!(lookups[1].tags["MusicBrainzRecordingId"], vec!["r1"]);
+        assert_eq!(lookups[2].tags["MusicBrainzTrackId"], vec!["t1"]);
+        assert!(lookups[..5].iter().all(|lookup| lookup.error.is_none()));
+        assert!(lookups[3].tags.is_empty());
+        assert!(lookups[5].error.as_deref().unwrap().contains("500"));
+        assert!(lookups[6].error.is_none());
+    }
+}
@@ -0,0 +1,368 @@
+use crate::acoustid::AcoustIdClient;
+use crate::fingerprint::fingerprint_file;
+use crate::musicbrainz::{lookup_recording, search_song_on_musicbrainz, Recording, Value};
+use crate::musicbrainz_client::MusicBrainzClient;
+use crate::musicbrainz_scoring::{score_recording, ScoreBreakdown, ScoringSettings};
+use crate::musicbrainz_tag_mapping::recording_to_tags;
+use crate::read_music_library::Song;
+use crate::tags::multi_value::{first_value, TagMap};
+use crate::tags::reading_tags::{read_audio_file_properties, read_primary_tags};
+use crate::tags::writing_tags::write_tags_to_file;
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+
+// AcoustID often finds the same audio on many recordings, the best few are enough
+const MAX_FINGERPRINT_RECORDINGS: usize = 5;
+
+/// A recording that could be the song, with the tags applying it would write
+#[derive(Serialize, Clone, Debug)]
+pub struct Candidate {
+    pub recording_id: String,
+    /// How well the recording fits the song, from 0 to 1
+    pub score: f64,
+    pub breakdown: ScoreBreakdown,
+    /// The score of the MusicBrainz search, from 0 to 100
+    pub search_score: u32,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub date: Option<String>,
+    pub tags: TagMap,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldChange {
+    Added,
+    Changed,
+    Unchanged,
+}
+
+/// One tag of a candidate next to the value the file has now
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FieldDiff {
+    pub key: String,
+    pub current: Vec<String>,
+    pub proposed: Vec<String>,
+    pub change: FieldChange,
+}
+
+/// Searches MusicBrainz for the song at `path`, best candidates first. Songs
+/// without a title are identified by their fingerprint instead.
+pub async fn search_candidates(
+    client: &MusicBrainzClient,
+    acoustid: &AcoustIdClient,
+    path: &Path,
+    settings: &ScoringSettings,
+) -> Result<Vec<Candidate>, String> {
+    let song = read_song(path)?;
+    let recordings = if first_value(&song.tags, "TrackTitle").is_some() {
+        search_song_on_musicbrainz(client, &song).await?
+    } else {
+        identify_recordings(client, acoustid, path).await?
+    };
+    Ok(rank_candidates(&song, &recordings, settings))
+}
+
+/// Identifies the song at `path` by its fingerprint, whatever its tags say
+pub async fn identify_candidates(
+    client: &MusicBrainzClient,
+    acoustid: &AcoustIdClient,
+    path: &Path,
+    settings: &ScoringSettings,
+) -> Result<Vec<Candidate>, String> {
+    let song = read_song(path)?;
+    let recordings = identify_recordings(client, acoustid, path).await?;
+    Ok(rank_candidates(&song, &recordings, settings))
+}
+
+pub(crate) fn read_song(path: &Path) -> Result<Song, String> {
+    let properties = read_audio_file_properties(path).map_err(|e| e.to_string())?;
+    Ok(Song {
+        path: path.to_string_lossy().to_string(),
+        name: path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+        duration_millis: properties.duration_millis,
+        tags: properties.tags,
+    })
+}
+
+/// Looks the fingerprint up on AcoustID and the recordings it names on
+/// MusicBrainz, with the AcoustID score as their search score
+async fn identify_recordings(
+    client: &MusicBrainzClient,
+    acoustid: &AcoustIdClient,
+    path: &Path,
+) -> Result<Vec<Recording>, String> {
+    let fingerprint_path = path.to_path_buf();
+    let fingerprint = tauri::async_runtime::spawn_blocking(move || fingerprint_file(&fingerprint_path))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    let matches = acoustid.lookup(&fingerprint).await.map_err(|e| e.to_string())?;
+
+    let mut recordings = Vec::new();
+    for acoustid_match in matches.iter().take(MAX_FINGERPRINT_RECORDINGS) {
+        // AcoustID can name recordings that were since deleted from MusicBrainz
+        match lookup_recording(client, &acoustid_match.recording_id).await {
+            Ok(mut recording) => {
+                let score = (acoustid_match.score * 100.0).round() as u64;
+                recording.extra.insert("score".to_string(), Value::from(score));
+                recordings.push(recording);
+            }
+            Err(e) => eprintln!("Failed to look up recording {}: {}", acoustid_match.recording_id, e),
+        }
+    }
+    Ok(recordings)
+}
+
+pub(crate) fn rank_candidates(song: &Song, recordings: &[Recording], settings: &ScoringSettings) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = recordings
+        .iter()
+        .map(|recording| to_candidate(song, recording, settings))
+        .collect();
+    // equal scores are ordered by the search score, then as MusicBrainz returned them
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score).then(b.search_score.cmp(&a.search_score)));
+    candidates
+}
+
+/// The candidate with the tags of the release of the recording that scored best
+fn to_candidate(song: &Song, recording: &Recording, settings: &ScoringSettings) -> Candidate {
+    let scored = score_recording(song, recording, settings);
+    let mut recording = recording.clone();
+    if let (Some(index), Some(releases)) = (scored.release_index, recording.releases.as_mut()) {
+        // recording_to_tags takes the first release
+        let release = releases.remove(index);
+        releases.insert(0, release);
+    }
+    let tags = recording_to_tags(&recording);
+    let search_score = recording
+        .extra
+        .get("score")
+        .and_then(|score| score.as_u64())
+        .unwrap_or(0) as u32;
+    Candidate {
+        recording_id: recording.id.clone(),
+        score: scored.breakdown.score,
+        breakdown: scored.breakdown,
+        search_score,
+        title: recording.title.clone(),
+        artist: first_value(&tags, "TrackArtist").map(|artist| artist.to_string()),
+        album: first_value(&tags, "AlbumTitle").map(|album| album.to_string()),
+        date: first_value(&tags, "ReleaseDate").map(|date| date.to_string()),
+        tags,
+    }
+}
+
+/// Compares every proposed tag with the current one, sorted by key. Keys the
+/// proposal lacks are kept when it is applied, so they are not part of the diff.
+pub fn diff_tags(current: &TagMap, proposed: &TagMap) -> Vec<FieldDiff> {
+    let mut diffs: Vec<FieldDiff> = proposed
+        .iter()
+        .map(|(key, proposed_values)| {
+            let current_values = current.get(key).cloned().unwrap_or_default();
+            let change = if current_values.is_empty() {
+                FieldChange::Added
+            } else if current_values == *proposed_values {
+                FieldChange::Unchanged
+            } else {
+                FieldChange::Changed
+            };
+            FieldDiff {
+                key: key.clone(),
+                current: current_values,
+                proposed: proposed_values.clone(),
+                change,
+            }
+        })
+        .collect();
+    diffs.sort_by(|a, b| a.key.cmp(&b.key));
+    diffs
+}
+
+/// The current tags with the proposed values of the accepted keys
+pub fn merge_accepted(current: &TagMap, proposed: &TagMap, accepted: &[String]) -> TagMap {
+    let mut tags = current.clone();
+    for key in accepted {
+        if let Some(values) = proposed.get(key) {
+            tags.insert(key.clone(), values.clone());
+        }
+    }
+    tags
+}
+
+/// The diff of the file at `path` against the proposed tags
+pub fn preview_tags(path: &Path, proposed: &TagMap) -> Result<Vec<FieldDiff>> {
+    Ok(diff_tags(&read_primary_tags(path)?, proposed))
+}
+
+/// Writes the accepted fields of the proposed tags and returns what changed.
+/// Rejected fields keep their current value.
+pub fn apply_tags(path: &Path, proposed: &TagMap, accepted: &[String]) -> Result<Vec<FieldDiff>> {
+    let current = read_primary_tags(path)?;
+    let changes: Vec<FieldDiff> = diff_tags(&current, proposed)
+        .into_iter()
+        .filter(|diff| diff.change != FieldChange::Unchanged && accepted.contains(&diff.key))
+        .collect();
+    if !changes.is_empty() {
+        write_tags_to_file(path, &merge_accepted(&current, proposed, accepted))?;
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acoustid::AcoustIdSettings;
+    use crate::musicbrainz::{Release, Value};
+    use crate::musicbrainz_client::MusicBrainzSettings;
+    use crate::musicbrainz_fixtures::{self as fixtures, song};
+    use crate::test_support::{tag_map, tagged_song, write_tone_wav};
+    use mockito::{Matcher, Server};
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    fn recording(id: &str, title: &str, score: u64, releases: Option<Vec<Release>>) -> Recording {
+        Recording {
+            releases,
+            extra: HashMap::from([("score".to_string(), Value::from(score))]),
+            ..fixtures::recording(id, title)
+        }
+    }
+
+    fn release(id: &str, status: &str) -> Release {
+        Release {
+            status: Some(status.to_string()),
+            ..fixtures::release(id)
+        }
+    }
+
+    #[test]
+    fn test_rank_candidates_by_score() {
+        let song = song("Song", "", 0);
+        let recordings = vec![
+            recording("a", "Other", 100, None),
+            recording("b", "Song", 80, None),
+            recording("c", "Song", 90, Some(vec![release("bootleg", "Bootleg"), release("official", "Official")])),
+        ];
+        let candidates = rank_candidates(&song, &recordings, &ScoringSettings::default());
+        let ids: Vec<&str> = candidates.iter().map(|candidate| candidate.recording_id.as_str()).collect();
+        assert_eq!(ids, vec!["c", "b", "a"]);
+        assert_eq!(candidates[0].search_score, 90);
+        assert_eq!(candidates[0].breakdown.parts.len(), 7);
+        // the tags are those of the best release
+        assert_eq!(candidates[0].tags["MusicBrainzReleaseId"], vec!["official"]);
+    }
+
+    #[test]
+    fn test_diff_tags() {
+        let current = tag_map(&[("TrackTitle", &["Song"]), ("TrackArtist", &["Someone"]), ("Comment", &["mine"])]);
+        let proposed = tag_map(&[("TrackTitle", &["Song"]), ("TrackArtist", &["Someone Else"]), ("Isrc", &["X"])]);
+        let diffs = diff_tags(&current, &proposed);
+        let changes: Vec<(&str, FieldChange)> = diffs.iter().map(|diff| (diff.key.as_str(), diff.change)).collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("Isrc", FieldChange::Added),
+                ("TrackArtist", FieldChange::Changed),
+                ("TrackTitle", FieldChange::Unchanged),
+            ]
+        );
+        assert_eq!(diffs[1].current, vec!["Someone"]);
+    }
+
+    #[test]
+    fn test_merge_accepted_keeps_rejected_and_other_fields() {
+        let current = tag_map(&[("TrackArtist", &["Someone"]), ("Comment", &["mine"])]);
+        let proposed = tag_map(&[("TrackArtist", &["Someone Else"]), ("Isrc", &["X"])]);
+        let merged = merge_accepted(&current, &proposed, &["Isrc".to_string()]);
+        assert_eq!(
+            merged,
+            tag_map(&[("TrackArtist", &["Someone"]), ("Comment", &["mine"]), ("Isrc", &["X"])])
+        );
+    }
+
+    #[test]
+    fn test_apply_tags_writes_accepted_fields_only() {
+        let dir = tempdir().unwrap();
+        let path = tagged_song(
+            &dir.path().join("some_song.mp3"),
+            &tag_map(&[("TrackTitle", &["Old Title"]), ("Comment", &["mine"])]),
+        );
+
+        let proposed = tag_map(&[("TrackTitle", &["New Title"]), ("AlbumTitle", &["Album"]), ("Isrc", &["X"])]);
+        let preview = preview_tags(&path, &proposed).unwrap();
+        assert_eq!(preview.len(), 3);
+
+        let accepted = vec!["TrackTitle".to_string(), "Isrc".to_string()];
+        let changes = apply_tags(&path, &proposed, &accepted).unwrap();
+        let keys: Vec<&str> = changes.iter().map(|diff| diff.key.as_str()).collect();
+        assert_eq!(keys, vec!["Isrc", "TrackTitle"]);
+
+        let tags = read_primary_tags(&path).unwrap();
+        assert_eq!(tags["TrackTitle"], vec!["New Title"]);
+        assert_eq!(tags["Isrc"], vec!["X"]);
+        assert_eq!(tags["Comment"], vec!["mine"]);
+        assert!(!tags.contains_key("AlbumTitle"));
+
+        // nothing left to change
+        assert!(apply_tags(&path, &proposed, &accepted).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_search_candidates_identifies_songs_without_title() {
+        const FOUND: &str = "5fb524f1-8cc8-4c04-a921-e34c0a911ea7";
+        const DELETED: &str = "3b8a2e0c-1d4f-4a6b-8c9e-7f2d1a0b5c6e";
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("untitled.wav");
+        write_tone_wav(&path, 440.0, 0.5, 12);
+
+        let mut acoustid_server = Server::new_async().await;
+        let acoustid_mock = acoustid_server
+            .mock("POST", "/v2/lookup")
+            .match_body(Matcher::UrlEncoded("duration".into(), "12".into()))
+            .with_body(
+                format!(
+                    r#"{{"status": "ok", "results": [
+                        {{"id": "a", "score": 0.93, "recordings": [{{"id": "{}"}}, {{"id": "{}"}}]}}
+                    ]}}"#,
+                    FOUND, DELETED
+                ),
+            )
+            .create_async()
+            .await;
+        let mut musicbrainz_server = Server::new_async().await;
+        musicbrainz_server
+            .mock("GET", format!("/ws/2/recording/{}", FOUND).as_str())
+            .match_query(Matcher::Any)
+            .with_body(format!(r#"{{"id": "{}", "title": "Found Song", "releases": []}}"#, FOUND))
+            .create_async()
+            .await;
+        musicbrainz_server
+            .mock("GET", format!("/ws/2/recording/{}", DELETED).as_str())
+            .match_query(Matcher::Any)
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let acoustid = AcoustIdClient::new(AcoustIdSettings {
+            endpoint: format!("{}/v2/lookup", acoustid_server.url()),
+            api_key: "key".to_string(),
+        })
+        .unwrap();
+        let settings = MusicBrainzSettings {
+            base_url: musicbrainz_server.url(),
+            ..Default::default()
+        };
+        let client = MusicBrainzClient::new(settings, None).unwrap();
+
+        let candidates = search_candidates(&client, &acoustid, &path, &ScoringSettings::default())
+            .await
+            .unwrap();
+        acoustid_mock.assert_async().await;
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].recording_id, FOUND);
+        assert_eq!(candidates[0].search_score, 93);
+        assert_eq!(candidates[0].tags["TrackTitle"], vec!["Found Song"]);
+    }
+}
@@ -0,0 +1,588 @@
+use crate::musicbrainz::{ArtistCredit, Recording, Release};
+use crate::read_music_library::Song;
+use crate::tags::multi_value::first_value;
+use serde::{Deserialize, Serialize};
+
+// durations closer than this are the same, further apart than the maximum nothing alike
+const DURATION_TOLERANCE_MILLIS: f64 = 2000.0;
+const MAX_DURATION_DIFFERENCE_MILLIS: f64 = 15000.0;
+// releases this many years apart from the wanted year score 0
+const MAX_YEAR_DIFFERENCE: f64 = 10.0;
+// what a factor scores when there is nothing to compare
+const UNKNOWN: f64 = 0.5;
+
+/// How much every factor counts. Only the ratios matter, a weight of 0 turns a factor off.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ScoreWeights {
+    pub title: f64,
+    pub artist: f64,
+    pub duration: f64,
+    pub release_status: f64,
+    pub release_type: f64,
+    pub release_country: f64,
+    pub release_date: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        ScoreWeights {
+            title: 0.3,
+            artist: 0.2,
+            duration: 0.2,
+            release_status: 0.1,
+            release_type: 0.08,
+            release_country: 0.05,
+            release_date: 0.07,
+        }
+    }
+}
+
+/// How much every factor counts when the files of an album are matched against
+/// the tracklist of a release
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AlbumScoreWeights {
+    pub track_count: f64,
+    pub track_durations: f64,
+    pub track_titles: f64,
+    pub disc_layout: f64,
+}
+
+impl Default for AlbumScoreWeights {
+    fn default() -> Self {
+        AlbumScoreWeights {
+            track_count: 0.25,
+            track_durations: 0.3,
+            track_titles: 0.3,
+            disc_layout: 0.15,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct ScoringSettings {
+    pub weights: ScoreWeights,
+    pub album_weights: AlbumScoreWeights,
+    /// Release countries like "XW" or "US", the first one preferred most
+    pub preferred_countries: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreFactor {
+    Title,
+    Artist,
+    Duration,
+    ReleaseStatus,
+    ReleaseType,
+    ReleaseCountry,
+    ReleaseDate,
+    TrackCount,
+    TrackDurations,
+    TrackTitles,
+    DiscLayout,
+}
+
+/// What one factor added to the score and why
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ScorePart {
+    pub factor: ScoreFactor,
+    pub weight: f64,
+    /// How well the candidate does on this factor, from 0 to 1
+    pub similarity: f64,
+    pub explanation: String,
+}
+
+/// The score of a candidate from 0 to 1, the weighted mean of its parts
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ScoreBreakdown {
+    pub score: f64,
+    pub parts: Vec<ScorePart>,
+}
+
+/// A recording scored against a song, on the release of the recording that fits best
+pub struct ScoredRecording {
+    pub release_index: Option<usize>,
+    pub breakdown: ScoreBreakdown,
+}
+
+/// Scores a recording on each of its releases and keeps the best release
+pub fn score_recording(song: &Song, recording: &Recording, settings: &ScoringSettings) -> ScoredRecording {
+    let weights = &settings.weights;
+    let mut recording_parts = Vec::new();
+
+    let title = first_value(&song.tags, "TrackTitle").unwrap_or_default();
+    let similarity = string_similarity(&normalize_title(title), &normalize_title(&recording.title));
+    recording_parts.push(part(
+        ScoreFactor::Title,
+        weights.title,
+        similarity,
+        format!("\"{}\" vs \"{}\"", title, recording.title),
+    ));
+
+    let artist = first_value(&song.tags, "TrackArtist").unwrap_or_default();
+    let credited = recording.artist_credit.as_deref().map(credit_name).unwrap_or_default();
+    let similarity = string_similarity(&normalize_title(artist), &normalize_title(&credited));
+    recording_parts.push(part(
+        ScoreFactor::Artist,
+        weights.artist,
+        similarity,
+        format!("\"{}\" vs \"{}\"", artist, credited),
+    ));
+
+    let (similarity, explanation) = match recording.length.filter(|_| song.duration_millis > 0) {
+        Some(length) => {
+            let difference = (length as f64 - song.duration_millis as f64).abs();
+            (
+                duration_similarity(difference),
+                format!("{:.1} s longer or shorter", difference / 1000.0),
+            )
+        }
+        None => (UNKNOWN, "unknown length".to_string()),
+    };
+    recording_parts.push(part(ScoreFactor::Duration, weights.duration, similarity, explanation));
+
+    let releases = recording.releases.as_deref().unwrap_or_default();
+    let wanted_year = song_year(song).or_else(|| year(recording.first_release_date.as_deref()));
+    let best = releases
+        .iter()
+        .enumerate()
+        .map(|(index, release)| {
+            let mut parts = recording_parts.clone();
+            parts.extend(release_parts(release, wanted_year, settings));
+            (Some(index), breakdown(parts))
+        })
+        // the first of equally good releases
+        .rev()
+        .max_by(|(_, a), (_, b)| a.score.total_cmp(&b.score));
+
+    let (release_index, breakdown) = best.unwrap_or_else(|| {
+        let mut parts = recording_parts;
+        parts.extend(release_parts_unknown(weights));
+        (None, breakdown(parts))
+    });
+    ScoredRecording {
+        release_index,
+        breakdown,
+    }
+}
+
+/// How the files of an album pair up with the tracks of a release. The sums are
+/// over the paired files, each from 0 to 1.
+pub struct TracklistFit {
+    pub file_count: usize,
+    pub track_count: usize,
+    pub paired_count: usize,
+    pub duration_sum: f64,
+    pub title_sum: f64,
+    pub disc_sum: f64,
+}
+
+/// Scores a tracklist by track count, durations, titles and disc layout. Files
+/// that are not paired with a track count as not alike at all.
+pub fn score_tracklist(fit: &TracklistFit, settings: &ScoringSettings) -> ScoreBreakdown {
+    let weights = &settings.album_weights;
+    let file_count = fit.file_count.max(1) as f64;
+    let track_count_similarity =
+        fit.file_count.min(fit.track_count) as f64 / fit.file_count.max(fit.track_count).max(1) as f64;
+    let paired = format!("{} of {} files paired", fit.paired_count, fit.file_count);
+    breakdown(vec![
+        part(
+            ScoreFactor::TrackCount,
+            weights.track_count,
+            track_count_similarity,
+            format!("{} files, {} tracks", fit.file_count, fit.track_count),
+        ),
+        part(ScoreFactor::TrackDurations, weights.track_durations, fit.duration_sum / file_count, paired.clone()),
+        part(ScoreFactor::TrackTitles, weights.track_titles, fit.title_sum / file_count, paired),
+        part(
+            ScoreFactor::DiscLayout,
+            weights.disc_layout,
+            fit.disc_sum / file_count,
+            format!("{} of {} files on their disc", fit.disc_sum, fit.file_count),
+        ),
+    ])
+}
+
+fn release_parts(release: &Release, wanted_year: Option<i32>, settings: &ScoringSettings) -> Vec<ScorePart> {
+    let weights = &settings.weights;
+    let status = release.status.as_deref().unwrap_or_default();
+    let status_similarity = match status.to_lowercase().as_str() {
+        "official" => 1.0,
+        "promotion" => 0.6,
+        "pseudo-release" => 0.2,
+        "bootleg" => 0.0,
+        _ => UNKNOWN,
+    };
+
+    let (primary_type, secondary_types) = release_types(release);
+    let type_similarity = if secondary_types.iter().any(|secondary| secondary.eq_ignore_ascii_case("compilation")) {
+        0.2
+    } else if secondary_types.iter().any(|secondary| secondary.eq_ignore_ascii_case("live")) {
+        0.4
+    } else {
+        match primary_type.as_deref().map(str::to_lowercase).as_deref() {
+            Some("album") => 1.0,
+            Some("ep") | Some("single") => 0.7,
+            Some(_) => 0.3,
+            None => UNKNOWN,
+        }
+    };
+    let type_names: Vec<String> = primary_type.into_iter().chain(secondary_types).collect();
+
+    let country = release.country.as_deref().unwrap_or_default();
+    let (country_similarity, country_explanation) = match settings
+        .preferred_countries
+        .iter()
+        .position(|preferred| preferred.eq_ignore_ascii_case(country))
+    {
+        _ if settings.preferred_countries.is_empty() => (UNKNOWN, "no preferred countries".to_string()),
+        Some(index) => (
+            (1.0 - index as f64 * 0.1).max(0.5),
+            format!("{} is preferred country #{}", country, index + 1),
+        ),
+        None => (0.0, format!("{} is not preferred", or_unknown(country))),
+    };
+
+    let release_year = year(release.date.as_deref());
+    let (date_similarity, date_explanation) = match (release_year, wanted_year) {
+        (Some(release_year), Some(wanted_year)) => (
+            1.0 - ((release_year - wanted_year).abs() as f64 / MAX_YEAR_DIFFERENCE).min(1.0),
+            format!("released {}, looking for {}", release_year, wanted_year),
+        ),
+        (Some(release_year), None) => (UNKNOWN, format!("released {}", release_year)),
+        (None, _) => (UNKNOWN, "unknown release date".to_string()),
+    };
+
+    vec![
+        part(ScoreFactor::ReleaseStatus, weights.release_status, status_similarity, format!("status {}", or_unknown(status))),
+        part(
+            ScoreFactor::ReleaseType,
+            weights.release_type,
+            type_similarity,
+            format!("type {}", or_unknown(&type_names.join(" + "))),
+        ),
+        part(ScoreFactor::ReleaseCountry, weights.release_country, country_similarity, country_explanation),
+        part(ScoreFactor::ReleaseDate, weights.release_date, date_similarity, date_explanation),
+    ]
+}
+
+/// The release factors of a recording without releases
+fn release_parts_unknown(weights: &ScoreWeights) -> Vec<ScorePart> {
+    [
+        (ScoreFactor::ReleaseStatus, weights.release_status),
+        (ScoreFactor::ReleaseType, weights.release_type),
+        (ScoreFactor::ReleaseCountry, weights.release_country),
+        (ScoreFactor::ReleaseDate, weights.release_date),
+    ]
+    .into_iter()
+    .map(|(factor, weight)| part(factor, weight, UNKNOWN, "no release".to_string()))
+    .collect()
+}
+
+/// The primary type of the release group and its secondary types like "Compilation"
+fn release_types(release: &Release) -> (Option<String>, Vec<String>) {
+    if let Some(release_group) = &release.release_group {
+        if release_group.primary_type.is_some() || release_group.secondary_types.is_some() {
+            return (
+                release_group.primary_type.clone(),
+                release_group.secondary_types.clone().unwrap_or_default(),
+            );
+        }
+    }
+    let mut types = release.release_type.clone().unwrap_or_default().into_iter();
+    (types.next(), types.collect())
+}
+
+fn part(factor: ScoreFactor, weight: f64, similarity: f64, explanation: String) -> ScorePart {
+    ScorePart {
+        factor,
+        weight: weight.max(0.0),
+        similarity,
+        explanation,
+    }
+}
+
+fn breakdown(parts: Vec<ScorePart>) -> ScoreBreakdown {
+    let total_weight: f64 = parts.iter().map(|part| part.weight).sum();
+    let score = if total_weight > 0.0 {
+        parts.iter().map(|part| part.weight * part.similarity).sum::<f64>() / total_weight
+    } else {
+        0.0
+    };
+    ScoreBreakdown { score, parts }
+}
+
+fn song_year(song: &Song) -> Option<i32> {
+    ["Year", "ReleaseDate", "RecordingDate", "OriginalReleaseDate"]
+        .iter()
+        .find_map(|key| year(first_value(&song.tags, key)))
+}
+
+/// "1991-09-24" -> 1991
+fn year(date: Option<&str>) -> Option<i32> {
+    date?.trim().get(0..4)?.parse().ok()
+}
+
+fn or_unknown(value: &str) -> &str {
+    if value.is_empty() {
+        "unknown"
+    } else {
+        value
+    }
+}
+
+/// The artists as they are credited, "A feat. B"
+pub fn credit_name(artist_credit: &[ArtistCredit]) -> String {
+    artist_credit
+        .iter()
+        .map(|credit| format!("{}{}", credit.name, credit.joinphrase.as_deref().unwrap_or_default()))
+        .collect()
+}
+
+/// 1 within two seconds, falling to 0 at 15 seconds
+pub fn duration_similarity(difference_millis: f64) -> f64 {
+    let range = MAX_DURATION_DIFFERENCE_MILLIS - DURATION_TOLERANCE_MILLIS;
+    1.0 - ((difference_millis - DURATION_TOLERANCE_MILLIS) / range).clamp(0.0, 1.0)
+}
+
+/// Lowercase letters and digits, words separated by single spaces
+pub fn normalize_title(title: &str) -> String {
+    title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 1 for equal strings, 0 for nothing in common, by Levenshtein distance
+pub fn string_similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    1.0 - previous[b.len()] as f64 / a.len().max(b.len()) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::musicbrainz::ReleaseGroup;
+    use crate::musicbrainz_fixtures::{self as fixtures, artist_credit, song};
+    use std::collections::HashMap;
+
+    fn release(id: &str, status: &str, country: &str, date: &str, types: (&str, &[&str])) -> Release {
+        Release {
+            date: Some(date.to_string()),
+            country: Some(country.to_string()),
+            release_group: Some(ReleaseGroup {
+                id: format!("group-{}", id),
+                first_release_date: None,
+                primary_type: Some(types.0.to_string()),
+                secondary_types: Some(types.1.iter().map(|secondary| secondary.to_string()).collect()),
+                extra: HashMap::new(),
+            }),
+            status: Some(status.to_string()),
+            ..fixtures::release(id)
+        }
+    }
+
+    fn recording(title: &str, artist: &str, length: Option<u64>, releases: Vec<Release>) -> Recording {
+        Recording {
+            artist_credit: Some(artist_credit(artist)),
+            releases: Some(releases),
+            first_release_date: Some("1991-09-10".to_string()),
+            length,
+            ..fixtures::recording("recording-id", title)
+        }
+    }
+
+    fn similarity(breakdown: &ScoreBreakdown, factor: ScoreFactor) -> f64 {
+        breakdown.parts.iter().find(|part| part.factor == factor).unwrap().similarity
+    }
+
+    #[test]
+    fn test_picks_the_preferred_release() {
+        let settings = ScoringSettings {
+            preferred_countries: vec!["GB".to_string(), "US".to_string()],
+            ..Default::default()
+        };
+        let releases = vec![
+            release("bootleg", "Bootleg", "US", "1991-09-24", ("Album", &[])),
+            release("compilation", "Official", "US", "1991-09-24", ("Album", &["Compilation"])),
+            release("later", "Official", "US", "2011-09-24", ("Album", &[])),
+            release("album", "Official", "US", "1991-09-24", ("Album", &[])),
+        ];
+        let recording = recording("Smells Like Teen Spirit", "Nirvana", Some(301_000), releases);
+        let scored = score_recording(&song("Smells Like Teen Spirit", "Nirvana", 300_000), &recording, &settings);
+
+        assert_eq!(scored.release_index, Some(3));
+        let breakdown = &scored.breakdown;
+        assert_eq!(breakdown.parts.len(), 7);
+        assert_eq!(similarity(breakdown, ScoreFactor::Title), 1.0);
+        assert_eq!(similarity(breakdown, ScoreFactor::Artist), 1.0);
+        assert_eq!(similarity(breakdown, ScoreFactor::Duration), 1.0);
+        assert_eq!(similarity(breakdown, ScoreFactor::ReleaseCountry), 0.9);
+        assert_eq!(similarity(breakdown, ScoreFactor::ReleaseDate), 1.0);
+        let country = breakdown.parts.iter().find(|part| part.factor == ScoreFactor::ReleaseCountry).unwrap();
+        assert_eq!(country.explanation, "US is preferred country #2");
+        assert!(breakdown.score > 0.95 && breakdown.score < 1.0, "{}", breakdown.score);
+    }
+
+    // a recording as the search API returns it, trimmed to two releases
+    const SEARCH_RECORDING: &str = r#"{
+        "id": "5fb524f1-8cc8-4c04-a921-e34c0a911ea7",
+        "score": 100,
+        "title": "Smells Like Teen Spirit",
+        "length": 301920,
+        "video": null,
+        "artist-credit": [{
+            "name": "Nirvana",
+            "artist": {"id": "5b11f4ce-a62d-471e-81fc-a69a8278c7da", "name": "Nirvana", "sort-name": "Nirvana"}
+        }],
+        "first-release-date": "1991-09-10",
+        "releases": [
+            {
+                "id": "0a2b8e1f-5a4c-4e0f-9b9a-1d7c6b2a3f4e",
+                "status-id": "4e304316-386d-3409-af2e-78857eec5cfe",
+                "count": 1,
+                "title": "Nirvana",
+                "status": "Official",
+                "release-group": {
+                    "id": "d1a5c2e0-3b1b-3c8a-8d2c-6f0a4a6b2f1e",
+                    "type-id": "f529b476-6e62-324f-b0aa-1f3e33d313fc",
+                    "primary-type-id": "f529b476-6e62-324f-b0aa-1f3e33d313fc",
+                    "title": "Nirvana",
+                    "primary-type": "Album",
+                    "secondary-type-ids": ["dd2a21e1-0c00-3729-a7a0-de60b84eb5d1"],
+                    "secondary-types": ["Compilation"]
+                },
+                "date": "2002-10-29",
+                "country": "US",
+                "release-events": [{"date": "2002-10-29", "area": {"id": "489ce91b", "name": "United States", "iso-3166-1-codes": ["US"]}}],
+                "track-count": 14,
+                "media": [{"position": 1, "format": "CD", "track": [{"id": "t", "number": "2", "title": "Smells Like Teen Spirit", "length": 301920}], "track-count": 14, "track-offset": 1}]
+            },
+            {
+                "id": "b52a8f31-b5ab-34e9-92f4-f5b7110220f0",
+                "status-id": "4e304316-386d-3409-af2e-78857eec5cfe",
+                "count": 1,
+                "title": "Nevermind",
+                "status": "Official",
+                "release-group": {
+                    "id": "1b022e01-4da6-387b-8658-8678046e4cef",
+                    "type-id": "f529b476-6e62-324f-b0aa-1f3e33d313fc",
+                    "primary-type-id": "f529b476-6e62-324f-b0aa-1f3e33d313fc",
+                    "title": "Nevermind",
+                    "primary-type": "Album"
+                },
+                "date": "1991-09-24",
+                "country": "US",
+                "release-events": [{"date": "1991-09-24", "area": {"id": "489ce91b", "name": "United States", "iso-3166-1-codes": ["US"]}}],
+                "track-count": 12,
+                "media": [{"position": 1, "format": "CD", "track": [{"id": "t", "number": "1", "title": "Smells Like Teen Spirit", "length": 301920}], "track-count": 12, "track-offset": 0}]
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_scores_a_search_response() {
+        let recording: Recording = serde_json::from_str(SEARCH_RECORDING).unwrap();
+        let song = song("Smells Like Teen Spirit", "Nirvana", 301_000);
+
+        let scored = score_recording(&song, &recording, &ScoringSettings::default());
+        // the album wins over the compilation by its release group type
+        assert_eq!(scored.release_index, Some(1));
+        assert_eq!(similarity(&scored.breakdown, ScoreFactor::ReleaseType), 1.0);
+        let release_type = scored.breakdown.parts.iter().find(|part| part.factor == ScoreFactor::ReleaseType).unwrap();
+        assert_eq!(release_type.explanation, "type Album");
+        assert_eq!(similarity(&scored.breakdown, ScoreFactor::Duration), 1.0);
+
+        let compilation = &recording.releases.as_ref().unwrap()[0];
+        let types = release_types(compilation);
+        assert_eq!(types, (Some("Album".to_string()), vec!["Compilation".to_string()]));
+    }
+
+    #[test]
+    fn test_weights() {
+        let recording = recording("Song", "Someone Else", None, Vec::new());
+        let song = song("Song", "Someone", 200_000);
+
+        let scored = score_recording(&song, &recording, &ScoringSettings::default());
+        assert_eq!(scored.release_index, None);
+        assert_eq!(similarity(&scored.breakdown, ScoreFactor::Duration), UNKNOWN);
+
+        // only the title counts
+        let title_only = ScoringSettings {
+            weights: ScoreWeights {
+                title: 1.0,
+                artist: 0.0,
+                duration: 0.0,
+                release_status: 0.0,
+                release_type: 0.0,
+                release_country: 0.0,
+                release_date: 0.0,
+            },
+            ..Default::default()
+        };
+        let scored = score_recording(&song, &recording, &title_only);
+        assert_eq!(scored.breakdown.score, 1.0);
+    }
+
+    #[test]
+    fn test_score_tracklist() {
+        // one of two files paired with a track of four, on its disc
+        let fit = TracklistFit {
+            file_count: 2,
+            track_count: 4,
+            paired_count: 1,
+            duration_sum: 0.5,
+            title_sum: 1.0,
+            disc_sum: 1.0,
+        };
+        let breakdown = score_tracklist(&fit, &ScoringSettings::default());
+        assert_eq!(similarity(&breakdown, ScoreFactor::TrackCount), 0.5);
+        assert_eq!(similarity(&breakdown, ScoreFactor::TrackDurations), 0.25);
+        assert_eq!(similarity(&breakdown, ScoreFactor::TrackTitles), 0.5);
+        assert_eq!(similarity(&breakdown, ScoreFactor::DiscLayout), 0.5);
+        assert!((breakdown.score - 0.425).abs() < 1e-9);
+
+        // only the durations count
+        let durations_only = ScoringSettings {
+            album_weights: AlbumScoreWeights {
+                track_count: 0.0,
+                track_durations: 1.0,
+                track_titles: 0.0,
+                disc_layout: 0.0,
+            },
+            ..Default::default()
+        };
+        assert_eq!(score_tracklist(&fit, &durations_only).score, 0.25);
+    }
+
+    #[test]
+    fn test_similarities() {
+        assert_eq!(normalize_title("  Hello,  World! (Live) "), "hello world live");
+        assert_eq!(string_similarity("abc", "abc"), 1.0);
+        assert_eq!(string_similarity("abc", "xyz"), 0.0);
+        assert!(string_similarity("hit single", "hit single radio edit") > 0.4);
+        assert_eq!(duration_similarity(1500.0), 1.0);
+        assert_eq!(duration_similarity(20000.0), 0.0);
+        assert!((duration_similarity(8500.0) - 0.5).abs() < 1e-9);
+        assert_eq!(year(Some("1991-09-24")), Some(1991));
+        assert_eq!(year(Some("")), None);
+    }
+}
@@ -1,10 +1,7 @@
-use crate::musicbrainz::{
-    Artist, ArtistCredit, Genre, Label, Recording, Release, ReleaseEvent, ReleaseGroup,
-    ReleaseMedia, Track,
-};
-use std::collections::HashMap;
+use crate::musicbrainz::Recording;
+use crate::tags::multi_value::TagMap;
 
-/// Maps a MusicBrainz recording to a HashMap of tags in lofty format.
+/// Maps a MusicBrainz recording to a map of tags in lofty format.
 ///
 /// MusicBrainz tags mapped to lofty equivalents:
 /// - album → AlbumTitle
@@ -40,28 +37,28 @@ use std::collections::HashMap;
 /// - totaldiscs → DiscTotal
 /// - totaltracks → TrackTotal
 /// - tracknumber → TrackNumber
-pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
-    let mut tags = HashMap::new();
+pub fn recording_to_tags(recording: &Recording) -> TagMap {
+    let mut tags = TagMap::new();
 
     // Basic recording info
-    tags.insert("TrackTitle".to_string(), recording.title.clone());
-    tags.insert("MusicBrainzRecordingId".to_string(), recording.id.clone());
+    set_value(&mut tags, "TrackTitle", recording.title.clone());
+    set_value(&mut tags, "MusicBrainzRecordingId", recording.id.clone());
 
     // ISRCs
     if let Some(isrcs) = &recording.isrcs {
         if let Some(first_isrc) = isrcs.first() {
-            tags.insert("Isrc".to_string(), first_isrc.clone());
+            set_value(&mut tags, "Isrc", first_isrc.clone());
         }
     }
 
     // Artist info from recording artist_credit
     if let Some(artist_credit) = &recording.artist_credit {
         let artist_names: Vec<String> = artist_credit.iter().map(|ac| ac.name.clone()).collect();
-        tags.insert("TrackArtist".to_string(), artist_names.join(", "));
+        set_value(&mut tags, "TrackArtist", artist_names.join(", "));
 
         // TrackArtists (multi-value)
         let track_artists: Vec<String> = artist_credit.iter().map(|ac| ac.name.clone()).collect();
-        tags.insert("TrackArtists".to_string(), track_artists.join("; "));
+        set_values(&mut tags, "TrackArtists", track_artists);
 
         // Artist sort names
         let artist_sort_names: Vec<String> = artist_credit
@@ -73,31 +70,28 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                     .unwrap_or_else(|| ac.name.clone())
             })
             .collect();
-        tags.insert(
-            "TrackArtistSortOrder".to_string(),
-            artist_sort_names.join(", "),
-        );
+        set_value(&mut tags, "TrackArtistSortOrder", artist_sort_names.join(", "));
 
         // MusicBrainz Artist IDs (multi-value)
         let artist_ids: Vec<String> = artist_credit
             .iter()
             .map(|ac| ac.artist.id.clone())
             .collect();
-        tags.insert("MusicBrainzArtistId".to_string(), artist_ids.join("; "));
+        set_values(&mut tags, "MusicBrainzArtistId", artist_ids);
     } else if let Some(releases) = &recording.releases {
         // Fallback to release artist if no recording artist
         if let Some(first_release) = releases.first() {
             if let Some(artist_credit) = &first_release.artist_credit {
                 let artist_names: Vec<String> =
                     artist_credit.iter().map(|ac| ac.name.clone()).collect();
-                tags.insert("TrackArtist".to_string(), artist_names.join(", "));
-                tags.insert("TrackArtists".to_string(), artist_names.join("; "));
+                set_value(&mut tags, "TrackArtist", artist_names.join(", "));
+                set_values(&mut tags, "TrackArtists", artist_names);
 
                 let artist_ids: Vec<String> = artist_credit
                     .iter()
                     .map(|ac| ac.artist.id.clone())
                     .collect();
-                tags.insert("MusicBrainzArtistId".to_string(), artist_ids.join("; "));
+                set_values(&mut tags, "MusicBrainzArtistId", artist_ids);
             }
         }
     }
@@ -106,45 +100,39 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
     if let Some(releases) = &recording.releases {
         if let Some(first_release) = releases.first() {
             // Album title
-            tags.insert("AlbumTitle".to_string(), first_release.title.clone());
-            tags.insert("MusicBrainzReleaseId".to_string(), first_release.id.clone());
+            set_value(&mut tags, "AlbumTitle", first_release.title.clone());
+            set_value(&mut tags, "MusicBrainzReleaseId", first_release.id.clone());
 
             // ASIN
             if let Some(asin) = &first_release.asin {
-                tags.insert("Asin".to_string(), asin.clone());
+                set_value(&mut tags, "Asin", asin.clone());
             }
 
             // Barcode
             if let Some(barcode) = &first_release.barcode {
                 if !barcode.is_empty() {
-                    tags.insert("Barcode".to_string(), barcode.clone());
+                    set_value(&mut tags, "Barcode", barcode.clone());
                 }
             }
 
             // Release date
             if let Some(date) = &first_release.date {
-                tags.insert("ReleaseDate".to_string(), date.clone());
+                set_value(&mut tags, "ReleaseDate", date.clone());
                 if let Some(year) = date.get(0..4) {
-                    tags.insert("Year".to_string(), year.to_string());
+                    set_value(&mut tags, "Year", year.to_string());
                 }
             }
 
             // Release group info
             if let Some(release_group) = &first_release.release_group {
-                tags.insert(
-                    "MusicBrainzReleaseGroupId".to_string(),
-                    release_group.id.clone(),
-                );
+                set_value(&mut tags, "MusicBrainzReleaseGroupId", release_group.id.clone());
 
                 // Original date from release group first release
                 if let Some(first_release_date) = &release_group.first_release_date {
                     if !first_release_date.is_empty() {
-                        tags.insert(
-                            "OriginalReleaseDate".to_string(),
-                            first_release_date.clone(),
-                        );
+                        set_value(&mut tags, "OriginalReleaseDate", first_release_date.clone());
                         if let Some(year) = first_release_date.get(0..4) {
-                            tags.insert("OriginalYear".to_string(), year.to_string());
+                            set_value(&mut tags, "OriginalYear", year.to_string());
                         }
                     }
                 }
@@ -155,7 +143,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                 let label_names: Vec<String> =
                     labels.iter().filter_map(|l| l.name.clone()).collect();
                 if !label_names.is_empty() {
-                    tags.insert("Label".to_string(), label_names.join("; "));
+                    set_values(&mut tags, "Label", label_names);
                 }
 
                 let catalog_numbers: Vec<String> = labels
@@ -163,7 +151,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                     .filter_map(|l| l.catalog_number.clone())
                     .collect();
                 if !catalog_numbers.is_empty() {
-                    tags.insert("CatalogNumber".to_string(), catalog_numbers.join("; "));
+                    set_values(&mut tags, "CatalogNumber", catalog_numbers);
                 }
             }
 
@@ -172,7 +160,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                 if let Some(first_event) = events.first() {
                     if let Some(country) = &first_event.country {
                         if !country.is_empty() {
-                            tags.insert("ReleaseCountry".to_string(), country.clone());
+                            set_value(&mut tags, "ReleaseCountry", country.clone());
                         }
                     }
                 }
@@ -180,23 +168,23 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
 
             // Release status
             if let Some(status) = &first_release.status {
-                tags.insert("ReleaseStatus".to_string(), status.clone());
+                set_value(&mut tags, "ReleaseStatus", status.clone());
             }
 
             // Release type (multi-value)
             if let Some(release_types) = &first_release.release_type {
-                tags.insert("ReleaseType".to_string(), release_types.join("; "));
+                set_values(&mut tags, "ReleaseType", release_types.clone());
             }
 
             // Script
             if let Some(script) = &first_release.script {
-                tags.insert("Script".to_string(), script.clone());
+                set_value(&mut tags, "Script", script.clone());
             }
 
             // Comment (from disambiguation)
             if let Some(comment) = &first_release.disambiguation {
                 if !comment.is_empty() {
-                    tags.insert("Comment".to_string(), comment.clone());
+                    set_value(&mut tags, "Comment", comment.clone());
                 }
             }
 
@@ -204,17 +192,14 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
             if let Some(artist_credit) = &first_release.artist_credit {
                 let album_artist_names: Vec<String> =
                     artist_credit.iter().map(|ac| ac.name.clone()).collect();
-                tags.insert("AlbumArtist".to_string(), album_artist_names.join(", "));
-                tags.insert("AlbumArtists".to_string(), album_artist_names.join("; "));
+                set_value(&mut tags, "AlbumArtist", album_artist_names.join(", "));
+                set_values(&mut tags, "AlbumArtists", album_artist_names);
 
                 let album_artist_ids: Vec<String> = artist_credit
                     .iter()
                     .map(|ac| ac.artist.id.clone())
                     .collect();
-                tags.insert(
-                    "MusicBrainzReleaseArtistId".to_string(),
-                    album_artist_ids.join("; "),
-                );
+                set_values(&mut tags, "MusicBrainzReleaseArtistId", album_artist_ids);
 
                 // Album artist sort names
                 let album_artist_sort: Vec<String> = artist_credit
@@ -226,10 +211,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                             .unwrap_or_else(|| ac.name.clone())
                     })
                     .collect();
-                tags.insert(
-                    "AlbumArtistSortOrder".to_string(),
-                    album_artist_sort.join(", "),
-                );
+                set_value(&mut tags, "AlbumArtistSortOrder", album_artist_sort.join(", "));
             }
 
             // Media/track info
@@ -239,7 +221,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                     // Disc subtitle (from media format if available)
                     if let Some(format) = &first_media.format {
                         // Some formats include disc subtitle info
-                        tags.insert("Media".to_string(), format.clone());
+                        set_value(&mut tags, "Media", format.clone());
                     }
 
                     // Track info for first media
@@ -247,17 +229,17 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                         if let Some(first_track) = tracks.first() {
                             // Track number
                             if let Some(number) = &first_track.number {
-                                tags.insert("TrackNumber".to_string(), number.clone());
+                                set_value(&mut tags, "TrackNumber", number.clone());
                             }
 
                             // Disc number (position)
                             if let Some(position) = first_track.position {
-                                tags.insert("DiscNumber".to_string(), position.to_string());
+                                set_value(&mut tags, "DiscNumber", position.to_string());
                             }
 
                             // MusicBrainz Track ID
                             if let Some(track_id) = &first_track.id {
-                                tags.insert("MusicBrainzTrackId".to_string(), track_id.clone());
+                                set_value(&mut tags, "MusicBrainzTrackId", track_id.clone());
                             }
 
                             // Track ISRC (from track)
@@ -265,7 +247,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                                 if let Some(first_isrc) = track_isrcs.first() {
                                     // Only override if we don't already have one from recording
                                     if !tags.contains_key("Isrc") {
-                                        tags.insert("Isrc".to_string(), first_isrc.clone());
+                                        set_value(&mut tags, "Isrc", first_isrc.clone());
                                     }
                                 }
                             }
@@ -274,7 +256,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                         // Total tracks on this disc
                         let track_count = tracks.len();
                         if track_count > 0 {
-                            tags.insert("TrackTotal".to_string(), track_count.to_string());
+                            set_value(&mut tags, "TrackTotal", track_count.to_string());
                         }
                     }
 
@@ -282,7 +264,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                     if let Some(track_count) = first_media.track_count {
                         // Only set if not already set from tracks
                         if !tags.contains_key("TrackTotal") {
-                            tags.insert("TrackTotal".to_string(), track_count.to_string());
+                            set_value(&mut tags, "TrackTotal", track_count.to_string());
                         }
                     }
                 }
@@ -290,7 +272,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
                 // Total discs
                 let total_discs = media.len();
                 if total_discs > 0 {
-                    tags.insert("DiscTotal".to_string(), total_discs.to_string());
+                    set_value(&mut tags, "DiscTotal", total_discs.to_string());
                 }
             }
         }
@@ -299,7 +281,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
     // Genres (from recording)
     if let Some(genres) = &recording.genres {
         if let Some(first_genre) = genres.first() {
-            tags.insert("Genre".to_string(), first_genre.name.clone());
+            set_value(&mut tags, "Genre", first_genre.name.clone());
         }
     }
 
@@ -308,7 +290,7 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
     if let Some(mb_tags) = &recording.tags {
         if !mb_tags.is_empty() && !tags.contains_key("Genre") {
             if let Some(first_tag) = mb_tags.first() {
-                tags.insert("Genre".to_string(), first_tag.name.clone());
+                set_value(&mut tags, "Genre", first_tag.name.clone());
             }
         }
     }
@@ -318,16 +300,29 @@ pub fn recording_to_tags(recording: &Recording) -> HashMap<String, String> {
         let has_multiple_artists =
             artist_credit.len() > 1 || artist_credit.iter().any(|ac| ac.joinphrase.is_some());
         if has_multiple_artists {
-            tags.insert("FlagCompilation".to_string(), "1".to_string());
+            set_value(&mut tags, "FlagCompilation", "1".to_string());
         }
     }
 
     tags
 }
 
+fn set_value(tags: &mut TagMap, key: &str, value: String) {
+    tags.insert(key.to_string(), vec![value]);
+}
+
+/// Multi-value keys like TrackArtists get one value per entry
+fn set_values(tags: &mut TagMap, key: &str, values: Vec<String>) {
+    tags.insert(key.to_string(), values);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::musicbrainz::{
+        Artist, ArtistCredit, Genre, Label, Release, ReleaseEvent, ReleaseGroup, ReleaseMedia, Track,
+    };
+    use std::collections::HashMap;
 
     #[test]
     fn test_recording_to_tags_basic() {
@@ -360,6 +355,7 @@ mod tests {
                         length: Some(180000),
                         isrcs: Some(vec!["USXXX1234567".to_string()]),
                         title: None,
+                        recording: None,
                         extra: HashMap::new(),
                     }]),
                     format: Some("CD".to_string()),
@@ -370,6 +366,8 @@ mod tests {
                 release_group: Some(ReleaseGroup {
                     id: "release-group-id".to_string(),
                     first_release_date: Some("1991-09-24".to_string()),
+                    primary_type: Some("Album".to_string()),
+                    secondary_types: None,
                     extra: HashMap::new(),
                 }),
                 events: Some(vec![ReleaseEvent {
@@ -400,69 +398,70 @@ mod tests {
             }]),
             disambiguation: None,
             first_release_date: None,
+            length: None,
             extra: HashMap::new(),
         };
 
         let tags = recording_to_tags(&recording);
 
         // Basic info
-        assert_eq!(tags.get("TrackTitle"), Some(&"Test Song".to_string()));
-        assert_eq!(tags.get("TrackArtist"), Some(&"Test Artist".to_string()));
-        assert_eq!(tags.get("AlbumTitle"), Some(&"Test Album".to_string()));
+        assert_eq!(tags.get("TrackTitle"), Some(&vec!["Test Song".to_string()]));
+        assert_eq!(tags.get("TrackArtist"), Some(&vec!["Test Artist".to_string()]));
+        assert_eq!(tags.get("AlbumTitle"), Some(&vec!["Test Album".to_string()]));
 
         // Dates
-        assert_eq!(tags.get("Year"), Some(&"1991".to_string()));
-        assert_eq!(tags.get("ReleaseDate"), Some(&"1991-09-24".to_string()));
+        assert_eq!(tags.get("Year"), Some(&vec!["1991".to_string()]));
+        assert_eq!(tags.get("ReleaseDate"), Some(&vec!["1991-09-24".to_string()]));
         assert_eq!(
             tags.get("OriginalReleaseDate"),
-            Some(&"1991-09-24".to_string())
+            Some(&vec!["1991-09-24".to_string()])
         );
 
         // Track info
-        assert_eq!(tags.get("TrackNumber"), Some(&"1".to_string()));
-        assert_eq!(tags.get("DiscNumber"), Some(&"1".to_string()));
-        assert_eq!(tags.get("TrackTotal"), Some(&"1".to_string()));
-        assert_eq!(tags.get("DiscTotal"), Some(&"1".to_string()));
+        assert_eq!(tags.get("TrackNumber"), Some(&vec!["1".to_string()]));
+        assert_eq!(tags.get("DiscNumber"), Some(&vec!["1".to_string()]));
+        assert_eq!(tags.get("TrackTotal"), Some(&vec!["1".to_string()]));
+        assert_eq!(tags.get("DiscTotal"), Some(&vec!["1".to_string()]));
 
         // MusicBrainz IDs
         assert_eq!(
             tags.get("MusicBrainzRecordingId"),
-            Some(&"test-id".to_string())
+            Some(&vec!["test-id".to_string()])
         );
         assert_eq!(
             tags.get("MusicBrainzArtistId"),
-            Some(&"artist-id".to_string())
+            Some(&vec!["artist-id".to_string()])
         );
         assert_eq!(
             tags.get("MusicBrainzReleaseId"),
-            Some(&"release-id".to_string())
+            Some(&vec!["release-id".to_string()])
         );
         assert_eq!(
             tags.get("MusicBrainzReleaseGroupId"),
-            Some(&"release-group-id".to_string())
+            Some(&vec!["release-group-id".to_string()])
         );
         assert_eq!(
             tags.get("MusicBrainzTrackId"),
-            Some(&"track-id".to_string())
+            Some(&vec!["track-id".to_string()])
         );
 
         // Other tags
-        assert_eq!(tags.get("Isrc"), Some(&"USXXX1234567".to_string()));
-        assert_eq!(tags.get("Barcode"), Some(&"0720642442525".to_string()));
-        assert_eq!(tags.get("Label"), Some(&"DGC Records".to_string()));
-        assert_eq!(tags.get("CatalogNumber"), Some(&"DGCD-24425".to_string()));
-        assert_eq!(tags.get("Script"), Some(&"Latn".to_string()));
-        assert_eq!(tags.get("Asin"), Some(&"B0000001".to_string()));
-        assert_eq!(tags.get("Comment"), Some(&"Remastered edition".to_string()));
-        assert_eq!(tags.get("Genre"), Some(&"Rock".to_string()));
-        assert_eq!(tags.get("ReleaseStatus"), Some(&"Official".to_string()));
-        assert_eq!(tags.get("ReleaseType"), Some(&"album; studio".to_string()));
-        assert_eq!(tags.get("ReleaseCountry"), Some(&"US".to_string()));
+        assert_eq!(tags.get("Isrc"), Some(&vec!["USXXX1234567".to_string()]));
+        assert_eq!(tags.get("Barcode"), Some(&vec!["0720642442525".to_string()]));
+        assert_eq!(tags.get("Label"), Some(&vec!["DGC Records".to_string()]));
+        assert_eq!(tags.get("CatalogNumber"), Some(&vec!["DGCD-24425".to_string()]));
+        assert_eq!(tags.get("Script"), Some(&vec!["Latn".to_string()]));
+        assert_eq!(tags.get("Asin"), Some(&vec!["B0000001".to_string()]));
+        assert_eq!(tags.get("Comment"), Some(&vec!["Remastered edition".to_string()]));
+        assert_eq!(tags.get("Genre"), Some(&vec!["Rock".to_string()]));
+        assert_eq!(tags.get("ReleaseStatus"), Some(&vec!["Official".to_string()]));
+        assert_eq!(tags.get("ReleaseType"), Some(&vec!["album".to_string(), "studio".to_string()]));
+        assert_eq!(tags.get("ReleaseCountry"), Some(&vec!["US".to_string()]));
 
         // Sort orders
         assert_eq!(
             tags.get("TrackArtistSortOrder"),
-            Some(&"Artist, Test".to_string())
+            Some(&vec!["Artist, Test".to_string()])
         );
     }
 
@@ -510,6 +509,7 @@ mod tests {
                         length: Some(200000),
                         isrcs: None,
                         title: None,
+                        recording: None,
                         extra: HashMap::new(),
                     }]),
                     format: Some("Digital".to_string()),
@@ -543,6 +543,7 @@ mod tests {
             genres: None,
             disambiguation: None,
             first_release_date: None,
+            length: None,
             extra: HashMap::new(),
         };
 
@@ -551,14 +552,14 @@ mod tests {
         // Multiple artists should be joined
         assert_eq!(
             tags.get("TrackArtist"),
-            Some(&"Primary Artist, Featured Artist".to_string())
+            Some(&vec!["Primary Artist, Featured Artist".to_string()])
         );
 
         // Should be marked as compilation due to multiple artists
-        assert_eq!(tags.get("FlagCompilation"), Some(&"1".to_string()));
+        assert_eq!(tags.get("FlagCompilation"), Some(&vec!["1".to_string()]));
 
         // Album artist should be separate
-        assert_eq!(tags.get("AlbumArtist"), Some(&"Album Artist".to_string()));
+        assert_eq!(tags.get("AlbumArtist"), Some(&vec!["Album Artist".to_string()]));
     }
 
     #[test]
@@ -593,6 +594,7 @@ mod tests {
                             length: Some(180000),
                             isrcs: None,
                             title: None,
+                            recording: None,
                             extra: HashMap::new(),
                         }]),
                         format: Some("CD".to_string()),
@@ -609,6 +611,7 @@ mod tests {
                             length: Some(200000),
                             isrcs: None,
                             title: None,
+                            recording: None,
                             extra: HashMap::new(),
                         }]),
                         format: Some("CD".to_string()),
@@ -633,12 +636,13 @@ mod tests {
             genres: None,
             disambiguation: None,
             first_release_date: None,
+            length: None,
             extra: HashMap::new(),
         };
 
         let tags = recording_to_tags(&recording);
 
         // Total discs should be 2
-        assert_eq!(tags.get("DiscTotal"), Some(&"2".to_string()));
+        assert_eq!(tags.get("DiscTotal"), Some(&vec!["2".to_string()]));
     }
 }
@@ -0,0 +1,88 @@
+use crate::tags::multi_value::{first_value, TagMap};
+
+/// Whether `next` directly follows `current` on the same album, judged by the
+/// album, track number and disc number tags. Such transitions are played
+/// gaplessly instead of crossfaded.
+pub fn is_consecutive_album_track(current: &TagMap, next: &TagMap) -> bool {
+    let same_album = match (first_value(current, "AlbumTitle"), first_value(next, "AlbumTitle")) {
+        (Some(current_album), Some(next_album)) => {
+            !current_album.is_empty()
+                && current_album == next_album
+                && first_value(current, "AlbumArtist") == first_value(next, "AlbumArtist")
+        }
+        _ => false,
+    };
+    if !same_album {
+        return false;
+    }
+
+    let (Some(current_track), Some(next_track)) = (
+        parse_number(first_value(current, "TrackNumber")),
+        parse_number(first_value(next, "TrackNumber")),
+    ) else {
+        return false;
+    };
+
+    let current_disc = parse_number(first_value(current, "DiscNumber")).unwrap_or(1);
+    let next_disc = parse_number(first_value(next, "DiscNumber")).unwrap_or(1);
+
+    if current_disc == next_disc {
+        next_track == current_track + 1
+    } else {
+        // first track of the following disc
+        next_disc == current_disc + 1 && next_track == 1
+    }
+}
+
+/// Parses track and disc numbers like "3" or "3/12"
+fn parse_number(value: Option<&str>) -> Option<u32> {
+    value?.split('/').next()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(album: &str, track: &str, disc: Option<&str>) -> TagMap {
+        let mut tags = TagMap::from([
+            ("AlbumTitle".to_string(), vec![album.to_string()]),
+            ("AlbumArtist".to_string(), vec!["Artist".to_string()]),
+            ("TrackNumber".to_string(), vec![track.to_string()]),
+        ]);
+        if let Some(disc) = disc {
+            tags.insert("DiscNumber".to_string(), vec![disc.to_string()]);
+        }
+        tags
+    }
+
+    #[test]
+    fn test_consecutive_tracks() {
+        assert!(is_consecutive_album_track(&tags("Album", "3", None), &tags("Album", "4", None)));
+        assert!(is_consecutive_album_track(&tags("Album", "3/12", None), &tags("Album", "4/12", None)));
+    }
+
+    #[test]
+    fn test_non_consecutive_tracks() {
+        assert!(!is_consecutive_album_track(&tags("Album", "3", None), &tags("Album", "5", None)));
+        assert!(!is_consecutive_album_track(&tags("Album", "4", None), &tags("Album", "3", None)));
+        assert!(!is_consecutive_album_track(&tags("Album", "3", None), &tags("Other", "4", None)));
+    }
+
+    #[test]
+    fn test_disc_change() {
+        assert!(is_consecutive_album_track(
+            &tags("Album", "12", Some("1/2")),
+            &tags("Album", "1", Some("2/2"))
+        ));
+        assert!(!is_consecutive_album_track(
+            &tags("Album", "3", Some("1")),
+            &tags("Album", "4", Some("2"))
+        ));
+    }
+
+    #[test]
+    fn test_missing_tags() {
+        assert!(!is_consecutive_album_track(&TagMap::new(), &TagMap::new()));
+        assert!(!is_consecutive_album_track(&tags("", "1", None), &tags("", "2", None)));
+    }
+}
@@ -0,0 +1,55 @@
+use crate::decoder::crossfade::CrossfadeSettings;
+use crate::decoder::decoder_commands::DecoderCommand;
+use crate::decoder::track_source::TrackSource;
+use crate::player::pipeline::Pipeline;
+use crate::player::shared::PlaybackState;
+use std::sync::{Arc, Mutex};
+
+/// Fades from the playing track into the given one without reloading the output
+/// stream. Returns false if that isn't possible, because nothing is playing or
+/// the track can't be opened.
+pub fn crossfade_to(
+    state: &Arc<Mutex<PlaybackState>>,
+    pipeline: &mut Pipeline,
+    path: &String,
+    crossfade: CrossfadeSettings,
+    gain: f32,
+) -> bool {
+    {
+        let state = state.lock().unwrap();
+        if !state.is_playing || state.is_paused {
+            return false;
+        }
+    }
+    let (Some(decoder_command_sender), Some((sample_rate, channels))) =
+        (&pipeline.decoder_command_sender, pipeline.output_format)
+    else {
+        return false;
+    };
+
+    let source = TrackSource::open(path).and_then(|mut source| {
+        source.convert_to(sample_rate, channels)?;
+        source.set_gain(gain);
+        Ok(source)
+    });
+    let source = match source {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Failed to open audio file: {}", e);
+            return false;
+        }
+    };
+
+    println!("Crossfading into: {}", path);
+    if decoder_command_sender
+        .send(DecoderCommand::CrossfadeTo(source, crossfade))
+        .is_err()
+    {
+        return false;
+    }
+    pipeline.current_track = Some(path.clone());
+    pipeline.current_gain = gain;
+    // the decoder drops the prepared next track of the previous one
+    pipeline.next_track = None;
+    true
+}
@@ -1,87 +1,105 @@
-use crate::audio::audio_thread::start_cpal_audio_stream;
-use crate::player::probe::probe_audio_file;
-use crate::player::shared::{PlaybackState};
+use crate::audio::audio_thread::{get_default_audio_device, start_cpal_audio_stream};
+use crate::audio::output_config::choose_output_config;
+use crate::audio::output_devices::find_output_device;
+use crate::player::pipeline::Pipeline;
+use crate::player::shared::{AudioPlayerCommand, PlaybackState};
 use cpal::traits::StreamTrait;
-use cpal::Stream;
 use ringbuf::traits::Split;
 use ringbuf::HeapRb;
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::thread::JoinHandle;
 use crate::decoder::decoder_commands::DecoderCommand;
 use crate::decoder::decoder_thread::start_decoder_thread;
+use crate::decoder::track_source::TrackSource;
+
+/// Where a loaded track starts playing
+#[derive(Clone, Copy, Default)]
+pub struct PlaybackStart {
+    pub position_seconds: f64,
+    pub paused: bool,
+}
 
 pub fn load_and_play(
     state: &Arc<Mutex<PlaybackState>>,
-    decoder_handle: &mut Option<JoinHandle<()>>,
-    decoder_command_sender: &mut Option<Sender<DecoderCommand>>,
-    stream: &mut Option<Stream>,
+    pipeline: &mut Pipeline,
+    player_command_sender: &Sender<AudioPlayerCommand>,
     path: &String,
-) -> () {
+    gain: f32,
+    start: PlaybackStart,
+) {
     println!("Loading: {}", path);
 
-    
-    // stop existing playback
-    {
-        let mut state = state.lock().unwrap();
-        state.is_playing = false;
-    }
-
-    // send stop command to old decoder
-    if let Some(decoder_command_sender) = decoder_command_sender.take() {
-        let _ = decoder_command_sender.send(DecoderCommand::Stop);
-    }
-
-    // Let old decoder finish without blocking
-    if let Some(handle) = decoder_handle.take() {
-        thread::spawn(move || {
-            let _ = handle.join();
-        });
-    }
+    stop(state, pipeline);
 
-    // Drop old stream immediately
-    drop(stream.take());
+    // a new generation invalidates end-of-stream notifications of the old decoder
+    pipeline.generation += 1;
 
-    // Probe the file to get sample rate, channels and the format reader
-    let probe_result = match probe_audio_file(&path) {
-        Ok(pr) => pr,
+    // Probe the file to get sample rate, channels and the decoder
+    let mut source = match TrackSource::open(path) {
+        Ok(source) => source,
         Err(e) => {
-            eprintln!("Failed to probe audio file: {}", e);
+            eprintln!("Failed to open audio file: {}", e);
             return;
         }
     };
 
-    let format_reader = probe_result.format;
-    let track = match format_reader.default_track() {
-        Some(track) => track,
-        None => {
-            eprintln!("No default track found");
+    println!(
+        "File sample rate: {}, channels: {}",
+        source.sample_rate, source.channels
+    );
+
+    // the selected device may be unplugged, play on the default one then
+    let device = find_output_device(pipeline.output_device.as_ref()).or_else(|e| {
+        eprintln!("Failed to open selected output device, using the default: {}", e);
+        get_default_audio_device()
+    });
+    let device = match device {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("Failed to create audio output: {}", e);
             return;
         }
     };
 
-    let sample_rate = match track.codec_params.sample_rate {
-        Some(sample_rate) => sample_rate,
-        None => {
-            eprintln!("No sample rate found in audio file");
+    // play the file's format if the device takes it, otherwise convert to the device's
+    let output_config = match choose_output_config(&device, source.sample_rate, source.channels) {
+        Ok(output_config) => output_config,
+        Err(e) => {
+            eprintln!("Failed to create audio output: {}", e);
             return;
         }
     };
+    let sample_rate = output_config.sample_rate();
+    let channels = output_config.channels();
 
-    let channels = track
-        .codec_params
-        .channels
-        .map(|c| c.count() as u16)
-        .unwrap_or(2);
-
-    println!("File sample rate: {}, channels: {}", sample_rate, channels);
+    if let Err(e) = source.convert_to(sample_rate, channels) {
+        eprintln!("Failed to convert to output format: {}", e);
+        return;
+    }
+    source.set_gain(gain);
+
+    // the decoder starts where playback starts, so nothing before it is heard
+    let mut position_samples = 0;
+    if start.position_seconds > 0.0 {
+        match source.seek(start.position_seconds) {
+            Ok(_) => position_samples = (start.position_seconds * sample_rate as f64) as u64,
+            Err(e) => eprintln!("Failed to seek to {:.2}s: {}", start.position_seconds, e),
+        }
+    }
 
     let sample_buffer = HeapRb::<f32>::new(sample_rate as usize * channels as usize);
     let (producer, consumer) = sample_buffer.split();
 
-    // create audio output stream with the correct sample rate and channels
-    let new_stream = match start_cpal_audio_stream(state.clone(), consumer, sample_rate, channels) {
+    // create audio output stream with the sample rate and channels of the device config
+    let new_stream = match start_cpal_audio_stream(
+        state.clone(),
+        consumer,
+        &device,
+        &output_config,
+        player_command_sender.clone(),
+        pipeline.generation,
+    ) {
         Ok(stream) => stream,
         Err(e) => {
             eprintln!("Failed to create audio output: {}", e);
@@ -89,32 +107,82 @@ pub fn load_and_play(
         }
     };
 
+    // update state with track info before the stream and the decoder start, so that
+    // nothing is played from the wrong position and commands following the load
+    // (seek, pause) can't be overwritten by the decoder
+    {
+        let mut state = state.lock().unwrap();
+        state.sample_rate = sample_rate;
+        state.current_position_samples = position_samples;
+        state.is_playing = true;
+        state.is_paused = start.paused;
+        state.needs_buffer_clear = false;
+        state.frames_until_track_switch = None;
+    }
+
     // start the stream
     if let Err(e) = new_stream.play() {
         eprintln!("Failed to start audio stream: {}", e);
+        state.lock().unwrap().is_playing = false;
         return;
     }
 
     // keep stream from being dropped at end of loop
-    *stream = Some(new_stream);
+    pipeline.stream = Some(new_stream);
+    pipeline.output_format = Some((sample_rate, channels));
+    pipeline.current_track = Some(path.clone());
+    pipeline.current_gain = gain;
 
     eprintln!("Audio output stream started");
 
     // create decoder command channel
     let (new_decoder_command_sender, decoder_command_receiver) = channel::<DecoderCommand>();
 
-    *decoder_command_sender = Some(new_decoder_command_sender);
+    pipeline.decoder_command_sender = Some(new_decoder_command_sender);
 
     // spawn new decoder thread
     let state_clone = state.clone();
-    *decoder_handle = Some(thread::spawn(move || {
+    let player_command_sender = player_command_sender.clone();
+    let generation = pipeline.generation;
+    pipeline.decoder_handle = Some(thread::spawn(move || {
         if let Err(e) = start_decoder_thread(
-            format_reader,
+            source,
             producer,
             state_clone,
             decoder_command_receiver,
+            player_command_sender,
+            generation,
         ) {
             eprintln!("Decoder error: {}", e);
         }
     }));
 }
+
+/// Stops the decoder thread and drops the output stream of the current track
+pub fn stop(state: &Arc<Mutex<PlaybackState>>, pipeline: &mut Pipeline) {
+    // stop existing playback
+    {
+        let mut state = state.lock().unwrap();
+        state.is_playing = false;
+    }
+
+    // send stop command to old decoder
+    if let Some(decoder_command_sender) = pipeline.decoder_command_sender.take() {
+        let _ = decoder_command_sender.send(DecoderCommand::Stop);
+    }
+
+    // Let old decoder finish without blocking
+    if let Some(handle) = pipeline.decoder_handle.take() {
+        thread::spawn(move || {
+            let _ = handle.join();
+        });
+    }
+
+    // Drop old stream immediately
+    drop(pipeline.stream.take());
+
+    // a prepared next track belonged to the old decoder
+    pipeline.output_format = None;
+    pipeline.current_track = None;
+    pipeline.next_track = None;
+}
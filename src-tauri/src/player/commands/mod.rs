@@ -1,4 +1,7 @@
 pub mod load_and_play;
 pub mod seek;
 pub mod toggle_playback;
-pub mod change_volume;
\ No newline at end of file
+pub mod change_volume;
+pub mod set_next_track;
+pub mod crossfade_to;
+pub mod switch_output_device;
@@ -42,6 +42,7 @@ mod tests {
             current_position_samples: 0,
             sample_rate: 48000,
             needs_buffer_clear: false,
+            frames_until_track_switch: None,
         }));
 
         // Create a mutable option containing the sender
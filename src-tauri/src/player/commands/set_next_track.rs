@@ -0,0 +1,47 @@
+use crate::decoder::crossfade::CrossfadeSettings;
+use crate::decoder::decoder_commands::DecoderCommand;
+use crate::decoder::track_source::TrackSource;
+use crate::player::pipeline::Pipeline;
+
+/// Hands the track to play after the current one to the decoder, so it can be
+/// decoded into the same buffer and stream without a gap, or crossfaded into if
+/// `crossfade` is given. `None` clears it. `gain` is its normalization gain.
+pub fn set_next_track(
+    pipeline: &mut Pipeline,
+    path: Option<&String>,
+    crossfade: Option<CrossfadeSettings>,
+    gain: f32,
+) {
+    if pipeline.next_track.as_ref() == path {
+        return;
+    }
+    let Some(decoder_command_sender) = &pipeline.decoder_command_sender else {
+        return;
+    };
+    pipeline.next_track = None;
+
+    let Some(path) = path else {
+        let _ = decoder_command_sender.send(DecoderCommand::ClearNext);
+        return;
+    };
+
+    let Some((sample_rate, channels)) = pipeline.output_format else {
+        return;
+    };
+
+    // probe the file up front so the transition doesn't have to wait for it
+    let source = TrackSource::open(path).and_then(|mut source| {
+        source.convert_to(sample_rate, channels)?;
+        source.set_gain(gain);
+        Ok(source)
+    });
+    match source {
+        Ok(source) => {
+            if decoder_command_sender.send(DecoderCommand::SetNext(source, crossfade)).is_ok() {
+                pipeline.next_track = Some(path.clone());
+                pipeline.next_gain = gain;
+            }
+        }
+        Err(e) => eprintln!("Failed to prepare next track {}: {}", path, e),
+    }
+}
@@ -0,0 +1,31 @@
+use crate::player::commands::load_and_play::{load_and_play, PlaybackStart};
+use crate::player::pipeline::Pipeline;
+use crate::player::shared::{AudioPlayerCommand, PlaybackState};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+/// Moves playback of the current track to `pipeline.output_device`, continuing at
+/// the same position and keeping it paused if it was
+pub fn switch_output_device(
+    state: &Arc<Mutex<PlaybackState>>,
+    pipeline: &mut Pipeline,
+    player_command_sender: &Sender<AudioPlayerCommand>,
+) {
+    let Some(path) = pipeline.current_track.clone() else {
+        return;
+    };
+    let start = {
+        let state = state.lock().unwrap();
+        if !state.is_playing {
+            return;
+        }
+        PlaybackStart {
+            position_seconds: state.current_position_samples as f64 / state.sample_rate as f64,
+            paused: state.is_paused,
+        }
+    };
+
+    println!("Switching output device at {:.2}s", start.position_seconds);
+    let gain = pipeline.current_gain;
+    load_and_play(state, pipeline, player_command_sender, &path, gain, start);
+}
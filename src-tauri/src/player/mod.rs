@@ -1,5 +1,10 @@
 pub mod shared;
 
-mod probe;
+pub mod probe;
 pub mod threads;
 mod commands;
+mod album_order;
+pub mod normalization;
+mod pipeline;
+mod queue;
+mod tag_cache;
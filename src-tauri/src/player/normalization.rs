@@ -0,0 +1,213 @@
+use crate::tags::multi_value::{first_value, TagMap};
+use serde::Deserialize;
+
+// R128 gains are relative to -23 LUFS, ReplayGain 2.0 to -18 LUFS
+const R128_TO_REPLAY_GAIN_DB: f32 = 5.0;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NormalizationMode {
+    Off,
+    Track,
+    Album,
+    Auto, // album gain while an album is played in order, track gain otherwise
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NormalizationSettings {
+    pub mode: NormalizationMode,
+    pub preamp_db: f32,
+    pub prevent_clipping: bool, // lower the gain so that the peak stays below full scale
+}
+
+impl Default for NormalizationSettings {
+    fn default() -> Self {
+        NormalizationSettings {
+            mode: NormalizationMode::Off,
+            preamp_db: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+/// Loudness tags of a track, gains in dB relative to the ReplayGain reference
+/// and peaks as linear sample values
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain_db: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain_db: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    /// Reads ReplayGain tags, falling back to the R128 tags of Opus and Vorbis files
+    pub fn from_tags(tags: &TagMap) -> ReplayGain {
+        ReplayGain {
+            track_gain_db: parse_gain(tags, &["ReplayGainTrackGain", "REPLAYGAIN_TRACK_GAIN"])
+                .or_else(|| parse_r128_gain(tags, "R128_TRACK_GAIN")),
+            track_peak: parse_peak(tags, &["ReplayGainTrackPeak", "REPLAYGAIN_TRACK_PEAK"]),
+            album_gain_db: parse_gain(tags, &["ReplayGainAlbumGain", "REPLAYGAIN_ALBUM_GAIN"])
+                .or_else(|| parse_r128_gain(tags, "R128_ALBUM_GAIN")),
+            album_peak: parse_peak(tags, &["ReplayGainAlbumPeak", "REPLAYGAIN_ALBUM_PEAK"]),
+        }
+    }
+}
+
+impl NormalizationSettings {
+    /// Linear gain to apply to a track. `in_album` tells the auto mode whether
+    /// the track is played as part of its album. Tracks without loudness tags
+    /// are left as they are.
+    pub fn gain(&self, replay_gain: &ReplayGain, in_album: bool) -> f32 {
+        let use_album = match self.mode {
+            NormalizationMode::Off => return 1.0,
+            NormalizationMode::Track => false,
+            NormalizationMode::Album => true,
+            NormalizationMode::Auto => in_album,
+        };
+
+        let track = (replay_gain.track_gain_db, replay_gain.track_peak);
+        let album = (replay_gain.album_gain_db, replay_gain.album_peak);
+        let (gain_db, peak) = match (use_album, album, track) {
+            (true, (Some(gain_db), peak), _) => (gain_db, peak),
+            (_, _, (Some(gain_db), peak)) => (gain_db, peak),
+            // only album gain available in track mode
+            (false, (Some(gain_db), peak), _) => (gain_db, peak),
+            _ => return 1.0,
+        };
+
+        let gain = db_to_linear(gain_db + self.preamp_db);
+        match peak {
+            Some(peak) if self.prevent_clipping && peak > 0.0 => gain.min(1.0 / peak),
+            _ => gain,
+        }
+    }
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn find_tag<'a>(tags: &'a TagMap, keys: &[&str]) -> Option<&'a str> {
+    let (key, _) = tags
+        .iter()
+        .find(|(key, _)| keys.iter().any(|wanted| key.eq_ignore_ascii_case(wanted)))?;
+    first_value(tags, key)
+}
+
+/// Parses gains like "-6.54 dB"
+fn parse_gain(tags: &TagMap, keys: &[&str]) -> Option<f32> {
+    find_tag(tags, keys)?.split_whitespace().next()?.parse().ok()
+}
+
+fn parse_peak(tags: &TagMap, keys: &[&str]) -> Option<f32> {
+    find_tag(tags, keys)?.trim().parse().ok()
+}
+
+/// Parses R128 gains, which are Q7.8 fixed point numbers in dB relative to -23 LUFS
+fn parse_r128_gain(tags: &TagMap, key: &str) -> Option<f32> {
+    let q78: i16 = find_tag(tags, &[key])?.trim().parse().ok()?;
+    Some(q78 as f32 / 256.0 + R128_TO_REPLAY_GAIN_DB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tag_map;
+
+    fn settings(mode: NormalizationMode) -> NormalizationSettings {
+        NormalizationSettings {
+            mode,
+            preamp_db: 0.0,
+            prevent_clipping: false,
+        }
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn test_reads_replay_gain_tags() {
+        let replay_gain = ReplayGain::from_tags(&tag_map(&[
+            ("ReplayGainTrackGain", &["-6.54 dB"]),
+            ("ReplayGainTrackPeak", &["0.988"]),
+            ("ReplayGainAlbumGain", &["+1.5 dB"]),
+            ("ReplayGainAlbumPeak", &["1.2"]),
+        ]));
+        assert_eq!(
+            replay_gain,
+            ReplayGain {
+                track_gain_db: Some(-6.54),
+                track_peak: Some(0.988),
+                album_gain_db: Some(1.5),
+                album_peak: Some(1.2),
+            }
+        );
+    }
+
+    #[test]
+    fn test_reads_r128_tags() {
+        // -2.5 dB relative to -23 LUFS is +2.5 dB relative to -18 LUFS
+        let replay_gain = ReplayGain::from_tags(&tag_map(&[("R128_TRACK_GAIN", &["-640"])]));
+        assert_close(replay_gain.track_gain_db.unwrap(), 2.5);
+        assert_eq!(replay_gain.track_peak, None);
+    }
+
+    #[test]
+    fn test_replay_gain_takes_precedence_over_r128() {
+        let replay_gain = ReplayGain::from_tags(&tag_map(&[
+            ("REPLAYGAIN_TRACK_GAIN", &["-3 dB"]),
+            ("R128_TRACK_GAIN", &["0"]),
+        ]));
+        assert_eq!(replay_gain.track_gain_db, Some(-3.0));
+    }
+
+    #[test]
+    fn test_modes() {
+        let replay_gain = ReplayGain {
+            track_gain_db: Some(-6.0),
+            track_peak: None,
+            album_gain_db: Some(-12.0),
+            album_peak: None,
+        };
+        assert_eq!(settings(NormalizationMode::Off).gain(&replay_gain, true), 1.0);
+        assert_close(settings(NormalizationMode::Track).gain(&replay_gain, true), 0.501187);
+        assert_close(settings(NormalizationMode::Album).gain(&replay_gain, false), 0.251189);
+        assert_close(settings(NormalizationMode::Auto).gain(&replay_gain, true), 0.251189);
+        assert_close(settings(NormalizationMode::Auto).gain(&replay_gain, false), 0.501187);
+    }
+
+    #[test]
+    fn test_falls_back_to_available_gain() {
+        let track_only = ReplayGain {
+            track_gain_db: Some(-6.0),
+            ..Default::default()
+        };
+        assert_close(settings(NormalizationMode::Album).gain(&track_only, true), 0.501187);
+
+        let album_only = ReplayGain {
+            album_gain_db: Some(-6.0),
+            ..Default::default()
+        };
+        assert_close(settings(NormalizationMode::Track).gain(&album_only, false), 0.501187);
+
+        assert_eq!(settings(NormalizationMode::Track).gain(&ReplayGain::default(), false), 1.0);
+    }
+
+    #[test]
+    fn test_preamp_and_clipping_prevention() {
+        let replay_gain = ReplayGain {
+            track_gain_db: Some(0.0),
+            track_peak: Some(0.5),
+            ..Default::default()
+        };
+        let mut settings = settings(NormalizationMode::Track);
+        settings.preamp_db = 12.0;
+        assert_close(settings.gain(&replay_gain, false), 3.981072);
+
+        // a peak of 0.5 allows at most twice the level
+        settings.prevent_clipping = true;
+        assert_close(settings.gain(&replay_gain, false), 2.0);
+    }
+}
@@ -0,0 +1,28 @@
+use crate::decoder::decoder_commands::DecoderCommand;
+use crate::player::tag_cache::TagCache;
+use cpal::Stream;
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+
+/// Handles to the decoder thread and output stream of the currently loaded track
+#[derive(Default)]
+pub struct Pipeline {
+    pub decoder_handle: Option<JoinHandle<()>>,
+    pub decoder_command_sender: Option<Sender<DecoderCommand>>,
+    pub stream: Option<Stream>,
+    // incremented on every load so that end-of-stream notifications of
+    // previously loaded tracks can be told apart from the current one
+    pub generation: u64,
+    // id of the selected output device, None for the default device
+    pub output_device: Option<String>,
+    // sample rate and channels the output stream was opened with
+    pub output_format: Option<(u32, u16)>,
+    // track the decoder is playing and its normalization gain
+    pub current_track: Option<String>,
+    pub current_gain: f32,
+    // track handed to the decoder to continue with gaplessly and its normalization gain
+    pub next_track: Option<String>,
+    pub next_gain: f32,
+    // tags of these tracks and the one after, to pick crossfades and album gain
+    pub track_tags: TagCache,
+}
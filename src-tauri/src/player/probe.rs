@@ -20,11 +20,17 @@ pub fn probe_audio_file(path: &str) -> Result<ProbeResult, Error> {
         }
     }
 
+    // gapless mode makes the format readers trim encoder delay and padding
+    let format_options = FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    };
+
     // probe the media source for format
     let probed = get_probe().format(
         &hint,
         mss,
-        &FormatOptions::default(),
+        &format_options,
         &MetadataOptions::default(),
     )?;
     Ok(probed)
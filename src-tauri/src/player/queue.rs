@@ -0,0 +1,237 @@
+/// Backend-owned play queue. Holds the paths of the queued tracks and the index
+/// of the track that is currently playing (if it is part of the queue).
+#[derive(Default)]
+pub struct PlayQueue {
+    tracks: Vec<String>,
+    current_index: Option<usize>,
+}
+
+/// Payload of the `queue:changed` event
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct QueueSnapshot {
+    pub tracks: Vec<String>,
+    pub current_index: Option<usize>,
+}
+
+impl PlayQueue {
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            tracks: self.tracks.clone(),
+            current_index: self.current_index,
+        }
+    }
+
+    /// Returns the track `advance()` would step to
+    pub fn peek_next(&self) -> Option<&String> {
+        self.peek_ahead(1)
+    }
+
+    /// Returns the track the given number of steps after the current one
+    pub fn peek_ahead(&self, steps: usize) -> Option<&String> {
+        self.tracks.get(self.current_index? + steps)
+    }
+
+    /// Appends paths to the end of the queue
+    pub fn enqueue(&mut self, paths: Vec<String>) {
+        self.tracks.extend(paths);
+    }
+
+    /// Inserts paths before the given index. Indices past the end append.
+    pub fn insert(&mut self, index: usize, paths: Vec<String>) {
+        let index = index.min(self.tracks.len());
+        let inserted = paths.len();
+        self.tracks.splice(index..index, paths);
+
+        if let Some(current) = self.current_index {
+            if current >= index {
+                self.current_index = Some(current + inserted);
+            }
+        }
+    }
+
+    /// Removes the track at the given index. Removing the current track detaches
+    /// the queue from playback, so the next `next()` starts at the beginning.
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index >= self.tracks.len() {
+            return false;
+        }
+        self.tracks.remove(index);
+
+        self.current_index = match self.current_index {
+            Some(current) if current == index => None,
+            Some(current) if current > index => Some(current - 1),
+            other => other,
+        };
+        true
+    }
+
+    /// Moves the track at `from` so that it ends up at index `to`
+    pub fn move_track(&mut self, from: usize, to: usize) -> bool {
+        if from >= self.tracks.len() || to >= self.tracks.len() {
+            return false;
+        }
+        let track = self.tracks.remove(from);
+        self.tracks.insert(to, track);
+
+        if let Some(current) = self.current_index {
+            self.current_index = Some(if current == from {
+                to
+            } else if from < current && current <= to {
+                current - 1
+            } else if to <= current && current < from {
+                current + 1
+            } else {
+                current
+            });
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+        self.current_index = None;
+    }
+
+    /// Makes the track at the given index the current one
+    pub fn select(&mut self, index: usize) -> Option<String> {
+        let track = self.tracks.get(index)?.clone();
+        self.current_index = Some(index);
+        Some(track)
+    }
+
+    /// Points the current index at the first occurrence of the path, or detaches
+    /// the queue if the path is not queued
+    pub fn select_path(&mut self, path: &str) {
+        self.current_index = self.tracks.iter().position(|track| track == path);
+    }
+
+    /// Steps to the next track. A detached queue starts from the beginning.
+    pub fn next(&mut self) -> Option<String> {
+        let next_index = match self.current_index {
+            Some(current) => current + 1,
+            None => 0,
+        };
+        self.select(next_index)
+    }
+
+    /// Steps to the previous track, staying on the first one
+    pub fn previous(&mut self) -> Option<String> {
+        let current = self.current_index?;
+        self.select(current.saturating_sub(1))
+    }
+
+    /// Steps to the next track after the current one finished playing. Unlike
+    /// `next()`, a detached queue does not start playing on its own.
+    pub fn advance(&mut self) -> Option<String> {
+        self.current_index?;
+        self.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_with(tracks: &[&str]) -> PlayQueue {
+        let mut queue = PlayQueue::default();
+        queue.enqueue(tracks.iter().map(|t| t.to_string()).collect());
+        queue
+    }
+
+    #[test]
+    fn test_next_and_previous() {
+        let mut queue = queue_with(&["a", "b", "c"]);
+
+        // a detached queue starts at the first track
+        assert_eq!(queue.next(), Some("a".to_string()));
+        assert_eq!(queue.next(), Some("b".to_string()));
+        assert_eq!(queue.next(), Some("c".to_string()));
+        assert_eq!(queue.next(), None);
+        assert_eq!(queue.snapshot().current_index, Some(2));
+
+        assert_eq!(queue.previous(), Some("b".to_string()));
+        assert_eq!(queue.previous(), Some("a".to_string()));
+        assert_eq!(queue.previous(), Some("a".to_string()));
+    }
+
+    #[test]
+    fn test_advance_only_when_attached() {
+        let mut queue = queue_with(&["a", "b"]);
+        assert_eq!(queue.advance(), None);
+
+        queue.select_path("a");
+        assert_eq!(queue.peek_next(), Some(&"b".to_string()));
+        assert_eq!(queue.advance(), Some("b".to_string()));
+        assert_eq!(queue.peek_next(), None);
+        assert_eq!(queue.advance(), None);
+    }
+
+    #[test]
+    fn test_peek_ahead() {
+        let mut queue = queue_with(&["a", "b", "c"]);
+        assert_eq!(queue.peek_ahead(1), None);
+
+        queue.select_path("a");
+        assert_eq!(queue.peek_ahead(2), Some(&"c".to_string()));
+        assert_eq!(queue.peek_ahead(3), None);
+    }
+
+    #[test]
+    fn test_insert_before_current_shifts_index() {
+        let mut queue = queue_with(&["a", "b"]);
+        queue.select(1);
+
+        queue.insert(0, vec!["x".to_string(), "y".to_string()]);
+
+        assert_eq!(queue.snapshot().tracks, vec!["x", "y", "a", "b"]);
+        assert_eq!(queue.snapshot().current_index, Some(3));
+    }
+
+    #[test]
+    fn test_remove_adjusts_current_index() {
+        let mut queue = queue_with(&["a", "b", "c"]);
+        queue.select(2);
+
+        assert!(queue.remove(0));
+        assert_eq!(queue.snapshot().current_index, Some(1));
+
+        assert!(queue.remove(1));
+        assert_eq!(queue.snapshot().current_index, None);
+
+        assert!(!queue.remove(5));
+    }
+
+    #[test]
+    fn test_move_track_keeps_current_track() {
+        let mut queue = queue_with(&["a", "b", "c", "d"]);
+        queue.select(1);
+
+        // move current track
+        assert!(queue.move_track(1, 3));
+        assert_eq!(queue.snapshot().tracks, vec!["a", "c", "d", "b"]);
+        assert_eq!(queue.snapshot().current_index, Some(3));
+
+        // move another track across the current one
+        assert!(queue.move_track(0, 3));
+        assert_eq!(queue.snapshot().tracks, vec!["c", "d", "b", "a"]);
+        assert_eq!(queue.snapshot().current_index, Some(2));
+
+        assert!(queue.move_track(3, 0));
+        assert_eq!(queue.snapshot().tracks, vec!["a", "c", "d", "b"]);
+        assert_eq!(queue.snapshot().current_index, Some(3));
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut queue = queue_with(&["a", "b"]);
+        queue.select(0);
+        queue.clear();
+        assert_eq!(
+            queue.snapshot(),
+            QueueSnapshot {
+                tracks: vec![],
+                current_index: None
+            }
+        );
+    }
+}
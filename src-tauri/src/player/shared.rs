@@ -1,8 +1,27 @@
+use crate::decoder::crossfade::CrossfadeSettings;
+use crate::player::normalization::NormalizationSettings;
+
 pub enum AudioPlayerCommand {
     LoadAndPlay(String), // path to audio file
     TogglePlayback,
     VolumeChange(f32), // volume between 0.0 and 1.0
     Seek(f64),         // position in seconds
+    Enqueue(Vec<String>),                // paths to append to the queue
+    InsertIntoQueue(usize, Vec<String>), // queue index, paths to insert before it
+    RemoveFromQueue(usize),              // queue index
+    MoveInQueue(usize, usize),           // from queue index, to queue index
+    ClearQueue,
+    PlayQueueIndex(usize), // queue index
+    Next,
+    Previous,
+    SetNextTrack(Option<String>), // path of the track to continue with gaplessly
+    SetCrossfade(Option<CrossfadeSettings>), // crossfade between tracks, None for hard cuts
+    SetNormalization(NormalizationSettings), // ReplayGain mode, preamp and clipping prevention
+    SetOutputDevice(Option<String>), // output device id, None for the default device
+    OutputDeviceLost(u64), // sent by the output stream when its device disappeared, carries the load generation
+    TrackFinished(u64), // sent by the decoder at end of stream, carries the load generation
+    TrackAdvanced(u64, String), // sent by the decoder after a gapless transition or crossfade to the given path
+    Shutdown, // sent when the app exits, stops the decoder and ends the player thread
 }
 
 
@@ -13,5 +32,5 @@ pub struct PlaybackState {
     pub current_position_samples: u64,
     pub sample_rate: u32,
     pub needs_buffer_clear: bool, // buffer needs to be cleared after seeking
+    pub frames_until_track_switch: Option<u64>, // frames of the previous track still buffered after a gapless transition
 }
-
@@ -0,0 +1,70 @@
+use crate::tags::multi_value::TagMap;
+use crate::tags::reading_tags::read_audio_file_properties;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Tags of the current and upcoming tracks, read once per path instead of on
+/// every queue change, skip and track advance
+#[derive(Default)]
+pub struct TagCache {
+    tags: HashMap<String, Rc<TagMap>>,
+}
+
+impl TagCache {
+    /// The tags of a track, empty if the file can't be read
+    pub fn get(&mut self, path: &str) -> Rc<TagMap> {
+        self.tags
+            .entry(path.to_string())
+            .or_insert_with(|| Rc::new(read_tags(path)))
+            .clone()
+    }
+
+    /// Forgets the tags of every track but these
+    pub fn retain<'a>(&mut self, paths: impl IntoIterator<Item = &'a str>) {
+        let paths: HashSet<&str> = paths.into_iter().collect();
+        self.tags.retain(|path, _| paths.contains(path.as_str()));
+    }
+}
+
+fn read_tags(path: &str) -> TagMap {
+    read_audio_file_properties(Path::new(path))
+        .map(|properties| properties.tags)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::writing_tags::write_tags_to_file;
+    use crate::test_support::tagged_song;
+    use tempfile::tempdir;
+
+    fn album(title: &str) -> TagMap {
+        TagMap::from([("AlbumTitle".to_string(), vec![title.to_string()])])
+    }
+
+    #[test]
+    fn test_reads_tags_once_until_forgotten() {
+        let dir = tempdir().unwrap();
+        let path = tagged_song(&dir.path().join("song.mp3"), &album("First"));
+        let path = path.to_string_lossy().to_string();
+
+        let mut cache = TagCache::default();
+        assert_eq!(cache.get(&path)["AlbumTitle"], ["First"]);
+
+        write_tags_to_file(Path::new(&path), &album("Second")).unwrap();
+        assert_eq!(cache.get(&path)["AlbumTitle"], ["First"]);
+        cache.retain([path.as_str()]);
+        assert_eq!(cache.get(&path)["AlbumTitle"], ["First"]);
+
+        cache.retain(None);
+        assert_eq!(cache.get(&path)["AlbumTitle"], ["Second"]);
+    }
+
+    #[test]
+    fn test_unreadable_file_has_no_tags() {
+        let mut cache = TagCache::default();
+        assert!(cache.get("/nonexistent/file.mp3").is_empty());
+    }
+}
@@ -1,18 +1,30 @@
+use crate::audio::output_devices::{
+    load_output_device_settings, output_device_settings_path, save_output_device_settings,
+    OutputDeviceChanged, OutputDeviceSettings,
+};
+use crate::decoder::crossfade::CrossfadeSettings;
+use crate::player::album_order::is_consecutive_album_track;
 use crate::player::commands::change_volume::change_volume;
-use crate::player::commands::load_and_play;
+use crate::player::commands::crossfade_to::crossfade_to;
+use crate::player::commands::load_and_play::{self, PlaybackStart};
 use crate::player::commands::seek::seek;
+use crate::player::commands::set_next_track::set_next_track;
+use crate::player::commands::switch_output_device::switch_output_device;
 use crate::player::commands::toggle_playback::toggle_playback;
+use crate::player::normalization::{NormalizationMode, NormalizationSettings, ReplayGain};
+use crate::player::pipeline::Pipeline;
+use crate::player::queue::PlayQueue;
 use crate::player::shared::{AudioPlayerCommand, PlaybackState};
-use cpal::Stream;
+use crate::player::tag_cache::TagCache;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::thread::JoinHandle;
-use tauri::AppHandle;
+use tauri::{AppHandle, Emitter};
 use crate::decoder::decoder_commands::DecoderCommand;
 use crate::player::threads::position_updater_thread::start_position_updater_thread;
 
 pub fn player_thread(
     receiver: Receiver<AudioPlayerCommand>,
+    sender: Sender<AudioPlayerCommand>,
     app_handle: Arc<AppHandle>,
 ) {
     let state = Arc::new(Mutex::new(PlaybackState {
@@ -22,39 +34,187 @@ pub fn player_thread(
         current_position_samples: 0,
         sample_rate: 48000,
         needs_buffer_clear: false,
+        frames_until_track_switch: None,
     }));
 
     // spawn position updater thread
     let state_clone = state.clone();
-    start_position_updater_thread(state_clone, app_handle);
-
-    let mut decoder_handle: Option<JoinHandle<_>> = None;
-    let mut decoder_command_sender: Option<Sender<DecoderCommand>> = None;
-    let mut stream: Option<Stream> = None;
-
-    // command loop
-    loop {
-        match receiver.recv() {
-            Ok(AudioPlayerCommand::LoadAndPlay(path)) => {
-                load_and_play::load_and_play(
-                    &state,
-                    &mut decoder_handle,
-                    &mut decoder_command_sender,
-                    &mut stream,
-                    &path,
-                );
-            }
-
-            Ok(AudioPlayerCommand::TogglePlayback) => {
+    start_position_updater_thread(state_clone, app_handle.clone());
+
+    let mut pipeline = Pipeline::default();
+    let output_device_settings_path = output_device_settings_path(&app_handle);
+    if let Some(path) = &output_device_settings_path {
+        pipeline.output_device = load_output_device_settings(path).device_id;
+    }
+    let mut queue = PlayQueue::default();
+    // next track set explicitly, takes precedence over the queue
+    let mut manual_next_track: Option<String> = None;
+    let mut settings = PlayerSettings::default();
+
+    // command loop, until the app exits
+    for command in receiver.iter() {
+        match command {
+            AudioPlayerCommand::LoadAndPlay(path) => {
+                manual_next_track = None;
+                queue.select_path(&path);
+                emit_queue(&app_handle, &queue);
+                let following_track = queue.peek_next();
+                play_track(&state, &mut pipeline, &sender, &path, following_track, &settings);
+                refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+            }
+
+            AudioPlayerCommand::TogglePlayback => {
                 toggle_playback(&state);
             }
-            Ok(AudioPlayerCommand::VolumeChange(volume)) => {
+            AudioPlayerCommand::VolumeChange(volume) => {
                 change_volume(&state, volume);
             }
-            Ok(AudioPlayerCommand::Seek(position_seconds)) => {
-                seek(&state, &mut decoder_command_sender, position_seconds);
+            AudioPlayerCommand::Seek(position_seconds) => {
+                seek(&state, &mut pipeline.decoder_command_sender, position_seconds);
+            }
+            AudioPlayerCommand::Enqueue(paths) => {
+                queue.enqueue(paths);
+                emit_queue(&app_handle, &queue);
+                refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+            }
+            AudioPlayerCommand::InsertIntoQueue(index, paths) => {
+                queue.insert(index, paths);
+                emit_queue(&app_handle, &queue);
+                refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+            }
+            AudioPlayerCommand::RemoveFromQueue(index) => {
+                if queue.remove(index) {
+                    emit_queue(&app_handle, &queue);
+                    refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+                }
+            }
+            AudioPlayerCommand::MoveInQueue(from, to) => {
+                if queue.move_track(from, to) {
+                    emit_queue(&app_handle, &queue);
+                    refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+                }
+            }
+            AudioPlayerCommand::ClearQueue => {
+                queue.clear();
+                emit_queue(&app_handle, &queue);
+                refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+            }
+            AudioPlayerCommand::PlayQueueIndex(index) => {
+                manual_next_track = None;
+                let track = queue.select(index);
+                play_queue_track(&state, &mut pipeline, &sender, &app_handle, &queue, track, &settings);
             }
-            Err(_) => {
+            AudioPlayerCommand::Next => {
+                manual_next_track = None;
+                let track = queue.next();
+                play_queue_track(&state, &mut pipeline, &sender, &app_handle, &queue, track, &settings);
+            }
+            AudioPlayerCommand::Previous => {
+                manual_next_track = None;
+                let track = queue.previous();
+                play_queue_track(&state, &mut pipeline, &sender, &app_handle, &queue, track, &settings);
+            }
+            AudioPlayerCommand::SetNextTrack(path) => {
+                manual_next_track = path;
+                refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+            }
+            AudioPlayerCommand::SetCrossfade(crossfade) => {
+                settings.crossfade = crossfade.filter(|crossfade| crossfade.duration_seconds > 0.0);
+                // prepare the next track again so the decoder picks up the new settings
+                set_next_track(&mut pipeline, None, None, 1.0);
+                refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+            }
+            AudioPlayerCommand::SetNormalization(normalization) => {
+                settings.normalization = normalization;
+                if let Some(current_track) = pipeline.current_track.clone() {
+                    let following_track = manual_next_track.as_ref().or(queue.peek_next());
+                    let gain = normalization_gain(
+                        &mut pipeline.track_tags,
+                        &normalization,
+                        &current_track,
+                        None,
+                        following_track,
+                    );
+                    pipeline.current_gain = gain;
+                    if let Some(decoder_command_sender) = &pipeline.decoder_command_sender {
+                        let _ = decoder_command_sender.send(DecoderCommand::SetGain(gain));
+                    }
+                }
+                set_next_track(&mut pipeline, None, None, 1.0);
+                refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+            }
+            AudioPlayerCommand::SetOutputDevice(device_id) => {
+                pipeline.output_device = device_id.clone();
+                if let Some(path) = &output_device_settings_path {
+                    let settings = OutputDeviceSettings {
+                        device_id: device_id.clone(),
+                    };
+                    if let Err(e) = save_output_device_settings(path, &settings) {
+                        eprintln!("Failed to save output device: {}", e);
+                    }
+                }
+                switch_output_device(&state, &mut pipeline, &sender);
+                refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+                emit_output_device(&app_handle, device_id, false);
+            }
+            AudioPlayerCommand::OutputDeviceLost(generation) => {
+                if generation != pipeline.generation {
+                    continue;
+                }
+                eprintln!("Output device lost, falling back to the default device");
+                // the saved choice is kept, so the device is used again after a restart
+                pipeline.output_device = None;
+                switch_output_device(&state, &mut pipeline, &sender);
+                refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+                emit_output_device(&app_handle, None, true);
+            }
+            AudioPlayerCommand::TrackFinished(generation) => {
+                // ignore notifications of tracks that were replaced in the meantime
+                if generation != pipeline.generation {
+                    continue;
+                }
+                let track = match manual_next_track.take() {
+                    Some(track) => {
+                        queue.select_path(&track);
+                        Some(track)
+                    }
+                    None => queue.advance(),
+                };
+                match track {
+                    Some(track) => play_queue_track(
+                        &state,
+                        &mut pipeline,
+                        &sender,
+                        &app_handle,
+                        &queue,
+                        Some(track),
+                        &settings,
+                    ),
+                    None => load_and_play::stop(&state, &mut pipeline),
+                }
+            }
+            AudioPlayerCommand::TrackAdvanced(generation, path) => {
+                if generation != pipeline.generation {
+                    continue;
+                }
+                // the decoder continued with the prepared track without reloading
+                if pipeline.next_track.as_ref() == Some(&path) {
+                    pipeline.current_gain = pipeline.next_gain;
+                }
+                pipeline.current_track = Some(path.clone());
+                pipeline.next_track = None;
+                if manual_next_track.as_ref() == Some(&path) {
+                    manual_next_track = None;
+                }
+                if queue.peek_next() == Some(&path) {
+                    queue.advance();
+                } else {
+                    queue.select_path(&path);
+                }
+                emit_queue(&app_handle, &queue);
+                refresh_next_track(&mut pipeline, &manual_next_track, &queue, &settings);
+            }
+            AudioPlayerCommand::Shutdown => {
                 println!("Audio thread shutting down");
                 break;
             }
@@ -67,11 +227,156 @@ pub fn player_thread(
     }
 
     // stop decoder
-    if let Some(decoder_command_sender) = decoder_command_sender {
+    if let Some(decoder_command_sender) = pipeline.decoder_command_sender {
         let _ = decoder_command_sender.send(DecoderCommand::Stop);
     }
 
-    if let Some(decoder_handle) = decoder_handle {
+    if let Some(decoder_handle) = pipeline.decoder_handle {
         let _ = decoder_handle.join();
     }
 }
+
+/// Settings that decide how tracks are played and joined
+#[derive(Default, Clone, Copy)]
+struct PlayerSettings {
+    crossfade: Option<CrossfadeSettings>,
+    normalization: NormalizationSettings,
+}
+
+fn play_queue_track(
+    state: &Arc<Mutex<PlaybackState>>,
+    pipeline: &mut Pipeline,
+    sender: &Sender<AudioPlayerCommand>,
+    app_handle: &AppHandle,
+    queue: &PlayQueue,
+    track: Option<String>,
+    settings: &PlayerSettings,
+) {
+    let Some(track) = track else {
+        return;
+    };
+    emit_queue(app_handle, queue);
+    play_track(state, pipeline, sender, &track, queue.peek_next(), settings);
+    refresh_next_track(pipeline, &None, queue, settings);
+}
+
+/// Crossfades into the track if a crossfade is set and something is playing,
+/// otherwise loads it from scratch. `following_track` is the one expected to
+/// play after it.
+fn play_track(
+    state: &Arc<Mutex<PlaybackState>>,
+    pipeline: &mut Pipeline,
+    sender: &Sender<AudioPlayerCommand>,
+    path: &String,
+    following_track: Option<&String>,
+    settings: &PlayerSettings,
+) {
+    let gain = normalization_gain(
+        &mut pipeline.track_tags,
+        &settings.normalization,
+        path,
+        pipeline.current_track.as_ref(),
+        following_track,
+    );
+    if let Some(crossfade) = crossfade_between(
+        &mut pipeline.track_tags,
+        pipeline.current_track.as_deref(),
+        path,
+        settings.crossfade,
+    ) {
+        if crossfade_to(state, pipeline, path, crossfade, gain) {
+            return;
+        }
+    }
+    load_and_play::load_and_play(state, pipeline, sender, path, gain, PlaybackStart::default());
+}
+
+/// Prepares the explicitly set next track or else the next one in the queue
+/// for a gapless transition or crossfade
+fn refresh_next_track(
+    pipeline: &mut Pipeline,
+    manual_next_track: &Option<String>,
+    queue: &PlayQueue,
+    settings: &PlayerSettings,
+) {
+    let next_track = manual_next_track.as_ref().or(queue.peek_next());
+    let Some(next_track) = next_track else {
+        pipeline.track_tags.retain(pipeline.current_track.as_deref());
+        set_next_track(pipeline, None, None, 1.0);
+        return;
+    };
+    // what follows an explicitly set next track isn't known
+    let following_track = match manual_next_track {
+        Some(_) => None,
+        None => queue.peek_ahead(2),
+    };
+
+    let crossfade = crossfade_between(
+        &mut pipeline.track_tags,
+        pipeline.current_track.as_deref(),
+        next_track,
+        settings.crossfade,
+    );
+    let gain = normalization_gain(
+        &mut pipeline.track_tags,
+        &settings.normalization,
+        next_track,
+        pipeline.current_track.as_ref(),
+        following_track,
+    );
+    let tracks = [pipeline.current_track.as_ref(), Some(next_track), following_track];
+    pipeline.track_tags.retain(tracks.into_iter().flatten().map(String::as_str));
+    set_next_track(pipeline, Some(next_track), crossfade, gain);
+}
+
+/// The crossfade to use between two tracks. Consecutive tracks of the same album
+/// are played gaplessly instead.
+fn crossfade_between(
+    track_tags: &mut TagCache,
+    current_track: Option<&str>,
+    next_track: &str,
+    crossfade: Option<CrossfadeSettings>,
+) -> Option<CrossfadeSettings> {
+    let crossfade = crossfade?;
+    let current_track = current_track?;
+    if is_consecutive_album_track(&track_tags.get(current_track), &track_tags.get(next_track)) {
+        return None;
+    }
+    Some(crossfade)
+}
+
+/// The normalization gain of a track. In auto mode the album gain is used if the
+/// track continues or is continued by its album in order.
+fn normalization_gain(
+    track_tags: &mut TagCache,
+    normalization: &NormalizationSettings,
+    path: &str,
+    previous_track: Option<&String>,
+    following_track: Option<&String>,
+) -> f32 {
+    if normalization.mode == NormalizationMode::Off {
+        return 1.0;
+    }
+    let tags = track_tags.get(path);
+    let in_album = normalization.mode == NormalizationMode::Auto
+        && (previous_track.is_some_and(|previous| {
+            is_consecutive_album_track(&track_tags.get(previous), &tags)
+        }) || following_track.is_some_and(|following| {
+            is_consecutive_album_track(&tags, &track_tags.get(following))
+        }));
+    normalization.gain(&ReplayGain::from_tags(&tags), in_album)
+}
+
+fn emit_queue(app_handle: &AppHandle, queue: &PlayQueue) {
+    _ = app_handle.emit("queue:changed", queue.snapshot());
+}
+
+fn emit_output_device(app_handle: &AppHandle, device_id: Option<String>, fallback: bool) {
+    _ = app_handle.emit(
+        "audio:device-changed",
+        OutputDeviceChanged {
+            device_id,
+            fallback,
+        },
+    );
+}
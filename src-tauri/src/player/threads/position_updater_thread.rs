@@ -57,6 +57,7 @@ mod test {
             current_position_samples: 48000,
             sample_rate: 48000,
             needs_buffer_clear: false,
+            frames_until_track_switch: None,
         }));
         let result = get_audio_position(state.clone());
         assert!(result.is_some());
@@ -73,6 +74,7 @@ mod test {
             current_position_samples: 48000,
             sample_rate: 48000,
             needs_buffer_clear: false,
+            frames_until_track_switch: None,
         }));
         let result = get_audio_position(state.clone());
         assert!(result.is_none());
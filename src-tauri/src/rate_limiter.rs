@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+pub const MAX_RETRIES: u32 = 3;
+
+/// Keeps the requests to a web service at least an interval apart
+pub struct RateLimiter {
+    interval: Duration,
+    // when the last request was sent; held while waiting, so requests queue up
+    last_request: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> RateLimiter {
+        RateLimiter {
+            interval,
+            last_request: Mutex::new(None),
+        }
+    }
+
+    /// Waits until the last request is at least the interval ago
+    pub async fn wait_for_turn(&self) {
+        let mut last_request = self.last_request.lock().await;
+        if let Some(last_request) = *last_request {
+            let elapsed = last_request.elapsed();
+            if elapsed < self.interval {
+                tokio::time::sleep(self.interval - elapsed).await;
+            }
+        }
+        *last_request = Some(Instant::now());
+    }
+
+    /// Sends a request in turn. A 503 answer is retried after its Retry-After
+    /// delay, or else after `retry_delay` doubled with every attempt, up to three
+    /// times. Other answers are for the caller to check.
+    pub async fn send(&self, request: RequestBuilder, retry_delay: Duration) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let retry = request.try_clone().context("The request can't be retried")?;
+            self.wait_for_turn().await;
+            let response = retry.send().await.context("Request failed")?;
+            if response.status() == StatusCode::SERVICE_UNAVAILABLE && attempt < MAX_RETRIES {
+                let delay = retry_after(&response).unwrap_or(retry_delay * 2u32.pow(attempt));
+                println!("{} is busy, retrying in {:?}", response.url(), delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
+            return Ok(response);
+        }
+    }
+}
+
+/// The delay of a `Retry-After` header in seconds
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::advance;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_turns_are_an_interval_apart() {
+        let rate_limiter = Arc::new(RateLimiter::new(Duration::from_millis(50)));
+        let turns = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let turns = turns.clone();
+            async move {
+                for _ in 0..3 {
+                    rate_limiter.wait_for_turn().await;
+                    turns.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        // the first turn is right away
+        advance(Duration::ZERO).await;
+        assert_eq!(turns.load(Ordering::SeqCst), 1);
+        advance(Duration::from_millis(49)).await;
+        assert_eq!(turns.load(Ordering::SeqCst), 1);
+        advance(Duration::from_millis(1)).await;
+        assert_eq!(turns.load(Ordering::SeqCst), 2);
+        advance(Duration::from_millis(50)).await;
+        assert_eq!(turns.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::acoustid::AcoustIdSettings;
+use crate::cover_art_archive::CoverArtArchiveSettings;
+use crate::musicbrainz_client::MusicBrainzSettings;
+use crate::musicbrainz_scoring::ScoringSettings;
+use anyhow::{bail, Context, Error, Result};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tauri::{AppHandle, Manager};
+
+/// Settings of the online services, kept apart from the library database
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct ServiceSettings {
+    pub musicbrainz: MusicBrainzSettings,
+    pub musicbrainz_scoring: ScoringSettings,
+    pub acoustid: AcoustIdSettings,
+    pub cover_art_archive: CoverArtArchiveSettings,
+}
+
+pub fn service_settings_path(app_handle: &AppHandle) -> Option<PathBuf> {
+    app_handle
+        .path()
+        .app_config_dir()
+        .map(|dir| dir.join("online_services.json"))
+        .ok()
+}
+
+pub fn load_service_settings(path: &Path) -> ServiceSettings {
+    let Ok(json) = fs::read_to_string(path) else {
+        return ServiceSettings::default();
+    };
+    serde_json::from_str(&json).unwrap_or_else(|e| {
+        eprintln!("Failed to read online service settings: {}", e);
+        ServiceSettings::default()
+    })
+}
+
+pub fn save_service_settings(path: &Path, settings: &ServiceSettings) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(settings)?)?;
+    Ok(())
+}
+
+/// The settings of one service client
+pub trait ClientSettings: Clone {
+    fn validate(&self) -> Result<()>;
+}
+
+/// Checks that a setting is an http or https URL, `name` as in "base URL"
+pub fn validate_service_url(name: &str, url: &str) -> Result<()> {
+    let parsed = Url::parse(url).with_context(|| format!("Invalid {}: {}", name, url))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        bail!("The {} has to be http or https: {}", name, url);
+    }
+    Ok(())
+}
+
+/// The settings a client is using, which can change while it is in use
+pub struct SharedSettings<T: ClientSettings>(RwLock<T>);
+
+impl<T: ClientSettings> SharedSettings<T> {
+    pub fn new(settings: T) -> Result<SharedSettings<T>> {
+        settings.validate()?;
+        Ok(SharedSettings(RwLock::new(settings)))
+    }
+
+    pub fn get(&self) -> T {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Replaces the settings, unless they are invalid
+    pub fn set(&self, settings: T) -> Result<()> {
+        settings.validate()?;
+        *self.0.write().unwrap() = settings;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_settings_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config").join("online_services.json");
+
+        let mut settings = ServiceSettings::default();
+        settings.musicbrainz.base_url = "http://localhost:5000".to_string();
+        settings.musicbrainz_scoring.preferred_countries = vec!["XW".to_string()];
+        settings.acoustid.api_key = "key".to_string();
+        save_service_settings(&path, &settings).unwrap();
+
+        assert_eq!(load_service_settings(&path), settings);
+    }
+
+    #[test]
+    fn test_missing_or_partial_settings() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("online_services.json");
+        assert_eq!(load_service_settings(&path), ServiceSettings::default());
+
+        // a file from before a setting was added
+        fs::write(&path, r#"{"musicbrainz": {"base_url": "http://localhost:5000"}}"#).unwrap();
+        let settings = load_service_settings(&path);
+        assert_eq!(settings.musicbrainz.base_url, "http://localhost:5000");
+        assert_eq!(settings.musicbrainz.user_agent, MusicBrainzSettings::default().user_agent);
+        assert_eq!(settings.musicbrainz_scoring, ScoringSettings::default());
+        assert_eq!(settings.acoustid, AcoustIdSettings::default());
+
+        fs::write(&path, "not json").unwrap();
+        assert_eq!(load_service_settings(&path), ServiceSettings::default());
+    }
+
+    #[test]
+    fn test_validate_service_url() {
+        assert!(validate_service_url("base URL", "https://musicbrainz.org").is_ok());
+        assert!(validate_service_url("base URL", "http://localhost:5000").is_ok());
+        assert!(validate_service_url("base URL", "ftp://example.com").is_err());
+        assert!(validate_service_url("base URL", "not a url").is_err());
+    }
+
+    #[derive(Clone)]
+    struct Port(u16);
+
+    impl ClientSettings for Port {
+        fn validate(&self) -> Result<()> {
+            if self.0 == 0 {
+                bail!("No port");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_invalid_settings_are_not_taken() {
+        assert!(SharedSettings::new(Port(0)).is_err());
+
+        let settings = SharedSettings::new(Port(80)).unwrap();
+        assert!(settings.set(Port(0)).is_err());
+        assert_eq!(settings.get().0, 80);
+        settings.set(Port(443)).unwrap();
+        assert_eq!(settings.get().0, 443);
+    }
+}
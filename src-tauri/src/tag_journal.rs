@@ -0,0 +1,490 @@
+use crate::tags::cover_art::{read_pictures, write_pictures};
+use crate::tags::multi_value::TagMap;
+use crate::tags::reading_tags::read_primary_tags;
+use crate::tags::writing_tags::write_tags_to_file;
+use anyhow::{anyhow, Context, Result};
+use lofty::picture::{MimeType, Picture, PictureType};
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the oldest batches are dropped when there are more
+const MAX_BATCHES: i64 = 1000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS batches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    description TEXT NOT NULL,
+    time_millis INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS edits (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    batch_id INTEGER NOT NULL REFERENCES batches (id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    tags_before TEXT NOT NULL,
+    tags_after TEXT,
+    with_pictures INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS edit_pictures (
+    edit_id INTEGER NOT NULL REFERENCES edits (id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    picture_type INTEGER NOT NULL,
+    mime_type TEXT,
+    description TEXT,
+    data BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS edits_by_path ON edits (path);
+CREATE INDEX IF NOT EXISTS edits_by_batch_id ON edits (batch_id);
+CREATE INDEX IF NOT EXISTS edit_pictures_by_edit_id ON edit_pictures (edit_id);
+";
+
+const EDIT_COLUMNS: &str = "edits.id, edits.batch_id, edits.path, batches.description, batches.time_millis,
+    edits.with_pictures, edits.tags_before, edits.tags_after";
+
+/// History of tag writes. The tags of a file are saved before it is written, so
+/// that every edit can be reverted, also after a restart.
+///
+/// Only the primary tag is saved: its text items, and its pictures for writes
+/// that change them. Other tag containers, like an ID3v1 or APE tag next to the
+/// ID3v2 tag of an MP3, are not, so stripping or overwriting them can't be undone.
+pub struct TagJournal {
+    connection: Connection,
+}
+
+/// Edits made together, like saving the tag editor or a batch edit
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TagBatch {
+    pub id: i64,
+    pub description: String,
+    pub time_millis: i64,
+    pub paths: Vec<String>,
+}
+
+/// The text items of the primary tag of a file before and after a write.
+/// `tags_after` is None if the write failed.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TagEdit {
+    pub id: i64,
+    pub batch_id: i64,
+    pub path: String,
+    pub description: String,
+    pub time_millis: i64,
+    /// The pictures before the write were saved too, a revert restores them
+    pub with_pictures: bool,
+    pub tags_before: TagMap,
+    pub tags_after: Option<TagMap>,
+}
+
+impl TagJournal {
+    pub fn open(path: &Path) -> Result<TagJournal> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let connection =
+            Connection::open(path).with_context(|| format!("Failed to open tag journal: {}", path.display()))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(TagJournal { connection })
+    }
+
+    /// Starts a batch that the following writes are recorded in
+    pub fn start_batch(&mut self, description: &str) -> Result<i64> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO batches (description, time_millis) VALUES (?1, ?2)",
+            params![description, now_millis()?],
+        )?;
+        let batch_id = transaction.last_insert_rowid();
+        transaction.execute(
+            "DELETE FROM batches WHERE id <= (SELECT MAX(id) FROM batches) - ?1",
+            params![MAX_BATCHES],
+        )?;
+        transaction.commit()?;
+        Ok(batch_id)
+    }
+
+    /// Runs `write` on a file, saving its tags before and after. The tags before
+    /// are saved first, so even a write that fails halfway can be reverted.
+    pub fn write(&mut self, batch_id: i64, path: &Path, write: impl FnOnce() -> Result<()>) -> Result<()> {
+        self.record(batch_id, path, false, write)
+    }
+
+    /// Like `write`, also saving the pictures of the primary tag, for writes that
+    /// embed, replace or remove pictures
+    pub fn write_with_pictures(
+        &mut self,
+        batch_id: i64,
+        path: &Path,
+        write: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        self.record(batch_id, path, true, write)
+    }
+
+    /// Starts a batch with a single write
+    pub fn write_one(&mut self, description: &str, path: &Path, write: impl FnOnce() -> Result<()>) -> Result<()> {
+        let batch_id = self.start_batch(description)?;
+        self.write(batch_id, path, write)
+    }
+
+    /// Starts a batch with a single write that saves the pictures too
+    pub fn write_one_with_pictures(
+        &mut self,
+        description: &str,
+        path: &Path,
+        write: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let batch_id = self.start_batch(description)?;
+        self.write_with_pictures(batch_id, path, write)
+    }
+
+    fn record(
+        &mut self,
+        batch_id: i64,
+        path: &Path,
+        with_pictures: bool,
+        write: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let tags_before = read_primary_tags(path)?;
+        let pictures_before = if with_pictures { read_pictures(path)? } else { Vec::new() };
+
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO edits (batch_id, path, tags_before, with_pictures) VALUES (?1, ?2, ?3, ?4)",
+            params![batch_id, path.to_string_lossy(), serde_json::to_string(&tags_before)?, with_pictures],
+        )?;
+        let edit_id = transaction.last_insert_rowid();
+        for (position, picture) in pictures_before.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO edit_pictures (edit_id, position, picture_type, mime_type, description, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    edit_id,
+                    position as i64,
+                    picture.pic_type().as_u8(),
+                    picture.mime_type().map(|mime_type| mime_type.as_str()),
+                    picture.description(),
+                    picture.data(),
+                ],
+            )?;
+        }
+        transaction.commit()?;
+
+        write()?;
+
+        let tags_after = read_primary_tags(path)?;
+        self.connection.execute(
+            "UPDATE edits SET tags_after = ?1 WHERE id = ?2",
+            params![serde_json::to_string(&tags_after)?, edit_id],
+        )?;
+        Ok(())
+    }
+
+    /// Moves the history of a file along when the file is moved or renamed
+    pub fn rename_path(&mut self, from: &str, to: &str) -> Result<()> {
+        self.connection.execute("UPDATE edits SET path = ?2 WHERE path = ?1", params![from, to])?;
+        Ok(())
+    }
+
+    /// The batches, newest first
+    pub fn batches(&self) -> Result<Vec<TagBatch>> {
+        let mut statement = self.connection.prepare(
+            "SELECT batches.id, batches.description, batches.time_millis, edits.path
+             FROM batches JOIN edits ON edits.batch_id = batches.id
+             ORDER BY batches.id DESC, edits.id",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+
+        let mut batches: Vec<TagBatch> = Vec::new();
+        for row in rows {
+            let (id, description, time_millis, path) = row?;
+            match batches.last_mut() {
+                Some(batch) if batch.id == id => batch.paths.push(path),
+                _ => batches.push(TagBatch {
+                    id,
+                    description,
+                    time_millis,
+                    paths: vec![path],
+                }),
+            }
+        }
+        Ok(batches)
+    }
+
+    /// The edits of a file, newest first
+    pub fn file_history(&self, path: &str) -> Result<Vec<TagEdit>> {
+        self.query_edits("edits.path = ?1 ORDER BY edits.id DESC", params![path])
+    }
+
+    /// The edits of a batch in the order they were made
+    pub fn batch_edits(&self, batch_id: i64) -> Result<Vec<TagEdit>> {
+        self.query_edits("edits.batch_id = ?1 ORDER BY edits.id", params![batch_id])
+    }
+
+    /// Writes the tags a file had before an edit, and its pictures if they were
+    /// saved. The revert is an edit of its own.
+    pub fn revert_edit(&mut self, edit_id: i64) -> Result<()> {
+        let edit = self
+            .query_edits("edits.id = ?1", params![edit_id])?
+            .pop()
+            .ok_or_else(|| anyhow!("No tag edit {}", edit_id))?;
+        let batch_id = self.start_batch(&format!("Revert: {}", edit.description))?;
+        self.revert(batch_id, &edit)
+    }
+
+    /// Reverts every edit of a batch, the last one first. Files that fail do not
+    /// stop the others from being reverted.
+    pub fn revert_batch(&mut self, batch_id: i64) -> Result<()> {
+        let edits = self.batch_edits(batch_id)?;
+        let Some(first_edit) = edits.first() else {
+            return Err(anyhow!("No tag edits in batch {}", batch_id));
+        };
+        let revert_batch_id = self.start_batch(&format!("Revert: {}", first_edit.description))?;
+
+        let mut errors = Vec::new();
+        for edit in edits.iter().rev() {
+            if let Err(e) = self.revert(revert_batch_id, edit) {
+                errors.push(format!("{}: {}", edit.path, e));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(anyhow!("Failed to revert {}", errors.join(", ")));
+        }
+        Ok(())
+    }
+
+    fn revert(&mut self, batch_id: i64, edit: &TagEdit) -> Result<()> {
+        let path = Path::new(&edit.path);
+        if !edit.with_pictures {
+            return self.write(batch_id, path, || write_tags_to_file(path, &edit.tags_before));
+        }
+        let pictures_before = self.pictures_before(edit.id)?;
+        self.write_with_pictures(batch_id, path, || {
+            write_tags_to_file(path, &edit.tags_before)?;
+            write_pictures(path, &pictures_before)
+        })
+    }
+
+    fn pictures_before(&self, edit_id: i64) -> Result<Vec<Picture>> {
+        let mut statement = self.connection.prepare(
+            "SELECT picture_type, mime_type, description, data FROM edit_pictures
+             WHERE edit_id = ?1 ORDER BY position",
+        )?;
+        let rows = statement.query_map(params![edit_id], |row| {
+            Ok(Picture::new_unchecked(
+                PictureType::from_u8(row.get(0)?),
+                row.get::<_, Option<String>>(1)?.map(|mime_type| MimeType::from_str(&mime_type)),
+                row.get(2)?,
+                row.get(3)?,
+            ))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn query_edits(&self, condition: &str, params: impl rusqlite::Params) -> Result<Vec<TagEdit>> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {} FROM edits JOIN batches ON batches.id = edits.batch_id WHERE {}",
+            EDIT_COLUMNS, condition
+        ))?;
+        let rows = statement.query_map(params, edit_row)?;
+
+        let mut edits = Vec::new();
+        for row in rows {
+            let (edit, tags_before, tags_after) = row?;
+            edits.push(TagEdit {
+                tags_before: serde_json::from_str(&tags_before)
+                    .with_context(|| format!("Broken tags of edit {} in the tag journal", edit.id))?,
+                tags_after: tags_after
+                    .map(|tags_after| serde_json::from_str(&tags_after))
+                    .transpose()
+                    .with_context(|| format!("Broken tags of edit {} in the tag journal", edit.id))?,
+                ..edit
+            });
+        }
+        Ok(edits)
+    }
+}
+
+/// An edit with its tags still as JSON
+fn edit_row(row: &Row) -> rusqlite::Result<(TagEdit, String, Option<String>)> {
+    Ok((
+        TagEdit {
+            id: row.get(0)?,
+            batch_id: row.get(1)?,
+            path: row.get(2)?,
+            description: row.get(3)?,
+            time_millis: row.get(4)?,
+            with_pictures: row.get(5)?,
+            tags_before: TagMap::new(),
+            tags_after: None,
+        },
+        row.get(6)?,
+        row.get(7)?,
+    ))
+}
+
+fn now_millis() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::writing_tags::{patch_tags_in_file, TagPatch};
+    use crate::test_support::{tag_map, tagged_song};
+    use tempfile::tempdir;
+
+    fn set_title(title: &str) -> TagPatch {
+        TagPatch {
+            set: TagMap::from([("TrackTitle".to_string(), vec![title.to_string()])]),
+            remove: Vec::new(),
+        }
+    }
+
+    fn title(path: &Path) -> Vec<String> {
+        read_primary_tags(path).unwrap()["TrackTitle"].clone()
+    }
+
+    #[test]
+    fn test_write_is_recorded_and_reverted() {
+        let dir = tempdir().unwrap();
+        let path = tagged_song(&dir.path().join("a.mp3"), &tag_map(&[("TrackTitle", &["Original"])]));
+        let journal_path = dir.path().join("tag_journal.sqlite3");
+
+        let mut journal = TagJournal::open(&journal_path).unwrap();
+        journal
+            .write_one("Edit tags", &path, || patch_tags_in_file(&path, &set_title("Changed")))
+            .unwrap();
+        assert_eq!(title(&path), vec!["Changed"]);
+
+        // survives reopening
+        let mut journal = TagJournal::open(&journal_path).unwrap();
+        let history = journal.file_history(&path.to_string_lossy()).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].description, "Edit tags");
+        assert_eq!(history[0].tags_before["TrackTitle"], vec!["Original"]);
+        assert_eq!(history[0].tags_after.as_ref().unwrap()["TrackTitle"], vec!["Changed"]);
+
+        journal.revert_edit(history[0].id).unwrap();
+        assert_eq!(title(&path), vec!["Original"]);
+
+        // the revert is in the history too, and can be reverted again
+        let history = journal.file_history(&path.to_string_lossy()).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].description, "Revert: Edit tags");
+        journal.revert_edit(history[0].id).unwrap();
+        assert_eq!(title(&path), vec!["Changed"]);
+    }
+
+    #[test]
+    fn test_batch_is_reverted_last_edit_first() {
+        let dir = tempdir().unwrap();
+        let a = tagged_song(&dir.path().join("a.mp3"), &tag_map(&[("TrackTitle", &["Original"])]));
+        let b = tagged_song(&dir.path().join("b.mp3"), &tag_map(&[("TrackTitle", &["Original"])]));
+
+        let mut journal = TagJournal::open(&dir.path().join("tag_journal.sqlite3")).unwrap();
+        let batch_id = journal.start_batch("Batch edit").unwrap();
+        journal.write(batch_id, &a, || patch_tags_in_file(&a, &set_title("First"))).unwrap();
+        journal.write(batch_id, &a, || patch_tags_in_file(&a, &set_title("Second"))).unwrap();
+        journal.write(batch_id, &b, || patch_tags_in_file(&b, &set_title("Third"))).unwrap();
+
+        let batches = journal.batches().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(
+            batches[0].paths,
+            vec![a.to_string_lossy().to_string(), a.to_string_lossy().to_string(), b.to_string_lossy().to_string()]
+        );
+
+        journal.revert_batch(batch_id).unwrap();
+        assert_eq!(title(&a), vec!["Original"]);
+        assert_eq!(title(&b), vec!["Original"]);
+        assert_eq!(journal.batches().unwrap()[0].description, "Revert: Batch edit");
+    }
+
+    #[test]
+    fn test_pictures_are_reverted() {
+        let dir = tempdir().unwrap();
+        let path = tagged_song(&dir.path().join("a.mp3"), &tag_map(&[("TrackTitle", &["Original"])]));
+        let cover = Picture::new_unchecked(PictureType::CoverFront, Some(MimeType::Png), None, vec![1, 2, 3]);
+        write_pictures(&path, std::slice::from_ref(&cover)).unwrap();
+
+        let mut journal = TagJournal::open(&dir.path().join("tag_journal.sqlite3")).unwrap();
+        journal
+            .write_one_with_pictures("Remove picture", &path, || write_pictures(&path, &[]))
+            .unwrap();
+        assert!(read_pictures(&path).unwrap().is_empty());
+
+        let history = journal.file_history(&path.to_string_lossy()).unwrap();
+        assert!(history[0].with_pictures);
+        journal.revert_edit(history[0].id).unwrap();
+        assert_eq!(read_pictures(&path).unwrap(), vec![cover]);
+        assert_eq!(title(&path), vec!["Original"]);
+
+        // a text edit leaves the pictures alone when reverted
+        journal
+            .write_one("Edit tags", &path, || patch_tags_in_file(&path, &set_title("Changed")))
+            .unwrap();
+        let history = journal.file_history(&path.to_string_lossy()).unwrap();
+        assert!(!history[0].with_pictures);
+        journal.revert_edit(history[0].id).unwrap();
+        assert_eq!(read_pictures(&path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_history_moves_with_the_file() {
+        let dir = tempdir().unwrap();
+        let path = tagged_song(&dir.path().join("a.mp3"), &tag_map(&[("TrackTitle", &["Original"])]));
+        let mut journal = TagJournal::open(&dir.path().join("tag_journal.sqlite3")).unwrap();
+        journal
+            .write_one("Edit tags", &path, || patch_tags_in_file(&path, &set_title("Changed")))
+            .unwrap();
+
+        let moved = dir.path().join("b.mp3");
+        fs::rename(&path, &moved).unwrap();
+        journal.rename_path(&path.to_string_lossy(), &moved.to_string_lossy()).unwrap();
+        assert!(journal.file_history(&path.to_string_lossy()).unwrap().is_empty());
+
+        let history = journal.file_history(&moved.to_string_lossy()).unwrap();
+        journal.revert_edit(history[0].id).unwrap();
+        assert_eq!(title(&moved), vec!["Original"]);
+    }
+
+    #[test]
+    fn test_failed_write_keeps_tags_before() {
+        let dir = tempdir().unwrap();
+        let path = tagged_song(&dir.path().join("a.mp3"), &tag_map(&[("TrackTitle", &["Original"])]));
+
+        let mut journal = TagJournal::open(&dir.path().join("tag_journal.sqlite3")).unwrap();
+        let result = journal.write_one("Edit tags", &path, || Err(anyhow!("disk full")));
+        assert!(result.is_err());
+
+        let history = journal.file_history(&path.to_string_lossy()).unwrap();
+        assert_eq!(history[0].tags_before["TrackTitle"], vec!["Original"]);
+        assert_eq!(history[0].tags_after, None);
+    }
+
+    #[test]
+    fn test_oldest_batches_are_dropped() {
+        let dir = tempdir().unwrap();
+        let path = tagged_song(&dir.path().join("a.mp3"), &tag_map(&[("TrackTitle", &["Original"])]));
+
+        let mut journal = TagJournal::open(&dir.path().join("tag_journal.sqlite3")).unwrap();
+        let first_batch_id = journal.start_batch("First").unwrap();
+        journal.write(first_batch_id, &path, || Ok(())).unwrap();
+        for _ in 0..MAX_BATCHES {
+            journal.start_batch("Later").unwrap();
+        }
+
+        assert!(journal.batch_edits(first_batch_id).unwrap().is_empty());
+        assert!(journal.file_history(&path.to_string_lossy()).unwrap().is_empty());
+    }
+}
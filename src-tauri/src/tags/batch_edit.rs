@@ -0,0 +1,372 @@
+use crate::tag_journal::TagJournal;
+use crate::tags::multi_value::TagMap;
+use crate::tags::reading_tags::{item_key_name, read_primary_tags};
+use crate::tags::writing_tags::{parse_item_key, patch_tags_in_file, TagPatch};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// An operation on one key, applied to every file of a batch edit in order
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum FieldOperation {
+    Set { key: String, values: Vec<String> },
+    /// One value per file, in the order of the paths, like a TrackNumber.
+    /// Files with an empty value keep the key as it is.
+    SetPerFile { key: String, values: Vec<String> },
+    Clear { key: String },
+    /// Adds the values a file does not have yet
+    Append { key: String, values: Vec<String> },
+    /// Replaces text in every value; values that become empty are dropped
+    Replace { key: String, find: String, replace: String },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct BatchEdit {
+    pub paths: Vec<String>,
+    pub operations: Vec<FieldOperation>,
+}
+
+/// Sent with the `tags:batch-progress` event after every file
+#[derive(Serialize, Clone, Debug)]
+pub struct BatchEditProgress {
+    pub path: String,
+    pub completed: usize,
+    pub total: usize,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum FileEditStatus {
+    Written,
+    /// The operations did not change the tags, the file was not written
+    Unchanged,
+    Failed { error: String },
+    /// Written, then restored to its tags from before because another file failed
+    RolledBack,
+    /// Not reached because an earlier file failed
+    Skipped,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FileEditResult {
+    pub path: String,
+    #[serde(flatten)]
+    pub status: FileEditStatus,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct BatchEditResult {
+    pub batch_id: i64,
+    pub files: Vec<FileEditResult>,
+    pub rolled_back: bool,
+    /// Set if a file could not be restored after a failure
+    pub rollback_error: Option<String>,
+}
+
+/// Applies the operations to every file as one batch of the tag journal. When a
+/// file fails, the files written before are restored to their tags from before.
+pub fn batch_edit(
+    journal: &mut TagJournal,
+    edit: &BatchEdit,
+    mut on_progress: impl FnMut(BatchEditProgress),
+) -> Result<BatchEditResult> {
+    validate(edit)?;
+
+    let batch_id = journal.start_batch(&format!("Batch edit of {} files", edit.paths.len()))?;
+    let mut result = BatchEditResult {
+        batch_id,
+        files: Vec::new(),
+        rolled_back: false,
+        rollback_error: None,
+    };
+
+    for (index, path) in edit.paths.iter().enumerate() {
+        let status = match edit_file(journal, batch_id, Path::new(path), index, &edit.operations) {
+            Ok(true) => FileEditStatus::Written,
+            Ok(false) => FileEditStatus::Unchanged,
+            Err(e) => {
+                eprintln!("Failed to edit the tags of {}: {}", path, e);
+                FileEditStatus::Failed { error: e.to_string() }
+            }
+        };
+        let failed = matches!(status, FileEditStatus::Failed { .. });
+        result.files.push(FileEditResult {
+            path: path.clone(),
+            status,
+        });
+        on_progress(BatchEditProgress {
+            path: path.clone(),
+            completed: index + 1,
+            total: edit.paths.len(),
+        });
+        if failed {
+            break;
+        }
+    }
+
+    if result.files.iter().any(|file| matches!(file.status, FileEditStatus::Failed { .. })) {
+        roll_back(journal, &mut result)?;
+        for path in &edit.paths[result.files.len()..] {
+            result.files.push(FileEditResult {
+                path: path.clone(),
+                status: FileEditStatus::Skipped,
+            });
+        }
+    }
+    Ok(result)
+}
+
+fn validate(edit: &BatchEdit) -> Result<()> {
+    for operation in &edit.operations {
+        match operation {
+            FieldOperation::SetPerFile { key, values } if values.len() != edit.paths.len() => {
+                bail!("{} has {} values for {} files", key, values.len(), edit.paths.len())
+            }
+            FieldOperation::Replace { key, find, .. } if find.is_empty() => {
+                bail!("Nothing to find in {}", key)
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Writes the edited tags of a file, returns whether they changed
+fn edit_file(
+    journal: &mut TagJournal,
+    batch_id: i64,
+    path: &Path,
+    index: usize,
+    operations: &[FieldOperation],
+) -> Result<bool> {
+    let tags = read_primary_tags(path)?;
+    let mut edited = tags.clone();
+    for operation in operations {
+        apply(operation, &mut edited, index);
+    }
+
+    let patch = diff(&tags, &edited);
+    if patch.set.is_empty() && patch.remove.is_empty() {
+        return Ok(false);
+    }
+    journal.write(batch_id, path, || patch_tags_in_file(path, &patch))?;
+    Ok(true)
+}
+
+fn apply(operation: &FieldOperation, tags: &mut TagMap, index: usize) {
+    match operation {
+        FieldOperation::Set { key, values } => {
+            tags.insert(canonical_key(key), values.clone());
+        }
+        FieldOperation::SetPerFile { key, values } => {
+            if !values[index].is_empty() {
+                tags.insert(canonical_key(key), vec![values[index].clone()]);
+            }
+        }
+        FieldOperation::Clear { key } => {
+            tags.remove(&canonical_key(key));
+        }
+        FieldOperation::Append { key, values } => {
+            let existing = tags.entry(canonical_key(key)).or_default();
+            for value in values {
+                if !existing.contains(value) {
+                    existing.push(value.clone());
+                }
+            }
+        }
+        FieldOperation::Replace { key, find, replace } => {
+            if let Some(values) = tags.get_mut(&canonical_key(key)) {
+                *values = values
+                    .iter()
+                    .map(|value| value.replace(find.as_str(), replace))
+                    .collect();
+            }
+        }
+    }
+    tags.retain(|_, values| {
+        values.retain(|value| !value.is_empty());
+        !values.is_empty()
+    });
+}
+
+/// The key name the tags of a file are read with, like "TrackNumber" for "tracknumber"
+fn canonical_key(key: &str) -> String {
+    item_key_name(&parse_item_key(key))
+}
+
+fn diff(tags: &TagMap, edited: &TagMap) -> TagPatch {
+    TagPatch {
+        set: edited
+            .iter()
+            .filter(|(key, values)| tags.get(*key) != Some(values))
+            .map(|(key, values)| (key.clone(), values.clone()))
+            .collect(),
+        remove: tags.keys().filter(|key| !edited.contains_key(*key)).cloned().collect(),
+    }
+}
+
+fn roll_back(journal: &mut TagJournal, result: &mut BatchEditResult) -> Result<()> {
+    result.rolled_back = true;
+    // nothing to restore if the failed file was the first one and could not be read
+    if journal.batch_edits(result.batch_id)?.is_empty() {
+        return Ok(());
+    }
+    if let Err(e) = journal.revert_batch(result.batch_id) {
+        eprintln!("Failed to roll back batch edit {}: {}", result.batch_id, e);
+        result.rollback_error = Some(e.to_string());
+    }
+    for file in result.files.iter_mut() {
+        if file.status == FileEditStatus::Written {
+            file.status = FileEditStatus::RolledBack;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{tag_map, tagged_song};
+    use tempfile::tempdir;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    /// Copies of a sample song, all tagged with the same album
+    fn album(dir: &Path, count: usize) -> Vec<String> {
+        (1..=count)
+            .map(|number| {
+                let tags = tag_map(&[("AlbumTitle", &["Album"]), ("Genre", &["Rock"])]);
+                let path = tagged_song(&dir.join(format!("{}.mp3", number)), &tags);
+                path.to_string_lossy().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_operations() {
+        let mut tags = tag_map(&[
+            ("TrackTitle", &["Title (Remastered)"]),
+            ("Genre", &["Rock"]),
+            ("Comment", &["Ripped"]),
+        ]);
+        let operations = [
+            FieldOperation::SetPerFile {
+                key: "tracknumber".to_string(),
+                values: strings(&["1", "2"]),
+            },
+            FieldOperation::Append {
+                key: "Genre".to_string(),
+                values: strings(&["Rock", "Pop"]),
+            },
+            FieldOperation::Replace {
+                key: "TrackTitle".to_string(),
+                find: " (Remastered)".to_string(),
+                replace: String::new(),
+            },
+            FieldOperation::Clear {
+                key: "comment".to_string(),
+            },
+            FieldOperation::Set {
+                key: "AlbumTitle".to_string(),
+                values: strings(&["Album"]),
+            },
+        ];
+        for operation in &operations {
+            apply(operation, &mut tags, 1);
+        }
+
+        assert_eq!(
+            tags,
+            tag_map(&[
+                ("TrackTitle", &["Title"]),
+                ("TrackNumber", &["2"]),
+                ("Genre", &["Rock", "Pop"]),
+                ("AlbumTitle", &["Album"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_batch_edit_writes_every_file() {
+        let dir = tempdir().unwrap();
+        let paths = album(dir.path(), 3);
+        let mut journal = TagJournal::open(&dir.path().join("tag_journal.sqlite3")).unwrap();
+
+        let edit = BatchEdit {
+            paths: paths.clone(),
+            operations: vec![
+                FieldOperation::SetPerFile {
+                    key: "TrackNumber".to_string(),
+                    values: strings(&["1", "2", "3"]),
+                },
+                FieldOperation::Set {
+                    key: "Genre".to_string(),
+                    values: strings(&["Rock"]),
+                },
+            ],
+        };
+        let mut progress = Vec::new();
+        let result = batch_edit(&mut journal, &edit, |p| progress.push(p.completed)).unwrap();
+
+        assert_eq!(progress, vec![1, 2, 3]);
+        assert!(!result.rolled_back);
+        for (index, path) in paths.iter().enumerate() {
+            assert_eq!(result.files[index].status, FileEditStatus::Written);
+            let tags = read_primary_tags(Path::new(path)).unwrap();
+            assert_eq!(tags["TrackNumber"], vec![(index + 1).to_string()]);
+            assert_eq!(tags["AlbumTitle"], vec!["Album"]);
+        }
+        assert_eq!(journal.batch_edits(result.batch_id).unwrap().len(), 3);
+
+        // a second run changes nothing
+        let result = batch_edit(&mut journal, &edit, |_| {}).unwrap();
+        assert!(result.files.iter().all(|file| file.status == FileEditStatus::Unchanged));
+    }
+
+    #[test]
+    fn test_failed_file_rolls_back_the_batch() {
+        let dir = tempdir().unwrap();
+        let mut paths = album(dir.path(), 2);
+        paths.insert(1, dir.path().join("missing.mp3").to_string_lossy().to_string());
+        let mut journal = TagJournal::open(&dir.path().join("tag_journal.sqlite3")).unwrap();
+
+        let edit = BatchEdit {
+            paths: paths.clone(),
+            operations: vec![FieldOperation::Set {
+                key: "AlbumTitle".to_string(),
+                values: strings(&["Other Album"]),
+            }],
+        };
+        let result = batch_edit(&mut journal, &edit, |_| {}).unwrap();
+
+        assert!(result.rolled_back);
+        assert_eq!(result.rollback_error, None);
+        assert_eq!(result.files[0].status, FileEditStatus::RolledBack);
+        assert!(matches!(result.files[1].status, FileEditStatus::Failed { .. }));
+        assert_eq!(result.files[2].status, FileEditStatus::Skipped);
+        for path in [&paths[0], &paths[2]] {
+            let tags = read_primary_tags(Path::new(path)).unwrap();
+            assert_eq!(tags["AlbumTitle"], vec!["Album"]);
+        }
+    }
+
+    #[test]
+    fn test_per_file_values_must_match_the_paths() {
+        let dir = tempdir().unwrap();
+        let paths = album(dir.path(), 2);
+        let mut journal = TagJournal::open(&dir.path().join("tag_journal.sqlite3")).unwrap();
+
+        let edit = BatchEdit {
+            paths: paths.clone(),
+            operations: vec![FieldOperation::SetPerFile {
+                key: "TrackNumber".to_string(),
+                values: strings(&["1"]),
+            }],
+        };
+        assert!(batch_edit(&mut journal, &edit, |_| {}).is_err());
+        assert!(journal.batches().unwrap().is_empty());
+    }
+}
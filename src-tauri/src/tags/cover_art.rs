@@ -0,0 +1,375 @@
+use crate::tags::reading_tags::{read_cover, read_tagged_file};
+use crate::tags::writing_tags::{primary_tag_mut, save_primary_tag};
+use anyhow::{anyhow, bail, Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{ImageFormat, ImageReader};
+use lofty::file::TaggedFileExt;
+use lofty::picture::{MimeType, Picture, PictureType};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// All picture types by the names the frontend uses
+const PICTURE_TYPES: [(&str, PictureType); 21] = [
+    ("Other", PictureType::Other),
+    ("Icon", PictureType::Icon),
+    ("OtherIcon", PictureType::OtherIcon),
+    ("CoverFront", PictureType::CoverFront),
+    ("CoverBack", PictureType::CoverBack),
+    ("Leaflet", PictureType::Leaflet),
+    ("Media", PictureType::Media),
+    ("LeadArtist", PictureType::LeadArtist),
+    ("Artist", PictureType::Artist),
+    ("Conductor", PictureType::Conductor),
+    ("Band", PictureType::Band),
+    ("Composer", PictureType::Composer),
+    ("Lyricist", PictureType::Lyricist),
+    ("RecordingLocation", PictureType::RecordingLocation),
+    ("DuringRecording", PictureType::DuringRecording),
+    ("DuringPerformance", PictureType::DuringPerformance),
+    ("ScreenCapture", PictureType::ScreenCapture),
+    ("BrightFish", PictureType::BrightFish),
+    ("Illustration", PictureType::Illustration),
+    ("BandLogo", PictureType::BandLogo),
+    ("PublisherLogo", PictureType::PublisherLogo),
+];
+
+const EXPORT_FILE_NAME: &str = "cover.jpg";
+const JPEG_QUALITY: u8 = 90;
+
+/// An embedded picture of the primary tag, `index` is its position in the tag
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PictureInfo {
+    pub index: usize,
+    pub picture_type: String,
+    pub mime_type: Option<String>,
+    pub description: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub size: usize,
+}
+
+/// How an image file is prepared before it is embedded. Without options it is
+/// embedded as it is, if it is a JPEG or PNG.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub struct EmbedOptions {
+    /// Larger images are scaled down to fit into a square of this size
+    #[serde(default)]
+    pub max_size: Option<u32>,
+    /// Encodes the image as JPEG again, with this quality from 1 to 100
+    #[serde(default)]
+    pub jpeg_quality: Option<u8>,
+}
+
+/// The pictures of the primary tag of a file, the tag that pictures are embedded in
+pub fn list_pictures(path: &Path) -> Result<Vec<PictureInfo>> {
+    let tagged_file = read_tagged_file(path)?;
+    let Some(tag) = tagged_file.primary_tag() else {
+        return Ok(Vec::new());
+    };
+    Ok(tag
+        .pictures()
+        .iter()
+        .enumerate()
+        .map(|(index, picture)| {
+            let dimensions = ImageReader::new(Cursor::new(picture.data()))
+                .with_guessed_format()
+                .ok()
+                .and_then(|reader| reader.into_dimensions().ok());
+            PictureInfo {
+                index,
+                picture_type: picture_type_name(picture.pic_type()),
+                mime_type: picture.mime_type().map(|mime_type| mime_type.as_str().to_string()),
+                description: picture.description().map(|description| description.to_string()),
+                width: dimensions.map(|(width, _)| width),
+                height: dimensions.map(|(_, height)| height),
+                size: picture.data().len(),
+            }
+        })
+        .collect())
+}
+
+/// The pictures of the primary tag as they are embedded
+pub fn read_pictures(path: &Path) -> Result<Vec<Picture>> {
+    let tagged_file = read_tagged_file(path)?;
+    Ok(tagged_file
+        .primary_tag()
+        .map(|tag| tag.pictures().to_vec())
+        .unwrap_or_default())
+}
+
+/// Replaces every picture of the primary tag, like a revert of the tag journal does
+pub fn write_pictures(path: &Path, pictures: &[Picture]) -> Result<()> {
+    let mut tagged_file = read_tagged_file(path)?;
+    let tag = primary_tag_mut(&mut tagged_file);
+    while tag.picture_count() > 0 {
+        tag.remove_picture(0);
+    }
+    for picture in pictures {
+        tag.push_picture(picture.clone());
+    }
+    save_primary_tag(tag, path)
+}
+
+/// Embeds an image file as a picture of the given type, replacing the pictures
+/// of that type. MP4 files keep no picture types, every picture is a cover there.
+pub fn embed_picture(path: &Path, image_path: &Path, picture_type: PictureType, options: EmbedOptions) -> Result<()> {
+    let (data, mime_type) = load_image(image_path, options)?;
+    embed_prepared_picture(path, data, mime_type, picture_type)
+}
+
+/// Embeds image data as `prepare_image` returned it, replacing the pictures of that type
+pub fn embed_prepared_picture(path: &Path, data: Vec<u8>, mime_type: MimeType, picture_type: PictureType) -> Result<()> {
+    let mut tagged_file = read_tagged_file(path)?;
+    let tag = primary_tag_mut(&mut tagged_file);
+    tag.remove_picture_type(picture_type);
+    tag.push_picture(Picture::new_unchecked(picture_type, Some(mime_type), None, data));
+    save_primary_tag(tag, path)
+}
+
+/// Replaces the image of a picture, keeping its type and description
+pub fn replace_picture(path: &Path, index: usize, image_path: &Path, options: EmbedOptions) -> Result<()> {
+    let (data, mime_type) = load_image(image_path, options)?;
+    let mut tagged_file = read_tagged_file(path)?;
+    let tag = primary_tag_mut(&mut tagged_file);
+    let old = tag
+        .pictures()
+        .get(index)
+        .ok_or_else(|| anyhow!("{} has no picture {}", path.display(), index))?;
+    let picture = Picture::new_unchecked(
+        old.pic_type(),
+        Some(mime_type),
+        old.description().map(|description| description.to_string()),
+        data,
+    );
+    tag.set_picture(index, picture);
+    save_primary_tag(tag, path)
+}
+
+pub fn remove_picture(path: &Path, index: usize) -> Result<()> {
+    let mut tagged_file = read_tagged_file(path)?;
+    let tag = primary_tag_mut(&mut tagged_file);
+    if index >= tag.picture_count() as usize {
+        bail!("{} has no picture {}", path.display(), index);
+    }
+    tag.remove_picture(index);
+    save_primary_tag(tag, path)
+}
+
+/// Saves a picture as `cover.jpg` next to the file, the front cover if no index
+/// is given. Other formats than JPEG are converted.
+pub fn export_cover(path: &Path, index: Option<usize>, overwrite: bool) -> Result<PathBuf> {
+    let picture = match index {
+        Some(index) => {
+            let tagged_file = read_tagged_file(path)?;
+            tagged_file
+                .primary_tag()
+                .and_then(|tag| tag.pictures().get(index))
+                .cloned()
+                .ok_or_else(|| anyhow!("{} has no picture {}", path.display(), index))?
+        }
+        None => read_cover(path)?.ok_or_else(|| anyhow!("{} has no cover", path.display()))?,
+    };
+
+    let folder = path.parent().ok_or_else(|| anyhow!("{} has no folder", path.display()))?;
+    save_cover(folder, picture.data(), EmbedOptions::default(), overwrite)
+}
+
+/// Saves an image as `cover.jpg` in a folder, prepared like an embedded picture
+/// and converted if it is no JPEG
+pub fn save_cover(folder: &Path, data: &[u8], options: EmbedOptions, overwrite: bool) -> Result<PathBuf> {
+    let cover_path = folder.join(EXPORT_FILE_NAME);
+    if cover_path.exists() && !overwrite {
+        bail!("{} exists already", cover_path.display());
+    }
+
+    let (data, _) = convert_image(data.to_vec(), options, &[ImageFormat::Jpeg])?;
+    fs::write(&cover_path, data).with_context(|| format!("Failed to write {}", cover_path.display()))?;
+    Ok(cover_path)
+}
+
+/// Reads an image file, scaled down and encoded again as the options say
+fn load_image(image_path: &Path, options: EmbedOptions) -> Result<(Vec<u8>, MimeType)> {
+    let data = fs::read(image_path).with_context(|| format!("Failed to read image {}", image_path.display()))?;
+    prepare_image(data, options)
+}
+
+/// Scales an image down and encodes it again as the options say. JPEG and PNG
+/// are kept as they are if the options allow it.
+pub fn prepare_image(data: Vec<u8>, options: EmbedOptions) -> Result<(Vec<u8>, MimeType)> {
+    // JPEG and PNG can be embedded in every format, others are converted
+    convert_image(data, options, &[ImageFormat::Jpeg, ImageFormat::Png])
+}
+
+/// Keeps an image of the kept formats as it is, unless it is too large or has to
+/// be encoded with another quality. Only images that change are decoded and
+/// encoded again, as JPEG.
+fn convert_image(data: Vec<u8>, options: EmbedOptions, kept_formats: &[ImageFormat]) -> Result<(Vec<u8>, MimeType)> {
+    let format = image::guess_format(&data).context("Unknown image format")?;
+    let (width, height) = ImageReader::with_format(Cursor::new(&data), format)
+        .into_dimensions()
+        .context("Failed to decode image")?;
+    let too_large = options
+        .max_size
+        .is_some_and(|max_size| width > max_size || height > max_size);
+
+    if kept_formats.contains(&format) && !too_large && options.jpeg_quality.is_none() {
+        let mime_type = if format == ImageFormat::Png { MimeType::Png } else { MimeType::Jpeg };
+        return Ok((data, mime_type));
+    }
+    let image = decode(&data)?;
+    let image = match options.max_size {
+        Some(max_size) if too_large => image.resize(max_size, max_size, FilterType::CatmullRom),
+        _ => image,
+    };
+    let quality = options.jpeg_quality.unwrap_or(JPEG_QUALITY).clamp(1, 100);
+    Ok((encode_jpeg(&image, quality)?, MimeType::Jpeg))
+}
+
+fn decode(data: &[u8]) -> Result<image::DynamicImage> {
+    image::load_from_memory(data).context("Failed to decode image")
+}
+
+fn encode_jpeg(image: &image::DynamicImage, quality: u8) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut data, quality).encode_image(&image.to_rgb8())?;
+    Ok(data)
+}
+
+/// Converts a string to a PictureType (case-insensitive)
+pub fn parse_picture_type(name: &str) -> Result<PictureType> {
+    PICTURE_TYPES
+        .iter()
+        .find(|(type_name, _)| type_name.eq_ignore_ascii_case(name))
+        .map(|(_, picture_type)| *picture_type)
+        .ok_or_else(|| anyhow!("Unknown picture type: {}", name))
+}
+
+pub fn picture_type_name(picture_type: PictureType) -> String {
+    PICTURE_TYPES
+        .iter()
+        .find(|(_, known)| *known == picture_type)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| format!("{:?}", picture_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::sample_file;
+    use image::{DynamicImage, RgbImage};
+    use tempfile::tempdir;
+
+    fn write_image(path: &Path, width: u32, height: u32, format: ImageFormat) {
+        DynamicImage::ImageRgb8(RgbImage::new(width, height)).save_with_format(path, format).unwrap();
+    }
+
+    #[test]
+    fn test_parse_picture_type() {
+        assert_eq!(parse_picture_type("coverfront").unwrap(), PictureType::CoverFront);
+        assert_eq!(parse_picture_type("CoverBack").unwrap(), PictureType::CoverBack);
+        assert!(parse_picture_type("Poster").is_err());
+        assert_eq!(picture_type_name(PictureType::Leaflet), "Leaflet");
+    }
+
+    #[test]
+    fn test_prepare_image() {
+        let dir = tempdir().unwrap();
+        let png_path = dir.path().join("cover.png");
+        write_image(&png_path, 64, 32, ImageFormat::Png);
+        let png = fs::read(&png_path).unwrap();
+
+        // small enough, embedded as it is
+        let (data, mime_type) = prepare_image(png.clone(), EmbedOptions::default()).unwrap();
+        assert_eq!((data, mime_type), (png.clone(), MimeType::Png));
+
+        let options = EmbedOptions {
+            max_size: Some(16),
+            jpeg_quality: None,
+        };
+        let (data, mime_type) = prepare_image(png.clone(), options).unwrap();
+        assert_eq!(mime_type, MimeType::Jpeg);
+        assert_eq!(image::load_from_memory(&data).unwrap().width(), 16);
+
+        let bmp_path = dir.path().join("cover.bmp");
+        write_image(&bmp_path, 8, 8, ImageFormat::Bmp);
+        let (_, mime_type) = prepare_image(fs::read(&bmp_path).unwrap(), EmbedOptions::default()).unwrap();
+        assert_eq!(mime_type, MimeType::Jpeg);
+
+        // a JPEG is kept byte for byte unless it changes
+        let jpeg_path = dir.path().join("cover.jpg");
+        write_image(&jpeg_path, 8, 8, ImageFormat::Jpeg);
+        let jpeg = fs::read(&jpeg_path).unwrap();
+        let (data, _) = convert_image(jpeg.clone(), EmbedOptions::default(), &[ImageFormat::Jpeg]).unwrap();
+        assert_eq!(data, jpeg);
+        let (data, mime_type) = convert_image(png, EmbedOptions::default(), &[ImageFormat::Jpeg]).unwrap();
+        assert_eq!(mime_type, MimeType::Jpeg);
+        assert_eq!(image::guess_format(&data).unwrap(), ImageFormat::Jpeg);
+
+        assert!(prepare_image(b"not an image".to_vec(), EmbedOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_embed_replace_and_remove_pictures() {
+        let dir = tempdir().unwrap();
+        let front = dir.path().join("front.png");
+        let back = dir.path().join("back.png");
+        let large = dir.path().join("large.png");
+        write_image(&front, 4, 4, ImageFormat::Png);
+        write_image(&back, 6, 3, ImageFormat::Png);
+        write_image(&large, 400, 200, ImageFormat::Png);
+
+        for name in ["some_song.mp3", "some_audio.flac"] {
+            let path = sample_file(name, &dir.path().join(name));
+            embed_picture(&path, &front, PictureType::CoverFront, EmbedOptions::default()).unwrap();
+            embed_picture(&path, &back, PictureType::CoverBack, EmbedOptions::default()).unwrap();
+            // a second front cover replaces the first
+            embed_picture(&path, &front, PictureType::CoverFront, EmbedOptions::default()).unwrap();
+
+            let pictures = list_pictures(&path).unwrap();
+            let mut types: Vec<&str> = pictures.iter().map(|picture| picture.picture_type.as_str()).collect();
+            types.sort();
+            assert_eq!(types, vec!["CoverBack", "CoverFront"], "{}", name);
+            let back_picture = pictures.iter().find(|picture| picture.picture_type == "CoverBack").unwrap();
+            assert_eq!((back_picture.width, back_picture.height), (Some(6), Some(3)));
+            assert_eq!(back_picture.mime_type.as_deref(), Some("image/png"));
+
+            let options = EmbedOptions {
+                max_size: Some(100),
+                jpeg_quality: Some(80),
+            };
+            replace_picture(&path, back_picture.index, &large, options).unwrap();
+            let pictures = list_pictures(&path).unwrap();
+            let back_picture = pictures.iter().find(|picture| picture.picture_type == "CoverBack").unwrap();
+            assert_eq!((back_picture.width, back_picture.height), (Some(100), Some(50)));
+            assert_eq!(back_picture.mime_type.as_deref(), Some("image/jpeg"));
+
+            remove_picture(&path, back_picture.index).unwrap();
+            let pictures = list_pictures(&path).unwrap();
+            assert_eq!(pictures.len(), 1);
+            assert_eq!(pictures[0].picture_type, "CoverFront");
+            assert!(remove_picture(&path, 5).is_err());
+        }
+    }
+
+    #[test]
+    fn test_export_cover() {
+        let dir = tempdir().unwrap();
+        let front = dir.path().join("front.png");
+        write_image(&front, 4, 4, ImageFormat::Png);
+        let path = sample_file("some_song.mp3", &dir.path().join("some_song.mp3"));
+        assert!(export_cover(&path, None, false).is_err());
+
+        embed_picture(&path, &front, PictureType::CoverFront, EmbedOptions::default()).unwrap();
+        let cover_path = export_cover(&path, None, false).unwrap();
+        assert_eq!(cover_path, dir.path().join("cover.jpg"));
+        let data = fs::read(&cover_path).unwrap();
+        assert_eq!(image::guess_format(&data).unwrap(), ImageFormat::Jpeg);
+
+        // an existing cover.jpg is only replaced when asked to
+        assert!(export_cover(&path, Some(0), false).is_err());
+        export_cover(&path, Some(0), true).unwrap();
+    }
+}
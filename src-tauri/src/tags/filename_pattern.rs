@@ -0,0 +1,637 @@
+use crate::tag_journal::TagJournal;
+use crate::tags::batch_edit::{BatchEdit, FieldOperation};
+use crate::tags::multi_value::{first_value, TagMap};
+use crate::tags::reading_tags::{item_key_name, read_audio_file_properties};
+use crate::tags::writing_tags::parse_item_key;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+// placeholders that are short for a tag key, like %artist% for TrackArtist
+const FIELD_ALIASES: [(&str, &str); 13] = [
+    ("tracknumber", "TrackNumber"),
+    ("track", "TrackNumber"),
+    ("discnumber", "DiscNumber"),
+    ("disc", "DiscNumber"),
+    ("artist", "TrackArtist"),
+    ("albumartist", "AlbumArtist"),
+    ("album", "AlbumTitle"),
+    ("title", "TrackTitle"),
+    ("year", "Year"),
+    ("date", "RecordingDate"),
+    ("genre", "Genre"),
+    ("composer", "Composer"),
+    ("comment", "Comment"),
+];
+
+// %ignore% matches text that is not a tag, like "[web rip]"
+const IGNORE_FIELD: &str = "ignore";
+
+// characters that are not allowed in file names on Windows, macOS or Linux
+const ILLEGAL_CHARACTERS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1",
+    "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+const MAX_NAME_LENGTH: usize = 200;
+const MISSING_VALUE: &str = "Unknown";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    /// A tag key, or None for %ignore%
+    Field(Option<String>),
+}
+
+/// A pattern like `%albumartist%/%album%/%tracknumber% - %title%`. Every `/`
+/// separated part stands for a folder, the last one for the file name without extension.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilenamePattern {
+    components: Vec<Vec<Token>>,
+}
+
+/// The tags parsed from the path of a file, None if the path does not match
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FilenameTags {
+    pub path: String,
+    pub tags: Option<TagMap>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RenameStatus {
+    /// Will be moved, in a preview
+    Planned,
+    Moved,
+    /// Already at the path built from its tags
+    Unchanged,
+    /// Another file would get the same path, or a file exists there already
+    Collision { with: String },
+    Failed { error: String },
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FileRename {
+    pub from: String,
+    pub to: String,
+    #[serde(flatten)]
+    pub status: RenameStatus,
+}
+
+impl FilenamePattern {
+    pub fn parse(pattern: &str) -> Result<FilenamePattern> {
+        let components = pattern
+            .split(['/', '\\'])
+            .filter(|component| !component.is_empty())
+            .map(parse_component)
+            .collect::<Result<Vec<_>>>()?;
+        if components.is_empty() {
+            bail!("Empty file name pattern");
+        }
+        Ok(FilenamePattern { components })
+    }
+
+    /// The tags in a path, matched against the last folders and the file name
+    /// without extension. Track and disc numbers lose their leading zeros.
+    pub fn tags_from_path(&self, path: &Path) -> Option<TagMap> {
+        let mut names: Vec<String> = path
+            .parent()?
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect();
+        names.push(path.file_stem()?.to_string_lossy().to_string());
+        if names.len() < self.components.len() {
+            return None;
+        }
+
+        let mut tags = TagMap::new();
+        let names = &names[names.len() - self.components.len()..];
+        for (tokens, name) in self.components.iter().zip(names) {
+            for (key, value) in match_tokens(tokens, name)? {
+                let value = match key.as_str() {
+                    "TrackNumber" | "DiscNumber" => trim_leading_zeros(&value),
+                    _ => value.trim(),
+                };
+                if !value.is_empty() {
+                    tags.insert(key, vec![value.to_string()]);
+                }
+            }
+        }
+        Some(tags)
+    }
+
+    /// The relative path built from tags, without extension. Missing tags become
+    /// "Unknown", several values are joined with ", " and every folder and file
+    /// name is made safe for all file systems.
+    pub fn path_from_tags(&self, tags: &TagMap) -> PathBuf {
+        self.components
+            .iter()
+            .map(|tokens| {
+                let name: String = tokens
+                    .iter()
+                    .map(|token| match token {
+                        Token::Literal(text) => text.clone(),
+                        Token::Field(Some(key)) => field_value(tags, key),
+                        Token::Field(None) => String::new(),
+                    })
+                    .collect();
+                sanitize(&name)
+            })
+            .collect()
+    }
+}
+
+fn parse_component(component: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut parts = component.split('%');
+    if let Some(text) = parts.next().filter(|text| !text.is_empty()) {
+        tokens.push(Token::Literal(text.to_string()));
+    }
+    // after the first part, every other part is a field name
+    let parts: Vec<&str> = parts.collect();
+    if parts.len() % 2 != 0 {
+        bail!("Unclosed % in pattern part {}", component);
+    }
+    for pair in parts.chunks(2) {
+        let name = pair[0].trim();
+        if name.is_empty() {
+            bail!("Empty field name in pattern part {}", component);
+        }
+        if matches!(tokens.last(), Some(Token::Field(_))) {
+            bail!("%{}% directly follows another field in {}, they need text between them", name, component);
+        }
+        tokens.push(Token::Field(field_key(name)));
+        if !pair[1].is_empty() {
+            tokens.push(Token::Literal(pair[1].to_string()));
+        }
+    }
+    Ok(tokens)
+}
+
+/// The tag key of a field name, None for %ignore%
+fn field_key(name: &str) -> Option<String> {
+    if name.eq_ignore_ascii_case(IGNORE_FIELD) {
+        return None;
+    }
+    let key = FIELD_ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+        .map(|(_, key)| key.to_string())
+        .unwrap_or_else(|| item_key_name(&parse_item_key(name)));
+    Some(key)
+}
+
+/// Matches a file or folder name against the tokens of a pattern part. Fields
+/// take as little text as possible, but never none.
+fn match_tokens(tokens: &[Token], name: &str) -> Option<Vec<(String, String)>> {
+    let mut fields = Vec::new();
+    let mut dead_ends = HashSet::new();
+    match_tokens_from(tokens, name, 0, &mut fields, &mut dead_ends).then_some(fields)
+}
+
+/// Matches the tokens against the name from byte `start` on. The (tokens left,
+/// start) pairs that failed are remembered, which keeps the backtracking of fields
+/// polynomial for names with many separators.
+fn match_tokens_from(
+    tokens: &[Token],
+    name: &str,
+    start: usize,
+    fields: &mut Vec<(String, String)>,
+    dead_ends: &mut HashSet<(usize, usize)>,
+) -> bool {
+    if dead_ends.contains(&(tokens.len(), start)) {
+        return false;
+    }
+    let rest = &name[start..];
+    let matched = match tokens.split_first() {
+        None => rest.is_empty(),
+        Some((Token::Literal(text), tokens)) => {
+            rest.starts_with(text.as_str()) && match_tokens_from(tokens, name, start + text.len(), fields, dead_ends)
+        }
+        Some((Token::Field(key), tokens)) => {
+            let ends = rest.char_indices().map(|(index, _)| start + index).skip(1).chain([name.len()]);
+            ends.into_iter().any(|end| {
+                let matched_fields = fields.len();
+                if let Some(key) = key {
+                    fields.push((key.clone(), name[start..end].to_string()));
+                }
+                let matched = match_tokens_from(tokens, name, end, fields, dead_ends);
+                if !matched {
+                    fields.truncate(matched_fields);
+                }
+                matched
+            })
+        }
+    };
+    if !matched {
+        dead_ends.insert((tokens.len(), start));
+    }
+    matched
+}
+
+fn trim_leading_zeros(value: &str) -> &str {
+    let value = value.trim();
+    let trimmed = value.trim_start_matches('0');
+    if trimmed.is_empty() && !value.is_empty() {
+        "0"
+    } else {
+        trimmed
+    }
+}
+
+fn field_value(tags: &TagMap, key: &str) -> String {
+    let values: Vec<&str> = tags
+        .iter()
+        .find(|(tag_key, _)| tag_key.eq_ignore_ascii_case(key))
+        .map(|(_, values)| values.iter().map(|value| value.trim()).filter(|value| !value.is_empty()).collect())
+        .unwrap_or_default();
+    if values.is_empty() {
+        return MISSING_VALUE.to_string();
+    }
+    match key {
+        // "3/12" and "3" both become "03"
+        "TrackNumber" | "DiscNumber" => {
+            let number = values[0].split('/').next().unwrap_or_default().trim();
+            match number.parse::<u32>() {
+                Ok(number) if key == "TrackNumber" => format!("{:02}", number),
+                Ok(number) => number.to_string(),
+                Err(_) => number.to_string(),
+            }
+        }
+        _ => values.join(", "),
+    }
+}
+
+/// Makes a file or folder name safe on every file system
+pub fn sanitize(name: &str) -> String {
+    let replaced: String = name
+        .chars()
+        .map(|c| if ILLEGAL_CHARACTERS.contains(&c) || c.is_control() { '_' } else { c })
+        .collect();
+    // Windows drops trailing dots and spaces
+    let mut sanitized: String = replaced
+        .trim()
+        .trim_end_matches('.')
+        .chars()
+        .take(MAX_NAME_LENGTH)
+        .collect::<String>()
+        .trim_end()
+        .to_string();
+    if sanitized.is_empty() || sanitized.chars().all(|c| c == '.') {
+        sanitized = "_".to_string();
+    }
+    let stem = sanitized.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// The tags every file would get from its path
+pub fn preview_tags_from_paths(paths: &[String], pattern: &FilenamePattern) -> Vec<FilenameTags> {
+    paths
+        .iter()
+        .map(|path| FilenameTags {
+            path: path.clone(),
+            tags: pattern.tags_from_path(Path::new(path)),
+        })
+        .collect()
+}
+
+/// A batch edit that sets the tags parsed from the paths. Fails if a path does not match.
+pub fn tags_from_paths_edit(paths: &[String], pattern: &FilenamePattern) -> Result<BatchEdit> {
+    let previews = preview_tags_from_paths(paths, pattern);
+    let unmatched: Vec<&str> = previews
+        .iter()
+        .filter(|preview| preview.tags.is_none())
+        .map(|preview| preview.path.as_str())
+        .collect();
+    if !unmatched.is_empty() {
+        bail!("The pattern does not match {}", unmatched.join(", "));
+    }
+
+    let mut keys: Vec<&String> = previews.iter().flat_map(|preview| preview.tags.iter().flat_map(|tags| tags.keys())).collect();
+    keys.sort();
+    keys.dedup();
+    let operations = keys
+        .into_iter()
+        .map(|key| FieldOperation::SetPerFile {
+            key: key.clone(),
+            values: previews
+                .iter()
+                .map(|preview| first_value(preview.tags.as_ref().unwrap(), key).unwrap_or_default().to_string())
+                .collect(),
+        })
+        .collect();
+    Ok(BatchEdit {
+        paths: paths.to_vec(),
+        operations,
+    })
+}
+
+/// Where every file would be moved to. Without `target_dir` the pattern is
+/// relative to the folder of each file.
+pub fn plan_renames(paths: &[String], pattern: &FilenamePattern, target_dir: Option<&Path>) -> Vec<FileRename> {
+    let mut renames: Vec<FileRename> = paths
+        .iter()
+        .map(|path| plan_rename(Path::new(path), pattern, target_dir))
+        .collect();
+
+    // several files with the same target, ignoring case for case-insensitive file systems
+    let mut by_target: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, rename) in renames.iter().enumerate() {
+        if rename.status == RenameStatus::Planned {
+            by_target.entry(rename.to.to_lowercase()).or_default().push(index);
+        }
+    }
+    for indices in by_target.values().filter(|indices| indices.len() > 1) {
+        for &index in indices {
+            let others: Vec<&str> = indices
+                .iter()
+                .filter(|&&other| other != index)
+                .map(|&other| renames[other].from.as_str())
+                .collect();
+            renames[index].status = RenameStatus::Collision { with: others.join(", ") };
+        }
+    }
+    renames
+}
+
+fn plan_rename(from: &Path, pattern: &FilenamePattern, target_dir: Option<&Path>) -> FileRename {
+    let mut rename = FileRename {
+        from: from.to_string_lossy().to_string(),
+        to: String::new(),
+        status: RenameStatus::Planned,
+    };
+    let tags = match read_audio_file_properties(from) {
+        Ok(properties) => properties.tags,
+        Err(e) => {
+            rename.status = RenameStatus::Failed { error: e.to_string() };
+            return rename;
+        }
+    };
+
+    let base_dir = target_dir.or_else(|| from.parent()).unwrap_or(Path::new(""));
+    let mut to = base_dir.join(pattern.path_from_tags(&tags));
+    if let Some(extension) = from.extension() {
+        let file_name = format!("{}.{}", to.file_name().unwrap_or_default().to_string_lossy(), extension.to_string_lossy());
+        to.set_file_name(file_name);
+    }
+    rename.to = to.to_string_lossy().to_string();
+
+    if to == from {
+        rename.status = RenameStatus::Unchanged;
+    } else if to.exists() && !is_same_file(from, &to) {
+        rename.status = RenameStatus::Collision {
+            with: rename.to.clone(),
+        };
+    }
+    rename
+}
+
+/// True for a rename that only changes the case on a case-insensitive file system
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Moves the files as planned and moves their history in the tag journal along.
+/// Files with a collision or without readable tags stay where they are.
+pub fn rename_files(
+    tag_journal: &mut TagJournal,
+    paths: &[String],
+    pattern: &FilenamePattern,
+    target_dir: Option<&Path>,
+) -> Vec<FileRename> {
+    let mut renames = plan_renames(paths, pattern, target_dir);
+    for rename in renames.iter_mut().filter(|rename| rename.status == RenameStatus::Planned) {
+        let (from, to) = (Path::new(&rename.from), Path::new(&rename.to));
+        // another file may have been put there since the renames were planned
+        if to.exists() && !is_same_file(from, to) {
+            rename.status = RenameStatus::Collision {
+                with: rename.to.clone(),
+            };
+            continue;
+        }
+        match move_file(from, to) {
+            Ok(()) => {
+                rename.status = RenameStatus::Moved;
+                if let Err(e) = tag_journal.rename_path(&rename.from, &rename.to) {
+                    eprintln!("Failed to move the tag history of {} to {}: {}", rename.from, rename.to, e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to move {} to {}: {}", rename.from, rename.to, e);
+                rename.status = RenameStatus::Failed { error: e.to_string() };
+            }
+        }
+    }
+    renames
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => move_across_devices(from, to),
+        result => Ok(result?),
+    }
+}
+
+/// Copies a file to another file system and removes the original. The copy is
+/// created new, so a file that appeared at the target is never overwritten.
+fn move_across_devices(from: &Path, to: &Path) -> Result<()> {
+    let mut source = File::open(from)?;
+    let mut target = OpenOptions::new().write(true).create_new(true).open(to)?;
+    let copied = io::copy(&mut source, &mut target)
+        .and_then(|_| target.set_permissions(source.metadata()?.permissions()))
+        .and_then(|_| target.sync_all());
+    if let Err(e) = copied {
+        drop(target);
+        _ = fs::remove_file(to);
+        return Err(e.into());
+    }
+    fs::remove_file(from)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{tag_map, tagged_song};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_pattern() {
+        let pattern = FilenamePattern::parse("%albumartist%/%Album%/%tracknumber% - %TrackTitle%").unwrap();
+        assert_eq!(
+            pattern.components,
+            vec![
+                vec![Token::Field(Some("AlbumArtist".to_string()))],
+                vec![Token::Field(Some("AlbumTitle".to_string()))],
+                vec![
+                    Token::Field(Some("TrackNumber".to_string())),
+                    Token::Literal(" - ".to_string()),
+                    Token::Field(Some("TrackTitle".to_string())),
+                ],
+            ]
+        );
+
+        assert!(FilenamePattern::parse("").is_err());
+        assert!(FilenamePattern::parse("%title").is_err());
+        assert!(FilenamePattern::parse("%%").is_err());
+        assert!(FilenamePattern::parse("%artist%%title%").is_err());
+    }
+
+    #[test]
+    fn test_tags_from_path() {
+        let pattern = FilenamePattern::parse("%tracknumber% - %artist% - %title%").unwrap();
+        assert_eq!(
+            pattern.tags_from_path(Path::new("/music/rips/01 - Artist - Title - Live.flac")),
+            Some(tag_map(&[("TrackNumber", &["1"]), ("TrackArtist", &["Artist"]), ("TrackTitle", &["Title - Live"])]))
+        );
+        assert_eq!(pattern.tags_from_path(Path::new("/music/rips/Title.flac")), None);
+
+        let pattern = FilenamePattern::parse("%album% (%year%)/%track%. %title% %ignore%").unwrap();
+        assert_eq!(
+            pattern.tags_from_path(Path::new("/music/Nevermind (1991)/00. Intro [web].mp3")),
+            Some(tag_map(&[
+                ("AlbumTitle", &["Nevermind"]),
+                ("Year", &["1991"]),
+                ("TrackNumber", &["0"]),
+                ("TrackTitle", &["Intro"]),
+            ]))
+        );
+        assert_eq!(pattern.tags_from_path(Path::new("01. Intro [web].mp3")), None);
+    }
+
+    #[test]
+    fn test_many_separators_match_quickly() {
+        let fields: Vec<String> = (0..30).map(|index| format!("%field{}%", index)).collect();
+        let pattern = FilenamePattern::parse(&format!("{}!", fields.join("-"))).unwrap();
+        // every way to split the dashes among the fields fails on the missing "!"
+        let name = vec!["a"; 60].join("-");
+        assert_eq!(pattern.tags_from_path(Path::new(&format!("/music/{}.mp3", name))), None);
+    }
+
+    #[test]
+    fn test_tags_from_paths_edit() {
+        let pattern = FilenamePattern::parse("%tracknumber% - %title%").unwrap();
+        let paths = vec!["/rips/01 - First.mp3".to_string(), "/rips/02 - Second.mp3".to_string()];
+        let edit = tags_from_paths_edit(&paths, &pattern).unwrap();
+        assert_eq!(
+            edit.operations,
+            vec![
+                FieldOperation::SetPerFile {
+                    key: "TrackNumber".to_string(),
+                    values: vec!["1".to_string(), "2".to_string()],
+                },
+                FieldOperation::SetPerFile {
+                    key: "TrackTitle".to_string(),
+                    values: vec!["First".to_string(), "Second".to_string()],
+                },
+            ]
+        );
+
+        let paths = vec!["/rips/01 - First.mp3".to_string(), "/rips/Second.mp3".to_string()];
+        assert!(tags_from_paths_edit(&paths, &pattern).is_err());
+    }
+
+    #[test]
+    fn test_path_from_tags() {
+        let pattern = FilenamePattern::parse("%albumartist%/%album%/%tracknumber% - %title%").unwrap();
+        let mut tags = tag_map(&[("AlbumArtist", &["AC/DC"]), ("TrackNumber", &["3/10"]), ("TrackTitle", &["What? "])]);
+        tags.insert("TrackArtists".to_string(), vec!["A".to_string(), "B".to_string()]);
+        assert_eq!(
+            pattern.path_from_tags(&tags),
+            PathBuf::from("AC_DC").join("Unknown").join("03 - What_")
+        );
+
+        let pattern = FilenamePattern::parse("%trackartists% - %title%").unwrap();
+        assert_eq!(pattern.path_from_tags(&tags), PathBuf::from("A, B - What_"));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("a<b>c:d\"e|f?g*h\\i"), "a_b_c_d_e_f_g_h_i");
+        assert_eq!(sanitize("  name... "), "name");
+        assert_eq!(sanitize("tab\there"), "tab_here");
+        assert_eq!(sanitize(".."), "_");
+        assert_eq!(sanitize(""), "_");
+        assert_eq!(sanitize("con"), "_con");
+        assert_eq!(sanitize("Nul.txt"), "_Nul.txt");
+        assert_eq!(sanitize(&"x".repeat(300)).len(), MAX_NAME_LENGTH);
+    }
+
+    #[test]
+    fn test_rename_files() {
+        let dir = tempdir().unwrap();
+        let tags = tag_map(&[("AlbumArtist", &["Artist"]), ("AlbumTitle", &["Album"]), ("TrackNumber", &["1"]), ("TrackTitle", &["Song"])]);
+        let song = |name: &str, tags: &TagMap| tagged_song(&dir.path().join("in").join(name), tags).to_string_lossy().to_string();
+        let first = song("a.mp3", &tags);
+        let duplicate = song("b.mp3", &tags);
+        let other = song("c.mp3", &tag_map(&[("TrackTitle", &["Other"])]));
+        let paths = vec![first.clone(), duplicate.clone(), other.clone()];
+
+        let pattern = FilenamePattern::parse("%albumartist%/%album%/%tracknumber% - %title%").unwrap();
+        let target_dir = dir.path().join("out");
+
+        // the preview changes nothing
+        let preview = plan_renames(&paths, &pattern, Some(&target_dir));
+        assert_eq!(preview[0].status, RenameStatus::Collision { with: duplicate.clone() });
+        assert_eq!(preview[1].status, RenameStatus::Collision { with: first.clone() });
+        assert_eq!(preview[2].status, RenameStatus::Planned);
+        assert!(Path::new(&other).exists());
+
+        let mut tag_journal = TagJournal::open(&dir.path().join("tag_journal.sqlite3")).unwrap();
+        tag_journal.write_one("Edit tags", Path::new(&other), || Ok(())).unwrap();
+        let renames = rename_files(&mut tag_journal, &paths, &pattern, Some(&target_dir));
+        let moved_to = target_dir.join("Unknown/Unknown/Unknown - Other.mp3");
+        assert_eq!(
+            renames[2],
+            FileRename {
+                from: other.clone(),
+                to: moved_to.to_string_lossy().to_string(),
+                status: RenameStatus::Moved,
+            }
+        );
+        assert!(moved_to.exists());
+        assert!(!Path::new(&other).exists());
+        assert!(Path::new(&first).exists() && Path::new(&duplicate).exists());
+        // the history moves along
+        assert!(tag_journal.file_history(&other).unwrap().is_empty());
+        assert_eq!(tag_journal.file_history(&moved_to.to_string_lossy()).unwrap().len(), 1);
+
+        // a file in the way is a collision too
+        let renames = rename_files(&mut tag_journal, std::slice::from_ref(&first), &pattern, Some(&target_dir));
+        assert_eq!(renames[0].status, RenameStatus::Moved);
+        let again = song("d.mp3", &tags);
+        let renames = rename_files(&mut tag_journal, std::slice::from_ref(&again), &pattern, Some(&target_dir));
+        assert!(matches!(renames[0].status, RenameStatus::Collision { .. }));
+        assert!(Path::new(&again).exists());
+    }
+
+    #[test]
+    fn test_move_across_devices_keeps_existing_files() {
+        let dir = tempdir().unwrap();
+        let from = dir.path().join("from.mp3");
+        let to = dir.path().join("to.mp3");
+        fs::write(&from, b"moved").unwrap();
+        fs::write(&to, b"existing").unwrap();
+
+        assert!(move_across_devices(&from, &to).is_err());
+        assert_eq!(fs::read(&to).unwrap(), b"existing");
+        assert!(from.exists());
+
+        fs::remove_file(&to).unwrap();
+        move_across_devices(&from, &to).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), b"moved");
+        assert!(!from.exists());
+    }
+}
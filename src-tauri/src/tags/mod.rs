@@ -1,3 +1,8 @@
 pub mod writing_tags;
 pub mod reading_tags;
+pub mod multi_value;
+pub mod tag_containers;
+pub mod batch_edit;
+pub mod filename_pattern;
+pub mod cover_art;
 mod test_read_write;
\ No newline at end of file
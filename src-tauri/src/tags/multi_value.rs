@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The values of the tags of a file by key. Keys like TrackArtists or Genre can
+/// hold several values, which are written as native multi-value frames.
+pub type TagMap = HashMap<String, Vec<String>>;
+
+// separators of artist lists in files tagged before multi-value frames were common
+const ARTIST_SEPARATORS: [&str; 2] = [" / ", "; "];
+const GENRE_SEPARATORS: [&str; 3] = [";", "/", ","];
+
+/// Separators that single values are split on when a file is read, by tag key
+/// (case-insensitive), for files that keep several values in one like "Rock; Pop".
+/// Works like the `split` rule of Navidrome's tag mappings.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SplitSettings {
+    pub separators: HashMap<String, Vec<String>>,
+}
+
+impl Default for SplitSettings {
+    fn default() -> Self {
+        let artist_separators: Vec<String> = ARTIST_SEPARATORS.iter().map(|s| s.to_string()).collect();
+        let genre_separators = GENRE_SEPARATORS.iter().map(|s| s.to_string()).collect();
+        SplitSettings {
+            separators: HashMap::from([
+                ("TrackArtist".to_string(), artist_separators.clone()),
+                ("TrackArtists".to_string(), artist_separators.clone()),
+                ("AlbumArtist".to_string(), artist_separators.clone()),
+                ("Composer".to_string(), artist_separators),
+                ("Genre".to_string(), genre_separators),
+            ]),
+        }
+    }
+}
+
+impl SplitSettings {
+    fn separators_of(&self, key: &str) -> Option<&Vec<String>> {
+        self.separators
+            .iter()
+            .find(|(split_key, _)| split_key.eq_ignore_ascii_case(key))
+            .map(|(_, separators)| separators)
+    }
+}
+
+/// Splits the values of the configured keys on their separators. The parts are
+/// trimmed; empty parts and repeated values are dropped.
+pub fn split_values(tags: &mut TagMap, settings: &SplitSettings) {
+    for (key, values) in tags.iter_mut() {
+        let Some(separators) = settings.separators_of(key) else {
+            continue;
+        };
+        let mut split = Vec::new();
+        for value in values.iter() {
+            for part in split_value(value, separators) {
+                if !split.contains(&part) {
+                    split.push(part);
+                }
+            }
+        }
+        *values = split;
+    }
+}
+
+fn split_value(value: &str, separators: &[String]) -> Vec<String> {
+    let mut parts = vec![value.to_string()];
+    for separator in separators.iter().filter(|separator| !separator.is_empty()) {
+        parts = parts
+            .iter()
+            .flat_map(|part| part.split(separator.as_str()))
+            .map(|part| part.to_string())
+            .collect();
+    }
+    parts
+        .into_iter()
+        .map(|part| part.trim().to_string())
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+/// The first value of a key, for places that need a single value like queries
+pub fn first_value<'a>(tags: &'a TagMap, key: &str) -> Option<&'a str> {
+    tags.get(key).and_then(|values| values.first()).map(|value| value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tag_map;
+
+    #[test]
+    fn test_split_values_with_default_settings() {
+        let mut tags = tag_map(&[
+            ("TrackArtist", &["Artist A / Artist B; Artist C"]),
+            ("genre", &["Rock;Pop", "Pop, Indie"]),
+            ("TrackTitle", &["Title; with semicolon"]),
+            ("AlbumArtist", &["AC/DC"]),
+        ]);
+        split_values(&mut tags, &SplitSettings::default());
+
+        assert_eq!(
+            tags,
+            tag_map(&[
+                ("TrackArtist", &["Artist A", "Artist B", "Artist C"]),
+                ("genre", &["Rock", "Pop", "Indie"]),
+                ("TrackTitle", &["Title; with semicolon"]),
+                ("AlbumArtist", &["AC/DC"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_split_values_with_custom_settings() {
+        let settings = SplitSettings {
+            separators: HashMap::from([("TrackArtist".to_string(), vec![" feat. ".to_string(), "".to_string()])]),
+        };
+        let mut tags = tag_map(&[("TrackArtist", &["A feat. B", " ", "B"]), ("Genre", &["Rock; Pop"])]);
+        split_values(&mut tags, &settings);
+
+        assert_eq!(
+            tags,
+            tag_map(&[("TrackArtist", &["A", "B"]), ("Genre", &["Rock; Pop"])])
+        );
+    }
+
+    #[test]
+    fn test_first_value() {
+        let tags = tag_map(&[("Genre", &["Rock", "Pop"]), ("Mood", &[])]);
+        assert_eq!(first_value(&tags, "Genre"), Some("Rock"));
+        assert_eq!(first_value(&tags, "Mood"), None);
+        assert_eq!(first_value(&tags, "TrackTitle"), None);
+    }
+}
@@ -1,79 +1,104 @@
+use crate::tags::multi_value::TagMap;
 use anyhow::{Context, Result};
-use base64::{engine::general_purpose::STANDARD, Engine};
 use lofty::config::{ParseOptions, ParsingMode};
-use lofty::file::{AudioFile, TaggedFileExt};
-use lofty::picture::PictureType;
+use lofty::file::{AudioFile, TaggedFile, TaggedFileExt};
+use lofty::picture::{Picture, PictureType};
 use lofty::prelude::ItemKey;
 use lofty::probe::Probe;
-use std::collections::HashMap;
 use std::path::Path;
 use lofty::tag::Tag;
 use walkdir::DirEntry;
 
 pub struct AudioFileProperties {
-    pub tags: HashMap<String, String>,
+    pub tags: TagMap,
     pub duration_millis: u32,
-    pub cover_base64: Option<String>,
 }
 
 pub fn read_audio_file_properties(path: &Path) -> Result<AudioFileProperties> {
-    let parse_options = ParseOptions::new().parsing_mode(ParsingMode::Relaxed);
-
-    let tagged_file = Probe::open(path)?
-        .options(parse_options)
-        .read()
-        .with_context(|| format!("Failed to read audio file: {}", path.display()))?;
+    let tagged_file = read_tagged_file(path)?;
 
     // Get duration from audio properties
     let duration_millis = tagged_file.properties().duration().as_millis() as u32;
 
-    // return with empty tags if tag is None
-    let Some(tag) = tagged_file.primary_tag() else {
-        return Ok(AudioFileProperties {
-            tags: HashMap::default(),
-            duration_millis,
-            cover_base64: None,
-        });
-    };
+    // the primary tag wins, keys it lacks are taken from the other tags of the file
+    let mut tags = TagMap::new();
+    for tag in tags_by_priority(&tagged_file) {
+        for (key, values) in tag_to_map(tag) {
+            tags.entry(key).or_insert(values);
+        }
+    }
+
+    Ok(AudioFileProperties {
+        tags,
+        duration_millis,
+    })
+}
+
+/// Reads a file with all its tags, tolerating malformed tag items
+pub fn read_tagged_file(path: &Path) -> Result<TaggedFile> {
+    let parse_options = ParseOptions::new().parsing_mode(ParsingMode::Relaxed);
+    Probe::open(path)?
+        .options(parse_options)
+        .read()
+        .with_context(|| format!("Failed to read audio file: {}", path.display()))
+}
 
-    let mut tags = HashMap::new();
+/// All tags of a file, the primary tag first
+pub fn tags_by_priority(tagged_file: &TaggedFile) -> Vec<&Tag> {
+    let primary_tag_type = tagged_file.primary_tag_type();
+    let mut tags: Vec<&Tag> = tagged_file.tags().iter().collect();
+    tags.sort_by_key(|tag| tag.tag_type() != primary_tag_type);
+    tags
+}
 
-    // read tags
+/// The text items of a tag by their key names. Repeated items, like several
+/// Vorbis comments or MP4 atoms, and null-separated ID3v2.4 values become several values.
+pub fn tag_to_map(tag: &Tag) -> TagMap {
+    let mut tags = TagMap::new();
     for item in tag.items() {
         if let Some(text) = item.value().text() {
-            let key = match item.key() {
-                ItemKey::Unknown(s) => s.clone(),
-                other => format!("{:?}", other),
-            };
-            tags.insert(key, text.to_string());
+            tags.entry(item_key_name(item.key()))
+                .or_default()
+                .extend(text.split('\0').map(|value| value.to_string()));
         }
     }
+    tags
+}
 
-    let cover_base64 = get_cover_as_base64(tag);
+pub fn item_key_name(key: &ItemKey) -> String {
+    match key {
+        ItemKey::Unknown(s) => s.clone(),
+        other => format!("{:?}", other),
+    }
+}
 
-    Ok(AudioFileProperties {
-        tags,
-        duration_millis,
-        cover_base64,
-    })
+/// The embedded front cover of a file, or its first picture if none is marked as front cover
+pub fn read_cover(path: &Path) -> Result<Option<Picture>> {
+    let tagged_file = read_tagged_file(path)?;
+    let cover = tags_by_priority(&tagged_file).into_iter().find_map(front_cover);
+    Ok(cover.cloned())
+}
+
+/// The text items of the primary tag of a file, the tag that writes go to
+pub fn read_primary_tags(path: &Path) -> Result<TagMap> {
+    let parse_options = ParseOptions::new()
+        .parsing_mode(ParsingMode::Relaxed)
+        .read_properties(false);
+
+    let tagged_file = Probe::open(path)?
+        .options(parse_options)
+        .read()
+        .with_context(|| format!("Failed to read audio file: {}", path.display()))?;
+
+    Ok(tagged_file.primary_tag().map(tag_to_map).unwrap_or_default())
 }
 
-fn get_cover_as_base64(tag: &Tag) -> Option<String> {
+fn front_cover(tag: &Tag) -> Option<&Picture> {
     tag
         .pictures()
         .iter()
         .find(|p| p.pic_type() == PictureType::CoverFront)
         .or_else(|| tag.pictures().first())
-        .map(picture_to_data_url)
-}
-
-fn picture_to_data_url(picture: &lofty::picture::Picture) -> String {
-    let mime = picture
-        .mime_type()
-        .map(|m| m.to_string())
-        .unwrap_or_else(|| "image/jpeg".to_string());
-    let data = STANDARD.encode(picture.data());
-    format!("data:{};base64,{}", mime, data)
 }
 
 pub fn is_music_file(entry: &DirEntry) -> bool {
@@ -120,4 +145,10 @@ mod tests {
         );
         assert!(properties.duration_millis > 0, "Expected non-zero duration");
     }
+
+    #[test]
+    fn test_read_cover_from_file_without_tags() {
+        let cover = read_cover(Path::new("./tests/music_libraries/different_formats/some_song.wav"));
+        assert!(cover.unwrap().is_none());
+    }
 }
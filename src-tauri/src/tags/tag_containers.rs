@@ -0,0 +1,361 @@
+use crate::tags::multi_value::TagMap;
+use crate::tags::reading_tags::{read_tagged_file, tag_to_map, tags_by_priority};
+use anyhow::{anyhow, bail, Context, Result};
+use lofty::config::WriteOptions;
+use lofty::file::{AudioFile, TaggedFile, TaggedFileExt};
+use lofty::tag::{Tag, TagExt, TagType};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// All tag types defined once as (Display Name, TagType) tuples
+const TAG_TYPES: [(&str, TagType); 7] = [
+    ("Id3v2", TagType::Id3v2),
+    ("Id3v1", TagType::Id3v1),
+    ("Ape", TagType::Ape),
+    ("VorbisComments", TagType::VorbisComments),
+    ("Mp4Ilst", TagType::Mp4Ilst),
+    ("RiffInfo", TagType::RiffInfo),
+    ("AiffText", TagType::AiffText),
+];
+
+// ID3v1 cuts its text fields off after this many characters
+const ID3V1_FIELD_LENGTH: usize = 30;
+
+/// One tag container of a file, like the ID3v2 or the APE tag of an MP3
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FileTag {
+    pub tag_type: String,
+    pub is_primary: bool,
+    pub tags: TagMap,
+    pub picture_count: usize,
+}
+
+/// A key that has different values in the tags of a file, by tag type
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TagConflict {
+    pub key: String,
+    pub values: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FileTags {
+    pub path: String,
+    pub tags: Vec<FileTag>,
+    pub conflicts: Vec<TagConflict>,
+    pub supported_tag_types: Vec<String>,
+}
+
+/// Reads every tag of a file, the primary tag first
+pub fn read_file_tags(path: &Path) -> Result<FileTags> {
+    let tagged_file = read_tagged_file(path)?;
+    let primary_tag_type = tagged_file.primary_tag_type();
+
+    let tags: Vec<FileTag> = tags_by_priority(&tagged_file)
+        .into_iter()
+        .map(|tag| FileTag {
+            tag_type: tag_type_name(tag.tag_type()),
+            is_primary: tag.tag_type() == primary_tag_type,
+            tags: tag_to_map(tag),
+            picture_count: tag.picture_count() as usize,
+        })
+        .collect();
+    let supported_tag_types = TAG_TYPES
+        .iter()
+        .filter(|(_, tag_type)| tagged_file.supports_tag_type(*tag_type))
+        .map(|(name, _)| name.to_string())
+        .collect();
+
+    Ok(FileTags {
+        path: path.to_string_lossy().to_string(),
+        conflicts: find_conflicts(&tags),
+        tags,
+        supported_tag_types,
+    })
+}
+
+/// Fills the keys and pictures a tag lacks from the other tags of the file, in
+/// order of priority. Values the tag already has are kept.
+pub fn merge_tag_types(path: &Path, into: TagType) -> Result<()> {
+    let tagged_file = read_tagged_file(path)?;
+    ensure_supported(&tagged_file, into)?;
+
+    let mut target = tagged_file.tag(into).cloned().unwrap_or_else(|| Tag::new(into));
+    for source in tags_by_priority(&tagged_file) {
+        if source.tag_type() != into {
+            add_missing_items(&mut target, source);
+        }
+    }
+    save_tag(&target, path)
+}
+
+/// Replaces the tag of type `to` with a copy of the tag of type `from`. Keys the
+/// target tag type cannot hold are left out.
+pub fn copy_tag_type(path: &Path, from: TagType, to: TagType) -> Result<()> {
+    let tagged_file = read_tagged_file(path)?;
+    ensure_supported(&tagged_file, to)?;
+
+    let source = tagged_file
+        .tag(from)
+        .ok_or_else(|| anyhow!("{} has no {} tag", path.display(), tag_type_name(from)))?;
+    let mut target = Tag::new(to);
+    add_missing_items(&mut target, source);
+    save_tag(&target, path)
+}
+
+/// Removes the tags of the given types from a file
+pub fn strip_tag_types(path: &Path, tag_types: &[TagType]) -> Result<()> {
+    let tagged_file = read_tagged_file(path)?;
+    // removing an ID3v1 tag the file doesn't have would append an empty one
+    for tag_type in tag_types.iter().filter(|tag_type| tagged_file.contains_tag_type(**tag_type)) {
+        tag_type
+            .remove_from_path(path)
+            .with_context(|| format!("Failed to strip the {} tag of {}", tag_type_name(*tag_type), path.display()))?;
+    }
+    Ok(())
+}
+
+/// Converts a tag type name like "Id3v2" to its TagType (case-insensitive)
+pub fn parse_tag_type(s: &str) -> Result<TagType> {
+    let s_lower = s.to_lowercase();
+    TAG_TYPES
+        .iter()
+        .find(|(name, _)| name.to_lowercase() == s_lower)
+        .map(|(_, tag_type)| *tag_type)
+        .ok_or_else(|| anyhow!("Unknown tag type: {}", s))
+}
+
+pub fn tag_type_name(tag_type: TagType) -> String {
+    TAG_TYPES
+        .iter()
+        .find(|(_, known)| *known == tag_type)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| format!("{:?}", tag_type))
+}
+
+/// Keys whose values differ between the tags. A value cut off by ID3v1 is no conflict.
+fn find_conflicts(tags: &[FileTag]) -> Vec<TagConflict> {
+    let mut values_by_key: BTreeMap<&str, BTreeMap<String, Vec<String>>> = BTreeMap::new();
+    for file_tag in tags {
+        for (key, values) in &file_tag.tags {
+            values_by_key
+                .entry(key)
+                .or_default()
+                .insert(file_tag.tag_type.clone(), values.clone());
+        }
+    }
+
+    values_by_key
+        .into_iter()
+        .filter(|(_, values)| !values_agree(values))
+        .map(|(key, values)| TagConflict {
+            key: key.to_string(),
+            values,
+        })
+        .collect()
+}
+
+fn values_agree(values: &BTreeMap<String, Vec<String>>) -> bool {
+    let id3v1_name = tag_type_name(TagType::Id3v1);
+    let mut full_values = values
+        .iter()
+        .filter(|(tag_type, _)| **tag_type != id3v1_name)
+        .map(|(_, value)| value);
+    let Some(first) = full_values.next() else {
+        return true;
+    };
+    if full_values.any(|value| value != first) {
+        return false;
+    }
+
+    // ID3v1 holds a single value
+    match values.get(&id3v1_name) {
+        Some(id3v1_values) => {
+            id3v1_values == first
+                || match (id3v1_values.as_slice(), first.first()) {
+                    ([id3v1_value], Some(first_value)) => {
+                        id3v1_value.chars().count() == ID3V1_FIELD_LENGTH
+                            && first_value.starts_with(id3v1_value.as_str())
+                    }
+                    _ => false,
+                }
+        }
+        None => true,
+    }
+}
+
+fn add_missing_items(target: &mut Tag, source: &Tag) {
+    let missing_keys: HashSet<_> = source
+        .items()
+        .map(|item| item.key())
+        .filter(|key| target.get(key).is_none())
+        .cloned()
+        .collect();
+    // every item of a missing key, so that all of its values come along
+    for item in source.items().filter(|item| missing_keys.contains(item.key())) {
+        target.push(item.clone());
+    }
+    if target.picture_count() == 0 {
+        for picture in source.pictures() {
+            target.push_picture(picture.clone());
+        }
+    }
+}
+
+fn ensure_supported(tagged_file: &TaggedFile, tag_type: TagType) -> Result<()> {
+    if !tagged_file.supports_tag_type(tag_type) {
+        bail!(
+            "{:?} files cannot hold {} tags",
+            tagged_file.file_type(),
+            tag_type_name(tag_type)
+        );
+    }
+    Ok(())
+}
+
+fn save_tag(tag: &Tag, path: &Path) -> Result<()> {
+    tag.save_to_path(path, WriteOptions::default())
+        .with_context(|| format!("Failed to save tags to file: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::reading_tags::read_audio_file_properties;
+    use crate::tags::writing_tags::write_tags_to_file;
+    use lofty::prelude::ItemKey;
+    use std::fs::copy;
+    use std::path::PathBuf;
+    use tempfile::{tempdir, TempDir};
+
+    /// An MP3 with an ID3v2 and an APE tag that disagree on the title
+    fn mp3_with_two_tags() -> (TempDir, PathBuf) {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("two_tags.mp3");
+        copy("./tests/music_libraries/different_formats/some_song.mp3", &path).unwrap();
+        strip_tag_types(&path, &[TagType::Id3v1, TagType::Ape]).unwrap();
+
+        let id3v2_tags = TagMap::from([
+            ("TrackTitle".to_string(), vec!["ID3 Title".to_string()]),
+            ("TrackArtist".to_string(), vec!["Some Artist".to_string()]),
+        ]);
+        write_tags_to_file(&path, &id3v2_tags).unwrap();
+
+        // the last 128 bytes of a 131 byte APE tag start with "TAG" and would be read as ID3v1
+        let mut ape = Tag::new(TagType::Ape);
+        ape.insert_text(ItemKey::TrackTitle, "APE Title".to_string());
+        ape.insert_text(ItemKey::TrackArtist, "Some Artist".to_string());
+        ape.insert_text(ItemKey::AlbumTitle, "APE Album".to_string());
+        ape.save_to_path(&path, WriteOptions::default()).unwrap();
+
+        (dir, path)
+    }
+
+    fn file_tag(tag_type: &str, tags: &[(&str, &str)]) -> FileTag {
+        FileTag {
+            tag_type: tag_type.to_string(),
+            is_primary: false,
+            tags: tags.iter().map(|(k, v)| (k.to_string(), vec![v.to_string()])).collect(),
+            picture_count: 0,
+        }
+    }
+
+    #[test]
+    fn test_parse_tag_type() {
+        assert_eq!(parse_tag_type("Id3v2").unwrap(), TagType::Id3v2);
+        assert_eq!(parse_tag_type("vorbiscomments").unwrap(), TagType::VorbisComments);
+        assert!(parse_tag_type("Id3v3").is_err());
+        for (name, tag_type) in TAG_TYPES {
+            assert_eq!(tag_type_name(tag_type), name);
+        }
+    }
+
+    #[test]
+    fn test_find_conflicts() {
+        let long_title = "A Title That Is Longer Than Thirty Characters";
+        let tags = vec![
+            file_tag("Id3v2", &[("TrackTitle", long_title), ("Genre", "Rock"), ("Year", "1991")]),
+            file_tag("Ape", &[("TrackTitle", long_title), ("Genre", "Pop")]),
+            file_tag("Id3v1", &[("TrackTitle", &long_title[..30]), ("Year", "1992")]),
+        ];
+        assert_eq!(
+            find_conflicts(&tags),
+            vec![
+                TagConflict {
+                    key: "Genre".to_string(),
+                    values: BTreeMap::from([
+                        ("Ape".to_string(), vec!["Pop".to_string()]),
+                        ("Id3v2".to_string(), vec!["Rock".to_string()]),
+                    ]),
+                },
+                TagConflict {
+                    key: "Year".to_string(),
+                    values: BTreeMap::from([
+                        ("Id3v1".to_string(), vec!["1992".to_string()]),
+                        ("Id3v2".to_string(), vec!["1991".to_string()]),
+                    ]),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_file_tags() {
+        let (_dir, path) = mp3_with_two_tags();
+        let file_tags = read_file_tags(&path).unwrap();
+
+        let tag_types: Vec<&str> = file_tags.tags.iter().map(|tag| tag.tag_type.as_str()).collect();
+        assert_eq!(tag_types, vec!["Id3v2", "Ape"]);
+        assert!(file_tags.tags[0].is_primary);
+        assert_eq!(file_tags.tags[1].tags.get("AlbumTitle"), Some(&vec!["APE Album".to_string()]));
+        assert_eq!(
+            file_tags.conflicts,
+            vec![TagConflict {
+                key: "TrackTitle".to_string(),
+                values: BTreeMap::from([
+                    ("Ape".to_string(), vec!["APE Title".to_string()]),
+                    ("Id3v2".to_string(), vec!["ID3 Title".to_string()]),
+                ]),
+            }]
+        );
+        for tag_type in ["Id3v2", "Id3v1", "Ape"] {
+            assert!(file_tags.supported_tag_types.contains(&tag_type.to_string()));
+        }
+        assert!(!file_tags.supported_tag_types.contains(&"Mp4Ilst".to_string()));
+
+        // the library shows the primary tag, completed by the others
+        let properties = read_audio_file_properties(&path).unwrap();
+        assert_eq!(properties.tags.get("TrackTitle"), Some(&vec!["ID3 Title".to_string()]));
+        assert_eq!(properties.tags.get("AlbumTitle"), Some(&vec!["APE Album".to_string()]));
+    }
+
+    #[test]
+    fn test_merge_copy_and_strip() {
+        let (_dir, path) = mp3_with_two_tags();
+
+        merge_tag_types(&path, TagType::Id3v2).unwrap();
+        let file_tags = read_file_tags(&path).unwrap();
+        let id3v2 = &file_tags.tags[0].tags;
+        assert_eq!(id3v2.get("TrackTitle"), Some(&vec!["ID3 Title".to_string()]));
+        assert_eq!(id3v2.get("AlbumTitle"), Some(&vec!["APE Album".to_string()]));
+        assert_eq!(file_tags.conflicts.len(), 1);
+
+        copy_tag_type(&path, TagType::Id3v2, TagType::Ape).unwrap();
+        let file_tags = read_file_tags(&path).unwrap();
+        assert_eq!(file_tags.tags[1].tags.get("TrackTitle"), Some(&vec!["ID3 Title".to_string()]));
+        assert!(file_tags.conflicts.is_empty());
+
+        strip_tag_types(&path, &[TagType::Ape]).unwrap();
+        let file_tags = read_file_tags(&path).unwrap();
+        assert_eq!(file_tags.tags.len(), 1);
+        assert_eq!(file_tags.tags[0].tags.get("AlbumTitle"), Some(&vec!["APE Album".to_string()]));
+    }
+
+    #[test]
+    fn test_copy_needs_a_source_and_a_supported_target() {
+        let (_dir, path) = mp3_with_two_tags();
+        assert!(copy_tag_type(&path, TagType::VorbisComments, TagType::Ape).is_err());
+        assert!(copy_tag_type(&path, TagType::Id3v2, TagType::Mp4Ilst).is_err());
+        assert!(merge_tag_types(&path, TagType::Mp4Ilst).is_err());
+    }
+}
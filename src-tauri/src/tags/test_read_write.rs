@@ -1,12 +1,63 @@
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
-    use std::fs::copy;
+    use image::{ImageFormat, RgbImage};
+    use lofty::config::WriteOptions;
+    use lofty::file::{AudioFile, TaggedFileExt};
+    use lofty::id3::v2::{Frame, Id3v2Tag, UnsynchronizedTextFrame};
+    use lofty::picture::{MimeType, Picture, PictureType};
+    use lofty::prelude::{ItemKey, TagExt};
+    use lofty::tag::{ItemValue, Tag, TagItem, TagType};
+    use lofty::TextEncoding;
+    use std::fs::{copy, read_dir};
+    use std::io::Cursor;
+    use std::path::{Path, PathBuf};
     use tempfile::tempdir;
 
-    use crate::tags::reading_tags::read_audio_file_properties;
-    use crate::tags::writing_tags::write_tags_to_file;
+    use crate::tags::multi_value::TagMap;
+    use crate::tags::reading_tags::{read_audio_file_properties, read_cover};
+    use crate::tags::writing_tags::{patch_tags_in_file, write_tags_to_file, TagPatch};
+
+    /// Copies of every file in tests/music_libraries
+    fn copy_sample_files(dir: &Path) -> Vec<PathBuf> {
+        let mut copies = Vec::new();
+        for library in read_dir("./tests/music_libraries").unwrap() {
+            let library = library.unwrap().path();
+            for file in read_dir(&library).unwrap() {
+                let file = file.unwrap().path();
+                let library_name = library.file_name().unwrap().to_string_lossy();
+                let file_name = file.file_name().unwrap().to_string_lossy();
+                let copy_path = dir.join(format!("{}_{}", library_name, file_name));
+                copy(&file, &copy_path).unwrap();
+                copies.push(copy_path);
+            }
+        }
+        copies.sort();
+        copies
+    }
+
+    fn png_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        RgbImage::new(2, 2)
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        data
+    }
+
+    /// Edits the primary tag of a file with lofty directly, like another tagger would
+    fn edit_primary_tag(path: &Path, edit: impl FnOnce(&mut Tag)) {
+        let mut tagged_file = lofty::read_from_path(path).unwrap();
+        if tagged_file.primary_tag().is_none() {
+            let tag_type = tagged_file.file_type().primary_tag_type();
+            tagged_file.insert_tag(Tag::new(tag_type));
+        }
+        edit(tagged_file.primary_tag_mut().unwrap());
+        tagged_file.save_to_path(path, WriteOptions::default()).unwrap();
+    }
+
+    fn primary_tag(path: &Path) -> Tag {
+        lofty::read_from_path(path).unwrap().primary_tag().unwrap().clone()
+    }
 
     #[test]
     fn test_write_read_normal_tag() {
@@ -21,9 +72,9 @@ mod tests {
         .unwrap();
 
         // Write a normal tag
-        let tags_to_write = HashMap::from([
-            ("TrackTitle".to_string(), "My Test Song".to_string()),
-            ("TrackArtist".to_string(), "Test Artist".to_string()),
+        let tags_to_write = TagMap::from([
+            ("TrackTitle".to_string(), vec!["My Test Song".to_string()]),
+            ("TrackArtist".to_string(), vec!["Test Artist".to_string()]),
         ]);
         write_tags_to_file(&temp_path, &tags_to_write).unwrap();
 
@@ -33,12 +84,12 @@ mod tests {
         // Verify the written tags match
         assert_eq!(
             properties.tags.get("TrackTitle"),
-            Some(&"My Test Song".to_string()),
+            Some(&vec!["My Test Song".to_string()]),
             "TrackTitle should match"
         );
         assert_eq!(
             properties.tags.get("TrackArtist"),
-            Some(&"Test Artist".to_string()),
+            Some(&vec!["Test Artist".to_string()]),
             "TrackArtist should match"
         );
     }
@@ -60,8 +111,8 @@ mod tests {
         // Write a tag with a custom key - this tests the ItemKey::Unknown fallback
         // The write should succeed even if the custom tag isn't preserved
         let custom_key = "CustomField";
-        let tags_to_write = HashMap::from([
-            (custom_key.to_string(), "Custom Value".to_string()),
+        let tags_to_write = TagMap::from([
+            (custom_key.to_string(), vec!["Custom Value".to_string()]),
         ]);
         let write_result = write_tags_to_file(&temp_path, &tags_to_write);
         
@@ -91,13 +142,13 @@ mod tests {
         .unwrap();
 
         // Write multiple tags of different types
-        let tags_to_write = HashMap::from([
-            ("TrackTitle".to_string(), "Multi Tag Song".to_string()),
-            ("TrackArtist".to_string(), "Various Artists".to_string()),
-            ("AlbumTitle".to_string(), "Test Album".to_string()),
-            ("Genre".to_string(), "Rock".to_string()),
-            ("RecordingDate".to_string(), "2024-01-01".to_string()),
-            ("Comment".to_string(), "A test comment".to_string()),
+        let tags_to_write = TagMap::from([
+            ("TrackTitle".to_string(), vec!["Multi Tag Song".to_string()]),
+            ("TrackArtist".to_string(), vec!["Various Artists".to_string()]),
+            ("AlbumTitle".to_string(), vec!["Test Album".to_string()]),
+            ("Genre".to_string(), vec!["Rock".to_string()]),
+            ("RecordingDate".to_string(), vec!["2024-01-01".to_string()]),
+            ("Comment".to_string(), vec!["A test comment".to_string()]),
         ]);
         write_tags_to_file(&temp_path, &tags_to_write).unwrap();
 
@@ -107,30 +158,197 @@ mod tests {
         // Verify all written tags match
         assert_eq!(
             properties.tags.get("TrackTitle"),
-            Some(&"Multi Tag Song".to_string()),
+            Some(&vec!["Multi Tag Song".to_string()]),
         );
         assert_eq!(
             properties.tags.get("TrackArtist"),
-            Some(&"Various Artists".to_string()),
+            Some(&vec!["Various Artists".to_string()]),
         );
         assert_eq!(
             properties.tags.get("AlbumTitle"),
-            Some(&"Test Album".to_string()),
+            Some(&vec!["Test Album".to_string()]),
         );
         assert_eq!(
             properties.tags.get("Genre"),
-            Some(&"Rock".to_string()),
+            Some(&vec!["Rock".to_string()]),
         );
         assert_eq!(
             properties.tags.get("RecordingDate"),
-            Some(&"2024-01-01".to_string()),
+            Some(&vec!["2024-01-01".to_string()]),
         );
         assert_eq!(
             properties.tags.get("Comment"),
-            Some(&"A test comment".to_string()),
+            Some(&vec!["A test comment".to_string()]),
         );
         
         // Verify we have exactly the number of tags we wrote
         assert_eq!(properties.tags.len(), 6, "Should have exactly 6 tags");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_write_read_multiple_values() {
+        let dir = tempdir().unwrap();
+        let tags_to_write = TagMap::from([
+            ("TrackTitle".to_string(), vec!["Multi Value Song".to_string()]),
+            ("TrackArtist".to_string(), vec!["Artist A".to_string(), "Artist B".to_string()]),
+            ("Genre".to_string(), vec!["Rock".to_string(), "Pop".to_string(), "Indie".to_string()]),
+        ]);
+
+        // ID3v2.4 frames and Vorbis comments
+        for file_name in ["some_song.mp3", "some_audio.flac"] {
+            let temp_path = dir.path().join(file_name);
+            copy(
+                format!("./tests/music_libraries/different_formats/{}", file_name),
+                &temp_path,
+            )
+            .unwrap();
+
+            write_tags_to_file(&temp_path, &tags_to_write).unwrap();
+            let properties = read_audio_file_properties(&temp_path).unwrap();
+            for (key, values) in &tags_to_write {
+                assert_eq!(properties.tags.get(key), Some(values), "{} should keep every {}", file_name, key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_multiple_values_are_repeated_vorbis_comments() {
+        let dir = tempdir().unwrap();
+        let temp_path = dir.path().join("test_vorbis.flac");
+        copy(
+            "./tests/music_libraries/different_formats/some_audio.flac",
+            &temp_path,
+        )
+        .unwrap();
+
+        let tags_to_write = TagMap::from([
+            ("TrackArtist".to_string(), vec!["Artist A".to_string(), "Artist B".to_string()]),
+        ]);
+        write_tags_to_file(&temp_path, &tags_to_write).unwrap();
+
+        let tagged_file = lofty::read_from_path(&temp_path).unwrap();
+        let vorbis_comments = tagged_file.tag(TagType::VorbisComments).unwrap();
+        let artists: Vec<&str> = vorbis_comments.get_strings(&ItemKey::TrackArtist).collect();
+        assert_eq!(artists, vec!["Artist A", "Artist B"]);
+    }
+
+    #[test]
+    fn test_writes_keep_pictures() {
+        let dir = tempdir().unwrap();
+        let png = png_data();
+        for path in copy_sample_files(dir.path()) {
+            let picture = Picture::new_unchecked(PictureType::CoverFront, Some(MimeType::Png), None, png.clone());
+            edit_primary_tag(&path, |tag| tag.push_picture(picture));
+
+            write_tags_to_file(&path, &TagMap::from([("TrackTitle".to_string(), vec!["Title".to_string()])])).unwrap();
+            patch_tags_in_file(
+                &path,
+                &TagPatch {
+                    set: TagMap::from([("AlbumTitle".to_string(), vec!["Album".to_string()])]),
+                    remove: vec!["TrackTitle".to_string()],
+                },
+            )
+            .unwrap();
+
+            let tag = primary_tag(&path);
+            assert_eq!(tag.picture_count(), 1, "{} should keep its picture", path.display());
+            assert_eq!(read_cover(&path).unwrap().unwrap().data(), png.as_slice());
+            assert_eq!(tag.get_string(&ItemKey::AlbumTitle), Some("Album"));
+            assert!(tag.get(&ItemKey::TrackTitle).is_none());
+        }
+    }
+
+    #[test]
+    fn test_patch_keeps_untouched_keys() {
+        let dir = tempdir().unwrap();
+        for path in copy_sample_files(dir.path()) {
+            let tags = TagMap::from([
+                ("TrackTitle".to_string(), vec!["Title".to_string()]),
+                ("TrackArtist".to_string(), vec!["Artist".to_string()]),
+                ("Genre".to_string(), vec!["Rock".to_string(), "Pop".to_string()]),
+            ]);
+            write_tags_to_file(&path, &tags).unwrap();
+
+            let patch = TagPatch {
+                set: TagMap::from([("TrackTitle".to_string(), vec!["New Title".to_string()])]),
+                remove: vec!["Genre".to_string()],
+            };
+            patch_tags_in_file(&path, &patch).unwrap();
+
+            // other tags of the file, like ID3v1, may still have a genre
+            let tag = primary_tag(&path);
+            assert_eq!(tag.get_string(&ItemKey::TrackTitle), Some("New Title"));
+            assert_eq!(
+                tag.get_string(&ItemKey::TrackArtist),
+                Some("Artist"),
+                "{} should keep TrackArtist",
+                path.display()
+            );
+            assert!(tag.get(&ItemKey::Genre).is_none());
+        }
+    }
+
+    #[test]
+    fn test_writes_keep_language_and_description_of_lyrics() {
+        let dir = tempdir().unwrap();
+        let temp_path = dir.path().join("test_lyrics.mp3");
+        copy(
+            "./tests/music_libraries/different_formats/some_song.mp3",
+            &temp_path,
+        )
+        .unwrap();
+
+        // written as an ID3v2 tag, lofty's generic writer would drop the language
+        let mut id3v2 = Id3v2Tag::default();
+        id3v2.insert(Frame::UnsynchronizedText(UnsynchronizedTextFrame::new(
+            TextEncoding::UTF8,
+            *b"deu",
+            "Chorus".to_string(),
+            "La la la".to_string(),
+        )));
+        id3v2.save_to_path(&temp_path, WriteOptions::default()).unwrap();
+
+        // saving the tags like the tag editor does, with the lyrics unchanged
+        let mut tags = read_audio_file_properties(&temp_path).unwrap().tags;
+        tags.insert("TrackTitle".to_string(), vec!["Title".to_string()]);
+        write_tags_to_file(&temp_path, &tags).unwrap();
+
+        let tag = primary_tag(&temp_path);
+        let lyrics = tag.get(&ItemKey::Lyrics).unwrap();
+        assert_eq!(lyrics.value().text(), Some("La la la"));
+        assert_eq!(lyrics.lang(), b"deu");
+        assert_eq!(lyrics.description(), "Chorus");
+    }
+
+    #[test]
+    fn test_patch_keeps_unknown_items() {
+        let dir = tempdir().unwrap();
+        let temp_path = dir.path().join("test_unknown_items.flac");
+        copy(
+            "./tests/music_libraries/different_formats/some_audio.flac",
+            &temp_path,
+        )
+        .unwrap();
+
+        edit_primary_tag(&temp_path, |tag| {
+            // push() drops keys that aren't mapped for the tag type
+            tag.push_unchecked(TagItem::new(
+                ItemKey::Unknown("MY_CUSTOM_FIELD".to_string()),
+                ItemValue::Text("Custom Value".to_string()),
+            ));
+        });
+
+        let patch = TagPatch {
+            set: TagMap::from([("TrackTitle".to_string(), vec!["Title".to_string()])]),
+            remove: Vec::new(),
+        };
+        patch_tags_in_file(&temp_path, &patch).unwrap();
+
+        let tag = primary_tag(&temp_path);
+        assert_eq!(tag.tag_type(), TagType::VorbisComments);
+        assert_eq!(
+            tag.get_string(&ItemKey::Unknown("MY_CUSTOM_FIELD".to_string())),
+            Some("Custom Value")
+        );
+    }
+}
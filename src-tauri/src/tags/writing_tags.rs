@@ -1,10 +1,13 @@
-use std::collections::HashMap;
+use crate::tags::multi_value::TagMap;
+use crate::tags::reading_tags::{item_key_name, read_tagged_file};
 use std::path::Path;
 use anyhow::{Context, Result};
 use lofty::config::WriteOptions;
-use lofty::file::{AudioFile, TaggedFileExt};
+use lofty::file::{TaggedFile, TaggedFileExt};
+use lofty::id3::v2::Id3v2Tag;
 use lofty::prelude::{ItemKey, TagExt};
-use lofty::tag::{ItemValue, Tag, TagItem};
+use lofty::tag::{ItemValue, Tag, TagItem, TagType};
+use serde::Deserialize;
 
 /// All supported tags defined once as (Display Name, ItemKey) tuples
 const SUPPORTED_TAGS: [(&str, ItemKey); 103] = [
@@ -126,38 +129,108 @@ const SUPPORTED_TAGS: [(&str, ItemKey); 103] = [
     ("AppleId3v2ContentGroup", ItemKey::AppleId3v2ContentGroup),
 ];
 
-pub fn write_tags_to_file(path: &Path, tags: &HashMap<String, String>) -> Result<()> {
-    let mut tagged_file = lofty::read_from_path(path)
-        .with_context(|| format!("Failed to read audio file: {}", path.display()))?;
-
-    // Get or create primary tag
-    let tag = match tagged_file.primary_tag_mut() {
-        Some(tag) => tag,
-        None => {
-            // If no tag exists, create one based on file type
-            let file_type = tagged_file.file_type();
-            let tag_type = file_type.primary_tag_type();
-            tagged_file.insert_tag(Tag::new(tag_type));
-            tagged_file.primary_tag_mut().unwrap()
-        }
+/// Keys to set and to remove in the primary tag of a file. Everything else in the
+/// tag, like pictures, binary items and untouched frames, is kept as it is.
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct TagPatch {
+    #[serde(default)]
+    pub set: TagMap,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// Replaces the text items of the primary tag: keys missing from `tags` are removed,
+/// the others are set. A key with several values is written as a native multi-value
+/// frame: null-separated in ID3v2.4, repeated in Vorbis comments and MP4.
+pub fn write_tags_to_file(path: &Path, tags: &TagMap) -> Result<()> {
+    let mut tagged_file = read_tagged_file(path)?;
+    let tag = primary_tag_mut(&mut tagged_file);
+
+    let kept_keys: Vec<ItemKey> = tags.keys().map(|key| parse_item_key(key)).collect();
+    let remove = tag
+        .items()
+        .filter(|item| item.value().text().is_some() && !kept_keys.contains(item.key()))
+        .map(|item| item_key_name(item.key()))
+        .collect();
+    let patch = TagPatch {
+        set: tags.clone(),
+        remove,
     };
 
-    // Clear existing tags
-    tag.clear();
+    apply_patch(tag, &patch);
+    save_primary_tag(tag, path)
+}
 
-    // Set new tags
-    for (tag_key, tag_value) in tags {
-        let item_key = parse_item_key(tag_key);
-        let tag_item = TagItem::new(item_key, ItemValue::Text(tag_value.clone()));
-        tag.insert(tag_item);
+/// Sets and removes the keys of the patch in the primary tag, keeping everything else
+pub fn patch_tags_in_file(path: &Path, patch: &TagPatch) -> Result<()> {
+    let mut tagged_file = read_tagged_file(path)?;
+    let tag = primary_tag_mut(&mut tagged_file);
+    apply_patch(tag, patch);
+    save_primary_tag(tag, path)
+}
+
+fn apply_patch(tag: &mut Tag, patch: &TagPatch) {
+    for key in &patch.remove {
+        let item_key = parse_item_key(key);
+        tag.retain(|item| item.key() != &item_key);
+    }
+    for (key, values) in &patch.set {
+        let item_key = parse_item_key(key);
+        // unchanged items stay untouched, with the language and description of
+        // their frame, like the language of ID3v2 lyrics and comments
+        if text_values(tag, &item_key) == *values {
+            continue;
+        }
+        remove_text_items(tag, &item_key);
+        push_values(tag, item_key, values);
     }
+}
+
+/// Removes the text items of a key; binary items like a Popularimeter stay
+fn remove_text_items(tag: &mut Tag, item_key: &ItemKey) {
+    tag.retain(|item| item.key() != item_key || item.value().text().is_none());
+}
+
+fn text_values(tag: &Tag, item_key: &ItemKey) -> Vec<String> {
+    tag.get_strings(item_key)
+        .flat_map(|value| value.split('\0'))
+        .map(|value| value.to_string())
+        .collect()
+}
 
-    // Save changes to file
-    tagged_file
-        .save_to_path(path, WriteOptions::default())
-        .with_context(|| format!("Failed to save tags to file: {}", path.display()))?;
+/// The primary tag of a file, created if the file has none
+pub fn primary_tag_mut(tagged_file: &mut TaggedFile) -> &mut Tag {
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.file_type().primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    tagged_file.primary_tag_mut().unwrap()
+}
 
-    Ok(())
+/// Saves the primary tag; the other tags of the file are left as they are
+pub fn save_primary_tag(tag: &Tag, path: &Path) -> Result<()> {
+    let saved = match tag.tag_type() {
+        // the generic ID3v2 writer drops the language and description of comments and lyrics
+        TagType::Id3v2 => Id3v2Tag::from(tag.clone()).save_to_path(path, WriteOptions::default()),
+        _ => tag.save_to_path(path, WriteOptions::default()),
+    };
+    saved.with_context(|| format!("Failed to save tags to file: {}", path.display()))
+}
+
+/// Adds the values of a key to a tag, in the multi-value form of its format
+fn push_values(tag: &mut Tag, item_key: ItemKey, values: &[String]) {
+    match tag.tag_type() {
+        // ID3v2.4 and APE keep several values null-separated in one frame
+        TagType::Id3v2 | TagType::Ape if values.len() > 1 => {
+            tag.push(TagItem::new(item_key, ItemValue::Text(values.join("\0"))));
+        }
+        // the others repeat the item, like Vorbis comments and MP4 atoms
+        _ => {
+            for value in values {
+                tag.push(TagItem::new(item_key.clone(), ItemValue::Text(value.clone())));
+            }
+        }
+    }
 }
 
 /// Converts a string to an ItemKey (case-insensitive).
@@ -181,12 +254,13 @@ pub fn get_supported_tags() -> Vec<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use lofty::picture::{MimeType, Picture, PictureType};
 
     #[test]
     fn test_write_tag_to_nonexistent_file() {
         // Writing tags to a non-existent file should return an error
-        let tags = HashMap::from([
-            ("TrackTitle".to_string(), "Test Title".to_string()),
+        let tags = TagMap::from([
+            ("TrackTitle".to_string(), vec!["Test Title".to_string()]),
         ]);
         let result = write_tags_to_file(Path::new("/nonexistent/path/file.mp3"), &tags);
         assert!(result.is_err());
@@ -214,4 +288,52 @@ mod tests {
             _ => panic!("Expected Unknown variant"),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_apply_patch_keeps_binary_items_and_pictures() {
+        let mut tag = Tag::new(TagType::Id3v2);
+        tag.push(TagItem::new(ItemKey::TrackTitle, ItemValue::Text("Old Title".to_string())));
+        tag.push(TagItem::new(ItemKey::Comment, ItemValue::Text("Old Comment".to_string())));
+        tag.push(TagItem::new(ItemKey::Popularimeter, ItemValue::Binary(vec![1, 2, 3])));
+        tag.push_picture(Picture::new_unchecked(PictureType::CoverFront, Some(MimeType::Png), None, vec![4, 5, 6]));
+
+        let patch = TagPatch {
+            set: TagMap::from([("TrackTitle".to_string(), vec!["New Title".to_string()])]),
+            remove: vec!["Comment".to_string()],
+        };
+        apply_patch(&mut tag, &patch);
+
+        assert_eq!(tag.get_string(&ItemKey::TrackTitle), Some("New Title"));
+        assert!(tag.get(&ItemKey::Comment).is_none());
+        assert_eq!(
+            tag.get(&ItemKey::Popularimeter).map(|item| item.value()),
+            Some(&ItemValue::Binary(vec![1, 2, 3]))
+        );
+        assert_eq!(tag.picture_count(), 1);
+    }
+
+    #[test]
+    fn test_apply_patch_keeps_unchanged_items() {
+        let mut tag = Tag::new(TagType::Id3v2);
+        let mut lyrics = TagItem::new(ItemKey::Lyrics, ItemValue::Text("La la la".to_string()));
+        lyrics.set_lang(*b"deu");
+        lyrics.set_description("Chorus".to_string());
+        tag.push(lyrics);
+
+        let same_lyrics = TagPatch {
+            set: TagMap::from([("Lyrics".to_string(), vec!["La la la".to_string()])]),
+            remove: Vec::new(),
+        };
+        apply_patch(&mut tag, &same_lyrics);
+        let item = tag.get(&ItemKey::Lyrics).unwrap();
+        assert_eq!(item.lang(), b"deu");
+        assert_eq!(item.description(), "Chorus");
+
+        let new_lyrics = TagPatch {
+            set: TagMap::from([("Lyrics".to_string(), vec!["Na na na".to_string()])]),
+            remove: Vec::new(),
+        };
+        apply_patch(&mut tag, &new_lyrics);
+        assert_eq!(tag.get_strings(&ItemKey::Lyrics).collect::<Vec<_>>(), vec!["Na na na"]);
+    }
+}
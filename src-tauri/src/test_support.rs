@@ -0,0 +1,80 @@
+//! Helpers shared by the tests of several modules
+
+use crate::tags::multi_value::TagMap;
+use crate::tags::writing_tags::write_tags_to_file;
+use std::f64::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub fn tag_map(entries: &[(&str, &[&str])]) -> TagMap {
+    entries
+        .iter()
+        .map(|(key, values)| (key.to_string(), values.iter().map(|value| value.to_string()).collect()))
+        .collect()
+}
+
+/// Copies `name` from the sample library of different formats to `path`
+pub fn sample_file(name: &str, path: &Path) -> PathBuf {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    fs::copy(Path::new("./tests/music_libraries/different_formats").join(name), path).unwrap();
+    path.to_path_buf()
+}
+
+/// Copies the sample MP3 to `path` and tags it with `tags`
+pub fn tagged_song(path: &Path, tags: &TagMap) -> PathBuf {
+    sample_file("some_song.mp3", path);
+    write_tags_to_file(path, tags).unwrap();
+    path.to_path_buf()
+}
+
+/// Writes a 16 bit WAV file at 44.1 kHz, every channel with the sample of
+/// `signal` at the time in seconds, from -1 to 1
+pub fn write_wav(path: &Path, channels: u16, seconds: u32, signal: impl Fn(f64) -> f64) {
+    let sample_rate: u32 = 44100;
+    let frames = sample_rate * seconds;
+    let block_align = channels as u32 * 2;
+    let data_len = frames * block_align;
+    let mut wav = Vec::new();
+    wav.extend(b"RIFF");
+    wav.extend((36 + data_len).to_le_bytes());
+    wav.extend(b"WAVEfmt ");
+    wav.extend(16u32.to_le_bytes());
+    wav.extend(1u16.to_le_bytes()); // PCM
+    wav.extend(channels.to_le_bytes());
+    wav.extend(sample_rate.to_le_bytes());
+    wav.extend((sample_rate * block_align).to_le_bytes());
+    wav.extend((block_align as u16).to_le_bytes());
+    wav.extend(16u16.to_le_bytes());
+    wav.extend(b"data");
+    wav.extend(data_len.to_le_bytes());
+    for frame in 0..frames {
+        let sample = (signal(frame as f64 / sample_rate as f64) * 32767.0) as i16;
+        for _ in 0..channels {
+            wav.extend(sample.to_le_bytes());
+        }
+    }
+    std::fs::write(path, wav).unwrap();
+}
+
+/// Writes a stereo WAV file with a 1 kHz sine
+pub fn write_sine_wav(path: &Path, amplitude: f64, seconds: u32) {
+    write_wav(path, 2, seconds, |t| amplitude * (2.0 * PI * 1000.0 * t).sin());
+}
+
+/// Writes a mono WAV file of a tone that rises by a semitone every second
+pub fn write_tone_wav(path: &Path, frequency: f64, amplitude: f64, seconds: u32) {
+    write_wav(path, 1, seconds, |t| {
+        let semitones = (t as u32 % 12) as f64;
+        amplitude * (2.0 * PI * frequency * 2f64.powf(semitones / 12.0) * t).sin()
+    });
+}
+
+/// Moves the paused clock of a `start_paused` test and lets the tasks whose
+/// timers fired run
+pub async fn advance(duration: Duration) {
+    tokio::time::advance(duration).await;
+    tokio::task::yield_now().await;
+}
@@ -1,4 +1,5 @@
-import { invoke } from "@tauri-apps/api/core"
+import { convertFileSrc, invoke } from "@tauri-apps/api/core"
+import { listen } from "@tauri-apps/api/event"
 import { type ErrorStore, useErrorStore } from "./errorStore.svelte"
 import {
   type TagEditorStore,
@@ -6,7 +7,10 @@ import {
 } from "./tagEditorStore.svelte.ts"
 import type {
   Library,
+  LibraryChangesDto,
   LibraryDto,
+  ScanFinished,
+  ScanProgress,
   Song,
   SongDto,
 } from "$lib/stores/playerTypes.ts"
@@ -16,11 +20,15 @@ function dto_to_song(dto: SongDto): Song {
     path: dto.path,
     name: dto.name,
     duration_millis: dto.duration_millis,
-    tags: new Map<string, string>(Object.entries(dto.tags)),
-    cover_base64: dto.cover_base64,
+    tags: new Map<string, string[]>(Object.entries(dto.tags)),
   }
 }
 
+// covers are served by the backend's cover:// protocol, scaled down to fit `size` pixels
+export function coverUrl(song: Song, size: number): string {
+  return `${convertFileSrc(song.path, "cover")}?size=${size}`
+}
+
 function dto_to_library(dto: LibraryDto): Library {
   return {
     songs: dto.songs.map(dto_to_song),
@@ -36,11 +44,24 @@ export class PlayerStore {
   searchQuery = $state("")
   positionMillis = $state(0)
   isSeeking = $state(false)
+  // null while no scan is running
+  scanProgress = $state<ScanProgress | null>(null)
 
   private errorStore: ErrorStore = useErrorStore()
   private tagEditorStore: TagEditorStore = useTagEditorStore()
 
-  constructor() {}
+  constructor() {
+    // the backend watches the library folder and reports changed files
+    listen<LibraryChangesDto>("library:changed", (event) =>
+      this.applyLibraryChanges(event.payload),
+    )
+    listen<ScanProgress>("library:scan-progress", (event) => {
+      this.scanProgress = event.payload
+    })
+    listen<ScanFinished>("library:scan-finished", () => {
+      this.scanProgress = null
+    })
+  }
 
   filteredSongs = $derived(
     this.searchQuery.trim() === ""
@@ -69,13 +90,32 @@ export class PlayerStore {
   }
 
   async loadMusicLibrary(libraryPath: string) {
-    const library = (await invoke("get_music_library", {
+    // the cached library comes back right away. The rescan runs in the background
+    // and sends new and changed songs in chunks, together with its errors.
+    const cached = (await invoke("get_music_library", {
       path: libraryPath,
     })) as LibraryDto
-    for (const error of library.errors) {
+    this.library = dto_to_library(cached)
+    await invoke("rescan_music_library", { path: libraryPath })
+  }
+
+  async cancelScan() {
+    await invoke("cancel_library_scan")
+  }
+
+  applyLibraryChanges(changes: LibraryChangesDto) {
+    for (const error of changes.errors) {
       this.errorStore.addError(error)
     }
-    this.library = dto_to_library(library)
+    const removed = new Set(changes.removed)
+    const changed = new Map(
+      changes.changed.map((dto) => [dto.path, dto_to_song(dto)]),
+    )
+    const songs = this.library.songs
+      .filter((song) => !removed.has(song.path))
+      .map((song) => changed.get(song.path) ?? song)
+    songs.push(...changes.added.map(dto_to_song))
+    this.library = { songs, errors: this.library.errors }
   }
 
   async changeVolume(volumeFrom0To1: number) {
@@ -102,7 +142,7 @@ export class PlayerStore {
 
 function matchesSearch(song: Song, query: string): boolean {
   const searchTerm = query.toLowerCase().trim()
-  const searchableFields = song.tags.values().toArray()
+  const searchableFields = song.tags.values().toArray().flat()
   return searchableFields.some((field) =>
     field?.toLowerCase().includes(searchTerm),
   )
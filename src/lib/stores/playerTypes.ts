@@ -2,8 +2,7 @@ export interface Song {
   path: string
   name: string
   duration_millis: number
-  tags: Map<string, string>
-  cover_base64: string | null
+  tags: Map<string, string[]>
 }
 
 export interface Library {
@@ -15,11 +14,36 @@ export interface SongDto {
   path: string
   name: string
   duration_millis: number
-  tags: Record<string, string>
-  cover_base64: string | null
+  tags: Record<string, string[]>
 }
 
 export interface LibraryDto {
   songs: SongDto[]
   errors: string[]
 }
+
+export interface LibraryChangesDto {
+  library_dir: string
+  added: SongDto[]
+  changed: SongDto[]
+  removed: string[]
+  errors: string[]
+}
+
+export interface ScanProgress {
+  library_dir: string
+  found: number
+  to_parse: number
+  parsed: number
+  failed: number
+  current_path: string | null
+}
+
+export interface ScanFinished {
+  library_dir: string
+  found: number
+  parsed: number
+  failed: number
+  removed: number
+  cancelled: boolean
+}
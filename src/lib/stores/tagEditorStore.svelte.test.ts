@@ -1,5 +1,11 @@
 import { describe, it, expect } from 'vitest'
-import { matchesTagName, sortTagFieldsByRelevance, TagField } from './tagEditorStore.svelte.js'
+import {
+  buildTagPatch,
+  matchesTagName,
+  sortTagFieldsByRelevance,
+  TagField,
+  TagStatus
+} from './tagEditorStore.svelte.js'
 
 function createTagField(tagName: string): TagField {
   return new TagField(tagName, `some value for tag ${tagName}`)
@@ -61,3 +67,39 @@ describe('sortTagFieldsByRelevance', () => {
     expect(result[0].tagName).toBe('DuplicateTag')
   })
 })
+
+describe('buildTagPatch', () => {
+  it('leaves untouched tags out, even if their values were split', () => {
+    const fields = [
+      new TagField('Genre', 'Rock'),
+      new TagField('Genre', 'Pop'),
+      new TagField('Artist', 'Someone')
+    ]
+
+    expect(buildTagPatch(fields)).toEqual({ set: {}, remove: [] })
+  })
+
+  it('sends every remaining value of an edited tag', () => {
+    const rock = new TagField('Genre', 'Rock')
+    const pop = new TagField('Genre', 'Pop')
+    pop.updateValue('Jazz')
+
+    expect(buildTagPatch([rock, pop, new TagField('Artist', 'Someone')])).toEqual({
+      set: { Genre: ['Rock', 'Jazz'] },
+      remove: []
+    })
+  })
+
+  it('removes tags without values and renamed tags', () => {
+    const genre = new TagField('Genre', 'Rock')
+    genre.status = TagStatus.REMOVED
+    const title = new TagField('Titel', 'Song')
+    title.updateName('TrackTitle')
+    const added = new TagField('Mood', 'Calm', TagStatus.ADDED)
+
+    expect(buildTagPatch([genre, title, added])).toEqual({
+      set: { TrackTitle: ['Song'], Mood: ['Calm'] },
+      remove: ['Genre', 'Titel']
+    })
+  })
+})
@@ -94,6 +94,39 @@ export function sortTagFieldsByRelevance(
   return [...otherTagFields, ...relevantTagFields.reverse()]
 }
 
+export interface TagPatch {
+  set: Record<string, string[]>
+  remove: string[]
+}
+
+/**
+ * The tags the user edited, added or removed, each with all of its remaining values.
+ * Untouched tags are left out, so the file keeps its own values for them even if
+ * they were split on legacy separators for display.
+ */
+export function buildTagPatch(tagFields: TagField[]): TagPatch {
+  const editedTagNames = new SvelteSet<string>()
+  for (const field of tagFields) {
+    if (field.status === TagStatus.UNCHANGED)
+      continue
+    editedTagNames.add(field.tagName)
+    if (field.status !== TagStatus.ADDED)
+      editedTagNames.add(field.originalName)
+  }
+
+  const set: Record<string, string[]> = {}
+  tagFields
+    .filter((field) => field.status !== TagStatus.REMOVED)
+    // filter blank tags. TODO: replace with validation
+    .filter((field) => field.tagName.trim() !== '')
+    .filter((field) => editedTagNames.has(field.tagName))
+    .forEach((field) => {
+      set[field.tagName] = [...(set[field.tagName] ?? []), field.tagValue]
+    })
+  const remove = [...editedTagNames].filter((tagName) => tagName.trim() !== '' && !(tagName in set))
+  return { set, remove }
+}
+
 export class TagEditorStore {
   private tagFields = $state<TagField[]>([])
 
@@ -167,7 +200,7 @@ export class TagEditorStore {
     }
   }
 
-  setTags(tags: Map<string, string> | undefined) {
+  setTags(tags: Map<string, string[]> | undefined) {
     this.addedTagStore.resetTags()
     if (!tags) {
       this.tagFields = []
@@ -176,8 +209,11 @@ export class TagEditorStore {
 
     const tagFields = []
 
-    for (const [tagName, value] of tags.entries()) {
-      tagFields.push(new TagField(tagName, value))
+    // a field for every value of multi-valued tags like TrackArtists or Genre
+    for (const [tagName, values] of tags.entries()) {
+      for (const value of values) {
+        tagFields.push(new TagField(tagName, value))
+      }
     }
     this.tagFields = tagFields
   }
@@ -190,17 +226,19 @@ export class TagEditorStore {
     this.saveMessage = ''
 
     try {
-      const newTags = new SvelteMap<string, string>(
-        this.sortedTagFields
-          .filter((field) => field.status !== TagStatus.REMOVED)
-          // filter blank tags. TODO: replace with validation
-          .filter((field) => field.tagName.trim() !== '')
-          .map((field) => [field.tagName, field.tagValue])
-      )
-
-      await invoke('write_tags', {
+      const newTags = new SvelteMap<string, string[]>()
+      this.sortedTagFields
+        .filter((field) => field.status !== TagStatus.REMOVED)
+        // filter blank tags. TODO: replace with validation
+        .filter((field) => field.tagName.trim() !== '')
+        .forEach((field) => {
+          newTags.set(field.tagName, [...(newTags.get(field.tagName) ?? []), field.tagValue])
+        })
+
+      // only edited keys are written, everything else in the file stays as it is
+      await invoke('patch_tags', {
         path: song.path,
-        tags: newTags
+        patch: buildTagPatch(this.sortedTagFields)
       })
       song.tags = newTags
       this.setTags(newTags)